@@ -0,0 +1,199 @@
+//! Recording and replaying the HTTP traffic of the test registry server.
+//!
+//! A cassette is a directory holding an `interactions.json` file, which lists
+//! every request served with the response that was sent, and a `bodies`
+//! directory with the response bodies, named by their SHA-256 checksum. Both
+//! are meant to be read by humans: the list is pretty-printed, and the bodies
+//! are stored as they were sent.
+//!
+//! The address of the server is replaced by `{addr}` in what is recorded, and
+//! the other way around when replaying, so a cassette can be replayed by a
+//! server listening on any port.
+
+use crate::paths::CargoPathExt;
+use crate::registry::{Request, Response};
+use cargo_util::Sha256;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+const ADDR_PLACEHOLDER: &str = "{addr}";
+
+/// A request and the response that was sent for it.
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    /// The path of the request URL, with its query if any.
+    path: String,
+    if_modified_since: Option<String>,
+    if_none_match: Option<String>,
+    /// Tokens differ between runs, so only whether one was sent is kept.
+    authorization: bool,
+    request_body_sha256: Option<String>,
+    status: u32,
+    headers: Vec<String>,
+    body_sha256: String,
+}
+
+impl Interaction {
+    fn new(req: &Request, response: &Response, addr: &str) -> Interaction {
+        Interaction {
+            method: req.method.clone(),
+            path: request_path(req),
+            if_modified_since: req.if_modified_since.clone(),
+            if_none_match: req.if_none_match.clone(),
+            authorization: req.authorization.is_some(),
+            request_body_sha256: req.body.as_deref().map(sha256),
+            status: response.code,
+            headers: response
+                .headers
+                .iter()
+                .map(|h| h.replace(addr, ADDR_PLACEHOLDER))
+                .collect(),
+            body_sha256: String::new(),
+        }
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        self.method == req.method
+            && self.path == request_path(req)
+            && self.if_modified_since == req.if_modified_since
+            && self.if_none_match == req.if_none_match
+            && self.authorization == req.authorization.is_some()
+            && self.request_body_sha256 == req.body.as_deref().map(sha256)
+    }
+}
+
+enum Mode {
+    Record,
+    Replay,
+}
+
+/// Records the responses of a server to a cassette, or replays them.
+pub struct Cassette {
+    dir: PathBuf,
+    mode: Mode,
+    interactions: Vec<Interaction>,
+    /// Which of the interactions were already replayed.
+    replayed: Vec<bool>,
+}
+
+impl Cassette {
+    /// Starts recording to the cassette in `dir`, replacing any previous
+    /// recording.
+    pub fn record(dir: &Path) -> Cassette {
+        dir.rm_rf();
+        t!(fs::create_dir_all(dir.join("bodies")));
+        let cassette = Cassette {
+            dir: dir.to_path_buf(),
+            mode: Mode::Record,
+            interactions: Vec::new(),
+            replayed: Vec::new(),
+        };
+        cassette.save();
+        cassette
+    }
+
+    /// Loads the cassette in `dir` to replay it.
+    pub fn replay(dir: &Path) -> Cassette {
+        let data = t!(fs::read(dir.join("interactions.json")));
+        let interactions: Vec<Interaction> = t!(serde_json::from_slice(&data));
+        Cassette {
+            dir: dir.to_path_buf(),
+            mode: Mode::Replay,
+            replayed: vec![false; interactions.len()],
+            interactions,
+        }
+    }
+
+    /// Answers `req`, either with `route` while recording the response, or
+    /// with the recorded response when replaying.
+    ///
+    /// When replaying, the first matching interaction that wasn't replayed
+    /// yet is used, falling back to any matching interaction, so requests
+    /// made in parallel don't have to come in the recorded order. Requests
+    /// that were never recorded get a 404.
+    pub fn respond(
+        &mut self,
+        req: &Request,
+        addr: SocketAddr,
+        route: impl FnOnce() -> Response,
+    ) -> Response {
+        let addr = addr.to_string();
+        match self.mode {
+            Mode::Record => {
+                let response = route();
+                let mut interaction = Interaction::new(req, &response, &addr);
+                let body = replace(&response.body, addr.as_bytes(), ADDR_PLACEHOLDER.as_bytes());
+                interaction.body_sha256 = sha256(&body);
+                t!(fs::write(
+                    self.dir.join("bodies").join(&interaction.body_sha256),
+                    &body
+                ));
+                self.interactions.push(interaction);
+                self.save();
+                response
+            }
+            Mode::Replay => {
+                let found = (0..self.interactions.len())
+                    .filter(|&i| self.interactions[i].matches(req))
+                    .min_by_key(|&i| self.replayed[i]);
+                let Some(i) = found else {
+                    return Response {
+                        code: 404,
+                        headers: vec![],
+                        body: format!("no recorded response for {} {}", req.method, req.url)
+                            .into_bytes(),
+                    };
+                };
+                self.replayed[i] = true;
+                let interaction = &self.interactions[i];
+                let body = t!(fs::read(
+                    self.dir.join("bodies").join(&interaction.body_sha256)
+                ));
+                Response {
+                    code: interaction.status,
+                    headers: interaction
+                        .headers
+                        .iter()
+                        .map(|h| h.replace(ADDR_PLACEHOLDER, &addr))
+                        .collect(),
+                    body: replace(&body, ADDR_PLACEHOLDER.as_bytes(), addr.as_bytes()),
+                }
+            }
+        }
+    }
+
+    fn save(&self) {
+        let data = t!(serde_json::to_vec_pretty(&self.interactions));
+        t!(fs::write(self.dir.join("interactions.json"), data));
+    }
+}
+
+fn request_path(req: &Request) -> String {
+    match req.url.query() {
+        Some(query) => format!("{}?{}", req.url.path(), query),
+        None => req.url.path().to_string(),
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    Sha256::new().update(data).finish_hex()
+}
+
+/// Replaces all occurrences of `from` in `data` with `to`.
+fn replace(data: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.starts_with(from) {
+            out.extend_from_slice(to);
+            rest = &rest[from.len()..];
+        } else {
+            out.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    out
+}
@@ -68,6 +68,7 @@ pub fn panic_error(what: &str, err: impl Into<anyhow::Error>) -> ! {
 
 pub use cargo_test_macro::cargo_test;
 
+pub mod cassette;
 pub mod compare;
 pub mod containers;
 pub mod cross_compile;
@@ -167,6 +167,7 @@ pub(crate) fn create_index_line(
     links: Option<String>,
     rust_version: Option<&str>,
     v: Option<u32>,
+    deprecated: Option<serde_json::Value>,
 ) -> String {
     // This emulates what crates.io does to retain backwards compatibility.
     let (features, features2) = split_index_features(features.clone());
@@ -189,6 +190,9 @@ pub(crate) fn create_index_line(
     if let Some(rust_version) = rust_version {
         json["rust_version"] = serde_json::json!(rust_version);
     }
+    if let Some(deprecated) = deprecated {
+        json["deprecated"] = deprecated;
+    }
 
     json.to_string()
 }
@@ -1,3 +1,4 @@
+use crate::cassette::Cassette;
 use crate::git::repo;
 use crate::paths;
 use crate::publish::{create_index_line, write_to_index};
@@ -8,6 +9,7 @@ use flate2::Compression;
 use pasetors::keys::{AsymmetricPublicKey, AsymmetricSecretKey};
 use pasetors::paserk::FormatAsPaserk;
 use pasetors::token::UntrustedToken;
+use std::cell::RefCell;
 use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::fs::{self, File};
@@ -104,6 +106,8 @@ pub struct RegistryBuilder {
     not_found_handler: RequestCallback,
     /// If nonzero, the git index update to be delayed by the given number of seconds.
     delayed_index_update: usize,
+    /// If set, the HTTP traffic is recorded to or replayed from this cassette.
+    cassette: Option<Cassette>,
 }
 
 pub struct TestRegistry {
@@ -138,6 +142,18 @@ impl TestRegistry {
         }
     }
 
+    /// Changes the `config.json` of the index with `f` and commits it.
+    pub fn edit_config(&self, f: impl FnOnce(&mut serde_json::Value)) {
+        let path = self.path.join("config.json");
+        let mut config: serde_json::Value =
+            t!(serde_json::from_str(&t!(fs::read_to_string(&path))));
+        f(&mut config);
+        t!(fs::write(&path, config.to_string()));
+        let repo = t!(git2::Repository::open(&self.path));
+        crate::git::add(&repo);
+        crate::git::commit(&repo);
+    }
+
     /// Shutdown the server thread and wait for it to stop.
     /// `Drop` automatically stops the server, but this additionally
     /// waits for the thread to stop.
@@ -172,6 +188,7 @@ impl RegistryBuilder {
             custom_responders: HashMap::new(),
             not_found_handler: Box::new(not_found),
             delayed_index_update: 0,
+            cassette: None,
         }
     }
 
@@ -266,6 +283,23 @@ impl RegistryBuilder {
         self
     }
 
+    /// Records all requests to the HTTP server and its responses to a
+    /// cassette in the given directory.
+    #[must_use]
+    pub fn record(mut self, cassette: &Path) -> Self {
+        self.cassette = Some(Cassette::record(cassette));
+        self
+    }
+
+    /// Answers all requests to the HTTP server with the responses recorded
+    /// in the cassette in the given directory, instead of serving the
+    /// registry.
+    #[must_use]
+    pub fn replay(mut self, cassette: &Path) -> Self {
+        self.cassette = Some(Cassette::replay(cassette));
+        self
+    }
+
     /// Initializes the registry.
     #[must_use]
     pub fn build(self) -> TestRegistry {
@@ -299,6 +333,7 @@ impl RegistryBuilder {
                 self.custom_responders,
                 self.not_found_handler,
                 self.delayed_index_update,
+                self.cassette,
             );
             let index_url = if self.http_index {
                 server.index_url()
@@ -504,6 +539,8 @@ pub struct Package {
     rust_version: Option<String>,
     cargo_features: Vec<String>,
     v: Option<u32>,
+    deprecated: Option<(Option<String>, Option<String>)>,
+    package_dir: Option<String>,
 }
 
 pub(crate) type FeatureMap = BTreeMap<String, Vec<String>>;
@@ -526,6 +563,7 @@ pub struct Dependency {
 enum EntryData {
     Regular(String),
     Symlink(PathBuf),
+    Hardlink(PathBuf),
 }
 
 /// A file to be created in a package.
@@ -538,6 +576,9 @@ struct PackageFile {
     /// If `true`, the file is created in the root of the tarfile, used for
     /// testing invalid packages.
     extra: bool,
+    /// If set, the path of the file in the package as raw bytes instead of
+    /// `path`, used for testing paths that are not valid UTF-8.
+    raw_path: Option<Vec<u8>>,
 }
 
 const DEFAULT_MODE: u32 = 0o644;
@@ -595,8 +636,10 @@ pub struct Request {
     pub method: String,
     pub body: Option<Vec<u8>>,
     pub authorization: Option<String>,
+    pub cookie: Option<String>,
     pub if_modified_since: Option<String>,
     pub if_none_match: Option<String>,
+    pub user_agent: Option<String>,
 }
 
 impl fmt::Debug for Request {
@@ -606,8 +649,10 @@ impl fmt::Debug for Request {
             .field("url", &self.url)
             .field("method", &self.method)
             .field("authorization", &self.authorization)
+            .field("cookie", &self.cookie)
             .field("if_modified_since", &self.if_modified_since)
             .field("if_none_match", &self.if_none_match)
+            .field("user_agent", &self.user_agent)
             .finish()
     }
 }
@@ -630,6 +675,7 @@ pub struct HttpServer {
     custom_responders: HashMap<String, RequestCallback>,
     not_found_handler: RequestCallback,
     delayed_index_update: usize,
+    cassette: Option<RefCell<Cassette>>,
 }
 
 /// A helper struct that collects the arguments for [`HttpServer::check_authorized`].
@@ -651,6 +697,7 @@ impl HttpServer {
         custom_responders: HashMap<String, RequestCallback>,
         not_found_handler: RequestCallback,
         delayed_index_update: usize,
+        cassette: Option<Cassette>,
     ) -> HttpServerHandle {
         let listener = TcpListener::bind("127.0.0.1:0").unwrap();
         let addr = listener.local_addr().unwrap();
@@ -665,6 +712,7 @@ impl HttpServer {
             custom_responders,
             not_found_handler,
             delayed_index_update,
+            cassette: cassette.map(RefCell::new),
         };
         let handle = Some(thread::spawn(move || server.start()));
         HttpServerHandle { addr, handle }
@@ -699,6 +747,8 @@ impl HttpServer {
             let mut if_modified_since = None;
             let mut if_none_match = None;
             let mut authorization = None;
+            let mut cookie = None;
+            let mut user_agent = None;
             let mut content_len = None;
             loop {
                 line.clear();
@@ -717,6 +767,8 @@ impl HttpServer {
                     "if-modified-since" => if_modified_since = Some(value),
                     "if-none-match" => if_none_match = Some(value),
                     "authorization" => authorization = Some(value),
+                    "cookie" => cookie = Some(value),
+                    "user-agent" => user_agent = Some(value),
                     "content-length" => content_len = Some(value),
                     _ => {}
                 }
@@ -732,14 +784,21 @@ impl HttpServer {
 
             let req = Request {
                 authorization,
+                cookie,
                 if_modified_since,
                 if_none_match,
+                user_agent,
                 method,
                 url,
                 body,
             };
             println!("req: {:#?}", req);
-            let response = self.route(&req);
+            let response = match &self.cassette {
+                Some(cassette) => cassette
+                    .borrow_mut()
+                    .respond(&req, self.addr, || self.route(&req)),
+                None => self.route(&req),
+            };
             let buf = buf.get_mut();
             write!(buf, "HTTP/1.1 {}\r\n", response.code).unwrap();
             write!(buf, "Content-Length: {}\r\n", response.body.len()).unwrap();
@@ -1148,6 +1207,7 @@ fn save_new_crate(
         new_crate.links,
         None,
         None,
+        None,
     );
 
     write_to_index(registry_path, &new_crate.name, line, false);
@@ -1176,6 +1236,8 @@ impl Package {
             rust_version: None,
             cargo_features: Vec::new(),
             v: None,
+            deprecated: None,
+            package_dir: None,
         }
     }
 
@@ -1213,6 +1275,7 @@ impl Package {
             contents: EntryData::Regular(contents.into()),
             mode,
             extra: false,
+            raw_path: None,
         });
         self
     }
@@ -1224,6 +1287,22 @@ impl Package {
             contents: EntryData::Symlink(src.into()),
             mode: DEFAULT_MODE,
             extra: false,
+            raw_path: None,
+        });
+        self
+    }
+
+    /// Adds a hardlink to the package.
+    ///
+    /// Unlike with symlinks, `src` is the path within the `.crate` file,
+    /// such as `foo-0.1.0/src/lib.rs`.
+    pub fn hardlink(&mut self, dst: &str, src: &str) -> &mut Package {
+        self.files.push(PackageFile {
+            path: dst.to_string(),
+            contents: EntryData::Hardlink(src.into()),
+            mode: DEFAULT_MODE,
+            extra: false,
+            raw_path: None,
         });
         self
     }
@@ -1239,6 +1318,20 @@ impl Package {
             contents: EntryData::Regular(contents.to_string()),
             mode: DEFAULT_MODE,
             extra: true,
+            raw_path: None,
+        });
+        self
+    }
+
+    /// Adds a file whose path in the package is given as raw bytes, which
+    /// need not be valid UTF-8.
+    pub fn file_with_raw_path(&mut self, path: &[u8], contents: &str) -> &mut Package {
+        self.files.push(PackageFile {
+            path: String::from_utf8_lossy(path).into_owned(),
+            contents: EntryData::Regular(contents.to_string()),
+            mode: DEFAULT_MODE,
+            extra: false,
+            raw_path: Some(path.to_vec()),
         });
         self
     }
@@ -1348,6 +1441,21 @@ impl Package {
         self
     }
 
+    /// Marks the package as deprecated by the registry, with an optional
+    /// reason and suggested replacement crate.
+    pub fn deprecated(&mut self, reason: Option<&str>, replacement: Option<&str>) -> &mut Package {
+        self.deprecated = Some((reason.map(String::from), replacement.map(String::from)));
+        self
+    }
+
+    /// Places the files of the package in the `.crate` file in a directory
+    /// named `dir` instead of `$PACKAGE-$VERSION`, typically for testing
+    /// invalid behavior.
+    pub fn package_dir(&mut self, dir: &str) -> &mut Package {
+        self.package_dir = Some(dir.to_string());
+        self
+    }
+
     /// Creates the package and place it in the registry.
     ///
     /// This does not actually use Cargo's publishing system, but instead
@@ -1406,6 +1514,12 @@ impl Package {
             self.links.clone(),
             self.rust_version.as_deref(),
             self.v,
+            self.deprecated.as_ref().map(|(reason, replacement)| {
+                serde_json::json!({
+                    "reason": reason,
+                    "replacement": replacement,
+                })
+            }),
         );
 
         let registry_path = if self.alternative {
@@ -1445,9 +1559,14 @@ impl Package {
                 contents,
                 mode,
                 extra,
+                raw_path,
             } in &self.files
             {
-                if *extra {
+                if let Some(raw_path) = raw_path {
+                    let mut full_path = format!("{}/", self.dir()).into_bytes();
+                    full_path.extend_from_slice(raw_path);
+                    self.append_raw_bytes(&mut a, &full_path, *mode, contents);
+                } else if *extra {
                     self.append_raw(&mut a, path, *mode, contents);
                 } else {
                     self.append(&mut a, path, *mode, contents);
@@ -1524,12 +1643,15 @@ impl Package {
     }
 
     fn append<W: Write>(&self, ar: &mut Builder<W>, file: &str, mode: u32, contents: &EntryData) {
-        self.append_raw(
-            ar,
-            &format!("{}-{}/{}", self.name, self.vers, file),
-            mode,
-            contents,
-        );
+        self.append_raw(ar, &format!("{}/{}", self.dir(), file), mode, contents);
+    }
+
+    /// The directory the files of the package are in in the `.crate` file.
+    fn dir(&self) -> String {
+        match &self.package_dir {
+            Some(dir) => dir.clone(),
+            None => format!("{}-{}", self.name, self.vers),
+        }
     }
 
     fn append_raw<W: Write>(
@@ -1547,6 +1669,11 @@ impl Package {
                 t!(header.set_link_name(src));
                 "" // Symlink has no contents.
             }
+            EntryData::Hardlink(src) => {
+                header.set_entry_type(tar::EntryType::Link);
+                t!(header.set_link_name(src));
+                "" // Hardlink has no contents.
+            }
         };
         header.set_size(contents.len() as u64);
         t!(header.set_path(path));
@@ -1555,6 +1682,27 @@ impl Package {
         t!(ar.append(&header, contents.as_bytes()));
     }
 
+    /// Like `append_raw`, but with a path that need not be valid UTF-8.
+    fn append_raw_bytes<W: Write>(
+        &self,
+        ar: &mut Builder<W>,
+        path: &[u8],
+        mode: u32,
+        contents: &EntryData,
+    ) {
+        let EntryData::Regular(contents) = contents else {
+            panic!("only regular files may have raw paths");
+        };
+        let mut header = Header::new_ustar();
+        let name = &mut header.as_old_mut().name;
+        assert!(path.len() <= name.len(), "raw path too long");
+        name[..path.len()].copy_from_slice(path);
+        header.set_size(contents.len() as u64);
+        header.set_mode(mode);
+        header.set_cksum();
+        t!(ar.append(&header, contents.as_bytes()));
+    }
+
     /// Returns the path to the compressed package file.
     pub fn archive_dst(&self) -> PathBuf {
         if self.local {
@@ -38,6 +38,21 @@ pub struct Crate {
     pub max_version: String,
 }
 
+/// Metadata of a crate as reported by the registry API.
+#[derive(Deserialize, Debug)]
+pub struct CrateInfo {
+    pub name: String,
+    pub description: Option<String>,
+    pub homepage: Option<String>,
+    pub documentation: Option<String>,
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    pub max_version: String,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct NewCrate {
     pub name: String,
@@ -116,6 +131,11 @@ struct Users {
     users: Vec<User>,
 }
 #[derive(Deserialize)]
+struct CrateResponse {
+    #[serde(rename = "crate")]
+    krate: CrateInfo,
+}
+#[derive(Deserialize)]
 struct TotalCrates {
     total: u32,
 }
@@ -362,6 +382,12 @@ impl Registry {
         Ok((crates.crates, crates.meta.total))
     }
 
+    pub fn crate_info(&mut self, krate: &str) -> Result<CrateInfo> {
+        self.handle.get(true)?;
+        let body = self.req(&format!("/crates/{}", krate), None, Auth::Unauthorized)?;
+        Ok(serde_json::from_str::<CrateResponse>(&body)?.krate)
+    }
+
     pub fn yank(&mut self, krate: &str, version: &str) -> Result<()> {
         let body = self.delete(&format!("/crates/{}/{}/yank", krate, version), None)?;
         assert!(serde_json::from_str::<R>(&body)?.ok);
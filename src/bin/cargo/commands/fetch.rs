@@ -1,5 +1,6 @@
 use crate::command_prelude::*;
 
+use cargo::core::dependency::DepKind;
 use cargo::ops;
 use cargo::ops::FetchOptions;
 
@@ -9,15 +10,40 @@ pub fn cli() -> Command {
         .arg_quiet()
         .arg_manifest_path()
         .arg_target_triple("Fetch dependencies for the target triple")
+        .arg(
+            multi_opt(
+                "dep-kind",
+                "KIND",
+                "Only fetch dependencies of the given kind (unstable)",
+            )
+            .value_parser(["normal", "build", "dev"]),
+        )
         .after_help("Run `cargo help fetch` for more detailed information.\n")
 }
 
 pub fn exec(config: &mut Config, args: &ArgMatches) -> CliResult {
     let ws = args.workspace(config)?;
 
+    let dep_kinds = values(args, "dep-kind");
+    if !dep_kinds.is_empty() {
+        config
+            .cli_unstable()
+            .fail_if_stable_opt("--dep-kind", 5133)?;
+    }
+    let dep_kinds = dep_kinds
+        .iter()
+        .map(|kind| match kind.as_str() {
+            "normal" => DepKind::Normal,
+            "build" => DepKind::Build,
+            "dev" => DepKind::Development,
+            _ => unreachable!("clap should make this impossible"),
+        })
+        .collect();
+
     let opts = FetchOptions {
         config,
         targets: args.targets(),
+        dep_kinds,
     };
     let _ = ops::fetch(&ws, &opts)?;
     Ok(())
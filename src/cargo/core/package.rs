@@ -1,6 +1,6 @@
 use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::hash;
 use std::mem;
@@ -15,7 +15,8 @@ use curl::multi::{EasyHandle, Multi};
 use lazycell::LazyCell;
 use log::{debug, warn};
 use semver::Version;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use url::Url;
 
 use crate::core::compiler::{CompileKind, RustcTargetData};
 use crate::core::dependency::DepKind;
@@ -25,9 +26,13 @@ use crate::core::source::MaybePackage;
 use crate::core::{Dependency, Manifest, PackageId, SourceId, Target};
 use crate::core::{SourceMap, Summary, Workspace};
 use crate::ops;
+use crate::util::auth;
 use crate::util::config::PackageCacheLock;
-use crate::util::errors::{CargoResult, HttpNotSuccessful, DEBUG_HEADERS};
+use crate::util::errors::{
+    CargoResult, DownloadRetry, HttpNotSuccessful, NotATarball, DEBUG_HEADERS,
+};
 use crate::util::interning::InternedString;
+use crate::util::network;
 use crate::util::network::retry::{Retry, RetryResult};
 use crate::util::network::sleep::SleepTracker;
 use crate::util::{self, internal, Config, Progress, ProgressStyle};
@@ -308,6 +313,9 @@ pub struct PackageSet<'cfg> {
     downloading: Cell<bool>,
     /// Whether or not to use curl HTTP/2 multiplexing.
     multiplexing: bool,
+    /// The URL each package was downloaded from, after redirects, if enabled
+    /// with [`PackageSet::record_download_endpoints`].
+    download_endpoints: Option<RefCell<HashMap<PackageId, String>>>,
 }
 
 /// Helper for downloading crates.
@@ -322,6 +330,12 @@ pub struct Downloads<'a, 'cfg> {
     pending_ids: HashSet<PackageId>,
     /// Downloads that have failed and are waiting to retry again later.
     sleeping: SleepTracker<(Download<'cfg>, Easy)>,
+    /// Number of downloads started per source that limits how many it
+    /// serves at the same time, including the ones waiting to retry.
+    in_flight: HashMap<SourceId, usize>,
+    /// Downloads waiting for another download of the same source to finish,
+    /// because that source is at its limit.
+    throttled: HashMap<SourceId, VecDeque<(Download<'cfg>, Easy)>>,
     /// The final result of each download. A pair `(token, result)`. This is a
     /// temporary holding area, needed because curl can report multiple
     /// downloads at once, but the main loop (`wait`) is written to only
@@ -333,6 +347,9 @@ pub struct Downloads<'a, 'cfg> {
     progress: RefCell<Option<Progress<'cfg>>>,
     /// Number of downloads that have successfully finished.
     downloads_finished: usize,
+    /// Whether `Downloading crates ...` was printed, which is done once
+    /// when the first download starts if there's no progress bar.
+    status_printed: bool,
     /// Total bytes for all successfully downloaded packages.
     downloaded_bytes: u64,
     /// Size (in bytes) and package name of the largest downloaded package.
@@ -363,6 +380,28 @@ pub struct Downloads<'a, 'cfg> {
     /// trigger a timeout; reset `next_speed_check` and set this back to the
     /// configured threshold.
     next_speed_check_bytes_threshold: Cell<u64>,
+    /// Hosts downloads may be redirected to besides the host of their own
+    /// URL, from `registry.allowed-redirect-hosts`. If this is None, any
+    /// redirect is followed.
+    allowed_redirect_hosts: Option<Vec<String>>,
+    /// The total size of the downloads above which to warn, from
+    /// `registry.download-size-warning`. It's taken when the downloads
+    /// started so far are checked before the first one is waited on.
+    download_size_warning: Option<u64>,
+    /// The size above which a single download is aborted, from
+    /// `http.max-download-size`.
+    max_download_size: Option<u64>,
+    /// The order of downloads, from `registry.download-order`.
+    order: DownloadOrder,
+    /// What to do with downloads that are HTML pages, from
+    /// `registry.non-tarball-downloads`.
+    non_tarball: NonTarballPolicy,
+    /// Packages whose download was started again after their session was
+    /// rejected, which only happens once per package.
+    session_retried: HashSet<PackageId>,
+    /// With [`DownloadOrder::Sorted`], the descriptors of the finished
+    /// downloads, which are only reported once all of them are done.
+    finished: Vec<(PackageId, String)>,
     /// Global filesystem lock to ensure only one Cargo is downloading at a
     /// time.
     _lock: PackageCacheLock<'cfg>,
@@ -399,6 +438,31 @@ struct Download<'cfg> {
 
     /// Logic used to track retrying this download if it's a spurious failure.
     retry: Retry<'cfg>,
+
+    /// Whether to point at HTTP basic auth credentials if the server rejects
+    /// the request as unauthorized, that is unless the registry uses tokens.
+    hint_basic_auth: bool,
+
+    /// The session cookie sent with the request, if the registry uses
+    /// sessions.
+    cookie: Option<String>,
+
+    /// Size of the `.crate` file listed in the index, if any. Transfers that
+    /// turn out to have a different size are aborted early.
+    size: Option<u64>,
+    /// Whether the status of the last response seen is `200`, or no status
+    /// was seen at all, like for `file://` URLs.
+    successful_response: Cell<bool>,
+    /// Why Cargo aborted the transfer, if it did, for example because of its
+    /// size.
+    rejected: Cell<Option<String>>,
+    /// Number of bytes at the start of `data` that earlier attempts received,
+    /// which this one asks the server to skip with a `Range` header.
+    resume_from: Cell<usize>,
+
+    /// The most downloads the source of this package serves at the same
+    /// time, if it has a limit.
+    max_concurrent: Option<usize>,
 }
 
 impl<'cfg> PackageSet<'cfg> {
@@ -428,9 +492,30 @@ impl<'cfg> PackageSet<'cfg> {
             multi,
             downloading: Cell::new(false),
             multiplexing,
+            download_endpoints: None,
         })
     }
 
+    /// Starts recording the URL each package is downloaded from, for
+    /// [`PackageSet::download_endpoint`].
+    ///
+    /// This is off by default, as few callers need it.
+    pub fn record_download_endpoints(&mut self) {
+        self.download_endpoints.get_or_insert_with(Default::default);
+    }
+
+    /// The URL the package `id` was downloaded from, which is the one curl
+    /// ended up at after following redirects, from whichever mirror the
+    /// source picked.
+    ///
+    /// Returns `None` if the package wasn't downloaded, or downloads weren't
+    /// recorded with [`PackageSet::record_download_endpoints`].
+    pub fn download_endpoint(&self, id: PackageId) -> Option<String> {
+        self.download_endpoints
+            .as_ref()
+            .and_then(|endpoints| endpoints.borrow().get(&id).cloned())
+    }
+
     pub fn package_ids(&self) -> impl Iterator<Item = PackageId> + '_ {
         self.packages.keys().cloned()
     }
@@ -449,6 +534,8 @@ impl<'cfg> PackageSet<'cfg> {
             pending: HashMap::new(),
             pending_ids: HashSet::new(),
             sleeping: SleepTracker::new(),
+            in_flight: HashMap::new(),
+            throttled: HashMap::new(),
             results: Vec::new(),
             progress: RefCell::new(Some(Progress::with_style(
                 "Downloading",
@@ -456,6 +543,7 @@ impl<'cfg> PackageSet<'cfg> {
                 self.config,
             ))),
             downloads_finished: 0,
+            status_printed: false,
             downloaded_bytes: 0,
             largest: (0, String::new()),
             success: false,
@@ -463,6 +551,21 @@ impl<'cfg> PackageSet<'cfg> {
             timeout,
             next_speed_check: Cell::new(Instant::now()),
             next_speed_check_bytes_threshold: Cell::new(0),
+            allowed_redirect_hosts: self
+                .config
+                .get::<Option<Vec<String>>>("registry.allowed-redirect-hosts")?,
+            download_size_warning: config_size(self.config, "registry.download-size-warning")?,
+            max_download_size: config_size(self.config, "http.max-download-size")?,
+            order: self
+                .config
+                .get::<Option<DownloadOrder>>("registry.download-order")?
+                .unwrap_or(DownloadOrder::Insertion),
+            non_tarball: self
+                .config
+                .get::<Option<NonTarballPolicy>>("registry.non-tarball-downloads")?
+                .unwrap_or(NonTarballPolicy::Reject),
+            finished: Vec::new(),
+            session_retried: HashSet::new(),
             _lock: self.config.acquire_package_cache_lock()?,
         })
     }
@@ -477,11 +580,35 @@ impl<'cfg> PackageSet<'cfg> {
     pub fn get_many(&self, ids: impl IntoIterator<Item = PackageId>) -> CargoResult<Vec<&Package>> {
         let mut pkgs = Vec::new();
         let mut downloads = self.enable_download()?;
+        let mut ids = ids.into_iter().collect::<Vec<_>>();
+        if downloads.order == DownloadOrder::Sorted {
+            ids.sort_by(|a, b| (a.name(), a.version()).cmp(&(b.name(), b.version())));
+        }
         for id in ids {
             pkgs.extend(downloads.start(id)?);
         }
+        // A failed download doesn't stop the others, so that all failures
+        // are reported at once instead of one per run.
+        let mut errors = Vec::new();
         while downloads.remaining() > 0 {
-            pkgs.push(downloads.wait()?);
+            let remaining = downloads.remaining();
+            match downloads.wait() {
+                Ok(pkg) => pkgs.push(pkg),
+                Err(e) => {
+                    errors.push(e);
+                    // Nothing finished, so waiting again wouldn't either.
+                    if downloads.remaining() >= remaining {
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(last) = errors.pop() {
+            let mut shell = self.config.shell();
+            for e in &errors {
+                crate::display_error(e, &mut shell);
+            }
+            return Err(last);
         }
         downloads.success = true;
         Ok(pkgs)
@@ -652,6 +779,9 @@ impl<'cfg> PackageSet<'cfg> {
         for (pkg_id, p_cell) in set.packages {
             self.packages.entry(pkg_id).or_insert(p_cell);
         }
+        if let (Some(endpoints), Some(other)) = (&self.download_endpoints, set.download_endpoints) {
+            endpoints.borrow_mut().extend(other.into_inner());
+        }
         let mut sources = self.sources.borrow_mut();
         let other_sources = set.sources.into_inner();
         sources.add_source_map(other_sources);
@@ -691,18 +821,39 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
         let pkg = source
             .download(id)
             .with_context(|| "unable to get packages from source")?;
-        let (url, descriptor, authorization) = match pkg {
-            MaybePackage::Ready(pkg) => {
-                debug!("{} doesn't need a download", id);
-                assert!(slot.fill(pkg).is_ok());
-                return Ok(Some(slot.borrow().unwrap()));
-            }
-            MaybePackage::Download {
-                url,
-                descriptor,
-                authorization,
-            } => (url, descriptor, authorization),
-        };
+        let (url, descriptor, authorization, cookie, size, max_concurrent, hint_basic_auth) =
+            match pkg {
+                MaybePackage::Ready(pkg) => {
+                    debug!("{} doesn't need a download", id);
+                    assert!(slot.fill(pkg).is_ok());
+                    return Ok(Some(slot.borrow().unwrap()));
+                }
+                MaybePackage::Download {
+                    url,
+                    descriptor,
+                    authorization,
+                    cookie,
+                    size,
+                    max_concurrent,
+                    hint_basic_auth,
+                } => (
+                    url,
+                    descriptor,
+                    authorization,
+                    cookie,
+                    size,
+                    max_concurrent,
+                    hint_basic_auth,
+                ),
+            };
+
+        if self.set.config.offline() {
+            anyhow::bail!(
+                "`{}` is not in the download cache and cannot be downloaded \
+                 because --offline was specified",
+                id
+            );
+        }
 
         // Ok we're going to download this crate, so let's set up all our
         // internal state and hand off an `Easy` handle to our libcurl `Multi`
@@ -724,6 +875,9 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
             headers.append(&format!("Authorization: {}", authorization))?;
             handle.http_headers(headers)?;
         }
+        if let Some(cookie) = &cookie {
+            handle.cookie(cookie)?;
+        }
 
         // Enable HTTP/2 to be used as it'll allow true multiplexing which makes
         // downloads much faster.
@@ -756,27 +910,96 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
             debug!("{} - {} bytes of data", token, buf.len());
             tls::with(|downloads| {
                 if let Some(downloads) = downloads {
-                    downloads.pending[&token]
-                        .0
-                        .data
-                        .borrow_mut()
-                        .extend_from_slice(buf);
+                    let dl = &downloads.pending[&token].0;
+                    let mut data = dl.data.borrow_mut();
+                    if let Some(max) = downloads.max_download_size {
+                        if (data.len() + buf.len()) as u64 > max {
+                            dl.rejected.set(Some(format!(
+                                "`{}` is larger than the {} of `http.max-download-size`",
+                                dl.id,
+                                ByteSize(max)
+                            )));
+                            return Ok(0);
+                        }
+                    }
+                    // Error pages are kept whole, to report the actual
+                    // error instead of their size.
+                    if let Some(size) = dl.size.filter(|_| dl.successful_response.get()) {
+                        if (data.len() + buf.len()) as u64 > size {
+                            // Returning less than was handed to us aborts the
+                            // transfer.
+                            dl.rejected.set(Some(format!(
+                                "received more than the {} bytes the index lists for `{}`",
+                                size, dl.id
+                            )));
+                            return Ok(0);
+                        }
+                    }
+                    data.extend_from_slice(buf);
                 }
-            });
-            Ok(buf.len())
+                Ok(buf.len())
+            })
         })?;
         handle.header_function(move |data| {
             tls::with(|downloads| {
                 if let Some(downloads) = downloads {
+                    let dl = &downloads.pending[&token].0;
                     // Headers contain trailing \r\n, trim them to make it easier
                     // to work with.
                     let h = String::from_utf8_lossy(data).trim().to_string();
+                    if h.starts_with("HTTP/") {
+                        let status = h.split_whitespace().nth(1);
+                        if status == Some("200") && dl.resume_from.get() > 0 {
+                            // The server ignored the `Range` header and sends
+                            // the whole file again, so start over.
+                            debug!("{} ignored the range, restarting", dl.url);
+                            dl.data.borrow_mut().clear();
+                            dl.resume_from.set(0);
+                        }
+                        // Only the length of a successful response is the
+                        // length of the `.crate` file, not that of redirects
+                        // or error pages.
+                        dl.successful_response.set(
+                            status == Some("200")
+                                || (status == Some("206") && dl.resume_from.get() > 0),
+                        );
+                    } else if let Some(location) = header_value(&h, "location") {
+                        if let Err(msg) = downloads.check_redirect(dl, location) {
+                            dl.rejected.set(Some(msg));
+                            return false;
+                        }
+                    } else if let Some(len) =
+                        content_length(&h).filter(|_| dl.successful_response.get())
+                    {
+                        let resumed = dl.resume_from.get() as u64;
+                        // Fails early, instead of once that much was received.
+                        if let Some(max) = downloads.max_download_size {
+                            if len + resumed > max {
+                                dl.rejected.set(Some(format!(
+                                    "`{}` is larger than the {} of `http.max-download-size`",
+                                    dl.id,
+                                    ByteSize(max)
+                                )));
+                                return false;
+                            }
+                        }
+                        if let Some(size) = dl.size {
+                            if len != size.saturating_sub(resumed) {
+                                dl.rejected.set(Some(format!(
+                                    "the server sends {} bytes for `{}`, but the index lists \
+                                     {} bytes",
+                                    len, dl.id, size
+                                )));
+                                return false;
+                            }
+                        }
+                    }
                     if DEBUG_HEADERS.iter().any(|p| h.starts_with(p)) {
-                        downloads.pending[&token].0.headers.borrow_mut().push(h);
+                        dl.headers.borrow_mut().push(h);
                     }
                 }
-            });
-            true
+                true
+            })
         })?;
 
         handle.progress(true)?;
@@ -790,14 +1013,12 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
         // If the progress bar isn't enabled then it may be awhile before the
         // first crate finishes downloading so we inform immediately that we're
         // downloading crates here.
-        if self.downloads_finished == 0
-            && self.pending.is_empty()
-            && !self.progress.borrow().as_ref().unwrap().is_enabled()
-        {
+        if !self.status_printed && !self.progress.borrow().as_ref().unwrap().is_enabled() {
             self.set
                 .config
                 .shell()
                 .status("Downloading", "crates ...")?;
+            self.status_printed = true;
         }
 
         let dl = Download {
@@ -812,7 +1033,26 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
             start: Instant::now(),
             timed_out: Cell::new(None),
             retry: Retry::new(self.set.config)?,
+            hint_basic_auth,
+            cookie,
+            size,
+            successful_response: Cell::new(true),
+            rejected: Cell::new(None),
+            resume_from: Cell::new(0),
+            max_concurrent,
         };
+        if let Some(max) = max_concurrent {
+            let source_id = id.source_id();
+            let in_flight = self.in_flight.entry(source_id).or_insert(0);
+            if *in_flight >= max {
+                debug!("throttling download of {} as {}", id, token);
+                self.pending_ids.remove(&id);
+                let throttled = self.throttled.entry(source_id).or_default();
+                throttled.push_back((dl, handle));
+                return Ok(None);
+            }
+            *in_flight += 1;
+        }
         self.enqueue(dl, handle)?;
         self.tick(WhyTick::DownloadStarted)?;
 
@@ -821,7 +1061,9 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
 
     /// Returns the number of crates that are still downloading.
     pub fn remaining(&self) -> usize {
-        self.pending.len() + self.sleeping.len()
+        self.pending.len()
+            + self.sleeping.len()
+            + self.throttled.values().map(VecDeque::len).sum::<usize>()
     }
 
     /// Blocks the current thread waiting for a package to finish downloading.
@@ -833,7 +1075,10 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
     ///
     /// This function will panic if there are no remaining downloads.
     pub fn wait(&mut self) -> CargoResult<&'a Package> {
-        let (dl, data) = loop {
+        if let Some(threshold) = self.download_size_warning.take() {
+            self.warn_download_size(threshold)?;
+        }
+        let (dl, data, effective_url) = loop {
             assert_eq!(self.pending.len(), self.pending_ids.len());
             let (token, result) = self.wait_for_curl()?;
             debug!("{} finished with {:?}", token, result);
@@ -842,18 +1087,38 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
                 .pending
                 .remove(&token)
                 .expect("got a token for a non-in-progress transfer");
-            let data = mem::take(&mut *dl.data.borrow_mut());
+            let mut data = mem::take(&mut *dl.data.borrow_mut());
             let headers = mem::take(&mut *dl.headers.borrow_mut());
+            // The part of the `.crate` file received before a failed
+            // transfer, to resume from if it's retried.
+            let resume_from = dl.resume_from.get();
+            let partial = match &result {
+                Err(_) if dl.successful_response.get() => data.clone(),
+                _ => data[..resume_from.min(data.len())].to_vec(),
+            };
             let mut handle = self.set.multi.remove(handle)?;
             self.pending_ids.remove(&dl.id);
+            let effective_url = match self.set.download_endpoints {
+                Some(_) => handle.effective_url()?.map(str::to_string),
+                None => None,
+            };
 
             // Check if this was a spurious error. If it was a spurious error
             // then we want to re-enqueue our request for another attempt and
             // then we wait for another request to finish.
             let ret = {
                 let timed_out = &dl.timed_out;
+                let rejected = &dl.rejected;
+                let id = dl.id;
                 let url = &dl.url;
+                let config = self.set.config;
+                let non_tarball = self.non_tarball;
                 dl.retry.r#try(|| {
+                    // A download of the wrong size or redirected to a host
+                    // that isn't allowed isn't worth retrying.
+                    if let Some(msg) = rejected.take() {
+                        anyhow::bail!(msg);
+                    }
                     if let Err(e) = result {
                         // If this error is "aborted by callback" then that's
                         // probably because our progress callback aborted due to
@@ -862,8 +1127,12 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
                         // If one is found we switch the error code (to ensure
                         // it's flagged as spurious) and then attach our extra
                         // information to the error.
+                        if e.is_operation_timedout() {
+                            let e = anyhow::Error::from(e);
+                            return Err(e.context(format!("download of `{}` timed out", id)));
+                        }
                         if !e.is_aborted_by_callback() {
-                            return Err(e.into());
+                            return Err(network::with_tls_context(e, config));
                         }
 
                         return Err(match timed_out.replace(None) {
@@ -879,7 +1148,10 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
                     }
 
                     let code = handle.response_code()?;
-                    if code != 200 && code != 0 {
+                    let resumed = code == 206 && resume_from > 0;
+                    if code != 200 && code != 0 && !resumed {
+                        // Only show the error page, not what came before.
+                        data.drain(..resume_from.min(data.len()));
                         return Err(HttpNotSuccessful::new_from_handle(
                             &mut handle,
                             &url,
@@ -888,16 +1160,93 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
                         )
                         .into());
                     }
+                    // Such pages would otherwise only fail the checksum or
+                    // unpacking, which doesn't say what went wrong.
+                    if looks_like_html(&data) {
+                        return Err(NotATarball {
+                            url: url.clone(),
+                            content_type: handle.content_type()?.map(str::to_string),
+                            spurious: non_tarball == NonTarballPolicy::Retry,
+                        }
+                        .into());
+                    }
                     Ok(data)
                 })
             };
+            // Anything but a retry ends this download, whether it's done,
+            // failed or started over, so it frees its slot for the next one.
+            if dl.max_concurrent.is_some() && !matches!(ret, RetryResult::Retry(_)) {
+                self.start_throttled(dl.id.source_id())?;
+            }
             match ret {
-                RetryResult::Success(data) => break (dl, data),
+                RetryResult::Success(data) => break (dl, data, effective_url),
                 RetryResult::Err(e) => {
-                    return Err(e.context(format!("failed to download from `{}`", dl.url)))
+                    let code = e.downcast_ref::<HttpNotSuccessful>().map(|e| e.code);
+                    let unauthorized = code == Some(401);
+                    if let Some(cookie) = dl.cookie.as_deref().filter(|_| unauthorized) {
+                        if self.session_retried.insert(dl.id) {
+                            // The session likely expired, so log in again.
+                            debug!("session of {} rejected, logging in again", dl.url);
+                            auth::clear_session_cookie(self.set.config, &dl.id.source_id(), cookie);
+                            if let Some(pkg) = self.start_inner(dl.id)? {
+                                return Ok(pkg);
+                            }
+                            continue;
+                        }
+                    }
+                    // The source may hand out another URL to try, like the
+                    // next of the download mirrors.
+                    let mut sources = self.set.sources.borrow_mut();
+                    let source = sources
+                        .get_mut(dl.id.source_id())
+                        .ok_or_else(|| internal(format!("couldn't find source for `{}`", dl.id)))?;
+                    if source.download_failed(dl.id, &e)? {
+                        drop(sources);
+                        if let Some(pkg) = self.start_inner(dl.id)? {
+                            return Ok(pkg);
+                        }
+                        continue;
+                    }
+                    drop(sources);
+                    let e = if unauthorized && dl.cookie.is_some() {
+                        e.context("the server rejected the session even after logging in again")
+                    } else if unauthorized && dl.hint_basic_auth {
+                        e.context(
+                            "the server requires authentication, the HTTP basic auth \
+                             credentials for this registry may be missing or wrong\n\
+                             set `registries.<name>.username` and \
+                             `registries.<name>.password` to authenticate",
+                        )
+                    } else if code == Some(404) {
+                        // Server errors are retried, but this won't get better
+                        // by trying again.
+                        e.context(format!(
+                            "`{}` was not found on the download server of its registry",
+                            dl.id
+                        ))
+                    } else {
+                        e
+                    };
+                    let attempts = dl.retry.attempts();
+                    return Err(if attempts > 1 {
+                        e.context(format!(
+                            "failed to download from `{}` after {} attempts",
+                            dl.url, attempts
+                        ))
+                    } else {
+                        e.context(format!("failed to download from `{}`", dl.url))
+                    });
                 }
                 RetryResult::Retry(sleep) => {
                     debug!("download retry {} for {sleep}ms", dl.url);
+                    // Skip what was already received, or undo skipping if
+                    // nothing is left of it.
+                    if !partial.is_empty() || resume_from > 0 {
+                        debug!("resuming {} from byte {}", dl.url, partial.len());
+                        handle.range(&format!("{}-", partial.len()))?;
+                    }
+                    dl.resume_from.set(partial.len());
+                    *dl.data.borrow_mut() = partial;
                     self.sleeping.push(sleep, (dl, handle));
                 }
             }
@@ -906,11 +1255,16 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
         // If the progress bar isn't enabled then we still want to provide some
         // semblance of progress of how we're downloading crates, and if the
         // progress bar is enabled this provides a good log of what's happening.
-        self.progress.borrow_mut().as_mut().unwrap().clear();
-        self.set
-            .config
-            .shell()
-            .status("Downloaded", &dl.descriptor)?;
+        match self.order {
+            DownloadOrder::Insertion => {
+                self.progress.borrow_mut().as_mut().unwrap().clear();
+                self.set
+                    .config
+                    .shell()
+                    .status("Downloaded", &dl.descriptor)?;
+            }
+            DownloadOrder::Sorted => self.finished.push((dl.id, dl.descriptor.clone())),
+        }
 
         self.downloads_finished += 1;
         self.downloaded_bytes += dl.total.get();
@@ -936,7 +1290,21 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
             .get_mut(dl.id.source_id())
             .ok_or_else(|| internal(format!("couldn't find source for `{}`", dl.id)))?;
         let start = Instant::now();
-        let pkg = source.finish_download(dl.id, data)?;
+        let pkg = match source.finish_download(dl.id, data) {
+            Ok(pkg) => pkg,
+            Err(e) => {
+                let Some(retry) = e.downcast_ref::<DownloadRetry>() else {
+                    return Err(e);
+                };
+                self.set.config.shell().warn(&retry.reason)?;
+                drop(sources);
+                // The source hands out where to download it from next.
+                if let Some(pkg) = self.start_inner(dl.id)? {
+                    return Ok(pkg);
+                }
+                return self.wait();
+            }
+        };
 
         // Assume that no time has passed while we were calling
         // `finish_download`, update all speed checks and timeout limits of all
@@ -947,6 +1315,10 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
         self.next_speed_check
             .set(self.next_speed_check.get() + finish_dur);
 
+        if let (Some(endpoints), Some(url)) = (&self.set.download_endpoints, effective_url) {
+            endpoints.borrow_mut().insert(dl.id, url);
+        }
+
         let slot = &self.set.packages[&dl.id];
         assert!(slot.fill(pkg).is_ok());
         Ok(slot.borrow().unwrap())
@@ -967,6 +1339,95 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
         Ok(())
     }
 
+    /// Warns if the sizes the index lists for the downloads started so far
+    /// add up to more than `threshold` bytes.
+    fn warn_download_size(&self, threshold: u64) -> CargoResult<()> {
+        let dls = self
+            .pending
+            .values()
+            .map(|(dl, _)| dl)
+            .chain(self.throttled.values().flatten().map(|(dl, _)| dl));
+        let mut unknown = 0;
+        let mut sizes = Vec::new();
+        for dl in dls {
+            match dl.size {
+                Some(size) => sizes.push((size, dl.id)),
+                None => unknown += 1,
+            }
+        }
+        let total: u64 = sizes.iter().map(|(size, _)| size).sum();
+        if total <= threshold {
+            return Ok(());
+        }
+        sizes.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let mut msg = format!(
+            "downloading {} of packages, more than the {} of `registry.download-size-warning`\n\
+             the largest downloads are:",
+            ByteSize(total),
+            ByteSize(threshold)
+        );
+        for (size, id) in sizes.iter().take(3) {
+            msg.push_str(&format!("\n  {} ({})", id, ByteSize(*size)));
+        }
+        if unknown > 0 {
+            msg.push_str(&format!(
+                "\n{} more package(s) of unknown size aren't counted",
+                unknown
+            ));
+        }
+        self.set.config.shell().warn(msg)
+    }
+
+    /// Checks a redirect of `dl` to `location` against
+    /// `registry.allowed-redirect-hosts`.
+    ///
+    /// Relative locations stay on the host of the previous response, which
+    /// has been checked already.
+    fn check_redirect(&self, dl: &Download<'_>, location: &str) -> Result<(), String> {
+        let Some(allowed) = &self.allowed_redirect_hosts else {
+            return Ok(());
+        };
+        let Ok(target) = Url::parse(location) else {
+            return Ok(());
+        };
+        let Some(host) = target.host_str() else {
+            return Ok(());
+        };
+        let own_host = Url::parse(&dl.url).ok();
+        let own_host = own_host.as_ref().and_then(|url| url.host_str());
+        if own_host == Some(host) || allowed.iter().any(|h| h == host) {
+            return Ok(());
+        }
+        Err(format!(
+            "refusing to follow the redirect of `{}` for `{}` to `{}`\n\
+             downloads may only be redirected to the host of the download URL \
+             or to the hosts in `registry.allowed-redirect-hosts`",
+            dl.url, dl.id, location
+        ))
+    }
+
+    /// Frees the slot of a download of `source_id` that ended, starting the next
+    /// download that was throttled because the source was at its limit.
+    fn start_throttled(&mut self, source_id: SourceId) -> CargoResult<()> {
+        let next = self
+            .throttled
+            .get_mut(&source_id)
+            .and_then(|queue| queue.pop_front());
+        match next {
+            Some((dl, handle)) => {
+                debug!("starting throttled download of {}", dl.id);
+                self.pending_ids.insert(dl.id);
+                self.enqueue(dl, handle)
+            }
+            None => {
+                if let Some(in_flight) = self.in_flight.get_mut(&source_id) {
+                    *in_flight -= 1;
+                }
+                Ok(())
+            }
+        }
+    }
+
     /// Block, waiting for curl. Returns a token and a `Result` for that token
     /// (`Ok` means the download successfully finished).
     fn wait_for_curl(&mut self) -> CargoResult<(usize, Result<(), curl::Error>)> {
@@ -1056,6 +1517,10 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
         if self.tick(WhyTick::DownloadUpdate).is_err() {
             return false;
         }
+        // A timeout of zero means downloads never time out.
+        if self.timeout.dur.is_zero() {
+            return true;
+        }
 
         // If we've spent too long not actually receiving any data we time out.
         if now > self.updated_at.get() + self.timeout.dur {
@@ -1112,17 +1577,28 @@ impl<'a, 'cfg> Downloads<'a, 'cfg> {
             _ => {
                 let mut dur = Duration::new(0, 0);
                 let mut remaining = 0;
+                let mut received = 0;
                 for (dl, _) in self.pending.values() {
                     dur += dl.start.elapsed();
+                    // Servers that don't send a `Content-Length` leave the
+                    // total at 0, so only what was received so far can be
+                    // shown for those.
+                    if dl.total.get() == 0 {
+                        received += dl.current.get();
                     // If the total/current look weird just throw out the data
                     // point, sounds like curl has more to learn before we have
                     // the true information.
-                    if dl.total.get() >= dl.current.get() {
+                    } else if dl.total.get() >= dl.current.get() {
                         remaining += dl.total.get() - dl.current.get();
                     }
                 }
-                if remaining > 0 && dur > Duration::from_millis(500) {
-                    msg.push_str(&format!(", remaining bytes: {}", ByteSize(remaining)));
+                if dur > Duration::from_millis(500) {
+                    if remaining > 0 {
+                        msg.push_str(&format!(", remaining bytes: {}", ByteSize(remaining)));
+                    }
+                    if received > 0 {
+                        msg.push_str(&format!(", received bytes: {}", ByteSize(received)));
+                    }
                 }
             }
         }
@@ -1141,7 +1617,15 @@ enum WhyTick<'a> {
 impl<'a, 'cfg> Drop for Downloads<'a, 'cfg> {
     fn drop(&mut self) {
         self.set.downloading.set(false);
-        let progress = self.progress.get_mut().take().unwrap();
+        let mut progress = self.progress.get_mut().take().unwrap();
+        if !self.finished.is_empty() {
+            progress.clear();
+            self.finished
+                .sort_by(|(a, _), (b, _)| (a.name(), a.version()).cmp(&(b.name(), b.version())));
+            for (_, descriptor) in &self.finished {
+                drop(self.set.config.shell().status("Downloaded", descriptor));
+            }
+        }
         // Don't print a download summary if we're not using a progress bar,
         // we've already printed lots of `Downloading...` items.
         if !progress.is_enabled() {
@@ -1184,6 +1668,73 @@ impl<'a, 'cfg> Drop for Downloads<'a, 'cfg> {
     }
 }
 
+/// The order in which packages are downloaded and reported, set with
+/// `registry.download-order`.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum DownloadOrder {
+    /// Downloads start in the order they are asked for, and are reported as
+    /// they finish.
+    Insertion,
+    /// Downloads start sorted by package name and version, and are reported
+    /// in that order once all of them finish, so logs are the same between
+    /// runs.
+    Sorted,
+}
+
+/// What to do with downloads that are HTML pages instead of `.crate` files,
+/// set with `registry.non-tarball-downloads`.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum NonTarballPolicy {
+    /// Fail the download.
+    Reject,
+    /// Download it again, as per `net.retry`, as the page may be a passing
+    /// rate limit or captcha.
+    Retry,
+}
+
+/// Whether the downloaded `data` is an HTML page rather than a gzip stream.
+fn looks_like_html(data: &[u8]) -> bool {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        return false;
+    }
+    let data = data.strip_prefix(b"\xef\xbb\xbf").unwrap_or(data);
+    let start = data
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(data.len());
+    let data = &data[start..];
+    [&b"<!doctype"[..], b"<html"]
+        .iter()
+        .any(|tag| data.len() >= tag.len() && data[..tag.len()].eq_ignore_ascii_case(tag))
+}
+
+/// Reads the config value `key`, a size like `500MB`.
+fn config_size(config: &Config, key: &str) -> CargoResult<Option<u64>> {
+    let Some(size) = config.get::<Option<String>>(key)? else {
+        return Ok(None);
+    };
+    let size: ByteSize = size
+        .parse()
+        .map_err(|e| anyhow::format_err!("invalid size `{}` in `{}`: {}", size, key, e))?;
+    Ok(Some(size.as_u64()))
+}
+
+/// Returns the value of `header` if it's named `name`, ignoring case.
+fn header_value<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    let (header_name, value) = header.split_once(':')?;
+    if !header_name.trim().eq_ignore_ascii_case(name) {
+        return None;
+    }
+    Some(value.trim())
+}
+
+/// Parses the value of a `Content-Length` header.
+fn content_length(header: &str) -> Option<u64> {
+    header_value(header, "content-length")?.parse().ok()
+}
+
 mod tls {
     use std::cell::Cell;
 
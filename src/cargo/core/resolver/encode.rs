@@ -574,6 +574,7 @@ impl ser::Serialize for Resolve {
         if self.version() == ResolveVersion::V1 {
             for &id in ids.iter().filter(|id| !id.source_id().is_path()) {
                 let checksum = match self.checksums()[&id] {
+                    Some(ref s) if !is_lockable_checksum(s) => continue,
                     Some(ref s) => &s[..],
                     None => "<none>",
                 };
@@ -599,7 +600,10 @@ impl ser::Serialize for Resolve {
                     dependencies: None,
                     replace: None,
                     checksum: if self.version() >= ResolveVersion::V2 {
-                        self.checksums().get(id).and_then(|x| x.clone())
+                        self.checksums()
+                            .get(id)
+                            .and_then(|x| x.clone())
+                            .filter(|x| is_lockable_checksum(x))
                     } else {
                         None
                     },
@@ -671,13 +675,26 @@ fn encodable_resolve_node(
         dependencies: deps,
         replace,
         checksum: if resolve.version() >= ResolveVersion::V2 {
-            resolve.checksums().get(&id).and_then(|s| s.clone())
+            resolve
+                .checksums()
+                .get(&id)
+                .and_then(|s| s.clone())
+                .filter(|s| is_lockable_checksum(s))
         } else {
             None
         },
     }
 }
 
+/// Whether `checksum` is recorded in the lock file.
+///
+/// Only sha256 checksums are. Registries may list checksums of other
+/// algorithms, kept as `<algo>:<hex>`, which Cargo versions not knowing them
+/// would report as changed, so those packages are locked without a checksum.
+fn is_lockable_checksum(checksum: &str) -> bool {
+    !checksum.contains(':')
+}
+
 pub fn encodable_package_id(
     id: PackageId,
     state: &EncodeState<'_>,
@@ -105,6 +105,15 @@ pub trait Source {
     /// Finalizes the download contents of the given [`PackageId`] to a [`Package`].
     fn finish_download(&mut self, pkg_id: PackageId, contents: Vec<u8>) -> CargoResult<Package>;
 
+    /// Called when the download of `pkg_id` from where [`Source::download`]
+    /// said failed with `err`, after all retries.
+    ///
+    /// Returns whether to download it again, in which case the next
+    /// [`Source::download`] may hand out another URL, such as a mirror.
+    fn download_failed(&mut self, _pkg_id: PackageId, _err: &anyhow::Error) -> CargoResult<bool> {
+        Ok(false)
+    }
+
     /// Generates a unique string which represents the fingerprint of the
     /// current state of the source.
     ///
@@ -185,6 +194,17 @@ pub enum MaybePackage {
         descriptor: String,
         /// Authorization data that may be required to attach when downloading.
         authorization: Option<String>,
+        /// Session cookie that may be required to attach when downloading.
+        cookie: Option<String>,
+        /// Expected size of the download in bytes, if known.
+        size: Option<u64>,
+        /// The most downloads the source wants to run at the same time, if it
+        /// has a limit.
+        max_concurrent: Option<usize>,
+        /// Whether a rejected download should point at the HTTP basic auth
+        /// credentials of the registry, which it doesn't if the registry uses
+        /// tokens.
+        hint_basic_auth: bool,
     },
 }
 
@@ -231,6 +251,10 @@ impl<'a, T: Source + ?Sized + 'a> Source for Box<T> {
         (**self).finish_download(id, data)
     }
 
+    fn download_failed(&mut self, id: PackageId, err: &anyhow::Error) -> CargoResult<bool> {
+        (**self).download_failed(id, err)
+    }
+
     fn fingerprint(&self, pkg: &Package) -> CargoResult<String> {
         (**self).fingerprint(pkg)
     }
@@ -303,6 +327,10 @@ impl<'a, T: Source + ?Sized + 'a> Source for &'a mut T {
         (**self).finish_download(id, data)
     }
 
+    fn download_failed(&mut self, id: PackageId, err: &anyhow::Error) -> CargoResult<bool> {
+        (**self).download_failed(id, err)
+    }
+
     fn fingerprint(&self, pkg: &Package) -> CargoResult<String> {
         (**self).fingerprint(pkg)
     }
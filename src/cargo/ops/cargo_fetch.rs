@@ -1,5 +1,6 @@
 use crate::core::compiler::standard_lib;
 use crate::core::compiler::{BuildConfig, CompileMode, RustcTargetData};
+use crate::core::dependency::DepKind;
 use crate::core::{PackageSet, Resolve, Workspace};
 use crate::ops;
 use crate::util::config::JobsConfig;
@@ -11,6 +12,8 @@ pub struct FetchOptions<'a> {
     pub config: &'a Config,
     /// The target arch triple to fetch dependencies for
     pub targets: Vec<String>,
+    /// The kinds of dependencies to fetch, all of them if empty
+    pub dep_kinds: Vec<DepKind>,
 }
 
 /// Executes `cargo fetch`.
@@ -46,6 +49,10 @@ pub fn fetch<'a>(
             .deps(id)
             .filter(|&(_id, deps)| {
                 deps.iter().any(|d| {
+                    if !options.dep_kinds.is_empty() && !options.dep_kinds.contains(&d.kind()) {
+                        return false;
+                    }
+
                     // If no target was specified then all dependencies are
                     // fetched.
                     if options.targets.is_empty() {
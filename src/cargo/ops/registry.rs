@@ -627,6 +627,23 @@ pub fn configure_http_handle(config: &Config, handle: &mut Easy) -> CargoResult<
         let cainfo = cainfo.resolve_path(config);
         handle.cainfo(&cainfo)?;
     }
+    if let Some(ssl_cert) = &http.ssl_cert {
+        handle.ssl_cert(ssl_cert.resolve_path(config))?;
+        if let Some(ssl_key) = &http.ssl_key {
+            handle.ssl_key(ssl_key.resolve_path(config))?;
+        }
+        // Only read from the environment, so it never ends up in a config
+        // file, and never logged. Not `CARGO_HTTP_SSL_KEY_PASSWORD`, as that
+        // would make `http.ssl-key` look like a table.
+        if let Some(password) = config.get_env_os("CARGO_HTTP_SSL_PASSWORD") {
+            let Some(password) = password.to_str() else {
+                bail!("`CARGO_HTTP_SSL_PASSWORD` is not valid UTF-8");
+            };
+            handle.key_password(password)?;
+        }
+    } else if http.ssl_key.is_some() {
+        bail!("`http.ssl-key` is set, but not `http.ssl-cert`");
+    }
     if let Some(check) = http.check_revoke {
         handle.ssl_options(SslOpt::new().no_revoke(!check))?;
     }
@@ -766,6 +783,9 @@ impl HttpTimeout {
         // timeouts for the connect phase as well as a "low speed" timeout so
         // if we don't receive many bytes in a large-ish period of time then we
         // time out.
+        //
+        // A timeout of zero disables the low speed timeout, and leaves the
+        // connect timeout at libcurl's default.
         handle.connect_timeout(self.dur)?;
         handle.low_speed_time(self.dur)?;
         handle.low_speed_limit(self.low_speed_limit)?;
@@ -513,6 +513,18 @@ pub fn resolve_with_previous<'cfg>(
         .collect();
     resolved.register_used_patches(&patches[..]);
 
+    if log::log_enabled!(log::Level::Trace) {
+        let mut checksums: Vec<_> = resolved.checksums().iter().collect();
+        checksums.sort();
+        for (id, cksum) in checksums {
+            trace!(
+                "checksum of {}: {}",
+                id,
+                cksum.as_deref().unwrap_or("<none>")
+            );
+        }
+    }
+
     if register_patches && !resolved.unused_patches().is_empty() {
         emit_warnings_of_unused_patches(ws, &resolved, registry)?;
     }
@@ -0,0 +1,234 @@
+use crate::core::source::MaybePackage;
+use crate::core::{Dependency, Package, PackageId, QueryKind, Source, SourceId, Summary};
+use crate::util::errors::CargoResult;
+use std::collections::{HashMap, HashSet};
+use std::task::Poll;
+
+use anyhow::Context as _;
+
+/// How a [`ChainedSource`] combines the results of its sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FallbackPolicy {
+    /// Use the results of the first source that has any.
+    First,
+    /// Use the results of all sources, where a version found in an earlier
+    /// source hides the same version in later ones.
+    Merge,
+}
+
+/// A source that queries an ordered list of sources, set with
+/// `source.<name>.fallbacks`.
+///
+/// This lets a private registry take precedence over a public one while
+/// still falling back to it for packages it doesn't have. Everything is
+/// reported with the ID of the first source, and downloads go to whichever
+/// source the package was found in.
+pub struct ChainedSource<'cfg> {
+    /// The identifier of the first source, which the others are reported as.
+    source_id: SourceId,
+    sources: Vec<Box<dyn Source + 'cfg>>,
+    policy: FallbackPolicy,
+    /// Index into `sources` of the source each package was found in.
+    origins: HashMap<PackageId, usize>,
+}
+
+impl<'cfg> ChainedSource<'cfg> {
+    /// Creates a chain of `sources`, which must not be empty.
+    pub fn new(
+        sources: Vec<Box<dyn Source + 'cfg>>,
+        policy: FallbackPolicy,
+    ) -> ChainedSource<'cfg> {
+        assert!(!sources.is_empty());
+        ChainedSource {
+            source_id: sources[0].source_id(),
+            sources,
+            policy,
+            origins: HashMap::new(),
+        }
+    }
+
+    /// Finds the source `id` comes from, if any of them has it.
+    ///
+    /// Packages are normally recorded when they are queried, but a package
+    /// may be asked for without being queried first.
+    fn origin(&mut self, id: PackageId) -> Poll<CargoResult<Option<usize>>> {
+        if let Some(&i) = self.origins.get(&id) {
+            return Poll::Ready(Ok(Some(i)));
+        }
+        for i in 0..self.sources.len() {
+            let src_id = self.sources[i].source_id();
+            let dep = Dependency::parse(id.name(), Some(&format!("={}", id.version())), src_id)?;
+            let found = std::task::ready!(self.sources[i].query_vec(&dep, QueryKind::Exact)?);
+            if !found.is_empty() {
+                self.origins.insert(id, i);
+                return Poll::Ready(Ok(Some(i)));
+            }
+        }
+        Poll::Ready(Ok(None))
+    }
+
+    /// Like [`ChainedSource::origin`], but blocks until it's known, and it's
+    /// an error for no source to have `id`.
+    fn wait_for_origin(&mut self, id: PackageId) -> CargoResult<usize> {
+        loop {
+            match self.origin(id)? {
+                Poll::Ready(Some(i)) => return Ok(i),
+                Poll::Ready(None) => {
+                    anyhow::bail!("none of the sources of {} has {}", self.describe(), id)
+                }
+                Poll::Pending => self.block_until_ready()?,
+            }
+        }
+    }
+}
+
+impl<'cfg> Source for ChainedSource<'cfg> {
+    fn source_id(&self) -> SourceId {
+        self.source_id
+    }
+
+    fn supports_checksums(&self) -> bool {
+        self.sources.iter().all(|s| s.supports_checksums())
+    }
+
+    fn requires_precise(&self) -> bool {
+        self.sources.iter().any(|s| s.requires_precise())
+    }
+
+    fn query(
+        &mut self,
+        dep: &Dependency,
+        kind: QueryKind,
+        f: &mut dyn FnMut(Summary),
+    ) -> Poll<CargoResult<()>> {
+        // Later sources are only asked once all earlier ones have answered,
+        // so results are collected rather than passed on right away.
+        let mut found: Vec<(usize, Summary)> = Vec::new();
+        let mut pending = false;
+        for (i, src) in self.sources.iter_mut().enumerate() {
+            let src_id = src.source_id();
+            let dep = dep.clone().map_source(self.source_id, src_id);
+            let mut summaries = Vec::new();
+            let poll = src
+                .query(&dep, kind, &mut |s| summaries.push(s))
+                .map_err(|e| e.context(format!("failed to query {}", src.describe())))?;
+            if poll.is_pending() {
+                pending = true;
+                if self.policy == FallbackPolicy::First {
+                    break;
+                }
+                continue;
+            }
+            let summaries = summaries
+                .into_iter()
+                .map(|s| s.map_source(src_id, self.source_id));
+            found.extend(summaries.map(|s| (i, s)));
+            if self.policy == FallbackPolicy::First && !found.is_empty() {
+                break;
+            }
+        }
+        if pending {
+            return Poll::Pending;
+        }
+
+        let mut seen = HashSet::new();
+        for (i, summary) in found {
+            let id = summary.package_id();
+            if seen.insert(id) {
+                self.origins.insert(id, i);
+                f(summary);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn invalidate_cache(&mut self) {
+        for src in &mut self.sources {
+            src.invalidate_cache();
+        }
+    }
+
+    fn set_quiet(&mut self, quiet: bool) {
+        for src in &mut self.sources {
+            src.set_quiet(quiet);
+        }
+    }
+
+    fn download(&mut self, id: PackageId) -> CargoResult<MaybePackage> {
+        let i = self.wait_for_origin(id)?;
+        let src_id = self.sources[i].source_id();
+        let pkg = self.sources[i]
+            .download(id.with_source_id(src_id))
+            .with_context(|| format!("failed to download from {}", self.sources[i].describe()))?;
+        Ok(match pkg {
+            MaybePackage::Ready(pkg) => MaybePackage::Ready(pkg.map_source(src_id, self.source_id)),
+            other @ MaybePackage::Download { .. } => other,
+        })
+    }
+
+    fn finish_download(&mut self, id: PackageId, data: Vec<u8>) -> CargoResult<Package> {
+        let i = self.wait_for_origin(id)?;
+        let src_id = self.sources[i].source_id();
+        let pkg = self.sources[i]
+            .finish_download(id.with_source_id(src_id), data)
+            .with_context(|| format!("failed to download from {}", self.sources[i].describe()))?;
+        Ok(pkg.map_source(src_id, self.source_id))
+    }
+
+    fn download_failed(&mut self, id: PackageId, err: &anyhow::Error) -> CargoResult<bool> {
+        let i = self.wait_for_origin(id)?;
+        let src_id = self.sources[i].source_id();
+        self.sources[i].download_failed(id.with_source_id(src_id), err)
+    }
+
+    fn fingerprint(&self, pkg: &Package) -> CargoResult<String> {
+        let i = self.origins.get(&pkg.package_id()).copied().unwrap_or(0);
+        self.sources[i].fingerprint(pkg)
+    }
+
+    fn verify(&self, id: PackageId) -> CargoResult<()> {
+        let i = self.origins.get(&id).copied().unwrap_or(0);
+        self.sources[i].verify(id.with_source_id(self.sources[i].source_id()))
+    }
+
+    fn describe(&self) -> String {
+        let fallbacks = self.sources[1..]
+            .iter()
+            .map(|s| s.describe())
+            .collect::<Vec<_>>();
+        format!(
+            "{} (falling back to {})",
+            self.sources[0].describe(),
+            fallbacks.join(", ")
+        )
+    }
+
+    fn add_to_yanked_whitelist(&mut self, pkgs: &[PackageId]) {
+        for src in &mut self.sources {
+            let src_id = src.source_id();
+            let pkgs = pkgs
+                .iter()
+                .map(|id| id.with_source_id(src_id))
+                .collect::<Vec<_>>();
+            src.add_to_yanked_whitelist(&pkgs);
+        }
+    }
+
+    fn is_yanked(&mut self, pkg: PackageId) -> Poll<CargoResult<bool>> {
+        let i = match std::task::ready!(self.origin(pkg)?) {
+            Some(i) => i,
+            None => 0,
+        };
+        let src_id = self.sources[i].source_id();
+        self.sources[i].is_yanked(pkg.with_source_id(src_id))
+    }
+
+    fn block_until_ready(&mut self) -> CargoResult<()> {
+        for src in &mut self.sources {
+            src.block_until_ready()
+                .with_context(|| format!("failed to update {}", src.describe()))?;
+        }
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@
 //! sources to one another via the `replace-with` key in `.cargo/config`.
 
 use crate::core::{GitReference, PackageId, Source, SourceId};
-use crate::sources::{ReplacedSource, CRATES_IO_REGISTRY};
+use crate::sources::{ChainedSource, FallbackPolicy, ReplacedSource, CRATES_IO_REGISTRY};
 use crate::util::config::{self, ConfigRelativePath, OptValue};
 use crate::util::errors::CargoResult;
 use crate::util::{Config, IntoUrl};
@@ -44,6 +44,11 @@ struct SourceConfigDef {
     tag: OptValue<String>,
     /// The git revision.
     rev: OptValue<String>,
+    /// Sources to query when this one, as a replacement, doesn't have a
+    /// package.
+    fallbacks: OptValue<Vec<String>>,
+    /// How the results of this source and its fallbacks are combined.
+    fallback_policy: Option<FallbackPolicy>,
 }
 
 /// Configuration for a particular source, found in TOML looking like:
@@ -65,6 +70,12 @@ struct SourceConfig {
     /// this configuration key was defined (such as the `.cargo/config` path
     /// or the environment variable name).
     replace_with: Option<(String, String)>,
+
+    /// Names of the sources to fall back to when this source is used as a
+    /// replacement, with where they were defined like `replace_with`.
+    fallbacks: Option<(Vec<String>, String)>,
+
+    fallback_policy: FallbackPolicy,
 }
 
 impl<'cfg> SourceConfigMap<'cfg> {
@@ -94,6 +105,8 @@ impl<'cfg> SourceConfigMap<'cfg> {
             SourceConfig {
                 id: SourceId::crates_io(config)?,
                 replace_with: None,
+                fallbacks: None,
+                fallback_policy: FallbackPolicy::First,
             },
         )?;
         if SourceId::crates_io_is_sparse(config)? {
@@ -102,6 +115,8 @@ impl<'cfg> SourceConfigMap<'cfg> {
                 SourceConfig {
                     id: SourceId::crates_io_maybe_sparse_http(config)?,
                     replace_with: None,
+                    fallbacks: None,
+                    fallback_policy: FallbackPolicy::First,
                 },
             )?;
         }
@@ -111,6 +126,8 @@ impl<'cfg> SourceConfigMap<'cfg> {
                 SourceConfig {
                     id: SourceId::for_alt_registry(&url.parse()?, CRATES_IO_REGISTRY)?,
                     replace_with: None,
+                    fallbacks: None,
+                    fallback_policy: FallbackPolicy::First,
                 },
             )?;
         }
@@ -138,14 +155,14 @@ impl<'cfg> SourceConfigMap<'cfg> {
         };
         let mut cfg_loc = "";
         let orig_name = name;
-        let new_id = loop {
+        let (new_id, new_cfg) = loop {
             let cfg = match self.cfgs.get(name) {
                 Some(cfg) => cfg,
                 None => {
                     // Attempt to interpret the source name as an alt registry name
                     if let Ok(alt_id) = SourceId::alt_registry(self.config, name) {
                         debug!("following pointer to registry {}", name);
-                        break alt_id.with_precise(id.precise().map(str::to_string));
+                        break (alt_id.with_precise(id.precise().map(str::to_string)), None);
                     }
                     bail!(
                         "could not find a configured source with the \
@@ -164,7 +181,10 @@ impl<'cfg> SourceConfigMap<'cfg> {
                 }
                 None if id == cfg.id => return id.load(self.config, yanked_whitelist),
                 None => {
-                    break cfg.id.with_precise(id.precise().map(|s| s.to_string()));
+                    break (
+                        cfg.id.with_precise(id.precise().map(|s| s.to_string())),
+                        Some(cfg),
+                    );
                 }
             }
             debug!("following pointer to {}", name);
@@ -186,6 +206,12 @@ impl<'cfg> SourceConfigMap<'cfg> {
                 .map(|p| p.map_source(id, new_id))
                 .collect(),
         )?;
+        let new_src = match new_cfg {
+            Some(cfg) if cfg.fallbacks.is_some() => {
+                self.load_fallbacks(name, cfg, id, new_src, yanked_whitelist)?
+            }
+            _ => new_src,
+        };
         let old_src = id.load(self.config, yanked_whitelist)?;
         if !new_src.supports_checksums() && old_src.supports_checksums() {
             bail!(
@@ -216,6 +242,53 @@ restore the source replacement configuration to continue the build
         Ok(Box::new(ReplacedSource::new(id, new_id, new_src)))
     }
 
+    /// Chains `new_src`, the replacement of `id` named `name`, with the
+    /// fallbacks listed in its configuration `cfg`.
+    fn load_fallbacks(
+        &self,
+        name: &str,
+        cfg: &SourceConfig,
+        id: SourceId,
+        new_src: Box<dyn Source + 'cfg>,
+        yanked_whitelist: &HashSet<PackageId>,
+    ) -> CargoResult<Box<dyn Source + 'cfg>> {
+        let (fallbacks, cfg_loc) = cfg.fallbacks.as_ref().unwrap();
+        let mut srcs = vec![new_src];
+        for fallback in fallbacks {
+            let fallback_id = match self.cfgs.get(fallback) {
+                Some(cfg) => cfg.id,
+                None => SourceId::alt_registry(self.config, fallback).with_context(|| {
+                    format!(
+                        "could not find a configured source with the name `{}` \
+                         listed in `source.{}.fallbacks` (configuration in `{}`)",
+                        fallback, name, cfg_loc
+                    )
+                })?,
+            };
+            if fallback_id == cfg.id {
+                bail!(
+                    "the source `{}` lists itself in `source.{}.fallbacks` \
+                     (configuration in `{}`)",
+                    name,
+                    name,
+                    cfg_loc
+                );
+            }
+            let fallback_id = fallback_id.with_precise(id.precise().map(str::to_string));
+            debug!("falling back from {} to {}", name, fallback);
+            srcs.push(
+                fallback_id.load(
+                    self.config,
+                    &yanked_whitelist
+                        .iter()
+                        .map(|p| p.map_source(id, fallback_id))
+                        .collect(),
+                )?,
+            );
+        }
+        Ok(Box::new(ChainedSource::new(srcs, cfg.fallback_policy)))
+    }
+
     /// Adds a source config with an associated name.
     fn add(&mut self, name: &str, cfg: SourceConfig) -> CargoResult<()> {
         if let Some(old_name) = self.id2name.insert(cfg.id, name.to_string()) {
@@ -302,11 +375,17 @@ restore the source replacement configuration to continue the build
             .replace_with
             .map(|val| (val.val, val.definition.to_string()));
 
+        let fallbacks = def
+            .fallbacks
+            .map(|val| (val.val, val.definition.to_string()));
+
         self.add(
             &name,
             SourceConfig {
                 id: src,
                 replace_with,
+                fallbacks,
+                fallback_policy: def.fallback_policy.unwrap_or(FallbackPolicy::First),
             },
         )?;
 
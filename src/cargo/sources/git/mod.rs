@@ -17,6 +17,7 @@ mod utils;
 /// For `-Zgitoxide` integration.
 pub mod fetch {
     use crate::core::features::GitoxideFeatures;
+    use crate::util::errors::CargoResult;
     use crate::Config;
 
     /// The kind remote repository to fetch.
@@ -36,7 +37,7 @@ pub mod fetch {
             &self,
             repo_is_shallow: bool,
             config: &Config,
-        ) -> gix::remote::fetch::Shallow {
+        ) -> CargoResult<gix::remote::fetch::Shallow> {
             let has_feature = |cb: &dyn Fn(GitoxideFeatures) -> bool| {
                 config
                     .cli_unstable()
@@ -48,14 +49,27 @@ pub mod fetch {
             if !repo_is_shallow {
                 match self {
                     RemoteKind::GitDependency if has_feature(&|git| git.shallow_deps) => {}
-                    RemoteKind::Registry if has_feature(&|git| git.shallow_index) => {}
-                    _ => return gix::remote::fetch::Shallow::NoChange,
+                    RemoteKind::Registry if shallow_index(config)? => {}
+                    _ => return Ok(gix::remote::fetch::Shallow::NoChange),
                 }
             };
 
-            gix::remote::fetch::Shallow::DepthAtRemote(1.try_into().expect("non-zero"))
+            Ok(gix::remote::fetch::Shallow::DepthAtRemote(
+                1.try_into().expect("non-zero"),
+            ))
         }
     }
 
+    /// Whether git indices of registries are cloned and updated with a depth
+    /// of 1, either through `-Zgitoxide=fetch,shallow-index` or the
+    /// `registry.shallow` config.
+    pub fn shallow_index(config: &Config) -> CargoResult<bool> {
+        Ok(config
+            .cli_unstable()
+            .gitoxide
+            .map_or(false, |gix| gix.fetch && gix.shallow_index)
+            || config.get::<Option<bool>>("registry.shallow")? == Some(true))
+    }
+
     pub type Error = gix::env::collate::fetch::Error<gix::refspec::parse::Error>;
 }
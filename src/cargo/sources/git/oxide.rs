@@ -32,7 +32,8 @@ pub fn with_retry_and_progress(
         let is_shallow = config
             .cli_unstable()
             .gitoxide
-            .map_or(false, |gix| gix.shallow_deps || gix.shallow_index);
+            .map_or(false, |gix| gix.shallow_deps || gix.shallow_index)
+            || super::fetch::shallow_index(config)?;
         network::retry::with_retry(config, || {
             let progress_root: Arc<gix::progress::tree::Root> =
                 gix::progress::tree::root::Options {
@@ -290,6 +291,9 @@ pub fn cargo_config_to_gitoxide_overrides(config: &Config) -> CargoResult<Vec<BS
     if let Some(check_revoke) = http.check_revoke {
         values.push(Http::SCHANNEL_CHECK_REVOKE.validated_assignment_fmt(&check_revoke)?);
     }
+    if http.ssl_cert.is_some() {
+        anyhow::bail!("`http.ssl-cert` is not supported with `-Zgitoxide`");
+    }
     if let Some(cainfo) = &http.cainfo {
         values.push(
             Http::SSL_CA_INFO.validated_assignment_fmt(&cainfo.resolve_path(config).display())?,
@@ -1,10 +1,11 @@
 //! Utilities for handling git repositories, mainly around
 //! authentication/cloning.
 
-use crate::core::{GitReference, Verbosity};
+use crate::core::{GitReference, SourceId, Verbosity};
 use crate::sources::git::fetch::RemoteKind;
 use crate::sources::git::oxide;
 use crate::sources::git::oxide::cargo_config_to_gitoxide_overrides;
+use crate::util::auth;
 use crate::util::errors::CargoResult;
 use crate::util::{human_readable_bytes, network, Config, IntoUrl, MetricsCounter, Progress};
 use anyhow::{anyhow, Context as _};
@@ -312,7 +313,7 @@ impl<'a> GitCheckout<'a> {
         // need authentication information we may want progress bars and such.
         let url = database.path.into_url()?;
         let mut repo = None;
-        with_fetch_options(&git_config, url.as_str(), config, &mut |fopts| {
+        with_fetch_options(&git_config, url.as_str(), None, config, &mut |fopts| {
             let mut checkout = git2::build::CheckoutBuilder::new();
             checkout.dry_run(); // we'll do this below during a `reset`
 
@@ -545,10 +546,14 @@ impl<'a> GitCheckout<'a> {
 /// credentials until we give it a reason to not do so. To ensure we don't
 /// just sit here looping forever we keep track of authentications we've
 /// attempted and we don't try the same ones again.
+///
+/// The `userpass` credentials, such as those of a registry whose index this
+/// is, are tried before the credential helper.
 fn with_authentication<T, F>(
     cargo_config: &Config,
     url: &str,
     cfg: &git2::Config,
+    userpass: Option<(&str, &str)>,
     mut f: F,
 ) -> CargoResult<T>
 where
@@ -562,6 +567,7 @@ where
     let mut ssh_agent_attempts = Vec::new();
     let mut any_attempts = false;
     let mut tried_sshkey = false;
+    let mut tried_userpass = false;
     let mut url_attempt = None;
 
     let orig_url = url;
@@ -624,6 +630,12 @@ where
         // callback asking for other authentication methods to try. Check
         // cred_helper_bad to make sure we only try the git credential helper
         // once, to avoid looping forever.
+        if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) && !tried_userpass {
+            if let Some((username, password)) = userpass {
+                tried_userpass = true;
+                return git2::Cred::userpass_plaintext(username, password);
+            }
+        }
         if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) && cred_helper_bad.is_none()
         {
             let r = git2::Cred::credential_helper(cfg, url, username);
@@ -811,9 +823,13 @@ fn reset(repo: &git2::Repository, obj: &git2::Object<'_>, config: &Config) -> Ca
 ///
 /// The callback is provided a fetch options, which can be used by the actual
 /// git fetch.
+///
+/// `userpass` is a username and password to authenticate with, if the
+/// server asks for them.
 pub fn with_fetch_options(
     git_config: &git2::Config,
     url: &str,
+    userpass: Option<(&str, &str)>,
     config: &Config,
     cb: &mut dyn FnMut(git2::FetchOptions<'_>) -> CargoResult<()>,
 ) -> CargoResult<()> {
@@ -822,7 +838,7 @@ pub fn with_fetch_options(
     let config_known_hosts = ssh_config.and_then(|ssh| ssh.known_hosts.as_ref());
     let diagnostic_home_config = config.diagnostic_home_config();
     network::retry::with_retry(config, || {
-        with_authentication(config, url, git_config, |f| {
+        with_authentication(config, url, git_config, userpass, |f| {
             let port = Url::parse(url).ok().and_then(|url| url.port());
             let mut last_update = Instant::now();
             let mut rcb = git2::RemoteCallbacks::new();
@@ -892,8 +908,10 @@ pub fn with_fetch_options(
 /// * Turns [`GitReference`] into refspecs accordingly.
 /// * Dispatches `git fetch` using libgit2, gitoxide, or git CLI.
 ///
-/// `remote_kind` is a thing for [`-Zgitoxide`] shallow clones at this time.
-/// It could be extended when libgit2 supports shallow clones.
+/// `remote_kind` is a thing for [`-Zgitoxide`] shallow clones and the
+/// `registry.shallow` config at this time. libgit2 can't fetch shallowly, so
+/// shallow fetches of an index are done with gitoxide, or with
+/// `git fetch --depth` if `net.git-fetch-with-cli` is set.
 ///
 /// [`-Zgitoxide`]: https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#gitoxide
 pub fn fetch(
@@ -913,7 +931,7 @@ pub fn fetch(
         anyhow::bail!("can't update a git repository in the offline mode")
     }
 
-    let shallow = remote_kind.to_shallow_setting(repo.is_shallow(), config);
+    let shallow = remote_kind.to_shallow_setting(repo.is_shallow(), config)?;
 
     let oid_to_fetch = match github_fast_path(repo, orig_url, reference, config) {
         Ok(FastPathRev::UpToDate) => return Ok(()),
@@ -978,13 +996,18 @@ pub fn fetch(
     }
 
     if let Some(true) = config.net_config()?.git_fetch_with_cli {
-        return fetch_with_cli(repo, orig_url, &refspecs, tags, config);
+        return fetch_with_cli(repo, orig_url, &refspecs, tags, &shallow, config);
     }
 
-    if config
-        .cli_unstable()
-        .gitoxide
-        .map_or(false, |git| git.fetch)
+    // libgit2 doesn't support shallow fetches, so `registry.shallow` implies
+    // fetching the index with gitoxide.
+    let shallow_registry = matches!(remote_kind, RemoteKind::Registry)
+        && !matches!(shallow, gix::remote::fetch::Shallow::NoChange);
+    if shallow_registry
+        || config
+            .cli_unstable()
+            .gitoxide
+            .map_or(false, |git| git.fetch)
     {
         let git2_repo = repo;
         let config_overrides = cargo_config_to_gitoxide_overrides(config)?;
@@ -1087,7 +1110,22 @@ pub fn fetch(
     } else {
         debug!("doing a fetch for {}", orig_url);
         let git_config = git2::Config::open_default()?;
-        with_fetch_options(&git_config, orig_url, config, &mut |mut opts| {
+        // A registry protecting its downloads with basic auth most likely
+        // protects its index the same way. Not finding its credentials isn't
+        // fatal though, the usual credential helpers are still tried then.
+        let registry_auth = match remote_kind {
+            RemoteKind::Registry => SourceId::for_registry(&orig_url.into_url()?)
+                .and_then(|sid| auth::basic_auth_credentials(config, &sid))
+                .unwrap_or_else(|e| {
+                    debug!("not using basic auth credentials for {}: {:#}", orig_url, e);
+                    None
+                }),
+            RemoteKind::GitDependency => None,
+        };
+        let userpass = registry_auth
+            .as_ref()
+            .map(|(username, password)| (username.as_str(), password.as_deref().expose()));
+        with_fetch_options(&git_config, orig_url, userpass, config, &mut |mut opts| {
             if tags {
                 opts.download_tags(git2::AutotagOption::All);
             }
@@ -1160,6 +1198,7 @@ fn fetch_with_cli(
     url: &str,
     refspecs: &[String],
     tags: bool,
+    shallow: &gix::remote::fetch::Shallow,
     config: &Config,
 ) -> CargoResult<()> {
     let mut cmd = ProcessBuilder::new("git");
@@ -1167,6 +1206,9 @@ fn fetch_with_cli(
     if tags {
         cmd.arg("--tags");
     }
+    if let gix::remote::fetch::Shallow::DepthAtRemote(depth) = shallow {
+        cmd.arg(format!("--depth={}", depth));
+    }
     match config.shell().verbosity() {
         Verbosity::Normal => {}
         Verbosity::Verbose => {
@@ -1445,7 +1487,8 @@ fn github_fast_path(
     debug!("attempting GitHub fast path for {}", url);
     handle.get(true)?;
     handle.url(&url)?;
-    handle.useragent("cargo")?;
+    // The handle already sends `http.user-agent`, or the default of Cargo,
+    // which GitHub requires.
     handle.http_headers({
         let mut headers = List::new();
         headers.append("Accept: application/vnd.github.3.sha")?;
@@ -12,6 +12,8 @@
 //!   filesystem.
 //! * [`ReplacedSource`] --- This manages the [source replacement] feature,
 //!   redirecting operations on the original source to the replacement.
+//! * [`ChainedSource`] --- This queries a list of sources in order, so that a
+//!   replacement source can fall back to others.
 //!
 //! This module also contains [`SourceConfigMap`], which is effectively the
 //! representation of the `[source.*]` value in Cargo configuration.
@@ -19,13 +21,18 @@
 //! [`Source`]: crate::core::Source
 //! [source replacement]: https://doc.rust-lang.org/nightly/cargo/reference/source-replacement.html
 
+pub use self::chained::{ChainedSource, FallbackPolicy};
 pub use self::config::SourceConfigMap;
 pub use self::directory::DirectorySource;
 pub use self::git::GitSource;
 pub use self::path::PathSource;
-pub use self::registry::{RegistrySource, CRATES_IO_DOMAIN, CRATES_IO_INDEX, CRATES_IO_REGISTRY};
+pub use self::registry::{
+    IndexReadCache, IoStats, RegistrySource, VerifyAction, VerifyReport, CRATES_IO_DOMAIN,
+    CRATES_IO_INDEX, CRATES_IO_REGISTRY,
+};
 pub use self::replaced::ReplacedSource;
 
+pub mod chained;
 pub mod config;
 pub mod directory;
 pub mod git;
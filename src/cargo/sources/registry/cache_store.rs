@@ -0,0 +1,315 @@
+//! Stores that keep `.crate` files across runs of Cargo, set with
+//! `registry.cache-store`.
+//!
+//! The downloads in `$CARGO_HOME/registry/cache` are lost whenever the home
+//! directory is, which on CI runners with ephemeral disks is every run. A
+//! store sits in front of the registry: a package missing from the local
+//! cache is looked up in the store before it's downloaded, and every verified
+//! download is added to it.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use cargo_util::{paths, ProcessBuilder};
+use flate2::read::GzDecoder;
+use flate2::{Compression, GzBuilder, GzHeader};
+use serde::Deserialize;
+
+use crate::core::PackageId;
+use crate::util::config::{ConfigRelativePath, PathAndArgs};
+use crate::util::errors::CargoResult;
+use crate::util::Config;
+
+/// A place to keep `.crate` files, in addition to the local cache of
+/// downloads.
+///
+/// Entries are keyed by the package and the sha256 checksum of its `.crate`
+/// file, so one store can be shared between registries. Data from a store is
+/// verified against the checksum before it's used.
+pub trait CacheStore {
+    /// Returns the `.crate` file of `pkg` with checksum `checksum`, if the
+    /// store has it.
+    fn get(&self, pkg: PackageId, checksum: &str) -> CargoResult<Option<Vec<u8>>>;
+
+    /// Adds the `.crate` file of `pkg` with checksum `checksum`.
+    fn put(&self, pkg: PackageId, checksum: &str, data: &[u8]) -> CargoResult<()>;
+
+    /// Whether the store has the `.crate` file of `pkg` with checksum
+    /// `checksum`.
+    fn exists(&self, pkg: PackageId, checksum: &str) -> CargoResult<bool>;
+}
+
+/// The `registry.cache-store` config table.
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CacheStoreConfig {
+    path: Option<ConfigRelativePath>,
+    command: Option<PathAndArgs>,
+    compression_level: Option<u32>,
+}
+
+/// Creates the store set with `registry.cache-store`, if any.
+pub(super) fn from_config(config: &Config) -> CargoResult<Option<Box<dyn CacheStore>>> {
+    let Some(store) = config.get::<Option<CacheStoreConfig>>("registry.cache-store")? else {
+        return Ok(None);
+    };
+    if let Some(level) = store.compression_level {
+        if level > 9 {
+            anyhow::bail!(
+                "`registry.cache-store.compression-level` must be between 0 and 9, got {}",
+                level
+            );
+        }
+        if store.path.is_none() {
+            anyhow::bail!(
+                "`registry.cache-store.compression-level` requires \
+                 `registry.cache-store.path` to be set"
+            );
+        }
+    }
+    match (store.path, store.command) {
+        (Some(_), Some(_)) => anyhow::bail!(
+            "only one of `registry.cache-store.path` and `registry.cache-store.command` \
+             may be set"
+        ),
+        (Some(path), None) => Ok(Some(Box::new(
+            FilesystemStore::new(path.resolve_path(config))
+                .compression_level(store.compression_level),
+        ))),
+        (None, Some(command)) => Ok(Some(Box::new(CommandStore {
+            program: command.path.resolve_program(config),
+            args: command.args,
+        }))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The name of the entry of `pkg` with checksum `checksum` in a store.
+fn entry_name(pkg: PackageId, checksum: &str) -> String {
+    format!("{}-{}-{}.crate", pkg.name(), pkg.version(), checksum)
+}
+
+/// The name of the recompressed entry of `pkg` with checksum `checksum` in a
+/// [`FilesystemStore`].
+fn recompressed_entry_name(pkg: PackageId, checksum: &str) -> String {
+    format!("{}.recompressed", entry_name(pkg, checksum))
+}
+
+/// A store in a directory, such as a cache volume mounted on CI runners.
+///
+/// Entries are plain files, written to a temporary file first so that other
+/// processes sharing the directory never see a partial entry.
+///
+/// With a compression level set, `.crate` files are recompressed at that
+/// level to save space, but only those that can be restored byte for byte,
+/// so they still match their checksum. Such entries are kept under a name
+/// ending in `.recompressed`, as the level the original was compressed at
+/// followed by the recompressed file. All others are stored as-is.
+pub struct FilesystemStore {
+    root: PathBuf,
+    compression_level: Option<u32>,
+}
+
+impl FilesystemStore {
+    pub fn new(root: PathBuf) -> FilesystemStore {
+        FilesystemStore {
+            root,
+            compression_level: None,
+        }
+    }
+
+    /// Recompresses `.crate` files at `level`, from 0 to 9, when they are
+    /// added, instead of storing them as-is.
+    pub fn compression_level(mut self, level: Option<u32>) -> FilesystemStore {
+        self.compression_level = level;
+        self
+    }
+
+    /// Writes `data` to `name` in the store.
+    fn write_entry(&self, name: &str, data: &[u8]) -> CargoResult<()> {
+        paths::create_dir_all(&self.root)?;
+        let tmp = self
+            .root
+            .join(format!(".{}.{}.tmp", name, std::process::id()));
+        paths::write(&tmp, data)?;
+        let dst = self.root.join(name);
+        fs::rename(&tmp, &dst).with_context(|| {
+            let _ = fs::remove_file(&tmp);
+            format!("failed to move `{}` into place", dst.display())
+        })
+    }
+}
+
+/// Reads the entry at `path`, if there is one.
+fn read_entry(path: &Path) -> CargoResult<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => {
+            Err(anyhow::Error::from(e).context(format!("failed to read `{}`", path.display())))
+        }
+    }
+}
+
+impl CacheStore for FilesystemStore {
+    fn get(&self, pkg: PackageId, checksum: &str) -> CargoResult<Option<Vec<u8>>> {
+        if let Some(data) = read_entry(&self.root.join(entry_name(pkg, checksum)))? {
+            return Ok(Some(data));
+        }
+        let path = self.root.join(recompressed_entry_name(pkg, checksum));
+        let Some(data) = read_entry(&path)? else {
+            return Ok(None);
+        };
+        let Some((&level, recompressed)) = data.split_first() else {
+            anyhow::bail!("`{}` is empty", path.display());
+        };
+        let (header, tar) = gunzip(recompressed)
+            .with_context(|| format!("failed to decompress `{}`", path.display()))?;
+        Ok(Some(gzip(&header, &tar, u32::from(level))?))
+    }
+
+    fn put(&self, pkg: PackageId, checksum: &str, data: &[u8]) -> CargoResult<()> {
+        let Some(level) = self.compression_level else {
+            return self.write_entry(&entry_name(pkg, checksum), data);
+        };
+        match reproducible_level(data)? {
+            Some((header, tar, original)) if original != level => {
+                let mut entry = vec![original as u8];
+                entry.extend(gzip(&header, &tar, level)?);
+                self.write_entry(&recompressed_entry_name(pkg, checksum), &entry)
+            }
+            _ => self.write_entry(&entry_name(pkg, checksum), data),
+        }
+    }
+
+    fn exists(&self, pkg: PackageId, checksum: &str) -> CargoResult<bool> {
+        Ok(self.root.join(entry_name(pkg, checksum)).is_file()
+            || self
+                .root
+                .join(recompressed_entry_name(pkg, checksum))
+                .is_file())
+    }
+}
+
+/// Decompresses the gzip file `data`, returning its header and contents.
+fn gunzip(data: &[u8]) -> CargoResult<(GzHeader, Vec<u8>)> {
+    let mut decoder = GzDecoder::new(data);
+    let mut contents = Vec::new();
+    decoder.read_to_end(&mut contents)?;
+    let header = decoder
+        .header()
+        .cloned()
+        .ok_or_else(|| anyhow::format_err!("missing gzip header"))?;
+    Ok((header, contents))
+}
+
+/// Compresses `contents` at `level` into a gzip file with the fields of
+/// `header`.
+fn gzip(header: &GzHeader, contents: &[u8], level: u32) -> CargoResult<Vec<u8>> {
+    let mut builder = GzBuilder::new()
+        .mtime(header.mtime())
+        .operating_system(header.operating_system());
+    if let Some(filename) = header.filename() {
+        builder = builder.filename(filename);
+    }
+    if let Some(comment) = header.comment() {
+        builder = builder.comment(comment);
+    }
+    if let Some(extra) = header.extra() {
+        builder = builder.extra(extra);
+    }
+    let mut encoder = builder.write(Vec::new(), Compression::new(level));
+    encoder.write_all(contents)?;
+    Ok(encoder.finish()?)
+}
+
+/// Finds the level the gzip file `data` was compressed at, if compressing
+/// its contents again at that level gives back exactly `data`.
+///
+/// Returns the header and contents of the file along with the level. Only
+/// the levels matching the compression flags in the header are tried, which
+/// is a single one for `.crate` files made by `cargo package`.
+fn reproducible_level(data: &[u8]) -> CargoResult<Option<(GzHeader, Vec<u8>, u32)>> {
+    let Ok((header, contents)) = gunzip(data) else {
+        return Ok(None);
+    };
+    // The extra flags byte says whether the fastest, the best or some other
+    // compression was used.
+    let candidates: &[u32] = match data.get(8) {
+        Some(2) => &[9],
+        Some(4) => &[1, 0],
+        _ => &[6, 2, 3, 4, 5, 7, 8],
+    };
+    for &level in candidates {
+        if gzip(&header, &contents, level)? == data {
+            return Ok(Some((header, contents, level)));
+        }
+    }
+    Ok(None)
+}
+
+/// A store backed by an external program, for object storage and the like.
+///
+/// The program is run with its configured arguments followed by the action
+/// and the package:
+///
+/// * `get <name> <version> <checksum> <path>` writes the `.crate` file to
+///   `path`, or leaves the file empty if the store doesn't have it.
+/// * `put <name> <version> <checksum> <path>` stores the `.crate` file at
+///   `path`.
+/// * `exists <name> <version> <checksum>` prints `true` if the store has the
+///   `.crate` file, and `false` otherwise.
+///
+/// Exiting with a non-zero status is an error.
+struct CommandStore {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl CommandStore {
+    fn process(&self, action: &str, pkg: PackageId, checksum: &str) -> ProcessBuilder {
+        let mut process = ProcessBuilder::new(&self.program);
+        process
+            .args(&self.args)
+            .arg(action)
+            .arg(pkg.name().as_str())
+            .arg(pkg.version().to_string())
+            .arg(checksum);
+        process
+    }
+}
+
+impl CacheStore for CommandStore {
+    fn get(&self, pkg: PackageId, checksum: &str) -> CargoResult<Option<Vec<u8>>> {
+        let tmp = tempfile::NamedTempFile::new()?;
+        self.process("get", pkg, checksum)
+            .arg(tmp.path())
+            .exec_with_output()?;
+        let data = paths::read_bytes(tmp.path())?;
+        Ok(if data.is_empty() { None } else { Some(data) })
+    }
+
+    fn put(&self, pkg: PackageId, checksum: &str, data: &[u8]) -> CargoResult<()> {
+        let tmp = tempfile::NamedTempFile::new()?;
+        paths::write(tmp.path(), data)?;
+        self.process("put", pkg, checksum)
+            .arg(tmp.path())
+            .exec_with_output()?;
+        Ok(())
+    }
+
+    fn exists(&self, pkg: PackageId, checksum: &str) -> CargoResult<bool> {
+        let output = self.process("exists", pkg, checksum).exec_with_output()?;
+        match std::str::from_utf8(&output.stdout).map(str::trim) {
+            Ok("true") => Ok(true),
+            Ok("false") => Ok(false),
+            _ => anyhow::bail!(
+                "expected `true` or `false` from `{}`, got `{}`",
+                self.program.display(),
+                String::from_utf8_lossy(&output.stdout).trim()
+            ),
+        }
+    }
+}
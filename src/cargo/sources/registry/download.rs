@@ -1,26 +1,108 @@
 use anyhow::Context;
-use cargo_util::Sha256;
+use cargo_util::{paths, Sha256};
 
 use crate::core::PackageId;
-use crate::sources::registry::make_dep_prefix;
 use crate::sources::registry::MaybeLock;
 use crate::sources::registry::{
-    RegistryConfig, CHECKSUM_TEMPLATE, CRATE_TEMPLATE, LOWER_PREFIX_TEMPLATE, PREFIX_TEMPLATE,
-    VERSION_TEMPLATE,
+    is_out_of_space, make_dep_prefix, out_of_space, resolve_config_url,
+};
+use crate::sources::registry::{
+    RegistryConfig, CHECKSUM_TEMPLATE, CID_TEMPLATE, CRATE_TEMPLATE, LOWER_PREFIX_TEMPLATE,
+    PREFIX_TEMPLATE, VERSION_TEMPLATE,
 };
 use crate::util::auth;
+use crate::util::config::ConfigRelativePath;
 use crate::util::errors::CargoResult;
 use crate::util::{Config, Filesystem};
-use std::fmt::Write as FmtWrite;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Write as FmtWrite};
 use std::fs::{self, File, OpenOptions};
+use std::io;
 use std::io::prelude::*;
-use std::io::SeekFrom;
+use std::path::Path;
 use std::str;
 
 pub(super) fn filename(pkg: PackageId) -> String {
     format!("{}-{}.crate", pkg.name(), pkg.version())
 }
 
+/// The file in the download cache of a registry recording the version of the
+/// layout of the cache, so that versions of Cargo that lay it out differently
+/// can tell which layout it has.
+const CACHE_LAYOUT_FILE: &str = "cache-layout-version";
+
+/// The version of the layout this version of Cargo uses, with all `.crate`
+/// files next to each other as `<name>-<version>.crate`.
+///
+/// Caches without a [`CACHE_LAYOUT_FILE`] were written before it was added,
+/// and have this layout.
+const CACHE_LAYOUT_VERSION: u32 = 1;
+
+/// Checks that the download cache at `cache_path` has a layout this version
+/// of Cargo understands.
+///
+/// Layouts from older versions of Cargo are migrated to the current one
+/// here, of which there are none yet.
+fn check_cache_layout(cache_path: &Filesystem, config: &Config) -> CargoResult<()> {
+    let path = cache_path.join(CACHE_LAYOUT_FILE);
+    let path = config.assert_package_cache_locked(&path);
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(
+                anyhow::Error::from(e).context(format!("failed to read `{}`", path.display()))
+            )
+        }
+    };
+    match contents.trim().parse::<u32>() {
+        Ok(CACHE_LAYOUT_VERSION) => Ok(()),
+        Ok(v) if v > CACHE_LAYOUT_VERSION => anyhow::bail!(
+            "the download cache at `{}` was written by a newer version of Cargo, \
+             with layout version {}, but this version of Cargo only supports \
+             layout version {}\n\
+             use a newer version of Cargo, or remove the directory to download \
+             the packages again",
+            path.parent().unwrap().display(),
+            v,
+            CACHE_LAYOUT_VERSION
+        ),
+        _ => anyhow::bail!(
+            "unknown layout version `{}` in `{}`\n\
+             remove the directory to download the packages again",
+            contents.trim(),
+            path.display()
+        ),
+    }
+}
+
+/// Records the layout of the download cache at `cache_path`, if it isn't
+/// already.
+fn write_cache_layout(cache_path: &Filesystem, config: &Config) -> CargoResult<()> {
+    let path = cache_path.join(CACHE_LAYOUT_FILE);
+    let path = config.assert_package_cache_locked(&path);
+    if !path.exists() {
+        paths::write(path, format!("{}\n", CACHE_LAYOUT_VERSION))?;
+    }
+    Ok(())
+}
+
+/// Looks up the download URL template `registry.mirror-pins` sets for `pkg`,
+/// if any.
+///
+/// A pin for `name:version` takes precedence over one for just `name`.
+fn mirror_pin(config: &Config, pkg: PackageId) -> CargoResult<Option<String>> {
+    let Some(mut pins) = config.get::<Option<HashMap<String, String>>>("registry.mirror-pins")?
+    else {
+        return Ok(None);
+    };
+    let exact = format!("{}:{}", pkg.name(), pkg.version());
+    Ok(pins
+        .remove(&exact)
+        .or_else(|| pins.remove(pkg.name().as_str())))
+}
+
 pub(super) fn download(
     cache_path: &Filesystem,
     config: &Config,
@@ -28,6 +110,7 @@ pub(super) fn download(
     checksum: &str,
     registry_config: RegistryConfig,
 ) -> CargoResult<MaybeLock> {
+    check_cache_layout(cache_path, config)?;
     let filename = filename(pkg);
     let path = cache_path.join(&filename);
     let path = config.assert_package_cache_locked(&path);
@@ -45,12 +128,60 @@ pub(super) fn download(
         }
     }
 
-    let mut url = registry_config.dl;
+    let url = match mirror_pin(config, pkg)? {
+        Some(dl) => dl,
+        None => registry_config.dl,
+    };
+    let url = download_url(config, url, pkg, checksum)?;
+
+    let authorization = if registry_config.auth_required {
+        Some(auth::auth_token(config, &pkg.source_id(), None, None)?)
+    } else {
+        auth::basic_auth(config, &pkg.source_id())?.map(|auth| auth.expose())
+    };
+
+    let cookie = auth::session_cookie(config, &pkg.source_id(), registry_config.api.as_deref())?
+        .map(|cookie| cookie.expose());
+
+    Ok(MaybeLock::Download {
+        url,
+        descriptor: pkg.to_string(),
+        authorization: authorization,
+        cookie,
+        max_concurrent: registry_config.max_concurrent_downloads,
+        hint_basic_auth: !registry_config.auth_required_declared,
+    })
+}
+
+/// The download URLs of `pkg` on the mirrors set with
+/// `registry.download-mirrors`, in order.
+pub(super) fn download_mirrors(
+    config: &Config,
+    pkg: PackageId,
+    checksum: &str,
+) -> CargoResult<Vec<String>> {
+    config
+        .get::<Option<Vec<String>>>("registry.download-mirrors")?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|template| download_url(config, template, pkg, checksum))
+        .collect()
+}
+
+/// Expands the download URL template `url`, like the `dl` field of
+/// [`RegistryConfig`], for `pkg`.
+fn download_url(
+    config: &Config,
+    mut url: String,
+    pkg: PackageId,
+    checksum: &str,
+) -> CargoResult<String> {
     if !url.contains(CRATE_TEMPLATE)
         && !url.contains(VERSION_TEMPLATE)
         && !url.contains(PREFIX_TEMPLATE)
         && !url.contains(LOWER_PREFIX_TEMPLATE)
         && !url.contains(CHECKSUM_TEMPLATE)
+        && !url.contains(CID_TEMPLATE)
     {
         // Original format before customizing the download URL was supported.
         write!(
@@ -61,6 +192,14 @@ pub(super) fn download(
         )
         .unwrap();
     } else {
+        if url.contains(CHECKSUM_TEMPLATE) && checksum.contains(':') {
+            anyhow::bail!(
+                "the download URL of `{}` needs its sha256 checksum, \
+                 but the index lists checksum `{}`",
+                pkg,
+                checksum
+            );
+        }
         let prefix = make_dep_prefix(&*pkg.name());
         url = url
             .replace(CRATE_TEMPLATE, &*pkg.name())
@@ -68,19 +207,59 @@ pub(super) fn download(
             .replace(PREFIX_TEMPLATE, &prefix)
             .replace(LOWER_PREFIX_TEMPLATE, &prefix.to_lowercase())
             .replace(CHECKSUM_TEMPLATE, checksum);
+        if url.contains(CID_TEMPLATE) {
+            url = url.replace(CID_TEMPLATE, &cid_from_checksum(checksum)?);
+        }
     }
 
-    let authorization = if registry_config.auth_required {
-        Some(auth::auth_token(config, &pkg.source_id(), None, None)?)
-    } else {
-        None
-    };
+    let mut url = resolve_config_url(&url, pkg.source_id())?;
 
-    Ok(MaybeLock::Download {
-        url,
-        descriptor: pkg.to_string(),
-        authorization: authorization,
-    })
+    if let Some(path) = url.strip_prefix("ipfs://") {
+        let Some(gateway) = config.get::<Option<String>>("registry.ipfs-gateway")? else {
+            anyhow::bail!(
+                "cannot download `{} v{}` from `{}`\n\
+                 set `registry.ipfs-gateway` to an HTTP gateway to fetch `ipfs://` URLs",
+                pkg.name(),
+                pkg.version(),
+                url
+            );
+        };
+        url = format!("{}/ipfs/{}", gateway.trim_end_matches('/'), path);
+    }
+    Ok(url)
+}
+
+/// Computes the IPFS content identifier of a `.crate` file from its sha256
+/// checksum.
+///
+/// This is a CIDv1 with the `raw` codec, so it addresses the tarball bytes
+/// directly and anyone can derive it from the file. It is encoded as
+/// lowercase base32 with the `b` multibase prefix.
+fn cid_from_checksum(checksum: &str) -> CargoResult<String> {
+    let digest = hex::decode(checksum)
+        .ok()
+        .filter(|digest| digest.len() == 32)
+        .ok_or_else(|| anyhow::format_err!("invalid sha256 checksum `{}`", checksum))?;
+    // CIDv1, raw codec, sha2-256 multihash of 32 bytes.
+    let mut bytes = vec![0x01, 0x55, 0x12, 0x20];
+    bytes.extend(digest);
+
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut cid = String::from("b");
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            cid.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        cid.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    Ok(cid)
 }
 
 pub(super) fn finish_download(
@@ -90,30 +269,184 @@ pub(super) fn finish_download(
     checksum: &str,
     data: &[u8],
 ) -> CargoResult<File> {
-    // Verify what we just downloaded
-    let actual = Sha256::new().update(data).finish_hex();
-    if actual != checksum {
-        anyhow::bail!("failed to verify the checksum of `{}`", pkg)
-    }
-
     let filename = filename(pkg);
     cache_path.create_dir()?;
+    check_cache_layout(cache_path, config)?;
+    write_cache_layout(cache_path, config)?;
     let path = cache_path.join(&filename);
     let path = config.assert_package_cache_locked(&path);
-    let mut dst = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(&path)
-        .with_context(|| format!("failed to open `{}`", path.display()))?;
-    let meta = dst.metadata()?;
-    if meta.len() > 0 {
-        return Ok(dst);
+    match File::open(path) {
+        Ok(dst) if dst.metadata()?.len() > 0 => return Ok(dst),
+        _ => {}
+    }
+
+    // The data is hashed as it's written to a `.part` file, which is only
+    // moved into place once it's verified. Neither a checksum mismatch nor an
+    // interrupted write leaves a file behind that would be mistaken for a
+    // complete download next time.
+    let part = path.with_file_name(format!("{}.part", filename));
+    if let Err(e) = write_verified(&part, data, checksum, pkg) {
+        if let Err(err) = fs::remove_file(&part) {
+            log::debug!("failed to remove partial download: {err}");
+        }
+        return Err(if is_out_of_space(&e) {
+            out_of_space(e, config, "downloading", pkg)
+        } else {
+            e
+        });
+    }
+    fs::rename(&part, path)
+        .with_context(|| format!("failed to move `{}` into place", path.display()))?;
+    File::open(path).with_context(|| format!("failed to open `{}`", path.display()))
+}
+
+/// Writes `data` to `path`, failing if its checksum isn't `checksum`.
+fn write_verified(path: &Path, data: &[u8], checksum: &str, pkg: PackageId) -> CargoResult<()> {
+    let mut dst =
+        File::create(path).with_context(|| format!("failed to open `{}`", path.display()))?;
+    let mut hasher = Hasher::for_checksum(checksum, pkg)?;
+    for chunk in data.chunks(64 * 1024) {
+        hasher.update(chunk);
+        dst.write_all(chunk)
+            .with_context(|| format!("failed to write `{}`", path.display()))?;
+    }
+    if hasher.finish_hex() != checksum {
+        anyhow::bail!("failed to verify the checksum of `{}`", pkg)
+    }
+    Ok(())
+}
+
+/// Checks that the `.crate` file at `path` has the sha256 checksum
+/// `expected`, given in hex, without reading the whole file into memory.
+pub fn verify_tarball(path: &Path, expected: &str) -> CargoResult<()> {
+    verify_checksum(paths::open(path)?, expected, path.display())
+}
+
+/// Checks that `data` has the checksum `expected`, hashing it as it is read.
+/// `what` is the name of the data in the error.
+pub(super) fn verify_checksum(
+    data: impl Read,
+    expected: &str,
+    what: impl fmt::Display,
+) -> CargoResult<()> {
+    if checksum_of(data, expected, &what)? != expected {
+        anyhow::bail!("failed to verify the checksum of `{}`", what)
+    }
+    Ok(())
+}
+
+/// Computes the checksum of `data` with the algorithm of the checksum `like`,
+/// in the same format. `what` is the name of the data in the error.
+pub(super) fn checksum_of(
+    mut data: impl Read,
+    like: &str,
+    what: impl fmt::Display,
+) -> CargoResult<String> {
+    let mut hasher = Hasher::for_checksum(like, what)?;
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buf[..n]);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(hasher.finish_hex())
+}
+
+/// Hashes `.crate` files with the algorithm the index declares with
+/// `cksum_algo`.
+///
+/// Checksums of algorithms other than sha256 are kept as `<algo>:<hex>`,
+/// while sha256 checksums are plain hex, like in indexes without
+/// `cksum_algo`.
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    /// Creates a hasher for the algorithm of `checksum`, the checksum of
+    /// `what`, failing for algorithms Cargo doesn't know.
+    fn for_checksum(checksum: &str, what: impl fmt::Display) -> CargoResult<Hasher> {
+        match checksum.split_once(':') {
+            None => Ok(Hasher::Sha256(Sha256::new())),
+            Some(("sha512", _)) => Ok(Hasher::Sha512(sha2::Digest::new())),
+            Some((algo, _)) => anyhow::bail!(
+                "the index lists a checksum of `{}` with the unsupported algorithm `{}`, \
+                 so it can't be verified",
+                what,
+                algo
+            ),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Sha512(hasher) => sha2::Digest::update(hasher, data),
+        }
+    }
+
+    fn finish_hex(self) -> String {
+        match self {
+            Hasher::Sha256(mut hasher) => hasher.finish_hex(),
+            Hasher::Sha512(hasher) => {
+                format!("sha512:{}", hex::encode(sha2::Digest::finalize(hasher)))
+            }
+        }
     }
+}
+
+/// A record of where a `.crate` file was fetched from, written as a line of
+/// JSON to the file set with `registry.provenance-log`.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Provenance<'a> {
+    name: &'a str,
+    version: String,
+    source: String,
+    url: &'a str,
+    /// The sha256 checksum the downloaded data was verified against.
+    checksum: &'a str,
+    index_revision: Option<&'a str>,
+}
 
-    dst.write_all(data)?;
-    dst.seek(SeekFrom::Start(0))?;
-    Ok(dst)
+/// Appends a [`Provenance`] record for a verified download of `pkg`, if
+/// `registry.provenance-log` is configured.
+pub(super) fn record_provenance(
+    config: &Config,
+    pkg: PackageId,
+    url: &str,
+    checksum: &str,
+    index_revision: Option<&str>,
+) -> CargoResult<()> {
+    let Some(log) = config.get::<Option<ConfigRelativePath>>("registry.provenance-log")? else {
+        return Ok(());
+    };
+    let path = log.resolve_path(config);
+    let record = Provenance {
+        name: &pkg.name(),
+        version: pkg.version().to_string(),
+        source: pkg.source_id().url().to_string(),
+        url,
+        checksum,
+        index_revision,
+    };
+    let mut line = serde_json::to_string(&record)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("failed to write provenance log `{}`", path.display()))?;
+    Ok(())
 }
 
 pub(super) fn is_crate_downloaded(
@@ -121,6 +454,10 @@ pub(super) fn is_crate_downloaded(
     config: &Config,
     pkg: PackageId,
 ) -> bool {
+    // `download` reports why a cache with an unknown layout can't be used.
+    if check_cache_layout(cache_path, config).is_err() {
+        return false;
+    }
     let path = cache_path.join(filename(pkg));
     let path = config.assert_package_cache_locked(&path);
     if let Ok(meta) = fs::metadata(path) {
@@ -128,3 +465,70 @@ pub(super) fn is_crate_downloaded(
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{cid_from_checksum, is_out_of_space, resolve_config_url, verify_tarball};
+    use crate::core::SourceId;
+    use crate::util::IntoUrl;
+
+    #[test]
+    fn cid_of_empty_file() {
+        let checksum = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(
+            cid_from_checksum(checksum).unwrap(),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+        assert!(cid_from_checksum("abcd").is_err());
+    }
+
+    #[test]
+    fn relative_dl_urls() {
+        let git = "https://example.com/git/index".into_url().unwrap();
+        let git = SourceId::for_registry(&git).unwrap();
+        let sparse = "sparse+https://example.com/index/".into_url().unwrap();
+        let sparse = SourceId::for_registry(&sparse).unwrap();
+        for sid in [git, sparse] {
+            assert_eq!(
+                resolve_config_url("/api/v1/crates", sid).unwrap(),
+                "https://example.com/api/v1/crates"
+            );
+            assert_eq!(
+                resolve_config_url("https://dl.example.org/crates", sid).unwrap(),
+                "https://dl.example.org/crates"
+            );
+        }
+        assert_eq!(
+            resolve_config_url("crates", sparse).unwrap(),
+            "https://example.com/index/crates"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn out_of_space_errors() {
+        let enospc = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        let err = anyhow::Error::from(enospc).context("failed to write `foo.crate`");
+        assert!(is_out_of_space(&err));
+        let eacces = std::io::Error::from_raw_os_error(libc::EACCES);
+        assert!(!is_out_of_space(&anyhow::Error::from(eacces)));
+    }
+
+    #[test]
+    fn verify_tarball_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("foo-0.1.0.crate");
+        std::fs::write(&path, b"").unwrap();
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        verify_tarball(&path, empty).unwrap();
+
+        std::fs::write(&path, b"not empty").unwrap();
+        let err = verify_tarball(&path, empty).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("failed to verify the checksum of `{}`", path.display())
+        );
+
+        assert!(verify_tarball(&dir.path().join("missing.crate"), empty).is_err());
+    }
+}
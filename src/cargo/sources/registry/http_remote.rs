@@ -8,6 +8,7 @@ use crate::sources::registry::download;
 use crate::sources::registry::MaybeLock;
 use crate::sources::registry::{LoadResponse, RegistryConfig, RegistryData};
 use crate::util::errors::{CargoResult, HttpNotSuccessful, DEBUG_HEADERS};
+use crate::util::network;
 use crate::util::network::retry::{Retry, RetryResult};
 use crate::util::network::sleep::SleepTracker;
 use crate::util::{auth, Config, Filesystem, IntoUrl, Progress, ProgressStyle};
@@ -114,6 +115,9 @@ struct Downloads<'cfg> {
     progress: RefCell<Option<Progress<'cfg>>>,
     /// Number of downloads that have successfully finished.
     downloads_finished: usize,
+    /// Number of bytes received for index files, including failed and
+    /// retried transfers.
+    bytes_received: u64,
     /// Number of times the caller has requested blocking. This is used for
     /// an estimate of progress.
     blocking_calls: usize,
@@ -196,6 +200,7 @@ impl<'cfg> HttpRegistry<'cfg> {
                     config,
                 ))),
                 downloads_finished: 0,
+                bytes_received: 0,
                 blocking_calls: 0,
             },
             fresh: HashSet::new(),
@@ -278,9 +283,12 @@ impl<'cfg> HttpRegistry<'cfg> {
             );
             let mut handle = self.multi.remove(handle)?;
             let data = download.data.take();
+            self.downloads.bytes_received += data.len() as u64;
             let url = self.full_url(&download.path);
             let result = match download.retry.r#try(|| {
-                result.with_context(|| format!("failed to download from `{}`", url))?;
+                result
+                    .map_err(|e| network::with_tls_context(e, self.config))
+                    .with_context(|| format!("failed to download from `{}`", url))?;
                 let code = handle.response_code()?;
                 // Keep this list of expected status codes in sync with the codes handled in `load`
                 let code = match code {
@@ -388,7 +396,8 @@ impl<'cfg> HttpRegistry<'cfg> {
                 index_version: _,
             } => {
                 trace!("config loaded");
-                self.registry_config = Some(serde_json::from_slice(&raw_data)?);
+                let url = self.full_url(Path::new("config.json"));
+                self.registry_config = Some(RegistryConfig::from_slice(&raw_data, &url)?);
                 if paths::create_dir_all(&config_json_path.parent().unwrap()).is_ok() {
                     if let Err(e) = fs::write(&config_json_path, &raw_data) {
                         log::debug!("failed to write config.json cache: {}", e);
@@ -435,6 +444,15 @@ impl<'cfg> RegistryData for HttpRegistry<'cfg> {
         self.requested_update
     }
 
+    fn is_index_ready(&self) -> bool {
+        // The index files are fetched on demand, so a cached `config.json` is
+        // all that's needed to start reading from the index.
+        self.index_path
+            .as_path_unlocked()
+            .join("config.json")
+            .is_file()
+    }
+
     fn load(
         &mut self,
         _root: &Path,
@@ -699,9 +717,7 @@ impl<'cfg> RegistryData for HttpRegistry<'cfg> {
 
     fn config(&mut self) -> Poll<CargoResult<Option<RegistryConfig>>> {
         let mut cfg = ready!(self.config()?).clone();
-        if !self.config.cli_unstable().registry_auth {
-            cfg.auth_required = false;
-        }
+        cfg.gate_auth_required(self.config);
         Poll::Ready(Ok(Some(cfg)))
     }
 
@@ -719,6 +735,10 @@ impl<'cfg> RegistryData for HttpRegistry<'cfg> {
         self.downloads.progress.replace(None);
     }
 
+    fn index_bytes_transferred(&self) -> u64 {
+        self.downloads.bytes_received
+    }
+
     fn download(&mut self, pkg: PackageId, checksum: &str) -> CargoResult<MaybeLock> {
         let registry_config = loop {
             match self.config()? {
@@ -62,22 +62,40 @@
 //! blobs and loads 0 files git. Removing 200ms or more from Cargo's startup
 //! time is certainly nothing to sneeze at!
 //!
+//! ## Concurrency
+//!
+//! Queries take `&mut self`, and a [`RegistryIndex`] borrows a [`Config`],
+//! which isn't `Sync`, so an index is never used from several threads and
+//! its caches need no locks. Concurrency comes from queries instead
+//! returning [`Poll::Pending`] while their index files are fetched: the
+//! resolver issues many queries, and the fetches of all of them are waited
+//! for together with [`RegistryData::block_until_ready`]. Sources of the
+//! same registry share what they read with an [`IndexReadCache`], which is
+//! single-threaded too.
+//!
 //! Note that this is just a high-level overview, there's of course lots of
 //! details like invalidating caches and whatnot which are handled below, but
 //! hopefully those are more obvious inline in the code itself.
 
 use crate::core::{PackageId, SourceId, Summary};
-use crate::sources::registry::{LoadResponse, RegistryData, RegistryPackage, INDEX_V_MAX};
+use crate::sources::registry::{
+    registries_key, CheckLevel, LoadResponse, RegistryData, RegistryDeprecation, RegistryPackage,
+    INDEX_V_MAX,
+};
 use crate::util::interning::InternedString;
 use crate::util::{internal, CargoResult, Config, Filesystem, OptVersionReq, ToSemver};
 use anyhow::bail;
 use cargo_util::{paths, registry::make_dep_path};
+use lazycell::LazyCell;
 use log::{debug, info};
 use semver::Version;
-use std::collections::{HashMap, HashSet};
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::io::ErrorKind;
 use std::path::Path;
+use std::rc::Rc;
 use std::str;
 use std::task::{ready, Poll};
 
@@ -104,6 +122,102 @@ pub struct RegistryIndex<'cfg> {
     summaries_cache: HashMap<InternedString, Summaries>,
     /// [`Config`] reference for convenience.
     config: &'cfg Config,
+    /// How to react to different versions of a crate sharing a checksum,
+    /// from the `registry.duplicate-checksums` config value.
+    duplicate_checksums: LazyCell<CheckLevel>,
+    /// Checksums that have already been reported as shared between versions,
+    /// so that each one is only reported once per session.
+    reported_checksums: HashSet<(InternedString, String)>,
+    /// Whether dependencies on packages of this registry never enable their
+    /// default features, from `registries.<name>.default-features`.
+    no_default_features: LazyCell<bool>,
+    /// Whether versions in the index are read with [`normalize_version`],
+    /// from the `registry.normalize-versions` config value.
+    normalize_versions: LazyCell<bool>,
+    /// Whether index entries without a checksum are read, from
+    /// `registries.<name>.allow-missing-checksum`.
+    allow_missing_checksum: LazyCell<bool>,
+    /// Where the files of packages are in the index, from
+    /// `registries.<name>.index-layout`.
+    layout: LazyCell<IndexLayout>,
+    /// The index lines of the versions loaded so far, keyed by the name they
+    /// were loaded for, if they are kept. See [`RegistryIndex::keep_lines`].
+    lines: Option<HashMap<(InternedString, Version), String>>,
+    /// Summaries read by other sources of the same index, if this source
+    /// shares them. See [`IndexReadCache`].
+    shared: Option<Rc<IndexReadCache>>,
+    /// What reading the index took so far, shared with the iterators handed
+    /// out by [`RegistryIndex::summaries`].
+    stats: Rc<Cell<IoStats>>,
+}
+
+/// Counters of the work a [`RegistryIndex`] did to read the index, to find
+/// out where the time of a slow resolve goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// Index files read, either from the registry or from the on-disk cache
+    /// of the index.
+    pub files_read: usize,
+    /// Total size of the index files read.
+    pub bytes_read: u64,
+    /// Index lines parsed into summaries. Lines are only parsed once a query
+    /// matches their version.
+    pub lines_parsed: usize,
+    /// Lookups of a package whose versions were already in memory, including
+    /// those read by other sources sharing an [`IndexReadCache`].
+    pub cache_hits: usize,
+    /// Lookups of a package that had to read its index file.
+    pub cache_misses: usize,
+}
+
+/// A cache of what has been read from registry indexes, to be shared by
+/// several sources, so that a source created for an index that another
+/// source has read already doesn't read and parse the same files again.
+///
+/// Summaries are keyed by the registry, the revision of its index, and the
+/// name of the package, so they are only reused while the index stays at the
+/// same revision, such as the same commit of a git index. Once a source
+/// reads a package from another revision, that replaces what was cached for
+/// the package. Registries without a single revision for the whole index,
+/// see [`RegistryData::index_revision`], don't use the cache.
+///
+/// Sources only share a cache when given one with
+/// [`RegistrySource::set_index_read_cache`].
+///
+/// [`RegistrySource::set_index_read_cache`]: super::RegistrySource::set_index_read_cache
+#[derive(Default)]
+pub struct IndexReadCache {
+    summaries: RefCell<HashMap<(SourceId, InternedString), (InternedString, Summaries)>>,
+}
+
+impl IndexReadCache {
+    pub fn new() -> IndexReadCache {
+        IndexReadCache::default()
+    }
+
+    fn get(
+        &self,
+        source_id: SourceId,
+        revision: InternedString,
+        name: InternedString,
+    ) -> Option<Summaries> {
+        match self.summaries.borrow().get(&(source_id, name)) {
+            Some((cached, summaries)) if *cached == revision => Some(summaries.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(
+        &self,
+        source_id: SourceId,
+        revision: InternedString,
+        name: InternedString,
+        summaries: &Summaries,
+    ) {
+        self.summaries
+            .borrow_mut()
+            .insert((source_id, name), (revision, summaries.clone()));
+    }
 }
 
 /// An internal cache of summaries for a particular package.
@@ -122,19 +236,31 @@ pub struct RegistryIndex<'cfg> {
 /// The outward-facing interface of this doesn't matter too much where it's
 /// loaded from, but it's important when reading the implementation to note that
 /// we try to parse as little as possible!
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct Summaries {
     /// A raw vector of uninterpreted bytes. This is what `Unparsed` start/end
     /// fields are indexes into. If a `Summaries` is loaded from the crates.io
     /// index then this field will be empty since nothing is `Unparsed`.
-    raw_data: Vec<u8>,
+    ///
+    /// It's reference-counted as it's shared with the copies of these
+    /// summaries in an [`IndexReadCache`].
+    raw_data: Rc<Vec<u8>>,
 
     /// All known versions of a crate, keyed from their `Version` to the
     /// possibly parsed or unparsed version of the full summary.
     versions: HashMap<Version, MaybeIndexSummary>,
 }
 
+/// The fields of a line in the index that identify its version of a package,
+/// which is all that's parsed of each line when reading a whole index file.
+#[derive(Deserialize)]
+struct IndexEntryId {
+    name: InternedString,
+    vers: InternedString,
+}
+
 /// A lazily parsed `IndexSummary`.
+#[derive(Clone)]
 enum MaybeIndexSummary {
     /// A summary which has not been parsed, The `start` and `end` are pointers
     /// into `Summaries::raw_data` which this is an entry of.
@@ -146,10 +272,15 @@ enum MaybeIndexSummary {
 
 /// A parsed representation of a summary from the index.
 ///
-/// In addition to a full `Summary` we have information on whether it is `yanked`.
+/// In addition to a full `Summary` we have information on whether it is
+/// `yanked`, and whether the registry has `deprecated` it.
+#[derive(Clone)]
 pub struct IndexSummary {
     pub summary: Summary,
     pub yanked: bool,
+    pub deprecated: Option<RegistryDeprecation>,
+    /// Size of the `.crate` file in bytes, if the index lists it.
+    pub size: Option<u64>,
     /// Schema version, see [`RegistryPackage`].
     v: u32,
 }
@@ -175,19 +306,91 @@ impl<'cfg> RegistryIndex<'cfg> {
             path: path.clone(),
             summaries_cache: HashMap::new(),
             config,
+            duplicate_checksums: LazyCell::new(),
+            reported_checksums: HashSet::new(),
+            no_default_features: LazyCell::new(),
+            normalize_versions: LazyCell::new(),
+            allow_missing_checksum: LazyCell::new(),
+            layout: LazyCell::new(),
+            lines: None,
+            shared: None,
+            stats: Rc::default(),
         }
     }
 
+    /// Returns what reading the index took so far.
+    pub fn io_stats(&self) -> IoStats {
+        self.stats.get()
+    }
+
+    /// Shares the summaries read from the index with other sources that use
+    /// the same `cache`, see [`IndexReadCache`].
+    pub fn set_read_cache(&mut self, cache: Rc<IndexReadCache>) {
+        self.shared = Some(cache);
+    }
+
+    /// Sets whether the index line of every version loaded from now on is
+    /// kept, to be looked up with [`RegistryIndex::line`].
+    ///
+    /// This is off by default, as it keeps another copy of the index files
+    /// in memory.
+    pub fn keep_lines(&mut self, keep: bool) {
+        if keep == self.lines.is_some() {
+            return;
+        }
+        // Summaries loaded before don't have their lines.
+        self.summaries_cache.clear();
+        self.lines = keep.then(HashMap::new);
+    }
+
+    /// Returns the line of the index `pkg` was read from, verbatim, if lines
+    /// are kept and the index has a line for it.
+    pub fn line(
+        &mut self,
+        pkg: PackageId,
+        load: &mut dyn RegistryData,
+    ) -> Poll<CargoResult<Option<&str>>> {
+        if self.lines.is_none() {
+            return Poll::Ready(Ok(None));
+        }
+        ready!(self.load_summaries(pkg.name(), load))?;
+        let key = (pkg.name(), pkg.version().clone());
+        Poll::Ready(Ok(self
+            .lines
+            .as_ref()
+            .and_then(|lines| lines.get(&key))
+            .map(String::as_str)))
+    }
+
     /// Returns the hash listed for a specified `PackageId`.
-    pub fn hash(&mut self, pkg: PackageId, load: &mut dyn RegistryData) -> Poll<CargoResult<&str>> {
+    ///
+    /// This is `None` if the index lists no hash for it, which is only the
+    /// case with `registries.<name>.allow-missing-checksum`.
+    pub fn hash(
+        &mut self,
+        pkg: PackageId,
+        load: &mut dyn RegistryData,
+    ) -> Poll<CargoResult<Option<&str>>> {
         let req = OptVersionReq::exact(pkg.version());
         let summary = self.summaries(&pkg.name(), &req, load)?;
         let summary = ready!(summary).next();
         Poll::Ready(Ok(summary
             .ok_or_else(|| internal(format!("no hash listed for {}", pkg)))?
             .summary
-            .checksum()
-            .ok_or_else(|| internal(format!("no hash listed for {}", pkg)))?))
+            .checksum()))
+    }
+
+    /// Returns the size of the `.crate` file of `pkg` listed in the index, if
+    /// any.
+    pub fn size(
+        &mut self,
+        pkg: PackageId,
+        load: &mut dyn RegistryData,
+    ) -> Poll<CargoResult<Option<u64>>> {
+        let req = OptVersionReq::exact(pkg.version());
+        let summary = self.summaries(&pkg.name(), &req, load)?;
+        let summary = ready!(summary).next();
+        Poll::Ready(Ok(summary.and_then(|s| s.size)))
     }
 
     /// Load a list of summaries for `name` package in this registry which
@@ -208,6 +411,9 @@ impl<'cfg> RegistryIndex<'cfg> {
     {
         let source_id = self.source_id;
         let config = self.config;
+        let normalize = self.normalize_versions()?;
+        let allow_missing_checksum = self.allow_missing_checksum()?;
+        let stats = Rc::clone(&self.stats);
 
         // First up actually parse what summaries we have available. If Cargo
         // has run previously this will parse a Cargo-specific cache file rather
@@ -227,15 +433,26 @@ impl<'cfg> RegistryIndex<'cfg> {
             .versions
             .iter_mut()
             .filter_map(move |(k, v)| if req.matches(k) { Some(v) } else { None })
-            .filter_map(
-                move |maybe| match maybe.parse(config, raw_data, source_id) {
+            .filter_map(move |maybe| {
+                if let MaybeIndexSummary::Unparsed { .. } = maybe {
+                    let mut s = stats.get();
+                    s.lines_parsed += 1;
+                    stats.set(s);
+                }
+                match maybe.parse(
+                    config,
+                    raw_data,
+                    source_id,
+                    normalize,
+                    allow_missing_checksum,
+                ) {
                     Ok(summary) => Some(summary),
                     Err(e) => {
                         info!("failed to parse `{}` registry package: {}", name, e);
                         None
                     }
-                },
-            )
+                }
+            })
             .filter(move |is| {
                 if is.v > INDEX_V_MAX {
                     debug!(
@@ -259,6 +476,7 @@ impl<'cfg> RegistryIndex<'cfg> {
         // If we've previously loaded what versions are present for `name`, just
         // return that since our cache should still be valid.
         if self.summaries_cache.contains_key(&name) {
+            self.count(|s| s.cache_hits += 1);
             return Poll::Ready(Ok(self.summaries_cache.get_mut(&name).unwrap()));
         }
 
@@ -276,23 +494,110 @@ impl<'cfg> RegistryIndex<'cfg> {
             .flat_map(|c| c.to_lowercase())
             .collect::<String>();
 
-        let path = make_dep_path(&fs_name, false);
-        let summaries = ready!(Summaries::parse(
-            root,
-            &cache_root,
-            path.as_ref(),
-            self.source_id,
-            load,
-            self.config,
-        ))?
-        .unwrap_or_default();
+        let Some(path) = self.layout()?.path(&fs_name) else {
+            let what = if name.is_empty() {
+                "an empty name".to_string()
+            } else {
+                format!("the name `{}`", name)
+            };
+            return Poll::Ready(Err(anyhow::format_err!(
+                "cannot look up a package with {} in registry `{}`, \
+                 as it has no path in the index",
+                what,
+                self.source_id.display_registry_name()
+            )));
+        };
+        let revision = self.shared.as_ref().and(load.index_revision());
+        let cached = match (&self.shared, revision) {
+            (Some(shared), Some(revision)) => shared.get(self.source_id, revision, name),
+            _ => None,
+        };
+        let summaries = match cached {
+            Some(summaries) => {
+                self.count(|s| s.cache_hits += 1);
+                summaries
+            }
+            None => {
+                let summaries = ready!(Summaries::parse(
+                    root,
+                    &cache_root,
+                    path.as_ref(),
+                    self.source_id,
+                    load,
+                    self.config,
+                ))?;
+                self.count(|s| {
+                    s.cache_misses += 1;
+                    if let Some(summaries) = &summaries {
+                        s.files_read += 1;
+                        s.bytes_read += summaries.raw_data.len() as u64;
+                    }
+                });
+                let summaries = summaries.unwrap_or_default();
+                // Loading may have updated the index to another revision.
+                if let (Some(shared), Some(revision)) = (&self.shared, load.index_revision()) {
+                    shared.insert(self.source_id, revision, name, &summaries);
+                }
+                summaries
+            }
+        };
+        if let Some(lines) = &mut self.lines {
+            for (version, summary) in &summaries.versions {
+                if let MaybeIndexSummary::Unparsed { start, end } = summary {
+                    let line = String::from_utf8_lossy(&summaries.raw_data[*start..*end]);
+                    lines.insert((name, version.clone()), line.into_owned());
+                }
+            }
+        }
         self.summaries_cache.insert(name, summaries);
         Poll::Ready(Ok(self.summaries_cache.get_mut(&name).unwrap()))
     }
 
+    /// Updates the counters of [`RegistryIndex::io_stats`].
+    fn count(&self, f: impl FnOnce(&mut IoStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// Reads the index from `path` from now on, such as after switching to
+    /// another protocol to access it.
+    pub fn set_path(&mut self, path: &Filesystem) {
+        self.path = path.clone();
+        self.clear_summaries_cache();
+    }
+
     /// Clears the in-memory summaries cache.
     pub fn clear_summaries_cache(&mut self) {
         self.summaries_cache.clear();
+        if let Some(lines) = &mut self.lines {
+            lines.clear();
+        }
+    }
+
+    /// Returns whether the index has any version of `name`.
+    ///
+    /// Only the versions of the lines are looked at, without parsing the
+    /// summaries themselves.
+    pub fn has_versions(
+        &mut self,
+        name: InternedString,
+        load: &mut dyn RegistryData,
+    ) -> Poll<CargoResult<bool>> {
+        let summaries = ready!(self.load_summaries(name, load))?;
+        Poll::Ready(Ok(!summaries.versions.is_empty()))
+    }
+
+    /// Iterates over the summaries that have been loaded into memory and
+    /// parsed so far.
+    pub fn loaded_summaries(&self) -> impl Iterator<Item = &IndexSummary> {
+        self.summaries_cache
+            .values()
+            .flat_map(|summaries| summaries.versions.values())
+            .filter_map(|maybe| match maybe {
+                MaybeIndexSummary::Parsed(summary) => Some(summary),
+                MaybeIndexSummary::Unparsed { .. } => None,
+            })
     }
 
     pub fn query_inner(
@@ -303,57 +608,71 @@ impl<'cfg> RegistryIndex<'cfg> {
         yanked_whitelist: &HashSet<PackageId>,
         f: &mut dyn FnMut(Summary),
     ) -> Poll<CargoResult<()>> {
-        if self.config.offline() {
-            // This should only return `Poll::Ready(Ok(()))` if there is at least 1 match.
+        if *self.duplicate_checksums_policy()? != CheckLevel::Allow {
+            // Checked before any filtering, so that a mistake in the index is
+            // noticed even if the affected version is yanked.
+            let checksums = ready!(self.summaries(name, req, load))?
+                .filter_map(|s| {
+                    let cksum = s.summary.checksum()?.to_string();
+                    Some((cksum, s.summary.version().clone()))
+                })
+                .collect();
+            self.check_duplicate_checksums(name, checksums)?;
+        }
+        ready!(self.query_iter(name, req, load, yanked_whitelist))?.for_each(f);
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns an iterator over the summaries `query_inner` would pass to its
+    /// callback, in the same order.
+    ///
+    /// Index lines are only parsed as the iterator is advanced, so a caller
+    /// that only needs some of the summaries can stop early without parsing
+    /// the remaining versions of a crate. Unlike `query_inner`, this doesn't
+    /// check the index for duplicate checksums.
+    pub fn query_iter<'a>(
+        &'a mut self,
+        name: &'a str,
+        req: &'a OptVersionReq,
+        load: &'a mut dyn RegistryData,
+        yanked_whitelist: &'a HashSet<PackageId>,
+    ) -> Poll<CargoResult<impl Iterator<Item = Summary> + 'a>> {
+        let mut online = !self.config.offline();
+        if !online {
+            // Only versions that are already downloaded are candidates when
+            // offline, unless there are none at all.
             //
-            // If there are 0 matches it should fall through and try again with online.
-            // This is necessary for dependencies that are not used (such as
+            // If there are none, fall back to every version. This is
+            // necessary for dependencies that are not used (such as
             // target-cfg or optional), but are not downloaded. Normally the
             // build should succeed if they are not downloaded and not used,
             // but they still need to resolve. If they are actually needed
             // then cargo will fail to download and an error message
             // indicating that the required dependency is unavailable while
             // offline will be displayed.
-            if ready!(self.query_inner_with_online(name, req, load, yanked_whitelist, f, false)?)
-                > 0
-            {
-                return Poll::Ready(Ok(()));
-            }
+            online = ready!(self.query_iter_with_online(
+                name,
+                req,
+                &mut *load,
+                yanked_whitelist,
+                false
+            ))?
+            .next()
+            .is_none();
         }
-        self.query_inner_with_online(name, req, load, yanked_whitelist, f, true)
-            .map_ok(|_| ())
+        self.query_iter_with_online(name, req, load, yanked_whitelist, online)
     }
 
-    fn query_inner_with_online(
-        &mut self,
-        name: &str,
-        req: &OptVersionReq,
-        load: &mut dyn RegistryData,
-        yanked_whitelist: &HashSet<PackageId>,
-        f: &mut dyn FnMut(Summary),
+    fn query_iter_with_online<'a>(
+        &'a mut self,
+        name: &'a str,
+        req: &'a OptVersionReq,
+        load: &'a mut dyn RegistryData,
+        yanked_whitelist: &'a HashSet<PackageId>,
         online: bool,
-    ) -> Poll<CargoResult<usize>> {
+    ) -> Poll<CargoResult<impl Iterator<Item = Summary> + 'a>> {
         let source_id = self.source_id;
-
-        let summaries = ready!(self.summaries(name, req, load))?;
-
-        let summaries = summaries
-            // First filter summaries for `--offline`. If we're online then
-            // everything is a candidate, otherwise if we're offline we're only
-            // going to consider candidates which are actually present on disk.
-            //
-            // Note: This particular logic can cause problems with
-            // optional dependencies when offline. If at least 1 version
-            // of an optional dependency is downloaded, but that version
-            // does not satisfy the requirements, then resolution will
-            // fail. Unfortunately, whether or not something is optional
-            // is not known here.
-            .filter(|s| (online || load.is_crate_downloaded(s.summary.package_id())))
-            // Next filter out all yanked packages. Some yanked packages may
-            // leak through if they're in a whitelist (aka if they were
-            // previously in `Cargo.lock`
-            .filter(|s| !s.yanked || yanked_whitelist.contains(&s.summary.package_id()))
-            .map(|s| s.summary.clone());
+        let no_default_features = self.no_default_features()?;
 
         // Handle `cargo update --precise` here. If specified, our own source
         // will have a precise version listed of the form
@@ -369,39 +688,231 @@ impl<'cfg> RegistryIndex<'cfg> {
             }
             _ => None,
         };
-        let summaries = summaries.filter(|s| match &precise {
-            Some((current, requested)) => {
-                if req.matches(current) {
-                    // Unfortunately crates.io allows versions to differ only
-                    // by build metadata. This shouldn't be allowed, but since
-                    // it is, this will honor it if requested. However, if not
-                    // specified, then ignore it.
-                    let s_vers = s.version();
-                    match (s_vers.build.is_empty(), requested.build.is_empty()) {
-                        (true, true) => s_vers == requested,
-                        (true, false) => false,
-                        (false, true) => {
-                            // Strip out the metadata.
-                            s_vers.major == requested.major
-                                && s_vers.minor == requested.minor
-                                && s_vers.patch == requested.patch
-                                && s_vers.pre == requested.pre
+
+        let summaries = ready!(self.summaries(name, req, &mut *load))?;
+        let load = &*load;
+
+        Poll::Ready(Ok(summaries
+            // First filter summaries for `--offline`. If we're online then
+            // everything is a candidate, otherwise if we're offline we're only
+            // going to consider candidates which are actually present on disk.
+            //
+            // Note: This particular logic can cause problems with
+            // optional dependencies when offline. If at least 1 version
+            // of an optional dependency is downloaded, but that version
+            // does not satisfy the requirements, then resolution will
+            // fail. Unfortunately, whether or not something is optional
+            // is not known here.
+            .filter(move |s| online || load.is_crate_downloaded(s.summary.package_id()))
+            // Next filter out all yanked packages. Some yanked packages may
+            // leak through if they're in a whitelist (aka if they were
+            // previously in `Cargo.lock`
+            .filter(move |s| !s.yanked || yanked_whitelist.contains(&s.summary.package_id()))
+            .filter(move |s| match &precise {
+                Some((current, requested)) => {
+                    if req.matches(current) {
+                        // Unfortunately crates.io allows versions to differ only
+                        // by build metadata. This shouldn't be allowed, but since
+                        // it is, this will honor it if requested. However, if not
+                        // specified, then ignore it.
+                        let s_vers = s.summary.version();
+                        match (s_vers.build.is_empty(), requested.build.is_empty()) {
+                            (true, true) => s_vers == requested,
+                            (true, false) => false,
+                            (false, true) => {
+                                // Strip out the metadata.
+                                s_vers.major == requested.major
+                                    && s_vers.minor == requested.minor
+                                    && s_vers.patch == requested.patch
+                                    && s_vers.pre == requested.pre
+                            }
+                            (false, false) => s_vers == requested,
                         }
-                        (false, false) => s_vers == requested,
+                    } else {
+                        true
                     }
-                } else {
-                    true
+                }
+                None => true,
+            })
+            .map(move |s| {
+                let summary = s.summary.clone();
+                if !no_default_features {
+                    return summary;
+                }
+                summary.map_dependencies(|mut dep| {
+                    if dep.source_id() == source_id {
+                        dep.set_default_features(false);
+                    }
+                    dep
+                })
+            })))
+    }
+
+    /// Parses every line in the index files of the crates `names`, and
+    /// describes each line that fails to parse.
+    ///
+    /// Queries silently skip such lines, as they may come from a newer
+    /// version of Cargo, so this is the only way to see all of them at once.
+    pub fn invalid_lines(
+        &mut self,
+        names: &[InternedString],
+        load: &mut dyn RegistryData,
+    ) -> Poll<CargoResult<Vec<String>>> {
+        load.prepare()?;
+        let root = load.assert_index_locked(&self.path);
+        let normalize = self.normalize_versions()?;
+        let allow_missing_checksum = self.allow_missing_checksum()?;
+        let mut invalid = Vec::new();
+        let mut pending = false;
+        for name in names {
+            let fs_name = name
+                .chars()
+                .flat_map(|c| c.to_lowercase())
+                .collect::<String>();
+            let Some(path) = self.layout()?.path(&fs_name) else {
+                continue;
+            };
+            let raw_data = match load.load(root, path.as_ref(), None)? {
+                Poll::Pending => {
+                    pending = true;
+                    continue;
+                }
+                Poll::Ready(LoadResponse::Data { raw_data, .. }) => raw_data,
+                Poll::Ready(LoadResponse::CacheValid | LoadResponse::NotFound) => continue,
+            };
+            for (i, line) in split(&raw_data, b'\n').enumerate() {
+                if let Err(e) = IndexSummary::parse(
+                    self.config,
+                    line,
+                    self.source_id,
+                    normalize,
+                    allow_missing_checksum,
+                ) {
+                    invalid.push(format!("`{}` line {}: {:#}", name, i + 1, e));
                 }
             }
-            None => true,
-        });
+        }
+        if pending {
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(invalid))
+    }
+
+    /// Whether `registries.<name>.default-features` is false for this
+    /// registry, warning about it the first time.
+    fn no_default_features(&self) -> CargoResult<bool> {
+        self.no_default_features
+            .try_borrow_with(|| {
+                let Some(key) = registries_key(self.config, self.source_id, "default-features")?
+                else {
+                    return Ok(false);
+                };
+                if self.config.get::<Option<bool>>(&key)? != Some(false) {
+                    return Ok(false);
+                }
+                self.config.shell().warn(format!(
+                    "default features of packages from registry `{}` are never enabled \
+                     by their dependents in the index, as `{}` is false",
+                    self.source_id.display_registry_name(),
+                    key
+                ))?;
+                Ok(true)
+            })
+            .copied()
+    }
+
+    /// Whether `registries.<name>.allow-missing-checksum` is true for this
+    /// registry, which can't be set for crates.io.
+    fn allow_missing_checksum(&self) -> CargoResult<bool> {
+        self.allow_missing_checksum
+            .try_borrow_with(|| {
+                let Some(key) =
+                    registries_key(self.config, self.source_id, "allow-missing-checksum")?
+                else {
+                    return Ok(false);
+                };
+                let allow = self.config.get::<Option<bool>>(&key)?.unwrap_or(false);
+                if allow && self.source_id.is_crates_io() {
+                    bail!("`{}` can't be set, crates.io always lists checksums", key);
+                }
+                Ok(allow)
+            })
+            .copied()
+    }
 
-        let mut count = 0;
-        for summary in summaries {
-            f(summary);
-            count += 1;
+    /// Reads `registries.<name>.index-layout` for this registry, defaulting to
+    /// [`IndexLayout::Sharded`].
+    pub fn layout(&self) -> CargoResult<IndexLayout> {
+        self.layout
+            .try_borrow_with(|| {
+                let Some(key) = registries_key(self.config, self.source_id, "index-layout")? else {
+                    return Ok(IndexLayout::Sharded);
+                };
+                Ok(self
+                    .config
+                    .get::<Option<IndexLayout>>(&key)?
+                    .unwrap_or(IndexLayout::Sharded))
+            })
+            .copied()
+    }
+
+    /// Reads the `registry.normalize-versions` config value, defaulting to
+    /// true.
+    fn normalize_versions(&self) -> CargoResult<bool> {
+        self.normalize_versions
+            .try_borrow_with(|| {
+                Ok(self
+                    .config
+                    .get::<Option<bool>>("registry.normalize-versions")?
+                    .unwrap_or(true))
+            })
+            .copied()
+    }
+
+    /// Reads the `registry.duplicate-checksums` config value, defaulting to
+    /// a warning.
+    fn duplicate_checksums_policy(&self) -> CargoResult<&CheckLevel> {
+        self.duplicate_checksums.try_borrow_with(|| {
+            Ok(self
+                .config
+                .get::<Option<CheckLevel>>("registry.duplicate-checksums")?
+                .unwrap_or(CheckLevel::Warn))
+        })
+    }
+
+    /// Reports checksums that the index lists for more than one version of
+    /// the crate `name`.
+    fn check_duplicate_checksums(
+        &mut self,
+        name: &str,
+        checksums: Vec<(String, Version)>,
+    ) -> CargoResult<()> {
+        let mut by_checksum: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+        for (cksum, version) in checksums {
+            by_checksum.entry(cksum).or_default().push(version);
         }
-        Poll::Ready(Ok(count))
+        let name = InternedString::new(name);
+        for (cksum, mut versions) in by_checksum {
+            if versions.len() < 2 || !self.reported_checksums.insert((name, cksum)) {
+                continue;
+            }
+            versions.sort();
+            let versions = versions
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let msg = format!(
+                "package `{name}` has versions with identical checksums in registry `{}`: {versions}\n\
+                 this usually means a version was published pointing at the wrong tarball",
+                self.source_id.display_registry_name()
+            );
+            if *self.duplicate_checksums_policy()? == CheckLevel::Deny {
+                bail!(msg);
+            }
+            self.config.shell().warn(msg)?;
+        }
+        Ok(())
     }
 
     pub fn is_yanked(
@@ -415,6 +926,73 @@ impl<'cfg> RegistryIndex<'cfg> {
             .map_ok(|mut p| p.any(|summary| summary.yanked));
         found
     }
+
+    /// Returns the deprecation notice the registry attached to the versions
+    /// of `name` matching `req`, if any.
+    pub fn deprecation(
+        &mut self,
+        name: InternedString,
+        req: &OptVersionReq,
+        load: &mut dyn RegistryData,
+    ) -> Poll<CargoResult<Option<RegistryDeprecation>>> {
+        self.summaries(&name, req, load)
+            .map_ok(|mut p| p.find_map(|summary| summary.deprecated.clone()))
+    }
+}
+
+/// Where the files of the packages are in an index, which is the same for the
+/// index files and the `.cache` of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexLayout {
+    /// In directories named after the first characters of the package name,
+    /// see [`make_dep_path`]. This is the layout of crates.io.
+    Sharded,
+    /// Directly in the root of the index, named after the package.
+    Flat,
+}
+
+impl IndexLayout {
+    /// The path of the file of the package with the lowercase `name`, relative
+    /// to the root of the index, or `None` if the name can't have one.
+    pub fn path(self, name: &str) -> Option<String> {
+        match self {
+            IndexLayout::Sharded => can_shard(name).then(|| make_dep_path(name, false)),
+            IndexLayout::Flat => (!name.is_empty()).then(|| name.to_string()),
+        }
+    }
+}
+
+/// Whether `name` can be split into the directories of its path in the index
+/// by [`make_dep_path`], which is not the case for an empty name, or if the
+/// split would fall in the middle of a character.
+fn can_shard(name: &str) -> bool {
+    match name.len() {
+        0 => false,
+        1 | 2 => true,
+        3 => name.is_char_boundary(1),
+        _ => name.is_char_boundary(2) && name.is_char_boundary(4),
+    }
+}
+
+/// Parses a version from the index, filling in a missing minor or patch
+/// version with zero, so that `1.0` is read as `1.0.0`.
+///
+/// Everything about a package version, from the key it's looked up with in
+/// the index to the name of its `.crate` file in the cache and its download
+/// URL, derives from the version this returns, so equivalent versions always
+/// end up at the same place.
+pub(crate) fn normalize_version(vers: &str) -> CargoResult<Version> {
+    let vers = vers.trim();
+    let core_len = vers.find(|c| c == '-' || c == '+').unwrap_or(vers.len());
+    let (core, rest) = vers.split_at(core_len);
+    let padding = match core.matches('.').count() {
+        0 if !core.is_empty() => ".0.0",
+        1 => ".0",
+        _ => "",
+    };
+    Version::parse(&format!("{}{}{}", core, padding, rest))
+        .map_err(|_| anyhow::format_err!("cannot parse '{}' as a semver", vers))
 }
 
 impl Summaries {
@@ -483,20 +1061,26 @@ impl Summaries {
                 index_version,
             } => {
                 // This is the fallback path where we actually talk to the registry backend to load
-                // information. Here we parse every single line in the index (as we need
-                // to find the versions)
+                // information. Here we go through every single line in the index (as we need
+                // to find the versions), but only parse the name and version of each. The
+                // rest of an entry is parsed once a query matches its version, just like for
+                // entries read from the cache, so a lookup of an exact version only fully
+                // parses a single line no matter how many versions the crate has.
                 log::debug!("slow path for {:?}", relative);
+                // Index files are named after the lowercased name of the
+                // package whose versions they list.
+                let file_name = relative.file_name().and_then(|f| f.to_str());
                 let mut cache = SummariesCache::default();
                 let mut ret = Summaries::default();
-                ret.raw_data = raw_data;
+                ret.raw_data = Rc::new(raw_data);
                 for line in split(&ret.raw_data, b'\n') {
                     // Attempt forwards-compatibility on the index by ignoring
                     // everything that we ourselves don't understand, that should
                     // allow future cargo implementations to break the
                     // interpretation of each line here and older cargo will simply
                     // ignore the new lines.
-                    let summary = match IndexSummary::parse(config, line, source_id) {
-                        Ok(summary) => summary,
+                    let entry = match serde_json::from_slice::<IndexEntryId>(line) {
+                        Ok(entry) => entry,
                         Err(e) => {
                             // This should only happen when there is an index
                             // entry from a future version of cargo that this
@@ -510,9 +1094,41 @@ impl Summaries {
                             continue;
                         }
                     };
-                    let version = summary.summary.package_id().version().clone();
-                    cache.versions.push((version.clone(), line));
-                    ret.versions.insert(version, summary.into());
+                    // Entries are keyed by the normalized version either
+                    // way, `registry.normalize-versions` only decides whether
+                    // the ones that needed it are parsed in full.
+                    let vers = match normalize_version(&entry.vers) {
+                        Ok(vers) => vers,
+                        Err(e) => {
+                            log::info!("failed to parse {:?} registry package: {}", relative, e);
+                            continue;
+                        }
+                    };
+                    if let Some(file_name) = file_name.filter(|f| entry.name.to_lowercase() != *f) {
+                        // A misfiled entry would never match a query for the
+                        // name it is filed under, so skip it, but loudly.
+                        config.shell().warn(format!(
+                            "skipping `{} v{}` in the index file of `{}` in registry `{}`\n\
+                             the package name doesn't match the file it is listed in, \
+                             which means the index is corrupt",
+                            entry.name,
+                            vers,
+                            file_name,
+                            source_id.display_registry_name(),
+                        ))?;
+                        continue;
+                    }
+                    // The line is cached even if the rest of it turns out to
+                    // be something this version of cargo can't parse. The
+                    // cache holds the raw line, which is parsed the same way
+                    // whether it's read from here or from the cache, and such
+                    // an entry is skipped when a query gets to it. A newer
+                    // cargo sharing the cache still finds the entries it
+                    // understands, so CURRENT_CACHE_VERSION stays the same.
+                    cache.versions.push((vers.clone(), line));
+                    let (start, end) = subslice_bounds(&ret.raw_data, line);
+                    ret.versions
+                        .insert(vers, MaybeIndexSummary::Unparsed { start, end });
                 }
                 if let Some(index_version) = index_version {
                     log::trace!("caching index_version {}", index_version);
@@ -560,23 +1176,23 @@ impl Summaries {
             ret.versions
                 .insert(version, MaybeIndexSummary::Unparsed { start, end });
         }
-        ret.raw_data = contents;
-        return Ok((ret, index_version));
-
-        // Returns the start/end offsets of `inner` with `outer`. Asserts that
-        // `inner` is a subslice of `outer`.
-        fn subslice_bounds(outer: &[u8], inner: &[u8]) -> (usize, usize) {
-            let outer_start = outer.as_ptr() as usize;
-            let outer_end = outer_start + outer.len();
-            let inner_start = inner.as_ptr() as usize;
-            let inner_end = inner_start + inner.len();
-            assert!(inner_start >= outer_start);
-            assert!(inner_end <= outer_end);
-            (inner_start - outer_start, inner_end - outer_start)
-        }
+        ret.raw_data = Rc::new(contents);
+        Ok((ret, index_version))
     }
 }
 
+/// Returns the start/end offsets of `inner` with `outer`. Asserts that
+/// `inner` is a subslice of `outer`.
+fn subslice_bounds(outer: &[u8], inner: &[u8]) -> (usize, usize) {
+    let outer_start = outer.as_ptr() as usize;
+    let outer_end = outer_start + outer.len();
+    let inner_start = inner.as_ptr() as usize;
+    let inner_end = inner_start + inner.len();
+    assert!(inner_start >= outer_start);
+    assert!(inner_end <= outer_end);
+    (inner_start - outer_start, inner_end - outer_start)
+}
+
 // Implementation of serializing/deserializing the cache of summaries on disk.
 // Currently the format looks like:
 //
@@ -613,8 +1229,11 @@ impl Summaries {
 //   change, just a version bump to clear the incorrect cache entries. Note:
 //   the index shouldn't allow these, but unfortunately crates.io doesn't
 //   check it.
+// * `4`: Versions missing their minor or patch version, like `1.0`, are
+//   normalized rather than skipped, so the cache of an index file with such
+//   versions has to be built again.
 
-const CURRENT_CACHE_VERSION: u8 = 3;
+const CURRENT_CACHE_VERSION: u8 = 4;
 
 impl<'a> SummariesCache<'a> {
     fn parse(data: &'a [u8]) -> CargoResult<SummariesCache<'a>> {
@@ -688,12 +1307,20 @@ impl MaybeIndexSummary {
         config: &Config,
         raw_data: &[u8],
         source_id: SourceId,
+        normalize: bool,
+        allow_missing_checksum: bool,
     ) -> CargoResult<&IndexSummary> {
         let (start, end) = match self {
             MaybeIndexSummary::Unparsed { start, end } => (*start, *end),
             MaybeIndexSummary::Parsed(summary) => return Ok(summary),
         };
-        let summary = IndexSummary::parse(config, &raw_data[start..end], source_id)?;
+        let summary = IndexSummary::parse(
+            config,
+            &raw_data[start..end],
+            source_id,
+            normalize,
+            allow_missing_checksum,
+        )?;
         *self = MaybeIndexSummary::Parsed(summary);
         match self {
             MaybeIndexSummary::Unparsed { .. } => unreachable!(),
@@ -712,18 +1339,28 @@ impl IndexSummary {
     /// Parses a line from the registry's index file into an `IndexSummary` for
     /// a package.
     ///
-    /// The `line` provided is expected to be valid JSON.
-    fn parse(config: &Config, line: &[u8], source_id: SourceId) -> CargoResult<IndexSummary> {
+    /// The `line` provided is expected to be valid JSON. Its version is read
+    /// with [`normalize_version`] if `normalize` is set, and it may lack a
+    /// checksum if `allow_missing_checksum` is set.
+    fn parse(
+        config: &Config,
+        line: &[u8],
+        source_id: SourceId,
+        normalize: bool,
+        allow_missing_checksum: bool,
+    ) -> CargoResult<IndexSummary> {
         // ****CAUTION**** Please be extremely careful with returning errors
-        // from this function. Entries that error are not included in the
-        // index cache, and can cause cargo to get confused when switching
-        // between different versions that understand the index differently.
+        // from this function. Entries that error are skipped by queries, and
+        // can cause cargo to get confused when switching between different
+        // versions that understand the index differently.
         // Make sure to consider the INDEX_V_MAX and CURRENT_CACHE_VERSION
         // values carefully when making changes here.
         let RegistryPackage {
             name,
             vers,
             cksum,
+            cksum_algo,
+            size,
             deps,
             mut features,
             features2,
@@ -731,10 +1368,15 @@ impl IndexSummary {
             links,
             rust_version,
             v,
+            deprecated,
         } = serde_json::from_slice(line)?;
         let v = v.unwrap_or(1);
         log::trace!("json parsed registry {}/{}", name, vers);
-        let pkgid = PackageId::new(name, &vers, source_id)?;
+        let pkgid = if normalize {
+            PackageId::pure(name, normalize_version(&vers)?, source_id)
+        } else {
+            PackageId::new(name, vers.as_str(), source_id)?
+        };
         let deps = deps
             .into_iter()
             .map(|dep| dep.into_dep(source_id))
@@ -745,10 +1387,17 @@ impl IndexSummary {
             }
         }
         let mut summary = Summary::new(config, pkgid, deps, &features, links, rust_version)?;
-        summary.set_checksum(cksum);
+        match (cksum, cksum_algo.as_deref()) {
+            (Some(cksum), None | Some("sha256")) => summary.set_checksum(cksum),
+            (Some(cksum), Some(algo)) => summary.set_checksum(format!("{}:{}", algo, cksum)),
+            (None, _) if allow_missing_checksum => {}
+            (None, _) => bail!("missing field `cksum`"),
+        }
         Ok(IndexSummary {
             summary,
             yanked: yanked.unwrap_or(false),
+            deprecated,
+            size,
             v,
         })
     }
@@ -1,8 +1,9 @@
 use crate::core::PackageId;
+use crate::sources::registry::download;
 use crate::sources::registry::{LoadResponse, MaybeLock, RegistryConfig, RegistryData};
 use crate::util::errors::CargoResult;
 use crate::util::{Config, Filesystem};
-use cargo_util::{paths, Sha256};
+use cargo_util::paths;
 use std::fs::File;
 use std::io::SeekFrom;
 use std::io::{self, prelude::*};
@@ -114,6 +115,11 @@ impl<'cfg> RegistryData for LocalRegistry<'cfg> {
         self.updated
     }
 
+    fn is_index_ready(&self) -> bool {
+        // Local registries have no `config.json`.
+        self.index_path.as_path_unlocked().is_dir()
+    }
+
     fn download(&mut self, pkg: PackageId, checksum: &str) -> CargoResult<MaybeLock> {
         let crate_file = format!("{}-{}.crate", pkg.name(), pkg.version());
 
@@ -136,10 +142,7 @@ impl<'cfg> RegistryData for LocalRegistry<'cfg> {
 
         // We don't actually need to download anything per-se, we just need to
         // verify the checksum matches the .crate file itself.
-        let actual = Sha256::new().update_file(&crate_file)?.finish_hex();
-        if actual != checksum {
-            anyhow::bail!("failed to verify the checksum of `{}`", pkg)
-        }
+        download::verify_checksum(&crate_file, checksum, pkg)?;
 
         crate_file.seek(SeekFrom::Start(0))?;
 
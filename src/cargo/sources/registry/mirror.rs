@@ -0,0 +1,352 @@
+use crate::core::{PackageId, SourceId};
+use crate::ops;
+use crate::sources::registry::download;
+use crate::sources::registry::{LoadResponse, MaybeLock, RegistryConfig, RegistryData};
+use crate::util::errors::{CargoResult, HttpNotSuccessful};
+use crate::util::network::{self, retry::with_retry};
+use crate::util::{Config, Filesystem};
+use anyhow::Context as _;
+use cargo_util::{paths, Sha256};
+use log::debug;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Component, Path};
+use std::task::Poll;
+use url::Url;
+
+/// Name of the file listing the shards of a mirrored index with their
+/// checksums, both on the mirror and in the local copy.
+const MANIFEST: &str = "index.sha256";
+
+/// A remote registry whose index is synced from a plain HTTP mirror, set with
+/// `registries.<name>.index-mirror`, instead of being fetched with git or the
+/// sparse protocol.
+///
+/// The mirror serves the files of the index (the "shards") as they are laid
+/// out in the index, next to an `index.sha256` manifest. The manifest has a
+/// line of the form `<sha256>  <path>` for every file, like the output of
+/// `sha256sum`. Updating compares the manifest with the one of the local copy
+/// and only downloads the shards whose checksum changed, removing the ones
+/// that are gone from the mirror.
+pub struct MirrorRegistry<'cfg> {
+    index_path: Filesystem,
+    /// Path to the cache of `.crate` files (`$CARGO_HOME/registry/cache/$REG-HASH`).
+    cache_path: Filesystem,
+    source_id: SourceId,
+    mirror: Url,
+    config: &'cfg Config,
+    /// The manifest of the local copy, read when the index is first loaded.
+    manifest: Option<BTreeMap<String, String>>,
+    needs_update: bool,
+    quiet: bool,
+}
+
+impl<'cfg> MirrorRegistry<'cfg> {
+    pub fn new(
+        source_id: SourceId,
+        mirror: Url,
+        config: &'cfg Config,
+        name: &str,
+    ) -> MirrorRegistry<'cfg> {
+        MirrorRegistry {
+            // Not the directory of the git index of the same registry, as
+            // that may still be used when the mirror isn't configured.
+            index_path: config
+                .registry_index_path()
+                .join(format!("{}-mirror", name)),
+            cache_path: config.registry_cache_path().join(name),
+            source_id,
+            mirror,
+            config,
+            manifest: None,
+            needs_update: false,
+            quiet: false,
+        }
+    }
+
+    /// The URL of `path` on the mirror.
+    fn mirror_url(&self, path: &str) -> CargoResult<Url> {
+        let mut base = self.mirror.clone();
+        if !base.path().ends_with('/') {
+            base.set_path(&format!("{}/", base.path()));
+        }
+        Ok(base.join(path)?)
+    }
+
+    /// Fetches `path` from the mirror.
+    fn fetch(&self, path: &str) -> CargoResult<Vec<u8>> {
+        let url = self.mirror_url(path)?;
+        with_retry(self.config, || {
+            let mut handle = ops::http_handle(self.config)?;
+            handle.get(true)?;
+            handle.url(url.as_str())?;
+            handle.follow_location(true)?;
+            let mut body = Vec::new();
+            {
+                let mut transfer = handle.transfer();
+                transfer.write_function(|buf| {
+                    body.extend_from_slice(buf);
+                    Ok(buf.len())
+                })?;
+                transfer
+                    .perform()
+                    .map_err(|e| network::with_tls_context(e, self.config))?;
+            }
+            let code = handle.response_code()?;
+            if code != 200 && code != 0 {
+                return Err(HttpNotSuccessful::new_from_handle(
+                    &mut handle,
+                    url.as_str(),
+                    body,
+                    Vec::new(),
+                )
+                .into());
+            }
+            Ok(body)
+        })
+        .with_context(|| format!("failed to fetch `{}` from the index mirror", url))
+    }
+
+    /// Reads the manifest of the local copy, which is empty if the index
+    /// was never synced.
+    fn local_manifest(&mut self) -> CargoResult<&BTreeMap<String, String>> {
+        if self.manifest.is_none() {
+            let path = self.index_path.as_path_unlocked().join(MANIFEST);
+            let manifest = match paths::read_bytes(&path) {
+                Ok(data) => parse_manifest(&data)
+                    .with_context(|| format!("failed to parse `{}`", path.display()))?,
+                Err(e)
+                    if e.downcast_ref::<io::Error>()
+                        .map_or(false, |ioe| ioe.kind() == io::ErrorKind::NotFound) =>
+                {
+                    BTreeMap::new()
+                }
+                Err(e) => return Err(e),
+            };
+            self.manifest = Some(manifest);
+        }
+        Ok(self.manifest.as_ref().unwrap())
+    }
+
+    /// Brings the local copy in line with the mirror, downloading only the
+    /// shards whose checksum changed.
+    fn sync(&mut self) -> CargoResult<()> {
+        let root = self
+            .config
+            .assert_package_cache_locked(&self.index_path)
+            .to_path_buf();
+        if !self.quiet {
+            self.config
+                .shell()
+                .status("Updating", self.source_id.display_index())?;
+        }
+        let raw_manifest = self.fetch(MANIFEST)?;
+        let manifest = parse_manifest(&raw_manifest)
+            .with_context(|| format!("failed to parse `{}` of the index mirror", MANIFEST))?;
+        let local = self.local_manifest()?.clone();
+
+        let mut fetched = 0;
+        for (path, cksum) in &manifest {
+            let dst = root.join(path);
+            if local.get(path) == Some(cksum) && dst.exists() {
+                continue;
+            }
+            let data = self.fetch(path)?;
+            let actual = Sha256::new().update(&data).finish_hex();
+            if actual != *cksum {
+                anyhow::bail!(
+                    "the index mirror sent `{}` with checksum `{}`, \
+                     but its manifest lists `{}`",
+                    path,
+                    actual,
+                    cksum
+                );
+            }
+            paths::create_dir_all(dst.parent().unwrap())?;
+            paths::write(&dst, &data)?;
+            fetched += 1;
+        }
+        for path in local.keys().filter(|path| !manifest.contains_key(*path)) {
+            let dst = root.join(path);
+            if dst.exists() {
+                paths::remove_file(&dst)?;
+            }
+        }
+        debug!(
+            "synced {} of {} shards from the index mirror",
+            fetched,
+            manifest.len()
+        );
+
+        // Written last, so an interrupted sync is picked up again next time.
+        paths::write(&root.join(MANIFEST), &raw_manifest)?;
+        self.manifest = Some(manifest);
+        Ok(())
+    }
+}
+
+/// Parses a manifest of shards, where each line is a checksum and a path.
+///
+/// Paths must be relative and stay within the index, and may not start with
+/// a `.`, which keeps them clear of the files Cargo keeps next to the shards.
+fn parse_manifest(data: &[u8]) -> CargoResult<BTreeMap<String, String>> {
+    let data = std::str::from_utf8(data)?;
+    let mut manifest = BTreeMap::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((cksum, path)) = line.split_once(char::is_whitespace) else {
+            anyhow::bail!("line {} is not of the form `<sha256>  <path>`", i + 1);
+        };
+        let path = path.trim_start();
+        let valid_path = !path.is_empty()
+            && Path::new(path).components().all(
+                |c| matches!(c, Component::Normal(c) if !c.to_string_lossy().starts_with('.')),
+            );
+        if !valid_path {
+            anyhow::bail!("line {} has invalid path `{}`", i + 1, path);
+        }
+        manifest.insert(path.to_string(), cksum.to_lowercase());
+    }
+    Ok(manifest)
+}
+
+impl<'cfg> RegistryData for MirrorRegistry<'cfg> {
+    fn prepare(&self) -> CargoResult<()> {
+        Ok(())
+    }
+
+    fn index_path(&self) -> &Filesystem {
+        &self.index_path
+    }
+
+    fn assert_index_locked<'a>(&self, path: &'a Filesystem) -> &'a Path {
+        self.config.assert_package_cache_locked(path)
+    }
+
+    fn load(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        index_version: Option<&str>,
+    ) -> Poll<CargoResult<LoadResponse>> {
+        if self.needs_update {
+            return Poll::Pending;
+        }
+        let is_updated = self.is_updated();
+        let manifest = match self.local_manifest() {
+            Ok(manifest) => manifest,
+            Err(e) => return Poll::Ready(Err(e)),
+        };
+        if manifest.is_empty() && !is_updated {
+            // Never synced, so there's nothing to read yet.
+            self.needs_update = true;
+            return Poll::Pending;
+        }
+        let key = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        // The checksum of a shard is what the index cache is keyed on.
+        let Some(cksum) = manifest.get(&key).cloned() else {
+            return Poll::Ready(Ok(LoadResponse::NotFound));
+        };
+        if index_version == Some(cksum.as_str()) {
+            return Poll::Ready(Ok(LoadResponse::CacheValid));
+        }
+        let raw_data = match paths::read_bytes(&root.join(path)) {
+            Err(e)
+                if e.downcast_ref::<io::Error>()
+                    .map_or(false, |ioe| ioe.kind() == io::ErrorKind::NotFound) =>
+            {
+                return Poll::Ready(Ok(LoadResponse::NotFound));
+            }
+            r => r,
+        };
+        Poll::Ready(raw_data.map(|raw_data| LoadResponse::Data {
+            raw_data,
+            index_version: Some(cksum),
+        }))
+    }
+
+    fn config(&mut self) -> Poll<CargoResult<Option<RegistryConfig>>> {
+        let root = self.index_path.clone();
+        let root = self.assert_index_locked(&root).to_path_buf();
+        match std::task::ready!(self.load(&root, Path::new("config.json"), None)?) {
+            LoadResponse::Data { raw_data, .. } => {
+                let path = root.join("config.json");
+                let mut cfg = RegistryConfig::from_slice(&raw_data, &path.display().to_string())?;
+                cfg.gate_auth_required(self.config);
+                Poll::Ready(Ok(Some(cfg)))
+            }
+            _ => Poll::Ready(Ok(None)),
+        }
+    }
+
+    fn block_until_ready(&mut self) -> CargoResult<()> {
+        if !self.needs_update {
+            return Ok(());
+        }
+        self.needs_update = false;
+
+        // Like git indexes, the mirror is synced at most once per session.
+        if self.is_updated() {
+            return Ok(());
+        }
+        self.config.updated_sources().insert(self.source_id);
+        if self.config.offline() || self.config.cli_unstable().no_index_update {
+            return Ok(());
+        }
+        self.sync()
+    }
+
+    fn invalidate_cache(&mut self) {
+        self.needs_update = true;
+    }
+
+    fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    fn is_updated(&self) -> bool {
+        self.config.updated_sources().contains(&self.source_id)
+    }
+
+    fn is_index_ready(&self) -> bool {
+        let root = self.index_path.as_path_unlocked();
+        root.join(MANIFEST).is_file() && root.join("config.json").is_file()
+    }
+
+    fn download(&mut self, pkg: PackageId, checksum: &str) -> CargoResult<MaybeLock> {
+        let registry_config = loop {
+            match self.config()? {
+                Poll::Pending => self.block_until_ready()?,
+                Poll::Ready(cfg) => break cfg.unwrap(),
+            }
+        };
+        download::download(
+            &self.cache_path,
+            &self.config,
+            pkg,
+            checksum,
+            registry_config,
+        )
+    }
+
+    fn finish_download(
+        &mut self,
+        pkg: PackageId,
+        checksum: &str,
+        data: &[u8],
+    ) -> CargoResult<File> {
+        download::finish_download(&self.cache_path, &self.config, pkg, checksum, data)
+    }
+
+    fn is_crate_downloaded(&self, pkg: PackageId) -> bool {
+        download::is_crate_downloaded(&self.cache_path, &self.config, pkg)
+    }
+}
@@ -160,33 +160,51 @@
 
 use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::collections::HashSet;
-use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 use std::task::{ready, Poll};
+use std::time::SystemTime;
 
-use anyhow::Context as _;
+use anyhow::{bail, Context as _};
 use cargo_util::paths::{self, exclude_from_backups_and_indexing};
-use flate2::read::GzDecoder;
+use cargo_util::Sha256;
+use flate2::bufread::GzDecoder;
+use glob::Pattern;
+use lazycell::LazyCell;
 use log::debug;
 use semver::Version;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tar::Archive;
+use url::Url;
 
 use crate::core::dependency::{DepKind, Dependency};
 use crate::core::source::MaybePackage;
-use crate::core::{Package, PackageId, QueryKind, Source, SourceId, Summary};
+use crate::core::{GitReference, Package, PackageId, QueryKind, Source, SourceId, Summary};
+use crate::ops;
+use crate::sources::git::fetch::shallow_index;
 use crate::sources::PathSource;
+use crate::util::auth;
+use crate::util::config::{ConfigRelativePath, Value};
+use crate::util::errors::{DownloadRetry, HttpNotSuccessful};
 use crate::util::hex;
 use crate::util::interning::InternedString;
 use crate::util::into_url::IntoUrl;
-use crate::util::network::PollExt;
+use crate::util::network::{self, retry::with_retry, PollExt};
 use crate::util::{
     restricted_names, CargoResult, Config, Filesystem, LimitErrorReader, OptVersionReq,
 };
 
 const PACKAGE_SOURCE_LOCK: &str = ".cargo-ok";
+/// Marks a package of which only some files were unpacked, see
+/// [`RegistrySource::unpack_matching`].
+const PACKAGE_PARTIAL_LOCK: &str = ".cargo-partial";
+/// Prefix of the temporary directory next to a package's that it's unpacked
+/// into before it's moved into place.
+const UNPACK_TMP_PREFIX: &str = ".tmp-";
 pub const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
 pub const CRATES_IO_HTTP_INDEX: &str = "sparse+https://index.crates.io/";
 pub const CRATES_IO_REGISTRY: &str = "crates-io";
@@ -196,8 +214,12 @@ const VERSION_TEMPLATE: &str = "{version}";
 const PREFIX_TEMPLATE: &str = "{prefix}";
 const LOWER_PREFIX_TEMPLATE: &str = "{lowerprefix}";
 const CHECKSUM_TEMPLATE: &str = "{sha256-checksum}";
+const CID_TEMPLATE: &str = "{cid}";
 const MAX_UNPACK_SIZE: u64 = 512 * 1024 * 1024;
 const MAX_COMPRESSION_RATIO: usize = 20; // 20:1
+/// Size of the buffer `.crate` files are read through when unpacking, the
+/// same as the one [`flate2::read::GzDecoder`] uses.
+const DEFAULT_UNPACK_BUFFER_SIZE: usize = 32 * 1024;
 
 /// A "source" for a local (see `local::LocalRegistry`) or remote (see
 /// `remote::RemoteRegistry`) registry.
@@ -209,6 +231,9 @@ pub struct RegistrySource<'cfg> {
     source_id: SourceId,
     /// The path where crate files are extracted (`$CARGO_HOME/registry/src/$REG-HASH`).
     src_path: Filesystem,
+    /// The path where crate files are downloaded to
+    /// (`$CARGO_HOME/registry/cache/$REG-HASH`), unless the registry is local.
+    cache_path: Filesystem,
     /// Local reference to [`Config`] for convenience.
     config: &'cfg Config,
     /// Abstraction for interfacing to the different registry kinds.
@@ -223,6 +248,43 @@ pub struct RegistrySource<'cfg> {
     /// Otherwise, the resolver would think that those entries no longer
     /// exist, and it would trigger updates to unrelated packages.
     yanked_whitelist: HashSet<PackageId>,
+    /// Versions locked in `Cargo.lock` that were already reported missing
+    /// from the index, so each is only reported once.
+    reported_missing_locked: HashSet<(InternedString, Version)>,
+    /// Crates already reported as deprecated by the registry, so each is
+    /// only reported once.
+    reported_deprecated: HashSet<InternedString>,
+    /// Download hosts that were already reported to differ from the host of
+    /// the index, so each is only reported once.
+    reported_dl_hosts: HashSet<String>,
+    /// URLs of the downloads handed out by [`Source::download`] that haven't
+    /// finished yet, kept for the provenance log.
+    download_urls: HashMap<PackageId, String>,
+    /// URLs that served data failing checksum verification, for packages
+    /// whose download is being retried.
+    mismatched_downloads: HashMap<PackageId, Vec<String>>,
+    /// Number of downloads of a package that failed, after which the next
+    /// one goes to the next of `registry.download-mirrors`.
+    failed_downloads: HashMap<PackageId, usize>,
+    /// Whether what crashed runs left behind was already cleaned up, see
+    /// [`RegistrySource::sweep_stale`].
+    swept_stale: bool,
+    /// Whether the index entries of the packages in `yanked_whitelist` have
+    /// been checked, if `registry.validate-locked-index` asks for it.
+    validated_locked_index: bool,
+    /// Bytes of `.crate` files downloaded since the last
+    /// [`RegistrySource::reset_bytes_transferred`].
+    crate_bytes_transferred: u64,
+    /// Value of [`RegistryData::index_bytes_transferred`] at the last
+    /// [`RegistrySource::reset_bytes_transferred`].
+    index_bytes_at_reset: u64,
+    /// Where `.crate` files are kept across runs, from `registry.cache-store`
+    /// unless set with [`RegistrySource::set_cache_store`].
+    cache_store: LazyCell<Option<Box<dyn CacheStore + 'cfg>>>,
+    /// The sparse index to switch to if fetching the git index fails in a
+    /// way that looks like the network blocks git, with
+    /// `registry.http-fallback`.
+    http_fallback: Option<SourceId>,
 }
 
 /// The `config.json` file stored in the index.
@@ -238,7 +300,11 @@ pub struct RegistryConfig {
     /// crate's prefix directory name, and the substring `{lowerprefix}` will
     /// be replaced with the crate's prefix directory name converted to
     /// lowercase. The substring `{sha256-checksum}` will be replaced with the
-    /// crate's sha256 checksum.
+    /// crate's sha256 checksum, and the substring `{cid}` with the IPFS
+    /// content identifier derived from that checksum.
+    ///
+    /// URLs with the `ipfs://` scheme are fetched through the HTTP gateway
+    /// configured with `registry.ipfs-gateway`.
     ///
     /// For backwards compatibility, if the string does not contain any
     /// markers (`{crate}`, `{version}`, `{prefix}`, or `{lowerprefix}`), it
@@ -255,6 +321,46 @@ pub struct RegistryConfig {
     /// Whether all operations require authentication.
     #[serde(default)]
     pub auth_required: bool,
+
+    /// Whether `auth-required` is set, even if Cargo ignores it because
+    /// `-Z registry-auth` isn't enabled.
+    #[serde(skip)]
+    pub auth_required_declared: bool,
+
+    /// The most `.crate` files the registry asks clients to download from it
+    /// at the same time. If this is None, there is no limit.
+    pub max_concurrent_downloads: Option<usize>,
+}
+
+impl RegistryConfig {
+    /// Decodes the contents of a `config.json`, where `path` says where it
+    /// was read from for error messages.
+    pub(crate) fn from_slice(raw_data: &[u8], path: &str) -> CargoResult<RegistryConfig> {
+        let context = || {
+            format!(
+                "failed to parse the registry configuration at `{}`, \
+                 the registry index may be stale or corrupt",
+                path
+            )
+        };
+        let json: serde_json::Value = serde_json::from_slice(raw_data).with_context(context)?;
+        if json.is_object() && json.get("dl").is_none() {
+            return Err(anyhow::format_err!(
+                "the `dl` key is missing, it is needed to know where to download crates from"
+            ))
+            .with_context(context);
+        }
+        serde_json::from_value(json).with_context(context)
+    }
+
+    /// Ignores `auth-required` unless `-Z registry-auth` is enabled, keeping
+    /// whether it was set in `auth_required_declared`.
+    pub(crate) fn gate_auth_required(&mut self, config: &Config) {
+        self.auth_required_declared = self.auth_required;
+        if !config.cli_unstable().registry_auth {
+            self.auth_required = false;
+        }
+    }
 }
 
 /// The maximum version of the `v` field in the index this version of cargo
@@ -265,7 +371,7 @@ pub(crate) const INDEX_V_MAX: u32 = 2;
 #[derive(Deserialize)]
 pub struct RegistryPackage<'a> {
     name: InternedString,
-    vers: Version,
+    vers: InternedString,
     #[serde(borrow)]
     deps: Vec<RegistryDependency<'a>>,
     features: BTreeMap<InternedString, Vec<InternedString>>,
@@ -276,7 +382,22 @@ pub struct RegistryPackage<'a> {
     /// will fail to load due to not being able to parse the new syntax, even
     /// with a `Cargo.lock` file.
     features2: Option<BTreeMap<InternedString, Vec<InternedString>>>,
-    cksum: String,
+    /// The checksum of the `.crate` file, in hex.
+    ///
+    /// Required, unless the registry is configured with
+    /// `registries.<name>.allow-missing-checksum`.
+    cksum: Option<String>,
+    /// The algorithm of `cksum`, `sha256` if not set.
+    ///
+    /// Checksums of other algorithms are kept as `<algo>:<hex>`, so that
+    /// they are never mistaken for a sha256 checksum. Verifying a checksum of
+    /// an algorithm Cargo doesn't know fails.
+    cksum_algo: Option<String>,
+    /// Size of the `.crate` file in bytes.
+    ///
+    /// Optional, if set, downloads of a different size are aborted as soon
+    /// as that's known instead of after fetching the whole file.
+    size: Option<u64>,
     /// If `true`, Cargo will skip this version when resolving.
     ///
     /// This was added in 2014. Everything in the crates.io index has this set
@@ -314,6 +435,219 @@ pub struct RegistryPackage<'a> {
     /// workaround is to downgrade any packages that are incompatible with the
     /// `--precise` flag of `cargo update`.
     v: Option<u32>,
+    /// Deprecation notice attached to this crate by the registry.
+    ///
+    /// Deprecated crates still resolve as usual, Cargo only warns about them
+    /// once they get selected for a build. Can be `None` if the registry
+    /// never deprecated the crate.
+    deprecated: Option<RegistryDeprecation>,
+}
+
+/// A deprecation notice for a crate, as encoded in the index JSON.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RegistryDeprecation {
+    /// Why the registry deprecated the crate.
+    pub reason: Option<String>,
+    /// Name of the crate the registry suggests using instead.
+    pub replacement: Option<InternedString>,
+}
+
+/// How strictly to handle a suspicious, but not necessarily fatal, condition
+/// in registry data, as set by the `registry.*` config checks.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum CheckLevel {
+    /// Don't check at all.
+    Allow,
+    /// Print a warning and carry on.
+    Warn,
+    /// Fail with an error.
+    Deny,
+}
+
+/// How symlinks inside of `.crate` files are unpacked, as set by the
+/// `registry.symlinks` config value.
+///
+/// Symlinks pointing outside of the package are rejected regardless.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum SymlinkPolicy {
+    /// Fail to unpack packages containing symlinks.
+    Reject,
+    /// Replace each symlink with a copy of what it points to.
+    Copy,
+    /// Unpack symlinks as symlinks.
+    Preserve,
+}
+
+/// How hardlinks inside of `.crate` files are unpacked, as set by the
+/// `registry.hardlinks` config value.
+///
+/// Hardlinks pointing outside of the package are rejected regardless.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum HardlinkPolicy {
+    /// Fail to unpack packages containing hardlinks.
+    Reject,
+    /// Replace each hardlink with a copy of the file it links to.
+    Copy,
+}
+
+/// How `.crate` files whose files aren't in the `{name}-{version}` directory
+/// are handled, as set by the `registry.unexpected-top-level` config value.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum TopLevelPolicy {
+    /// Fail to unpack such packages.
+    Reject,
+    /// Unpack the package from the top-level directory that contains its
+    /// `Cargo.toml`, with a warning.
+    Locate,
+}
+
+/// How paths inside of `.crate` files that are not valid UTF-8 are handled, as
+/// set by the `registry.non-utf8-paths` config value.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum NonUtf8Policy {
+    /// Fail to unpack packages containing such paths.
+    Reject,
+    /// Unpack them under a name made valid with [`sanitize_path`].
+    Sanitize,
+}
+
+/// Turns a path from a `.crate` file that is not valid UTF-8 into one that is,
+/// by replacing each byte of the invalid sequences with `%XX`.
+///
+/// This always gives the same path for the same bytes, so unpacking a package
+/// again puts its files in the same places.
+fn sanitize_path(mut bytes: &[u8]) -> String {
+    let mut sanitized = String::new();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                sanitized.push_str(valid);
+                return sanitized;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                sanitized.push_str(std::str::from_utf8(valid).unwrap());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                for byte in &rest[..invalid_len] {
+                    sanitized.push_str(&format!("%{:02X}", byte));
+                }
+                bytes = &rest[invalid_len..];
+            }
+        }
+    }
+}
+
+#[test]
+fn sanitized_paths() {
+    assert_eq!(
+        sanitize_path(b"bar-0.0.1/src/lib.rs"),
+        "bar-0.0.1/src/lib.rs"
+    );
+    assert_eq!(
+        sanitize_path(b"bar-0.0.1/caf\xe9.txt"),
+        "bar-0.0.1/caf%E9.txt"
+    );
+    assert_eq!(
+        sanitize_path(b"\xff\xfe\xc3\xa9\xe2\x82"),
+        "%FF%FE\u{e9}%E2%82"
+    );
+}
+
+/// Resolves the target of the symlink at `path` within a `.crate` file,
+/// without looking at the filesystem.
+///
+/// Returns `None` if the target is absolute or climbs above the root of the
+/// archive.
+fn symlink_target(path: &Path, link: &Path) -> Option<PathBuf> {
+    let mut target = PathBuf::new();
+    for component in path.parent()?.join(link).components() {
+        match component {
+            Component::Normal(c) => target.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !target.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(target)
+}
+
+/// Resolves the target of a hardlink within a `.crate` file, which unlike
+/// for symlinks is relative to the root of the archive.
+///
+/// Returns `None` if the target is absolute or climbs out of the archive.
+fn hardlink_target(link: &Path) -> Option<PathBuf> {
+    let mut target = PathBuf::new();
+    for component in link.components() {
+        match component {
+            Component::Normal(c) => target.push(c),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(target)
+}
+
+/// Unpacks `entry` of a `.crate` file under its sanitized `path`, relative to
+/// `parent`, as `Entry::unpack_in` can't be given another path.
+fn unpack_sanitized<R: Read>(
+    entry: &mut tar::Entry<'_, R>,
+    parent: &Path,
+    path: &Path,
+) -> CargoResult<()> {
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!(
+            "invalid tarball downloaded, contains a file at {:?} \
+             which isn't a plain relative path",
+            path
+        );
+    }
+    let dst = parent.join(path);
+    if let Some(dir) = dst.parent() {
+        paths::create_dir_all(dir)?;
+    }
+    entry.unpack(&dst)?;
+    Ok(())
+}
+
+/// Materializes a symlink from a `.crate` file at `dst` as a copy of its
+/// target `src`, recursing into directories.
+fn copy_symlink_target(src: &Path, dst: &Path) -> CargoResult<()> {
+    if dst.starts_with(src) {
+        bail!("symlink points to a directory containing itself");
+    }
+    if src.is_dir() {
+        paths::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_symlink_target(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    } else {
+        if let Some(parent) = dst.parent() {
+            paths::create_dir_all(parent)?;
+        }
+        paths::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn registry_ident_of_urls() {
+    let ident = |url: &str| registry_ident(&Url::parse(url).unwrap());
+    assert_eq!(ident("https://index.crates.io/"), "index.crates.io");
+    assert_eq!(ident("https://Index.Crates.IO/"), "index.crates.io");
+    assert_eq!(ident("https://index.crates.io./"), "index.crates.io");
+    assert_eq!(ident("file:///path/to/my-registry"), "my-registry");
+    assert_eq!(ident("file:///path/to/my-registry/"), "my-registry");
+    assert_eq!(ident("file:///"), "");
 }
 
 #[test]
@@ -344,11 +678,44 @@ fn escaped_char_in_json() {
         }],
         "cksum":"bae3",
         "features":{"test \n\t\" ":["k \n\t\" ","q \n\t\" "]},
-        "links":" \n\t\" "}"#,
+        "links":" \n\t\" ",
+        "deprecated":{"reason":" \n\t\" ","replacement":" \n\t\" "}}"#,
     )
     .unwrap();
 }
 
+#[test]
+fn target_of_registry_dependencies() {
+    let pkg: RegistryPackage<'_> = serde_json::from_str(
+        r#"{"name":"a","vers":"0.0.1","cksum":"bae3","features":{},"deps":[
+            {"name":"win","req":"1","features":[],"optional":false,"default_features":true,
+             "target":"x86_64-pc-windows-gnu","kind":"normal"},
+            {"name":"unix","req":"1","features":[],"optional":false,"default_features":true,
+             "target":"cfg(unix)","kind":"normal"},
+            {"name":"any","req":"1","features":[],"optional":false,"default_features":true,
+             "target":null,"kind":"normal"}
+        ]}"#,
+    )
+    .unwrap();
+    let source_id = SourceId::for_registry(&Url::parse("https://example.com/").unwrap()).unwrap();
+    let platforms: Vec<_> = pkg
+        .deps
+        .into_iter()
+        .map(|dep| {
+            let dep = dep.into_dep(source_id).unwrap();
+            (dep.package_name(), dep.platform().map(|p| p.to_string()))
+        })
+        .collect();
+    assert_eq!(
+        platforms,
+        [
+            ("win".into(), Some("x86_64-pc-windows-gnu".to_string())),
+            ("unix".into(), Some("cfg(unix)".to_string())),
+            ("any".into(), None),
+        ]
+    );
+}
+
 /// A dependency as encoded in the index JSON.
 #[derive(Deserialize)]
 struct RegistryDependency<'a> {
@@ -487,6 +854,44 @@ pub trait RegistryData {
     /// Is the local cached data up-to-date?
     fn is_updated(&self) -> bool;
 
+    /// Whether a copy of the index is already on disk to read from, without
+    /// fetching anything.
+    ///
+    /// This must be cheap and must not modify anything on disk.
+    fn is_index_ready(&self) -> bool;
+
+    /// Returns the revision of the index that summaries are currently read
+    /// from, such as the commit of a git index.
+    ///
+    /// Registries without a single revision for the whole index return
+    /// `None`.
+    fn index_revision(&self) -> Option<InternedString> {
+        None
+    }
+
+    /// Finds the revision of the index that added `version` to the file of
+    /// a package at `path`, relative to the root of the index.
+    ///
+    /// Registries without a history of their index return `None`.
+    fn version_introduced(&self, _path: &Path, _version: &Version) -> CargoResult<Option<String>> {
+        Ok(None)
+    }
+
+    /// Lists the versions whose yanked state changed with the last update of
+    /// the index in this session, sorted by name and version.
+    ///
+    /// Registries that can't tell, or weren't updated, return an empty list.
+    fn yank_changes(&self) -> CargoResult<Vec<YankChange>> {
+        Ok(Vec::new())
+    }
+
+    /// Number of bytes received over the network to update the index so far.
+    ///
+    /// Registries that can't tell, or don't use the network, return `0`.
+    fn index_bytes_transferred(&self) -> u64 {
+        0
+    }
+
     /// Prepare to start downloading a `.crate` file.
     ///
     /// Despite the name, this doesn't actually download anything. If the
@@ -533,6 +938,16 @@ pub trait RegistryData {
     fn block_until_ready(&mut self) -> CargoResult<()>;
 }
 
+/// A version whose yanked state changed with an update of the index, see
+/// [`RegistrySource::yank_changes`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct YankChange {
+    pub name: String,
+    pub version: String,
+    /// Whether the version is yanked now, or was unyanked otherwise.
+    pub yanked: bool,
+}
+
 /// The status of [`RegistryData::download`] which indicates if a `.crate`
 /// file has already been downloaded, or if not then the URL to download.
 pub enum MaybeLock {
@@ -547,23 +962,341 @@ pub enum MaybeLock {
         url: String,
         descriptor: String,
         authorization: Option<String>,
+        /// The session cookie of registries that use sessions, see
+        /// [`crate::util::auth::session_cookie`].
+        cookie: Option<String>,
+        /// See [`RegistryConfig::max_concurrent_downloads`].
+        max_concurrent: Option<usize>,
+        /// Whether the registry may use HTTP basic auth, that is unless it
+        /// declares `auth-required`.
+        hint_basic_auth: bool,
     },
 }
 
+pub use self::cache_store::{CacheStore, FilesystemStore};
+pub use self::download::verify_tarball;
+pub use self::index::{IndexLayout, IndexReadCache, IoStats};
+
+mod cache_store;
 mod download;
 mod http_remote;
 mod index;
 mod local;
+mod mirror;
 mod remote;
+mod snapshot;
 
-fn short_name(id: SourceId, is_shallow: bool) -> String {
+fn short_name(id: SourceId, is_shallow: bool, config: &Config) -> String {
     let hash = hex::short_hash(&id);
-    let ident = id.url().host_str().unwrap_or("").to_string();
-    let mut name = format!("{}-{}", ident, hash);
-    if is_shallow {
-        name.push_str("-shallow");
+    let suffix = if is_shallow { "-shallow" } else { "" };
+    // Registries without a host used to be named by their hash alone. Their
+    // existing directories keep that name, so that what they hold isn't
+    // downloaded and unpacked again.
+    if id.url().host_str().is_none() {
+        let legacy = format!("-{}{}", hash, suffix);
+        let dirs = [
+            config.registry_index_path(),
+            config.registry_cache_path(),
+            config.registry_source_path(),
+        ];
+        if dirs
+            .iter()
+            .any(|dir| dir.join(&legacy).as_path_unlocked().exists())
+        {
+            return legacy;
+        }
+    }
+    format!("{}-{}{}", registry_ident(id.url()), hash, suffix)
+}
+
+/// Gets the human readable part of a registry's directory name.
+///
+/// This is the host of the registry URL without the trailing dot of a fully
+/// qualified domain name, so that equivalent spellings of a host share a
+/// name. [`Url`] already lowercases the hosts of `http` and `https` URLs.
+/// URLs without a host, like `file://` URLs, use the last segment of their
+/// path instead.
+fn registry_ident(url: &Url) -> String {
+    match url.host_str() {
+        Some(host) => host.trim_end_matches('.').to_string(),
+        None => url
+            .path_segments()
+            .and_then(|segments| segments.filter(|s| !s.is_empty()).last())
+            .unwrap_or("")
+            .to_string(),
+    }
+}
+
+/// The contents of the `.cargo-ok` file of an unpacked package, recording
+/// what was unpacked there.
+///
+/// Versions of Cargo before this was added wrote just `ok` to the file.
+#[derive(Serialize, Deserialize)]
+struct UnpackMetadata {
+    /// The version of the format, which is bumped whenever older versions of
+    /// Cargo can't tell what the file says anymore.
+    #[serde(default = "first_unpack_metadata_version")]
+    v: u32,
+    /// The package ID of the unpacked package.
+    package: String,
+    /// The checksum of the `.crate` file it was unpacked from.
+    cksum: Option<String>,
+    /// The patterns of the files that were unpacked, if not all of them were.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<String>,
+    /// The checksum of the unpacked `Cargo.toml`, recorded if
+    /// `registry.verify-unpacked-manifest` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest_cksum: Option<String>,
+}
+
+/// The version of [`UnpackMetadata`] this version of Cargo writes.
+const UNPACK_METADATA_VERSION: u32 = 1;
+
+fn first_unpack_metadata_version() -> u32 {
+    1
+}
+
+/// What the `.cargo-ok` file of an unpacked package says about it.
+enum UnpackMarker {
+    /// A file from a version of Cargo that didn't record what it unpacked,
+    /// or one that can't be read.
+    Legacy,
+    /// A file in a format this version of Cargo understands.
+    Known(UnpackMetadata),
+    /// A file in a newer format, with this version.
+    Newer(u32),
+}
+
+/// How packages unpacked by versions of Cargo that didn't record what they
+/// unpacked are treated, as set by `registry.legacy-unpack-markers`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LegacyMarkerPolicy {
+    /// Reuse them, assuming they are right.
+    Lenient,
+    /// Unpack them again.
+    Strict,
+}
+
+/// Reads the `.cargo-ok` file at `path`.
+fn read_unpack_marker(path: &Path) -> CargoResult<UnpackMarker> {
+    let contents = paths::read_bytes(path)?;
+    // The version is read on its own, as newer formats may have changed the
+    // other fields.
+    #[derive(Deserialize)]
+    struct Version {
+        #[serde(default = "first_unpack_metadata_version")]
+        v: u32,
+    }
+    let Ok(Version { v }) = serde_json::from_slice::<Version>(&contents) else {
+        return Ok(UnpackMarker::Legacy);
+    };
+    if v > UNPACK_METADATA_VERSION {
+        return Ok(UnpackMarker::Newer(v));
+    }
+    Ok(match serde_json::from_slice(&contents) {
+        Ok(unpacked) => UnpackMarker::Known(unpacked),
+        Err(_) => UnpackMarker::Legacy,
+    })
+}
+
+/// Checks that the package `unpacked` in `dir` is `pkg` from a `.crate` file
+/// with checksum `cksum`.
+fn check_unpacked(
+    unpacked: &UnpackMetadata,
+    dir: &Path,
+    pkg: PackageId,
+    cksum: Option<&str>,
+) -> CargoResult<()> {
+    let (Some(unpacked_cksum), Some(cksum)) = (unpacked.cksum.as_deref(), cksum) else {
+        return Ok(());
+    };
+    if unpacked_cksum == cksum {
+        return Ok(());
+    }
+    bail!(
+        "`{}` already contains `{}` unpacked from a `.crate` file with checksum \
+         `{}`, but `{}` has checksum `{}`\n\
+         refusing to overwrite it; if the package changed in the registry, remove \
+         the directory to unpack it again",
+        dir.display(),
+        unpacked.package,
+        unpacked_cksum,
+        pkg,
+        cksum
+    )
+}
+
+/// The SHA-256 checksum of the `Cargo.toml` of the package unpacked in `dir`.
+fn manifest_checksum(dir: &Path) -> CargoResult<String> {
+    let manifest = paths::read_bytes(&dir.join("Cargo.toml"))?;
+    Ok(Sha256::new().update(&manifest).finish_hex())
+}
+
+/// Checks that the `Cargo.toml` of the package `unpacked` in `dir` wasn't
+/// modified since it was unpacked.
+///
+/// Nothing is checked for packages that were unpacked without recording the
+/// checksum of their `Cargo.toml`.
+fn check_unpacked_manifest(
+    unpacked: &UnpackMetadata,
+    dir: &Path,
+    pkg: PackageId,
+) -> CargoResult<()> {
+    let Some(expected) = &unpacked.manifest_cksum else {
+        return Ok(());
+    };
+    let actual = manifest_checksum(dir)
+        .with_context(|| format!("failed to verify the `Cargo.toml` of `{}`", pkg))?;
+    if actual == *expected {
+        return Ok(());
     }
-    name
+    bail!(
+        "the `Cargo.toml` of `{}` in `{}` has checksum `{}`, but it had checksum \
+         `{}` when it was unpacked\n\
+         the file was modified after unpacking; remove the directory to unpack \
+         the package again",
+        pkg,
+        dir.display(),
+        actual,
+        expected
+    )
+}
+
+/// Resolves a URL from the `config.json` of the registry of `source_id`,
+/// which may be relative, against the URL of its index.
+///
+/// Absolute URLs are returned unchanged, so `dl: "/api/v1/crates"` is on the
+/// host of the index while `dl: "https://example.com/crates"` isn't.
+fn resolve_config_url(url: &str, source_id: SourceId) -> CargoResult<String> {
+    match Url::parse(url) {
+        Err(url::ParseError::RelativeUrlWithoutBase) => {}
+        _ => return Ok(url.to_string()),
+    }
+    let index_url = source_id.url().as_str();
+    let base = index_url
+        .strip_prefix("sparse+")
+        .unwrap_or(index_url)
+        .into_url()?;
+    let resolved = base.join(url).with_context(|| {
+        format!(
+            "failed to resolve `{}` in the config.json of {} against its index URL",
+            url, source_id
+        )
+    })?;
+    Ok(resolved.to_string())
+}
+
+/// The config key `registries.<name>.<key>` for the registry of
+/// `source_id`, if it is crates.io or a named registry.
+///
+/// Returns `None` as well if no registry sets `key`, without looking up the
+/// name of the registry.
+fn registries_key(config: &Config, source_id: SourceId, key: &str) -> CargoResult<Option<String>> {
+    let name = if source_id.is_crates_io() {
+        Some(CRATES_IO_REGISTRY.to_string())
+    } else if auth::registries_key_set(config, &[key])? {
+        auth::registry_name(config, &source_id)?
+    } else {
+        None
+    };
+    Ok(name.map(|name| format!("registries.{name}.{key}")))
+}
+
+/// The directory set with `registries.<name>.index-snapshot` to read the
+/// index of `source_id` from, if any.
+fn index_snapshot(config: &Config, source_id: SourceId) -> CargoResult<Option<PathBuf>> {
+    let Some(key) = registries_key(config, source_id, "index-snapshot")? else {
+        return Ok(None);
+    };
+    Ok(config
+        .get::<Option<ConfigRelativePath>>(&key)?
+        .map(|path| path.resolve_path(config)))
+}
+
+/// The URL set with `registries.<name>.index-mirror` to sync the index of
+/// `source_id` from, if any.
+fn index_mirror(config: &Config, source_id: SourceId) -> CargoResult<Option<Url>> {
+    let Some(key) = registries_key(config, source_id, "index-mirror")? else {
+        return Ok(None);
+    };
+    let Some(url) = config.get::<Option<String>>(&key)? else {
+        return Ok(None);
+    };
+    let url = url
+        .into_url()
+        .with_context(|| format!("invalid URL in `{}`", key))?;
+    Ok(Some(url))
+}
+
+/// The branch set with `registries.<name>.index-branch` to fetch the git
+/// index of `source_id` from, if any.
+fn index_branch(config: &Config, source_id: SourceId) -> CargoResult<Option<String>> {
+    let Some(key) = registries_key(config, source_id, "index-branch")? else {
+        return Ok(None);
+    };
+    config.get::<Option<String>>(&key)
+}
+
+/// What [`RegistrySource::verify_all`] did and found.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The packages that are downloaded and unpacked now, with what had to
+    /// be done for each, which is nothing if it already was.
+    pub verified: Vec<(PackageId, Vec<VerifyAction>)>,
+    /// The packages that couldn't be verified, with why.
+    pub failed: Vec<(PackageId, anyhow::Error)>,
+}
+
+impl VerifyReport {
+    /// Whether every package was verified.
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Something [`RegistrySource::verify_all`] did to a package.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyAction {
+    /// Its `.crate` file was downloaded.
+    Downloaded,
+    /// It was unpacked.
+    Unpacked,
+    /// It was unpacked again, as these files were missing from it.
+    Repaired(Vec<String>),
+}
+
+/// The files of the `.crate` file `tarball` missing from the package
+/// unpacked in `dir`, relative to `dir`.
+///
+/// Only regular files are checked. Paths that aren't valid UTF-8 are skipped,
+/// as they are unpacked under a sanitized name if at all.
+fn missing_unpacked_files(tarball: &File, dir: &Path) -> CargoResult<Vec<String>> {
+    let prefix = dir.file_name().unwrap();
+    let mut tar = Archive::new(GzDecoder::new(BufReader::new(tarball)));
+    let mut missing = Vec::new();
+    for entry in tar.entries()? {
+        let entry = entry.with_context(|| "failed to iterate over archive")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Ok(entry_path) = entry.path() else {
+            continue;
+        };
+        let Ok(path) = entry_path.strip_prefix(prefix) else {
+            continue;
+        };
+        if path.file_name().map_or(false, |p| {
+            p == PACKAGE_SOURCE_LOCK || p == PACKAGE_PARTIAL_LOCK
+        }) {
+            continue;
+        }
+        if !dir.join(path).is_file() {
+            missing.push(path.display().to_string());
+        }
+    }
+    Ok(missing)
 }
 
 impl<'cfg> RegistrySource<'cfg> {
@@ -575,25 +1308,46 @@ impl<'cfg> RegistrySource<'cfg> {
         assert!(source_id.is_remote_registry());
         let name = short_name(
             source_id,
-            config
-                .cli_unstable()
-                .gitoxide
-                .map_or(false, |gix| gix.fetch && gix.shallow_index)
-                && !source_id.is_sparse(),
+            shallow_index(config)? && !source_id.is_sparse(),
+            config,
         );
-        let ops = if source_id.is_sparse() {
+        let mut http_fallback = None;
+        let ops = if let Some(snapshot) = index_snapshot(config, source_id)? {
+            Box::new(snapshot::SnapshotRegistry::new(&snapshot, config, &name)) as Box<_>
+        } else if let Some(mirror) = index_mirror(config, source_id)? {
+            Box::new(mirror::MirrorRegistry::new(
+                source_id, mirror, config, &name,
+            )) as Box<_>
+        } else if source_id.is_sparse() {
             Box::new(http_remote::HttpRegistry::new(source_id, config, &name)?) as Box<_>
         } else {
-            Box::new(remote::RemoteRegistry::new(source_id, config, &name)) as Box<_>
+            // Only crates.io is known to serve its index over the sparse
+            // protocol as well.
+            if source_id.is_crates_io()
+                && config.get::<Option<bool>>("registry.http-fallback")? == Some(true)
+            {
+                // Cargo's own tests have a crates.io of their own.
+                let url = config
+                    .get_env("__CARGO_TEST_CRATES_IO_SPARSE_URL_DO_NOT_USE_THIS")
+                    .unwrap_or_else(|_| CRATES_IO_HTTP_INDEX.to_string());
+                let url = url.as_str().into_url()?;
+                http_fallback = Some(SourceId::for_alt_registry(&url, CRATES_IO_REGISTRY)?);
+            }
+            let index_git_ref = match index_branch(config, source_id)? {
+                Some(branch) => GitReference::Branch(branch),
+                None => GitReference::DefaultBranch,
+            };
+            Box::new(remote::RemoteRegistry::new(
+                source_id,
+                config,
+                &name,
+                index_git_ref,
+            )) as Box<_>
         };
 
-        Ok(RegistrySource::new(
-            source_id,
-            config,
-            &name,
-            ops,
-            yanked_whitelist,
-        ))
+        let mut source = RegistrySource::new(source_id, config, &name, ops, yanked_whitelist);
+        source.http_fallback = http_fallback;
+        Ok(source)
     }
 
     pub fn local(
@@ -602,7 +1356,7 @@ impl<'cfg> RegistrySource<'cfg> {
         yanked_whitelist: &HashSet<PackageId>,
         config: &'cfg Config,
     ) -> RegistrySource<'cfg> {
-        let name = short_name(source_id, false);
+        let name = short_name(source_id, false, config);
         let ops = local::LocalRegistry::new(path, config, &name);
         RegistrySource::new(source_id, config, &name, Box::new(ops), yanked_whitelist)
     }
@@ -616,122 +1370,955 @@ impl<'cfg> RegistrySource<'cfg> {
     ) -> RegistrySource<'cfg> {
         RegistrySource {
             src_path: config.registry_source_path().join(name),
+            cache_path: config.registry_cache_path().join(name),
             config,
             source_id,
             index: index::RegistryIndex::new(source_id, ops.index_path(), config),
             yanked_whitelist: yanked_whitelist.clone(),
+            reported_missing_locked: HashSet::new(),
+            reported_deprecated: HashSet::new(),
+            reported_dl_hosts: HashSet::new(),
+            download_urls: HashMap::new(),
+            mismatched_downloads: HashMap::new(),
+            failed_downloads: HashMap::new(),
+            swept_stale: false,
+            validated_locked_index: false,
+            crate_bytes_transferred: 0,
+            index_bytes_at_reset: 0,
+            cache_store: LazyCell::new(),
+            http_fallback: None,
             ops,
         }
     }
 
-    /// Decode the configuration stored within the registry.
+    /// Uses `store` to keep `.crate` files across runs, instead of the one
+    /// set with `registry.cache-store`.
     ///
-    /// This requires that the index has been at least checked out.
-    pub fn config(&mut self) -> Poll<CargoResult<Option<RegistryConfig>>> {
-        self.ops.config()
+    /// Packages missing from the local cache are looked up in the store
+    /// before they are downloaded, and downloads are added to it.
+    pub fn set_cache_store(&mut self, store: Box<dyn CacheStore + 'cfg>) {
+        self.cache_store = LazyCell::new();
+        let _ = self.cache_store.fill(Some(store));
     }
 
-    /// Unpacks a downloaded package into a location where it's ready to be
-    /// compiled.
+    fn cache_store(&self) -> CargoResult<Option<&(dyn CacheStore + 'cfg)>> {
+        Ok(self
+            .cache_store
+            .try_borrow_with(|| cache_store::from_config(self.config).map(|s| s.map(|s| s as _)))?
+            .as_deref())
+    }
+
+    /// Looks up the `.crate` file of `package` in the cache store, adding it
+    /// to the local cache if it's there.
     ///
-    /// No action is taken if the source looks like it's already unpacked.
-    fn unpack_package(&self, pkg: PackageId, tarball: &File) -> CargoResult<PathBuf> {
-        // The `.cargo-ok` file is used to track if the source is already
-        // unpacked.
-        let package_dir = format!("{}-{}", pkg.name(), pkg.version());
-        let dst = self.src_path.join(&package_dir);
-        let path = dst.join(PACKAGE_SOURCE_LOCK);
-        let path = self.config.assert_package_cache_locked(&path);
-        let unpack_dir = path.parent().unwrap();
-        match path.metadata() {
-            Ok(meta) if meta.len() > 0 => return Ok(unpack_dir.to_path_buf()),
-            Ok(_meta) => {
-                // The `.cargo-ok` file is not in a state we expect it to be
-                // (with two bytes containing "ok").
-                //
-                // Cargo has always included a `.cargo-ok` file to detect if
-                // extraction was interrupted, but it was originally empty.
-                //
-                // In 1.34, Cargo was changed to create the `.cargo-ok` file
-                // before it started extraction to implement fine-grained
-                // locking. After it was finished extracting, it wrote two
-                // bytes to indicate it was complete. It would use the length
-                // check to detect if it was possibly interrupted.
-                //
-                // In 1.36, Cargo changed to not use fine-grained locking, and
-                // instead used a global lock. The use of `.cargo-ok` was no
-                // longer needed for locking purposes, but was kept to detect
-                // when extraction was interrupted.
-                //
-                // In 1.49, Cargo changed to not create the `.cargo-ok` file
-                // before it started extraction to deal with `.crate` files
-                // that inexplicably had a `.cargo-ok` file in them.
-                //
-                // In 1.64, Cargo changed to detect `.crate` files with
-                // `.cargo-ok` files in them in response to CVE-2022-36113,
-                // which dealt with malicious `.crate` files making
-                // `.cargo-ok` a symlink causing cargo to write "ok" to any
-                // arbitrary file on the filesystem it has permission to.
-                //
-                // This is all a long-winded way of explaining the
-                // circumstances that might cause a directory to contain a
-                // `.cargo-ok` file that is empty or otherwise corrupted.
-                // Either this was extracted by a version of Rust before 1.34,
-                // in which case everything should be fine. However, an empty
-                // file created by versions 1.36 to 1.49 indicates that the
-                // extraction was interrupted and that we need to start again.
-                //
-                // Another possibility is that the filesystem is simply
-                // corrupted, in which case deleting the directory might be
-                // the safe thing to do. That is probably unlikely, though.
-                //
-                // To be safe, this deletes the directory and starts over
-                // again.
-                log::warn!("unexpected length of {path:?}, clearing cache");
-                paths::remove_dir_all(dst.as_path_unlocked())?;
+    /// Problems with the store are only warned about, as the package can
+    /// still be downloaded from the registry instead.
+    fn fetch_from_cache_store(
+        &mut self,
+        package: PackageId,
+        hash: &str,
+    ) -> CargoResult<Option<File>> {
+        let Some(store) = self.cache_store()? else {
+            return Ok(None);
+        };
+        let data = match store.get(package, hash) {
+            Ok(Some(data)) => data,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.config.shell().warn(format!(
+                    "failed to fetch `{}` from the cache store, downloading it instead: {:#}",
+                    package, e
+                ))?;
+                return Ok(None);
             }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
-            Err(e) => anyhow::bail!("failed to access package completion {path:?}: {e}"),
-        }
-        dst.create_dir()?;
-        let mut tar = {
-            let size_limit = max_unpack_size(self.config, tarball.metadata()?.len());
-            let gz = GzDecoder::new(tarball);
-            let gz = LimitErrorReader::new(gz, size_limit);
-            Archive::new(gz)
         };
-        let prefix = unpack_dir.file_name().unwrap();
-        let parent = unpack_dir.parent().unwrap();
-        for entry in tar.entries()? {
-            let mut entry = entry.with_context(|| "failed to iterate over archive")?;
-            let entry_path = entry
-                .path()
-                .with_context(|| "failed to read entry path")?
-                .into_owned();
+        if download::verify_checksum(&data[..], hash, package).is_err() {
+            self.config.shell().warn(format!(
+                "the cache store has a corrupt copy of `{}`, downloading it instead",
+                package
+            ))?;
+            return Ok(None);
+        }
+        self.ops.finish_download(package, hash, &data).map(Some)
+    }
 
-            // We're going to unpack this tarball into the global source
-            // directory, but we want to make sure that it doesn't accidentally
-            // (or maliciously) overwrite source code from other crates. Cargo
-            // itself should never generate a tarball that hits this error, and
-            // crates.io should also block uploads with these sorts of tarballs,
-            // but be extra sure by adding a check here as well.
-            if !entry_path.starts_with(prefix) {
-                anyhow::bail!(
-                    "invalid tarball downloaded, contains \
-                     a file at {:?} which isn't under {:?}",
-                    entry_path,
-                    prefix
-                )
+    /// Verifies the checksum of `data` downloaded from `url` up front, if a
+    /// mismatch is to be retried, see `registry.checksum-mismatch-retries`
+    /// and `registry.download-mirrors`.
+    ///
+    /// While there are attempts left, a mismatch is a [`DownloadRetry`]
+    /// error, and the next [`Source::download`] of the package goes to the
+    /// next mirror, if any. Otherwise the checksum is verified as usual when
+    /// the `.crate` file is written to the cache.
+    fn check_download_checksum(
+        &mut self,
+        package: PackageId,
+        hash: &str,
+        url: &str,
+        data: &[u8],
+    ) -> CargoResult<()> {
+        let mirrors = download::download_mirrors(self.config, package, hash)?.len();
+        let retries = self
+            .config
+            .get::<Option<usize>>("registry.checksum-mismatch-retries")?
+            .unwrap_or(0);
+        let attempts = (retries + 1).max(mirrors + 1);
+        if attempts == 1 {
+            return Ok(());
+        }
+        if download::verify_checksum(data, hash, package).is_ok() {
+            self.mismatched_downloads.remove(&package);
+            return Ok(());
+        }
+        let failed = self.mismatched_downloads.entry(package).or_default();
+        failed.push(url.to_string());
+        if failed.len() < attempts {
+            return Err(DownloadRetry {
+                reason: format!(
+                    "`{}` downloaded from `{}` doesn't match the checksum in the index, \
+                     downloading it again",
+                    package, url
+                ),
             }
-            // Prevent unpacking the lockfile from the crate itself.
-            if entry_path
-                .file_name()
-                .map_or(false, |p| p == PACKAGE_SOURCE_LOCK)
+            .into());
+        }
+        let mut failed = self.mismatched_downloads.remove(&package).unwrap();
+        let mut seen = HashSet::new();
+        failed.retain(|url| seen.insert(url.clone()));
+        bail!(
+            "failed to verify the checksum of `{}`, the downloads from these URLs \
+             don't match the checksum in the index:\n  {}",
+            package,
+            failed.join("\n  ")
+        )
+    }
+
+    /// Adds the verified download of `package` to the cache store, unless it
+    /// already has it.
+    fn put_in_cache_store(&self, package: PackageId, hash: &str, data: &[u8]) -> CargoResult<()> {
+        let Some(store) = self.cache_store()? else {
+            return Ok(());
+        };
+        let result = store.exists(package, hash).and_then(|exists| {
+            if exists {
+                Ok(())
+            } else {
+                store.put(package, hash, data)
+            }
+        });
+        if let Err(e) = result {
+            self.config.shell().warn(format!(
+                "failed to add `{}` to the cache store: {:#}",
+                package, e
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Loads and parses the index entries of `pkgs` in one pass, typically
+    /// the packages of this source listed in `Cargo.lock`.
+    ///
+    /// Their summaries and checksums are kept in memory afterwards, so later
+    /// queries and downloads of them don't go back to the index. Doing all
+    /// of the index I/O up front is friendlier to disk caches than reading
+    /// each crate's entries as resolution gets to it.
+    pub fn prime_from_lock(&mut self, pkgs: &[PackageId]) -> CargoResult<()> {
+        loop {
+            let mut pending = false;
+            for pkg in pkgs {
+                let req = OptVersionReq::exact(pkg.version());
+                let summaries = self.index.summaries(&pkg.name(), &req, &mut *self.ops)?;
+                match summaries {
+                    Poll::Ready(summaries) => summaries.for_each(drop),
+                    Poll::Pending => pending = true,
+                }
+            }
+            if !pending {
+                return Ok(());
+            }
+            self.block_until_ready()?;
+        }
+    }
+
+    /// Returns the summaries of the versions of `name` matching the version
+    /// requirement `req`, like `">=1.2, <1.5"`, without having to build a
+    /// [`Dependency`] for it.
+    ///
+    /// This is an exact [`Source::query`], which blocks until the index is
+    /// loaded.
+    pub fn query_req(&mut self, name: &str, req: &str) -> CargoResult<Vec<Summary>> {
+        let dep = Dependency::parse(name, Some(req), self.source_id)?;
+        loop {
+            match self.query_vec(&dep, QueryKind::Exact)? {
+                Poll::Ready(summaries) => return Ok(summaries),
+                Poll::Pending => self.block_until_ready()?,
+            }
+        }
+    }
+
+    /// Returns an iterator over the summaries of this source matching `dep`,
+    /// like an exact [`Source::query`].
+    ///
+    /// The index lines of a crate are only parsed as the iterator is
+    /// advanced, so callers that stop at the first version they can use
+    /// don't pay for parsing every version of a crate with a long history.
+    /// It doesn't fall back to updating the index if a locked version is
+    /// missing though, nor check the index for duplicate checksums.
+    pub fn query_iter<'a>(
+        &'a mut self,
+        dep: &'a Dependency,
+    ) -> Poll<CargoResult<impl Iterator<Item = Summary> + 'a>> {
+        ready!(self.validate_locked_index())?;
+        let summaries = ready!(self.index.query_iter(
+            dep.package_name().as_str(),
+            dep.version_req(),
+            &mut *self.ops,
+            &self.yanked_whitelist,
+        ))?;
+        Poll::Ready(Ok(summaries.filter(move |s| dep.matches(s))))
+    }
+
+    /// Sets whether the index line each summary is read from is kept, to be
+    /// looked up with [`RegistrySource::index_line`], such as to record the
+    /// exact index entry a package was resolved from.
+    ///
+    /// This is off by default, as it keeps another copy of the index files
+    /// in memory.
+    pub fn keep_index_lines(&mut self, keep: bool) {
+        self.index.keep_lines(keep);
+    }
+
+    /// Shares what this source reads from the index with the other sources
+    /// given the same `cache`, and reuses what they have read, as long as the
+    /// index is at the same revision.
+    ///
+    /// This is for tools that create many sources for the same registry,
+    /// see [`IndexReadCache`] for details.
+    pub fn set_index_read_cache(&mut self, cache: Rc<IndexReadCache>) {
+        self.index.set_read_cache(cache);
+    }
+
+    /// Returns whether the registry has a package named `name`, or with `-`
+    /// and `_` swapped like the resolver does, no matter its versions.
+    ///
+    /// This is cheaper than a query, as the versions in the index are not
+    /// parsed, so it suits checking a name typed by a user.
+    pub fn crate_exists(&mut self, name: &str) -> CargoResult<bool> {
+        let mut names = vec![name.to_string()];
+        for permutation in [name.replace('-', "_"), name.replace('_', "-")] {
+            if !names.contains(&permutation) {
+                names.push(permutation);
+            }
+        }
+        loop {
+            let mut pending = false;
+            for name in &names {
+                match self
+                    .index
+                    .has_versions(name.as_str().into(), &mut *self.ops)?
+                {
+                    Poll::Ready(true) => return Ok(true),
+                    Poll::Ready(false) => {}
+                    Poll::Pending => pending = true,
+                }
+            }
+            if !pending {
+                return Ok(false);
+            }
+            self.block_until_ready()?;
+        }
+    }
+
+    /// Returns counters of the work reading the index took so far, such as
+    /// how many index files were read and how many lines were parsed.
+    pub fn io_stats(&self) -> IoStats {
+        self.index.io_stats()
+    }
+
+    /// Returns the JSON line of the index that the summary of `pkg` is read
+    /// from, verbatim.
+    ///
+    /// This is `None` unless lines are kept, see
+    /// [`RegistrySource::keep_index_lines`], or if the index has no line for
+    /// `pkg`.
+    pub fn index_line(&mut self, pkg: PackageId) -> CargoResult<Option<String>> {
+        loop {
+            match self.index.line(pkg, &mut *self.ops)? {
+                Poll::Ready(line) => return Ok(line.map(str::to_string)),
+                Poll::Pending => self.block_until_ready()?,
+            }
+        }
+    }
+
+    /// Lists the checksums Cargo has loaded from the index so far, as
+    /// `(name, version, checksum)` sorted by name and version.
+    ///
+    /// This is a debugging aid to find out what checksum Cargo thinks a
+    /// version has. Versions that are in memory but haven't been parsed yet
+    /// aren't included.
+    pub fn dump_hashes(&self) -> Vec<(String, String, String)> {
+        let mut hashes: Vec<_> = self
+            .index
+            .loaded_summaries()
+            .filter_map(|s| Some((s.summary.package_id(), s.summary.checksum()?)))
+            .collect();
+        hashes.sort();
+        hashes
+            .into_iter()
+            .map(|(id, cksum)| {
+                (
+                    id.name().to_string(),
+                    id.version().to_string(),
+                    cksum.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// Whether the index of this registry is already on disk, so resolving
+    /// against it doesn't require downloading the index first.
+    ///
+    /// This never fetches anything, so a ready index may still be out of
+    /// date. Front-ends can use it to tell users that the first use of a
+    /// registry will download its index.
+    pub fn is_index_ready(&self) -> bool {
+        self.ops.is_index_ready()
+    }
+
+    /// Lists the versions that were yanked or unyanked by the update of the
+    /// index in this session, sorted by name and version.
+    ///
+    /// The list is empty if the index wasn't updated, or if the registry
+    /// doesn't keep a history of its index to tell.
+    pub fn yank_changes(&self) -> CargoResult<Vec<YankChange>> {
+        self.ops.yank_changes()
+    }
+
+    /// Finds the revision of the index that added `version` of the package
+    /// `name`, such as the commit of a git index, to tell when it was
+    /// published.
+    ///
+    /// This looks at the index as it is on disk, without updating it first.
+    /// Returns `None` if the version isn't in the history of the index, or
+    /// if the registry doesn't keep one.
+    pub fn version_introduced(&self, name: &str, version: &Version) -> CargoResult<Option<String>> {
+        if !self.ops.is_index_ready() {
+            return Ok(None);
+        }
+        let fs_name = name.to_lowercase();
+        let Some(path) = self.index.layout()?.path(&fs_name) else {
+            return Ok(None);
+        };
+        self.ops
+            .version_introduced(Path::new(&path), version)
+            .with_context(|| {
+                format!(
+                    "failed to look up the history of `{}` in {}",
+                    name,
+                    self.source_id.display_index()
+                )
+            })
+    }
+
+    /// Makes sure every package in `packages` is downloaded, has a `.crate`
+    /// file matching the checksum in the index, and is completely unpacked,
+    /// downloading or unpacking whatever isn't.
+    ///
+    /// Unlike downloading the packages one by one, this doesn't stop at the
+    /// first package that fails, so the report covers all of them.
+    pub fn verify_all(&mut self, packages: &[PackageId]) -> CargoResult<VerifyReport> {
+        let _lock = self.config.acquire_package_cache_lock()?;
+        let mut report = VerifyReport::default();
+        for &pkg in packages {
+            match self.verify_one(pkg) {
+                Ok(actions) => report.verified.push((pkg, actions)),
+                Err(e) => report
+                    .failed
+                    .push((pkg, e.context(format!("failed to verify `{}`", pkg)))),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Verifies a single package for [`RegistrySource::verify_all`].
+    fn verify_one(&mut self, pkg: PackageId) -> CargoResult<Vec<VerifyAction>> {
+        let mut actions = Vec::new();
+        let hash = loop {
+            match self.index.hash(pkg, &mut *self.ops)? {
+                Poll::Pending => self.block_until_ready()?,
+                Poll::Ready(hash) => break hash.map(str::to_string),
+            }
+        };
+        let Some(hash) = hash else {
+            bail!("the index lists no checksum to verify `{}` against", pkg)
+        };
+
+        if self.ops.is_crate_downloaded(pkg) {
+            let MaybeLock::Ready(file) = self.ops.download(pkg, &hash)? else {
+                unreachable!("a downloaded `.crate` file isn't ready")
+            };
+            let actual = download::checksum_of(&file, &hash, pkg)?;
+            if actual != hash {
+                bail!(
+                    "the downloaded `.crate` file has checksum `{}`, but the index \
+                     lists checksum `{}`",
+                    actual,
+                    hash
+                );
+            }
+        }
+
+        let dir = self
+            .src_path
+            .join(format!("{}-{}", pkg.name(), pkg.version()));
+        let marker = dir.join(PACKAGE_SOURCE_LOCK);
+        let marker = self.config.assert_package_cache_locked(&marker);
+        let was_unpacked = marker.metadata().map_or(false, |m| m.len() > 0);
+
+        match Source::download(self, pkg)? {
+            MaybePackage::Ready(_) => {}
+            MaybePackage::Download {
+                url,
+                authorization,
+                cookie,
+                ..
+            } => {
+                let data = self.fetch_crate(&url, authorization.as_deref(), cookie.as_deref())?;
+                Source::finish_download(self, pkg, data)?;
+                actions.push(VerifyAction::Downloaded);
+            }
+        }
+        if !was_unpacked {
+            actions.push(VerifyAction::Unpacked);
+        }
+
+        let MaybeLock::Ready(file) = self.ops.download(pkg, &hash)? else {
+            bail!("the `.crate` file is missing after downloading it");
+        };
+        let unpack_dir = marker.parent().unwrap();
+        let missing = missing_unpacked_files(&file, unpack_dir)?;
+        if !missing.is_empty() {
+            log::debug!("unpacking {pkg} again, missing {missing:?}");
+            paths::remove_dir_all(unpack_dir)?;
+            // The file was read to the end above, so it's opened again.
+            let MaybeLock::Ready(file) = self.ops.download(pkg, &hash)? else {
+                unreachable!("a downloaded `.crate` file isn't ready")
+            };
+            self.get_pkg(pkg, &file)?;
+            actions.push(VerifyAction::Repaired(missing));
+        }
+        Ok(actions)
+    }
+
+    /// Downloads a `.crate` file from `url` on its own, outside of the
+    /// parallel downloads of [`crate::core::package::Downloads`].
+    fn fetch_crate(
+        &self,
+        url: &str,
+        authorization: Option<&str>,
+        cookie: Option<&str>,
+    ) -> CargoResult<Vec<u8>> {
+        with_retry(self.config, || {
+            let mut handle = ops::http_handle(self.config)?;
+            handle.get(true)?;
+            handle.url(url)?;
+            handle.follow_location(true)?;
+            if let Some(authorization) = authorization {
+                let mut headers = curl::easy::List::new();
+                headers.append(&format!("Authorization: {}", authorization))?;
+                handle.http_headers(headers)?;
+            }
+            if let Some(cookie) = cookie {
+                handle.cookie(cookie)?;
+            }
+            let mut body = Vec::new();
             {
+                let mut transfer = handle.transfer();
+                transfer.write_function(|buf| {
+                    body.extend_from_slice(buf);
+                    Ok(buf.len())
+                })?;
+                transfer
+                    .perform()
+                    .map_err(|e| network::with_tls_context(e, self.config))?;
+            }
+            let code = handle.response_code()?;
+            if code != 200 && code != 0 {
+                return Err(
+                    HttpNotSuccessful::new_from_handle(&mut handle, url, body, Vec::new()).into(),
+                );
+            }
+            Ok(body)
+        })
+        .with_context(|| format!("failed to download `{}`", url))
+    }
+
+    /// Number of bytes downloaded from this registry since it was created or
+    /// last reset with [`RegistrySource::reset_bytes_transferred`].
+    ///
+    /// This counts `.crate` files and the index files fetched by sparse
+    /// registries. Fetches of git indexes aren't included.
+    pub fn bytes_transferred(&self) -> u64 {
+        self.crate_bytes_transferred + self.ops.index_bytes_transferred()
+            - self.index_bytes_at_reset
+    }
+
+    /// Resets the counter behind [`RegistrySource::bytes_transferred`].
+    pub fn reset_bytes_transferred(&mut self) {
+        self.crate_bytes_transferred = 0;
+        self.index_bytes_at_reset = self.ops.index_bytes_transferred();
+    }
+
+    /// Fetches the metadata of crate `name`, such as its description and
+    /// keywords, from the web API of this registry.
+    ///
+    /// This is for informational commands only; resolution never depends on
+    /// the API.
+    pub fn api_crate_info(&mut self, name: &str) -> CargoResult<crates_io::CrateInfo> {
+        let cfg = loop {
+            match self.config()? {
+                Poll::Pending => self.block_until_ready()?,
+                Poll::Ready(cfg) => break cfg,
+            }
+        };
+        let cfg = cfg
+            .ok_or_else(|| anyhow::format_err!("{} does not have a config.json", self.source_id))?;
+        let api = cfg.api.ok_or_else(|| {
+            anyhow::format_err!("{} does not support API commands", self.source_id)
+        })?;
+        let token = if cfg.auth_required {
+            Some(auth::auth_token(self.config, &self.source_id, None, None)?)
+        } else {
+            None
+        };
+        let handle = ops::http_handle(self.config)?;
+        let mut registry = crates_io::Registry::new_handle(api, token, handle, cfg.auth_required);
+        registry.crate_info(name).with_context(|| {
+            format!(
+                "failed to fetch the metadata of `{}` from {}",
+                name, self.source_id
+            )
+        })
+    }
+
+    /// Decode the configuration stored within the registry.
+    ///
+    /// A relative `api` is resolved against the index URL. `dl` is left as
+    /// is, since it's a template that is only resolved once its markers are
+    /// replaced for a package, in `download::download`.
+    ///
+    /// This requires that the index has been at least checked out.
+    pub fn config(&mut self) -> Poll<CargoResult<Option<RegistryConfig>>> {
+        let Some(mut cfg) = ready!(self.ops.config()?) else {
+            return Poll::Ready(Ok(None));
+        };
+        if let Some(api) = &cfg.api {
+            cfg.api = Some(resolve_config_url(api, self.source_id)?);
+        }
+        Poll::Ready(Ok(Some(cfg)))
+    }
+
+    /// Unpacks a downloaded package into a location where it's ready to be
+    /// compiled.
+    ///
+    /// No action is taken if the source looks like it's already unpacked.
+    ///
+    /// `cksum` is the checksum of `tarball`, which is recorded in the
+    /// `.cargo-ok` file to detect a different package being unpacked to the
+    /// same place.
+    ///
+    /// If `include` is set, only the files matching one of its patterns are
+    /// unpacked, and a `.cargo-partial` file is written instead of
+    /// `.cargo-ok`. Unpacking the whole package later on completes it.
+    fn unpack_package(
+        &self,
+        pkg: PackageId,
+        tarball: &File,
+        cksum: Option<&str>,
+        include: Option<&[Pattern]>,
+    ) -> CargoResult<PathBuf> {
+        // The `.cargo-ok` file is used to track if the source is already
+        // unpacked.
+        let package_dir = format!("{}-{}", pkg.name(), pkg.version());
+        let dst = self.src_path.join(&package_dir);
+        let path = dst.join(PACKAGE_SOURCE_LOCK);
+        let path = self.config.assert_package_cache_locked(&path);
+        let unpack_dir = path.parent().unwrap();
+        match path.metadata() {
+            Ok(meta) if meta.len() > 0 => match read_unpack_marker(path)? {
+                UnpackMarker::Known(unpacked) => {
+                    check_unpacked(&unpacked, unpack_dir, pkg, cksum)?;
+                    if self.verify_unpacked_manifest()? {
+                        check_unpacked_manifest(&unpacked, unpack_dir, pkg)?;
+                    }
+                    return Ok(unpack_dir.to_path_buf());
+                }
+                // Packages unpacked by older versions of Cargo can't be
+                // checked, as they didn't record what they unpacked.
+                UnpackMarker::Legacy
+                    if self.legacy_marker_policy()? == LegacyMarkerPolicy::Lenient =>
+                {
+                    return Ok(unpack_dir.to_path_buf());
+                }
+                UnpackMarker::Legacy => {
+                    log::debug!("unpacking {pkg} again over a legacy {path:?}");
+                    paths::remove_dir_all(dst.as_path_unlocked())?;
+                }
+                // A newer version of Cargo may have unpacked it differently
+                // than this one would, so this one doesn't rely on it.
+                UnpackMarker::Newer(v) => {
+                    log::debug!("unpacking {pkg} again over {path:?} of version {v}");
+                    paths::remove_dir_all(dst.as_path_unlocked())?;
+                }
+            },
+            Ok(_meta) => {
+                // The `.cargo-ok` file is not in a state we expect it to be
+                // (with two bytes containing "ok").
+                //
+                // Cargo has always included a `.cargo-ok` file to detect if
+                // extraction was interrupted, but it was originally empty.
+                //
+                // In 1.34, Cargo was changed to create the `.cargo-ok` file
+                // before it started extraction to implement fine-grained
+                // locking. After it was finished extracting, it wrote two
+                // bytes to indicate it was complete. It would use the length
+                // check to detect if it was possibly interrupted.
+                //
+                // In 1.36, Cargo changed to not use fine-grained locking, and
+                // instead used a global lock. The use of `.cargo-ok` was no
+                // longer needed for locking purposes, but was kept to detect
+                // when extraction was interrupted.
+                //
+                // In 1.49, Cargo changed to not create the `.cargo-ok` file
+                // before it started extraction to deal with `.crate` files
+                // that inexplicably had a `.cargo-ok` file in them.
+                //
+                // In 1.64, Cargo changed to detect `.crate` files with
+                // `.cargo-ok` files in them in response to CVE-2022-36113,
+                // which dealt with malicious `.crate` files making
+                // `.cargo-ok` a symlink causing cargo to write "ok" to any
+                // arbitrary file on the filesystem it has permission to.
+                //
+                // This is all a long-winded way of explaining the
+                // circumstances that might cause a directory to contain a
+                // `.cargo-ok` file that is empty or otherwise corrupted.
+                // Either this was extracted by a version of Rust before 1.34,
+                // in which case everything should be fine. However, an empty
+                // file created by versions 1.36 to 1.49 indicates that the
+                // extraction was interrupted and that we need to start again.
+                //
+                // Another possibility is that the filesystem is simply
+                // corrupted, in which case deleting the directory might be
+                // the safe thing to do. That is probably unlikely, though.
+                //
+                // To be safe, this deletes the directory and starts over
+                // again.
+                log::warn!("unexpected length of {path:?}, clearing cache");
+                paths::remove_dir_all(dst.as_path_unlocked())?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => anyhow::bail!("failed to access package completion {path:?}: {e}"),
+        }
+        let partial_path = unpack_dir.join(PACKAGE_PARTIAL_LOCK);
+        if partial_path.exists() {
+            if let UnpackMarker::Known(unpacked) = read_unpack_marker(&partial_path)? {
+                check_unpacked(&unpacked, unpack_dir, pkg, cksum)?;
+            }
+        }
+        let mut tar = {
+            let size_limit = max_unpack_size(self.config, tarball.metadata()?.len());
+            let buffer_size = self
+                .config
+                .get::<Option<usize>>("registry.unpack-buffer-size")?
+                .filter(|&size| size > 0)
+                .unwrap_or(DEFAULT_UNPACK_BUFFER_SIZE);
+            let parallel = self
+                .config
+                .get::<Option<bool>>("registry.parallel-gunzip")?
+                .unwrap_or(false);
+            let gz: Box<dyn Read> = if parallel {
+                Box::new(gunzip_in_background(tarball.try_clone()?, buffer_size))
+            } else {
+                Box::new(GzDecoder::new(BufReader::with_capacity(
+                    buffer_size,
+                    tarball,
+                )))
+            };
+            let gz = LimitErrorReader::new(gz, size_limit);
+            Archive::new(gz)
+        };
+        let prefix = unpack_dir.file_name().unwrap();
+        let parent = unpack_dir.parent().unwrap();
+
+        // The package is unpacked into a temporary directory next to where
+        // it goes, and only moved into place once unpacking succeeded, so a
+        // failure halfway, such as from a full disk, never leaves a partially
+        // unpacked package behind.
+        let tmp = parent.join(format!("{}{}", UNPACK_TMP_PREFIX, package_dir));
+        if tmp.exists() {
+            paths::remove_dir_all(&tmp)?;
+        }
+        paths::create_dir_all(&tmp)?;
+        let top_level = self
+            .config
+            .get::<Option<TopLevelPolicy>>("registry.unexpected-top-level")?
+            .unwrap_or(TopLevelPolicy::Reject);
+        let sanitized = self
+            .unpack_entries(&mut tar, &tmp, prefix, include, top_level)
+            .and_then(|sanitized| {
+                // Whatever is left of an earlier attempt, or of a partial
+                // unpack, is replaced as a whole.
+                if unpack_dir.exists() {
+                    paths::remove_dir_all(unpack_dir)?;
+                }
+                let unpacked = match top_level {
+                    TopLevelPolicy::Reject => tmp.join(prefix),
+                    TopLevelPolicy::Locate => self.locate_unpacked(pkg, &tmp, prefix)?,
+                };
+                paths::create_dir_all(&unpacked)?;
+                // `tmp` is next to `unpack_dir`, so this never crosses
+                // filesystems.
+                fs::rename(&unpacked, unpack_dir).with_context(|| {
+                    format!(
+                        "failed to move `{}` to `{}`",
+                        unpacked.display(),
+                        unpack_dir.display()
+                    )
+                })?;
+                Ok(sanitized)
+            });
+        if let Err(e) = paths::remove_dir_all(&tmp) {
+            debug!("failed to remove `{}`: {e}", tmp.display());
+        }
+        let sanitized = sanitized?;
+
+        if !sanitized.is_empty() {
+            let mut msg = format!(
+                "package `{}` contains paths that are not valid UTF-8, unpacked as:",
+                pkg
+            );
+            for path in &sanitized {
+                msg.push_str(&format!("\n  {}", path.display()));
+            }
+            self.config.shell().warn(msg)?;
+        }
+
+        if let Some(include) = include {
+            let metadata = UnpackMetadata {
+                v: UNPACK_METADATA_VERSION,
+                package: pkg.to_string(),
+                cksum: cksum.map(str::to_string),
+                include: include.iter().map(|p| p.as_str().to_string()).collect(),
+                manifest_cksum: None,
+            };
+            paths::write(&partial_path, serde_json::to_vec(&metadata)?)?;
+            return Ok(unpack_dir.to_path_buf());
+        }
+
+        // Now that we've finished unpacking, create and write to the lock file to indicate that
+        // unpacking was successful.
+        let mut ok = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open `{}`", path.display()))?;
+        let manifest_cksum = if self.verify_unpacked_manifest()? {
+            Some(manifest_checksum(unpack_dir)?)
+        } else {
+            None
+        };
+        let metadata = UnpackMetadata {
+            v: UNPACK_METADATA_VERSION,
+            package: pkg.to_string(),
+            cksum: cksum.map(str::to_string),
+            include: Vec::new(),
+            manifest_cksum,
+        };
+        serde_json::to_writer(&mut ok, &metadata)?;
+
+        Ok(unpack_dir.to_path_buf())
+    }
+
+    /// Unpacks the entries of `tar` into `parent`, where the package ends up
+    /// in the directory `prefix`, returning the paths that had to be
+    /// sanitized.
+    ///
+    /// Only the files matching `include` are unpacked, if set. Files outside
+    /// of `prefix` are unpacked too with [`TopLevelPolicy::Locate`], to be
+    /// sorted out by [`RegistrySource::locate_unpacked`].
+    fn unpack_entries<R: Read>(
+        &self,
+        tar: &mut Archive<R>,
+        parent: &Path,
+        prefix: &OsStr,
+        include: Option<&[Pattern]>,
+        top_level: TopLevelPolicy,
+    ) -> CargoResult<Vec<PathBuf>> {
+        let symlinks = self
+            .config
+            .get::<Option<SymlinkPolicy>>("registry.symlinks")?
+            .unwrap_or(SymlinkPolicy::Reject);
+        // Symlinks to materialize as copies once everything is unpacked,
+        // since their targets may come later in the archive.
+        let mut symlink_copies = Vec::new();
+        let hardlinks = self
+            .config
+            .get::<Option<HardlinkPolicy>>("registry.hardlinks")?
+            .unwrap_or(HardlinkPolicy::Reject);
+        let mut hardlink_copies = Vec::new();
+        let non_utf8 = self
+            .config
+            .get::<Option<NonUtf8Policy>>("registry.non-utf8-paths")?
+            .unwrap_or(NonUtf8Policy::Reject);
+        let mut sanitized = Vec::new();
+        for entry in tar.entries()? {
+            let mut entry = entry.with_context(|| "failed to iterate over archive")?;
+            let raw_path = entry.path_bytes().into_owned();
+            let is_sanitized = std::str::from_utf8(&raw_path).is_err();
+            let entry_path = if is_sanitized {
+                match non_utf8 {
+                    NonUtf8Policy::Reject => bail!(
+                        "invalid tarball downloaded, contains a path that is not valid \
+                         UTF-8: {:?}\n\
+                         set `registry.non-utf8-paths` to `sanitize` to unpack such \
+                         paths under a sanitized name",
+                        String::from_utf8_lossy(&raw_path)
+                    ),
+                    NonUtf8Policy::Sanitize => PathBuf::from(sanitize_path(&raw_path)),
+                }
+            } else {
+                entry
+                    .path()
+                    .with_context(|| "failed to read entry path")?
+                    .into_owned()
+            };
+
+            // We're going to unpack this tarball into the global source
+            // directory, but we want to make sure that it doesn't accidentally
+            // (or maliciously) overwrite source code from other crates. Cargo
+            // itself should never generate a tarball that hits this error, and
+            // crates.io should also block uploads with these sorts of tarballs,
+            // but be extra sure by adding a check here as well.
+            //
+            // A path like `foo-0.1.0/../bar` starts with the package directory
+            // but leaves it, so such paths are rejected outright instead of
+            // being skipped when unpacking.
+            if entry_path.components().any(|c| {
+                matches!(
+                    c,
+                    Component::ParentDir | Component::RootDir | Component::Prefix(_)
+                )
+            }) {
+                bail!(
+                    "invalid tarball downloaded, contains a file at {:?} \
+                     which escapes the package directory",
+                    entry_path
+                )
+            }
+            // The top-level directory of this entry, which is `prefix` for
+            // packages made by `cargo package`.
+            let top = match entry_path.iter().next() {
+                Some(top) if top == prefix => top,
+                Some(top) if top_level == TopLevelPolicy::Locate => top,
+                _ => bail!(
+                    "invalid tarball downloaded, contains \
+                     a file at {:?} which isn't under {:?}\n\
+                     set `registry.unexpected-top-level` to `locate` to unpack \
+                     packages from the directory that contains their `Cargo.toml`",
+                    entry_path,
+                    prefix
+                ),
+            };
+            // Prevent unpacking the lockfile from the crate itself.
+            if entry_path.file_name().map_or(false, |p| {
+                p == PACKAGE_SOURCE_LOCK || p == PACKAGE_PARTIAL_LOCK
+            }) {
                 continue;
             }
+            if let Some(include) = include {
+                let path_in_package = entry_path.strip_prefix(top).unwrap();
+                // The manifest is needed to tell which directory is the
+                // package when it isn't `prefix`.
+                let is_manifest = top_level == TopLevelPolicy::Locate
+                    && path_in_package == Path::new("Cargo.toml");
+                if !is_manifest && !include.iter().any(|p| p.matches_path(path_in_package)) {
+                    continue;
+                }
+            }
+            if entry.header().entry_type().is_symlink() {
+                // No matter the policy, a symlink must never lead outside of
+                // the package, or reading or writing through it could touch
+                // arbitrary files.
+                let link = entry
+                    .link_name()
+                    .with_context(|| "failed to read symlink target")?;
+                let Some(target) = link
+                    .and_then(|link| symlink_target(&entry_path, &link))
+                    .filter(|target| target.starts_with(top))
+                else {
+                    bail!(
+                        "invalid tarball downloaded, contains \
+                         a symlink at {:?} which points outside of {:?}",
+                        entry_path,
+                        top
+                    )
+                };
+                match symlinks {
+                    SymlinkPolicy::Reject => bail!(
+                        "invalid tarball downloaded, contains a symlink at {:?}\n\
+                         set `registry.symlinks` to `copy` or `preserve` to allow \
+                         symlinks in packages",
+                        entry_path
+                    ),
+                    SymlinkPolicy::Copy => {
+                        symlink_copies.push((entry_path, target));
+                        continue;
+                    }
+                    SymlinkPolicy::Preserve => {}
+                }
+            }
+            if entry.header().entry_type().is_hard_link() {
+                // Like symlinks, a hardlink must never lead outside of the
+                // package. It's never unpacked as a hardlink, since that
+                // would share the file with whatever it links to.
+                let link = entry
+                    .link_name()
+                    .with_context(|| "failed to read hardlink target")?;
+                let Some(target) = link
+                    .and_then(|link| hardlink_target(&link))
+                    .filter(|target| target.starts_with(top) && *target != entry_path)
+                else {
+                    bail!(
+                        "invalid tarball downloaded, contains \
+                         a hardlink at {:?} which points outside of {:?}",
+                        entry_path,
+                        top
+                    )
+                };
+                match hardlinks {
+                    HardlinkPolicy::Reject => bail!(
+                        "invalid tarball downloaded, contains a hardlink at {:?}\n\
+                         set `registry.hardlinks` to `copy` to allow hardlinks in \
+                         packages",
+                        entry_path
+                    ),
+                    HardlinkPolicy::Copy => {
+                        hardlink_copies.push((entry_path, target));
+                        continue;
+                    }
+                }
+            }
             // Unpacking failed
-            let mut result = entry.unpack_in(parent).map_err(anyhow::Error::from);
+            let mut result = if is_sanitized {
+                sanitized.push(entry_path.clone());
+                unpack_sanitized(&mut entry, parent, &entry_path)
+            } else {
+                entry
+                    .unpack_in(parent)
+                    .map(drop)
+                    .map_err(anyhow::Error::from)
+            };
             if cfg!(windows) && restricted_names::is_windows_reserved_path(&entry_path) {
                 result = result.with_context(|| {
                     format!(
@@ -744,33 +2331,372 @@ impl<'cfg> RegistrySource<'cfg> {
             result
                 .with_context(|| format!("failed to unpack entry at `{}`", entry_path.display()))?;
         }
+        for (link, target) in symlink_copies {
+            copy_symlink_target(&parent.join(&target), &parent.join(&link)).with_context(|| {
+                format!(
+                    "failed to copy `{}` to the symlink at `{}`",
+                    target.display(),
+                    link.display()
+                )
+            })?;
+        }
+        for (link, target) in hardlink_copies {
+            let src = parent.join(&target);
+            let dst = parent.join(&link);
+            let result = if src.is_file() {
+                dst.parent()
+                    .map_or(Ok(()), paths::create_dir_all)
+                    .and_then(|()| paths::copy(&src, &dst).map(drop))
+            } else {
+                Err(anyhow::format_err!("it isn't a file in the package"))
+            };
+            result.with_context(|| {
+                format!(
+                    "failed to copy `{}` to the hardlink at `{}`",
+                    target.display(),
+                    link.display()
+                )
+            })?;
+        }
+        Ok(sanitized)
+    }
 
-        // Now that we've finished unpacking, create and write to the lock file to indicate that
-        // unpacking was successful.
-        let mut ok = OpenOptions::new()
-            .create_new(true)
-            .read(true)
-            .write(true)
-            .open(&path)
-            .with_context(|| format!("failed to open `{}`", path.display()))?;
-        write!(ok, "ok")?;
+    /// Removes what crashed runs left behind, if `registry.cleanup-stale` is
+    /// set: directories packages were being unpacked to, and `.part` files
+    /// of downloads that are older than the configured age.
+    ///
+    /// Nothing else unpacks packages while the package cache is locked, so
+    /// any such directory is abandoned. This only runs on the first download
+    /// of the source.
+    fn sweep_stale(&mut self) -> CargoResult<()> {
+        if std::mem::replace(&mut self.swept_stale, true) {
+            return Ok(());
+        }
+        let Some(max_age) = self
+            .config
+            .get::<Option<Value<String>>>("registry.cleanup-stale")?
+        else {
+            return Ok(());
+        };
+        let max_age = humantime::parse_duration(&max_age.val).with_context(|| {
+            format!(
+                "failed to parse `registry.cleanup-stale` value `{}` (in {})",
+                max_age.val, max_age.definition
+            )
+        })?;
+        // Directories that don't exist yet have nothing to clean up.
+        let entries = |dir: &Path| fs::read_dir(dir).into_iter().flatten().flatten();
+
+        let src_path = self.config.assert_package_cache_locked(&self.src_path);
+        for entry in entries(src_path) {
+            let is_tmp = entry
+                .file_name()
+                .to_str()
+                .map_or(false, |name| name.starts_with(UNPACK_TMP_PREFIX));
+            if is_tmp {
+                debug!("removing stale `{}`", entry.path().display());
+                paths::remove_dir_all(entry.path())?;
+            }
+        }
 
-        Ok(unpack_dir.to_path_buf())
+        let now = SystemTime::now();
+        let cache_path = self.config.assert_package_cache_locked(&self.cache_path);
+        for entry in entries(cache_path) {
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new("part")) {
+                continue;
+            }
+            let age = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .map(|modified| now.duration_since(modified).unwrap_or_default());
+            if age.map_or(false, |age| age > max_age) {
+                debug!("removing stale `{}`", path.display());
+                paths::remove_file(&path)?;
+            }
+        }
+        Ok(())
     }
 
-    fn get_pkg(&mut self, package: PackageId, path: &File) -> CargoResult<Package> {
-        let path = self
-            .unpack_package(package, path)
-            .with_context(|| format!("failed to unpack package `{}`", package))?;
-        let mut src = PathSource::new(&path, self.source_id, self.config);
-        src.update()?;
-        let mut pkg = match src.download(package)? {
-            MaybePackage::Ready(pkg) => pkg,
-            MaybePackage::Download { .. } => unreachable!(),
+    /// Finds the directory of `pkg` among the top-level directories it was
+    /// unpacked to in `tmp`, for [`TopLevelPolicy::Locate`].
+    ///
+    /// That's the expected `prefix` if it has a `Cargo.toml`, and otherwise
+    /// the one other directory that has one. Anything else is skipped, with
+    /// a warning.
+    fn locate_unpacked(&self, pkg: PackageId, tmp: &Path, prefix: &OsStr) -> CargoResult<PathBuf> {
+        let mut others = Vec::new();
+        for entry in fs::read_dir(tmp)? {
+            let name = entry?.file_name();
+            if name != prefix {
+                others.push(name);
+            }
+        }
+        if others.is_empty() {
+            return Ok(tmp.join(prefix));
+        }
+        others.sort();
+        let has_manifest = |name: &OsStr| tmp.join(name).join("Cargo.toml").is_file();
+        let dir = if has_manifest(prefix) {
+            prefix
+        } else {
+            let candidates: Vec<_> = others.iter().filter(|name| has_manifest(name)).collect();
+            match candidates[..] {
+                [dir] => {
+                    self.config.shell().warn(format!(
+                        "package `{}` is in a directory named {:?} instead of {:?} \
+                         in its `.crate` file",
+                        pkg, dir, prefix
+                    ))?;
+                    dir.as_os_str()
+                }
+                [] => bail!(
+                    "invalid tarball downloaded, none of its top-level directories \
+                     contains a `Cargo.toml`"
+                ),
+                _ => bail!(
+                    "invalid tarball downloaded, several of its top-level directories \
+                     contain a `Cargo.toml`: {:?}",
+                    candidates
+                ),
+            }
         };
+        let skipped: Vec<_> = others.iter().filter(|name| *name != dir).collect();
+        if !skipped.is_empty() {
+            self.config.shell().warn(format!(
+                "package `{}` has files outside of its directory in its `.crate` file, \
+                 which were skipped: {:?}",
+                pkg, skipped
+            ))?;
+        }
+        Ok(tmp.join(dir))
+    }
 
-        // After we've loaded the package configure its summary's `checksum`
-        // field with the checksum we know for this `PackageId`.
+    /// Unpacks only the files of the downloaded package `package` whose path
+    /// within the package matches one of `patterns`, returning the directory
+    /// they were unpacked to.
+    ///
+    /// This is for tools that need a few files of many packages, such as
+    /// their `build.rs` or `*.proto` files, without unpacking all of them.
+    /// The files go where the package is unpacked for builds, marked as
+    /// incomplete so that building the package unpacks the rest. If the
+    /// package is already fully unpacked, nothing is done.
+    pub fn unpack_matching(
+        &mut self,
+        package: PackageId,
+        tarball: &File,
+        patterns: &[Pattern],
+    ) -> CargoResult<PathBuf> {
+        let cksum = loop {
+            match self.index.hash(package, &mut *self.ops)? {
+                Poll::Pending => self.block_until_ready()?,
+                Poll::Ready(hash) => break hash.map(str::to_string),
+            }
+        };
+        self.unpack_package(package, tarball, cksum.as_deref(), Some(patterns))
+    }
+
+    /// Warns about the crate `dep` asks for if the registry has marked the
+    /// versions it matches as deprecated.
+    ///
+    /// Deprecation isn't removal, so this never stops the crate from being
+    /// selected.
+    fn warn_if_deprecated(&mut self, dep: &Dependency) -> Poll<CargoResult<()>> {
+        let name = dep.package_name();
+        if self.reported_deprecated.contains(&name) {
+            return Poll::Ready(Ok(()));
+        }
+        let deprecation = ready!(self
+            .index
+            .deprecation(name, dep.version_req(), &mut *self.ops))?;
+        let Some(deprecation) = deprecation else {
+            return Poll::Ready(Ok(()));
+        };
+        self.reported_deprecated.insert(name);
+        let mut msg = format!(
+            "package `{}` is deprecated in registry `{}`",
+            name,
+            self.source_id.display_registry_name()
+        );
+        if let Some(reason) = &deprecation.reason {
+            msg.push_str(&format!(": {reason}"));
+        }
+        if let Some(replacement) = deprecation.replacement {
+            msg.push_str(&format!("\nconsider using `{replacement}` instead"));
+        }
+        Poll::Ready(self.config.shell().warn(msg))
+    }
+
+    /// Reads `registry.legacy-unpack-markers`, defaulting to lenient.
+    fn legacy_marker_policy(&self) -> CargoResult<LegacyMarkerPolicy> {
+        Ok(self
+            .config
+            .get::<Option<LegacyMarkerPolicy>>("registry.legacy-unpack-markers")?
+            .unwrap_or(LegacyMarkerPolicy::Lenient))
+    }
+
+    /// Whether `registry.verify-unpacked-manifest` is set, to record the
+    /// checksum of the `Cargo.toml` of unpacked packages and check it when
+    /// they are reused.
+    fn verify_unpacked_manifest(&self) -> CargoResult<bool> {
+        Ok(self
+            .config
+            .get::<Option<bool>>("registry.verify-unpacked-manifest")?
+            == Some(true))
+    }
+
+    /// Checks that every line in the index for the crates in `Cargo.lock`
+    /// parses, if `registry.validate-locked-index` is set.
+    ///
+    /// All problems are reported together, rather than turning up one at a
+    /// time as the resolver runs into missing versions.
+    fn validate_locked_index(&mut self) -> Poll<CargoResult<()>> {
+        if self.validated_locked_index {
+            return Poll::Ready(Ok(()));
+        }
+        if self
+            .config
+            .get::<Option<bool>>("registry.validate-locked-index")?
+            != Some(true)
+        {
+            self.validated_locked_index = true;
+            return Poll::Ready(Ok(()));
+        }
+        let mut names = self
+            .yanked_whitelist
+            .iter()
+            .filter(|pkg| pkg.source_id() == self.source_id)
+            .map(|pkg| pkg.name())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        let invalid = ready!(self.index.invalid_lines(&names, &mut *self.ops))?;
+        self.validated_locked_index = true;
+        if !invalid.is_empty() {
+            return Poll::Ready(Err(anyhow::format_err!(
+                "the index of registry `{}` has entries that fail to parse \
+                 for packages in Cargo.lock:\n  {}",
+                self.source_id.display_registry_name(),
+                invalid.join("\n  ")
+            )));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reports a version pinned by `Cargo.lock` that the index doesn't list,
+    /// according to the `registry.missing-locked-versions` config value.
+    ///
+    /// Without this, the lock file entry only shows up as a confusing
+    /// resolution or download failure later on.
+    fn check_missing_locked_version(
+        &mut self,
+        name: InternedString,
+        version: &Version,
+    ) -> CargoResult<()> {
+        let level = self
+            .config
+            .get::<Option<CheckLevel>>("registry.missing-locked-versions")?
+            .unwrap_or(CheckLevel::Allow);
+        if level == CheckLevel::Allow
+            || !self.reported_missing_locked.insert((name, version.clone()))
+        {
+            return Ok(());
+        }
+        let msg = format!(
+            "package `{name} v{version}` is locked in Cargo.lock, \
+             but registry `{}` no longer has that version",
+            self.source_id.display_registry_name()
+        );
+        if level == CheckLevel::Deny {
+            bail!(msg);
+        }
+        self.config.shell().warn(msg)
+    }
+
+    /// Reports a download from a host other than the one of the index,
+    /// according to the `registry.dl-host-mismatch` config value, unless the
+    /// host is listed in `registry.known-dl-hosts`.
+    ///
+    /// Checksums still protect the downloads either way, but an unexpected
+    /// host after an index update may be a sign of a misconfigured or
+    /// compromised registry.
+    fn check_dl_host(&mut self, url: &str) -> CargoResult<()> {
+        let level = self
+            .config
+            .get::<Option<CheckLevel>>("registry.dl-host-mismatch")?
+            .unwrap_or(CheckLevel::Allow);
+        if level == CheckLevel::Allow {
+            return Ok(());
+        }
+        let Some(dl_host) = Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string))
+        else {
+            return Ok(());
+        };
+        if self.source_id.url().host_str() == Some(dl_host.as_str()) {
+            return Ok(());
+        }
+        let known = self
+            .config
+            .get::<Option<Vec<String>>>("registry.known-dl-hosts")?
+            .unwrap_or_default();
+        if known.contains(&dl_host) || !self.reported_dl_hosts.insert(dl_host.clone()) {
+            return Ok(());
+        }
+        let msg = format!(
+            "registry `{}` downloads crates from `{}`, \
+             which is not the host of its index\n\
+             Add the host to `registry.known-dl-hosts` if this is expected.",
+            self.source_id.display_registry_name(),
+            dl_host
+        );
+        if level == CheckLevel::Deny {
+            bail!(msg);
+        }
+        self.config.shell().warn(msg)
+    }
+
+    /// Checks the `.crate` file of `package` from the download cache against
+    /// `hash` before it is unpacked, unless `registry.verify-cached-downloads`
+    /// is false, and warns if it doesn't match.
+    ///
+    /// Without this, a file corrupted on disk would be reused forever, and
+    /// only fail to unpack. Packages that are already unpacked aren't checked
+    /// again, as that would read every `.crate` file on every build.
+    fn cached_download_is_corrupt(
+        &self,
+        package: PackageId,
+        hash: Option<&str>,
+        mut file: &File,
+    ) -> CargoResult<bool> {
+        // Local registries verify their own files.
+        let Some(hash) = hash.filter(|_| self.source_id.is_remote_registry()) else {
+            return Ok(false);
+        };
+        let unpacked = self
+            .src_path
+            .join(format!("{}-{}", package.name(), package.version()))
+            .join(PACKAGE_SOURCE_LOCK);
+        if unpacked.as_path_unlocked().exists()
+            || !self
+                .config
+                .get::<Option<bool>>("registry.verify-cached-downloads")?
+                .unwrap_or(true)
+        {
+            return Ok(false);
+        }
+        let matches = download::verify_checksum(file, hash, package).is_ok();
+        file.seek(io::SeekFrom::Start(0))?;
+        if !matches {
+            self.config.shell().warn(format!(
+                "the cached `.crate` file of `{}` doesn't match the checksum in the index, \
+                 downloading it again",
+                package
+            ))?;
+        }
+        Ok(!matches)
+    }
+
+    fn get_pkg(&mut self, package: PackageId, path: &File) -> CargoResult<Package> {
         let req = OptVersionReq::exact(package.version());
         let summary_with_cksum = self
             .index
@@ -779,7 +2705,27 @@ impl<'cfg> RegistrySource<'cfg> {
             .map(|s| s.summary.clone())
             .next()
             .expect("summary not found");
-        if let Some(cksum) = summary_with_cksum.checksum() {
+        let cksum = summary_with_cksum.checksum();
+        let path = match self.unpack_package(package, path, cksum, None) {
+            Ok(path) => path,
+            Err(e) if is_out_of_space(&e) => {
+                return Err(out_of_space(e, self.config, "unpacking", package)
+                    .context(format!("failed to unpack package `{}`", package)));
+            }
+            Err(e) => {
+                return Err(e.context(format!("failed to unpack package `{}`", package)));
+            }
+        };
+        let mut src = PathSource::new(&path, self.source_id, self.config);
+        src.update()?;
+        let mut pkg = match src.download(package)? {
+            MaybePackage::Ready(pkg) => pkg,
+            MaybePackage::Download { .. } => unreachable!(),
+        };
+
+        // After we've loaded the package configure its summary's `checksum`
+        // field with the checksum we know for this `PackageId`.
+        if let Some(cksum) = cksum {
             pkg.manifest_mut()
                 .summary_mut()
                 .set_checksum(cksum.to_string());
@@ -796,6 +2742,11 @@ impl<'cfg> Source for RegistrySource<'cfg> {
         kind: QueryKind,
         f: &mut dyn FnMut(Summary),
     ) -> Poll<CargoResult<()>> {
+        ready!(self.validate_locked_index())?;
+        if kind == QueryKind::Exact {
+            ready!(self.warn_if_deprecated(dep))?;
+        }
+
         // If this is a precise dependency, then it came from a lock file and in
         // theory the registry is known to contain this version. If, however, we
         // come back with no summaries, then our registry may need to be
@@ -844,6 +2795,11 @@ impl<'cfg> Source for RegistrySource<'cfg> {
             if called {
                 return Poll::Ready(Ok(()));
             }
+            if kind == QueryKind::Exact {
+                if let Some(version) = dep.version_req().locked_version() {
+                    self.check_missing_locked_version(dep.package_name(), version)?;
+                }
+            }
             let mut any_pending = false;
             if kind == QueryKind::Fuzzy {
                 // Attempt to handle misspellings by searching for a chain of related
@@ -901,23 +2857,84 @@ impl<'cfg> Source for RegistrySource<'cfg> {
     }
 
     fn download(&mut self, package: PackageId) -> CargoResult<MaybePackage> {
+        self.sweep_stale()?;
         let hash = loop {
             match self.index.hash(package, &mut *self.ops)? {
                 Poll::Pending => self.block_until_ready()?,
                 Poll::Ready(hash) => break hash,
             }
         };
-        match self.ops.download(package, hash)? {
-            MaybeLock::Ready(file) => self.get_pkg(package, &file).map(MaybePackage::Ready),
+        match self.ops.download(package, hash.unwrap_or_default())? {
+            MaybeLock::Ready(file) => {
+                let hash = hash.map(str::to_string);
+                if self.cached_download_is_corrupt(package, hash.as_deref(), &file)? {
+                    drop(file);
+                    let path = self.cache_path.join(download::filename(package));
+                    paths::remove_file(self.config.assert_package_cache_locked(&path))?;
+                    return self.download(package);
+                }
+                self.get_pkg(package, &file).map(MaybePackage::Ready)
+            }
             MaybeLock::Download {
                 url,
                 descriptor,
                 authorization,
-            } => Ok(MaybePackage::Download {
-                url,
-                descriptor,
-                authorization,
-            }),
+                cookie,
+                max_concurrent,
+                hint_basic_auth,
+            } => {
+                let hash = hash.map(str::to_string);
+                if let Some(hash) = &hash {
+                    if let Some(file) = self.fetch_from_cache_store(package, hash)? {
+                        return self.get_pkg(package, &file).map(MaybePackage::Ready);
+                    }
+                }
+                // Retries after a failed download or a checksum mismatch go
+                // to the next mirror.
+                let failed = self.mismatched_downloads.get(&package).map_or(0, Vec::len)
+                    + self.failed_downloads.get(&package).copied().unwrap_or(0);
+                let prefer_mirrors = self
+                    .config
+                    .get::<Option<bool>>("registry.prefer-download-mirrors")?
+                    .unwrap_or(false);
+                let (url, authorization, cookie, is_primary) = match &hash {
+                    Some(hash) if failed > 0 || prefer_mirrors => {
+                        let mut urls = download::download_mirrors(self.config, package, hash)?;
+                        // The registry's own URL comes first, unless the
+                        // mirrors are preferred.
+                        let primary = if prefer_mirrors { urls.len() } else { 0 };
+                        urls.insert(primary, url);
+                        let i = failed % urls.len();
+                        let url = urls.swap_remove(i);
+                        // Credentials of the registry aren't sent to mirrors.
+                        if i == primary {
+                            (url, authorization, cookie, true)
+                        } else {
+                            (url, None, None, false)
+                        }
+                    }
+                    _ => (url, authorization, cookie, true),
+                };
+                if is_primary {
+                    self.check_dl_host(&url)?;
+                }
+                self.download_urls.insert(package, url.clone());
+                // The summary was just loaded to get the hash.
+                let size = match self.index.size(package, &mut *self.ops)? {
+                    Poll::Ready(size) => size,
+                    Poll::Pending => None,
+                };
+                Ok(MaybePackage::Download {
+                    url,
+                    descriptor,
+                    authorization,
+                    cookie,
+                    size,
+                    max_concurrent,
+                    // Mirrors aren't sent the credentials of the registry.
+                    hint_basic_auth: hint_basic_auth && is_primary,
+                })
+            }
         }
     }
 
@@ -925,13 +2942,85 @@ impl<'cfg> Source for RegistrySource<'cfg> {
         let hash = loop {
             match self.index.hash(package, &mut *self.ops)? {
                 Poll::Pending => self.block_until_ready()?,
-                Poll::Ready(hash) => break hash,
+                Poll::Ready(hash) => break hash.map(str::to_string),
             }
         };
-        let file = self.ops.finish_download(package, hash, &data)?;
+        self.crate_bytes_transferred += data.len() as u64;
+        let url = self.download_urls.remove(&package);
+        // Transfers with a `Content-Length` were already checked against the
+        // size, but others may still have been cut short. This is cheaper to
+        // find out than a checksum mismatch.
+        let size = loop {
+            match self.index.size(package, &mut *self.ops)? {
+                Poll::Pending => self.block_until_ready()?,
+                Poll::Ready(size) => break size,
+            }
+        };
+        if let Some(size) = size.filter(|&size| size != data.len() as u64) {
+            bail!(
+                "received {} bytes for `{}`, but the index lists {} bytes",
+                data.len(),
+                package,
+                size
+            )
+        }
+        let verified = hash.is_some();
+        let hash = match hash {
+            Some(hash) => {
+                if let Some(url) = &url {
+                    self.check_download_checksum(package, &hash, url, &data)?;
+                }
+                hash
+            }
+            None => {
+                self.config.shell().warn(format!(
+                    "`{}` is used without verifying it, the index of registry `{}` \
+                     lists no checksum for it",
+                    package,
+                    self.source_id.display_registry_name()
+                ))?;
+                Sha256::new().update(&data).finish_hex()
+            }
+        };
+        let file = self.ops.finish_download(package, &hash, &data)?;
+        if let Some(url) = url {
+            let index_revision = self.ops.index_revision();
+            download::record_provenance(
+                self.config,
+                package,
+                &url,
+                &hash,
+                index_revision.as_deref(),
+            )?;
+        }
+        // Entries of the store are looked up by the checksum in the index.
+        if verified {
+            self.put_in_cache_store(package, &hash, &data)?;
+        }
         self.get_pkg(package, &file)
     }
 
+    fn download_failed(&mut self, package: PackageId, err: &anyhow::Error) -> CargoResult<bool> {
+        let mirrors = self
+            .config
+            .get::<Option<Vec<String>>>("registry.download-mirrors")?
+            .map_or(0, |mirrors| mirrors.len());
+        let failed = self.failed_downloads.entry(package).or_insert(0);
+        *failed += 1;
+        // Every URL was tried.
+        if *failed > mirrors {
+            self.failed_downloads.remove(&package);
+            return Ok(false);
+        }
+        let url = self.download_urls.remove(&package).unwrap_or_default();
+        self.config.shell().warn(format!(
+            "failed to download `{}` from `{}`, trying the next download URL\n\
+             the error was: {:#}",
+            package, url, err
+        ))?;
+        Ok(true)
+    }
+
     fn fingerprint(&self, pkg: &Package) -> CargoResult<String> {
         Ok(pkg.package_id().version().to_string())
     }
@@ -964,10 +3053,60 @@ impl<'cfg> Source for RegistrySource<'cfg> {
         let _ = registry_base.create_dir();
         exclude_from_backups_and_indexing(&registry_base.into_path_unlocked());
 
-        self.ops.block_until_ready()
+        match self.ops.block_until_ready() {
+            Err(e) if self.http_fallback.is_some() && looks_blocked(&e) => {
+                let sparse = self.http_fallback.take().unwrap();
+                self.config.shell().warn(format!(
+                    "failed to fetch the git index of registry `{}`, \
+                     using its sparse index `{}` instead\n\
+                     the error from git was: {:#}",
+                    self.source_id.display_registry_name(),
+                    sparse.url(),
+                    e
+                ))?;
+                let name = short_name(sparse, false, self.config);
+                self.ops = Box::new(http_remote::HttpRegistry::new(sparse, self.config, &name)?);
+                // Whatever was read from the git index before is read from the
+                // sparse index again.
+                self.index.set_path(self.ops.index_path());
+                Ok(())
+            }
+            res => res,
+        }
     }
 }
 
+/// Whether `e` from fetching a git index looks like the network doesn't let
+/// git through, such as a firewall refusing the connection or a proxy denying
+/// access, rather than like the server being unknown, down or slow.
+///
+/// libgit2, gitoxide and the git CLI all fetch over HTTP with curl, but none
+/// of them pass on its errors as such, only their messages.
+fn looks_blocked(e: &anyhow::Error) -> bool {
+    const BLOCKED: &[&str] = &[
+        // The connection was refused or reset.
+        "Couldn't connect to server",
+        "Connection refused",
+        "Connection reset by peer",
+        // A proxy refused to tunnel the connection.
+        "from proxy after CONNECT",
+        "CONNECT tunnel failed",
+        // A proxy intercepts TLS with a certificate of its own.
+        "SSL certificate problem",
+        // Access was denied, in the words of libgit2, gitoxide and git.
+        "HTTP 200 response: got 403",
+        "HTTP 200 response: got 407",
+        "Received HTTP status 403",
+        "Received HTTP status 407",
+        "The requested URL returned error: 403",
+        "The requested URL returned error: 407",
+    ];
+    e.chain().any(|cause| {
+        let msg = cause.to_string();
+        BLOCKED.iter().any(|blocked| msg.contains(blocked))
+    })
+}
+
 /// Get the maximum upack size that Cargo permits
 /// based on a given `size` of your compressed file.
 ///
@@ -1013,6 +3152,94 @@ fn max_unpack_size(config: &Config, size: u64) -> u64 {
     u64::max(max_unpack_size, size * max_compression_ratio as u64)
 }
 
+/// Whether `err` was caused by the disk running out of space.
+fn is_out_of_space(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        if let Some(io) = err.downcast_ref::<io::Error>() {
+            #[cfg(unix)]
+            return io.raw_os_error() == Some(libc::ENOSPC);
+            #[cfg(windows)]
+            return io.raw_os_error().map_or(false, |x| {
+                use windows_sys::Win32::Foundation::{ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL};
+                x == ERROR_DISK_FULL as i32 || x == ERROR_HANDLE_DISK_FULL as i32
+            });
+        }
+
+        false
+    })
+}
+
+/// Adds context to an out of space error hit while `action` `pkg`, saying
+/// how to recover from it.
+fn out_of_space(
+    err: anyhow::Error,
+    config: &Config,
+    action: &str,
+    pkg: PackageId,
+) -> anyhow::Error {
+    err.context(format!(
+        "ran out of disk space while {action} `{pkg}`\n\
+         free up some space and try again, for example by clearing Cargo's \
+         package cache in `{}`",
+        config.registry_base_path().as_path_unlocked().display()
+    ))
+}
+
+/// Decompresses `tarball` on another thread, so that inflating the archive
+/// overlaps with writing out the files already extracted from it.
+///
+/// The returned reader yields the decompressed bytes. If it is dropped
+/// early, the thread stops at its next write.
+fn gunzip_in_background(tarball: File, buffer_size: usize) -> impl Read {
+    // A couple of chunks in flight are enough to keep both sides busy.
+    let (tx, rx) = std::sync::mpsc::sync_channel(2);
+    std::thread::spawn(move || {
+        let mut gz = GzDecoder::new(BufReader::with_capacity(buffer_size, tarball));
+        loop {
+            let mut chunk = vec![0; buffer_size];
+            let msg = match gz.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Ok(chunk)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = msg.is_err();
+            if tx.send(msg).is_err() || failed {
+                break;
+            }
+        }
+    });
+    ChannelReader {
+        rx,
+        chunk: io::Cursor::new(Vec::new()),
+    }
+}
+
+/// Reads the chunks sent by [`gunzip_in_background`].
+struct ChannelReader {
+    rx: std::sync::mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.chunk.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            match self.rx.recv() {
+                Ok(chunk) => self.chunk = io::Cursor::new(chunk?),
+                // The sender is gone once the whole stream was decompressed.
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
 /// Constructs a path to a dependency in the registry index on filesystem.
 /// See [`cargo_util::registry::make_dep_path`] for more.
 fn make_dep_prefix(name: &str) -> String {
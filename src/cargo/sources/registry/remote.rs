@@ -2,8 +2,10 @@ use crate::core::{GitReference, PackageId, SourceId};
 use crate::sources::git;
 use crate::sources::git::fetch::RemoteKind;
 use crate::sources::registry::download;
+use crate::sources::registry::index;
 use crate::sources::registry::MaybeLock;
-use crate::sources::registry::{LoadResponse, RegistryConfig, RegistryData};
+use crate::sources::registry::{LoadResponse, RegistryConfig, RegistryData, YankChange};
+use crate::util::config::Value;
 use crate::util::errors::CargoResult;
 use crate::util::interning::InternedString;
 use crate::util::{Config, Filesystem};
@@ -11,12 +13,15 @@ use anyhow::Context as _;
 use cargo_util::paths;
 use lazycell::LazyCell;
 use log::{debug, trace};
+use semver::Version;
 use std::cell::{Cell, Ref, RefCell};
-use std::fs::File;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
 use std::mem;
 use std::path::Path;
 use std::str;
 use std::task::{ready, Poll};
+use std::time::{Duration, SystemTime};
 
 /// A remote registry is a registry that lives at a remote URL (such as
 /// crates.io). The git index is cloned locally, and `.crate` files are
@@ -32,24 +37,39 @@ pub struct RemoteRegistry<'cfg> {
     repo: LazyCell<git2::Repository>,
     head: Cell<Option<git2::Oid>>,
     current_sha: Cell<Option<InternedString>>,
+    /// The `config.json` of the commit the index is at, decoded once instead
+    /// of for every download. Cleared whenever the index is updated.
+    registry_config: Option<RegistryConfig>,
     needs_update: bool, // Does this registry need to be updated?
+    /// Whether the age of the index was already checked against
+    /// `registry.max-index-age`.
+    index_age_checked: bool,
+    /// The commit the index was at before it was updated in this session.
+    updated_from: Option<git2::Oid>,
     quiet: bool,
 }
 
 impl<'cfg> RemoteRegistry<'cfg> {
-    pub fn new(source_id: SourceId, config: &'cfg Config, name: &str) -> RemoteRegistry<'cfg> {
+    pub fn new(
+        source_id: SourceId,
+        config: &'cfg Config,
+        name: &str,
+        index_git_ref: GitReference,
+    ) -> RemoteRegistry<'cfg> {
         RemoteRegistry {
             index_path: config.registry_index_path().join(name),
             cache_path: config.registry_cache_path().join(name),
             source_id,
             config,
-            // TODO: we should probably make this configurable
-            index_git_ref: GitReference::DefaultBranch,
+            index_git_ref,
             tree: RefCell::new(None),
             repo: LazyCell::new(),
             head: Cell::new(None),
             current_sha: Cell::new(None),
+            registry_config: None,
             needs_update: false,
+            index_age_checked: false,
+            updated_from: None,
             quiet: false,
         }
     }
@@ -147,6 +167,71 @@ impl<'cfg> RemoteRegistry<'cfg> {
         self.config.updated_sources().contains(&self.source_id)
     }
 
+    /// Asks for the index to be updated if its last update, as recorded by
+    /// the mtime of the `.last-updated` file, is longer ago than
+    /// `registry.max-index-age`.
+    ///
+    /// Otherwise the index is only updated when something can't be found in
+    /// it, which never happens for builds with a lock file.
+    fn check_index_age(&mut self) -> CargoResult<()> {
+        if self.is_updated() || self.config.offline() || self.config.cli_unstable().no_index_update
+        {
+            return Ok(());
+        }
+        let Some(max_age) = self
+            .config
+            .get::<Option<Value<String>>>("registry.max-index-age")?
+        else {
+            return Ok(());
+        };
+        let max = humantime::parse_duration(&max_age.val).with_context(|| {
+            format!(
+                "failed to parse `registry.max-index-age` value `{}` (in {})",
+                max_age.val, max_age.definition
+            )
+        })?;
+        // An index that was never updated is updated anyway on first use.
+        let path = self.index_path.as_path_unlocked().join(LAST_UPDATED_FILE);
+        let Ok(updated) = fs::metadata(&path).and_then(|m| m.modified()) else {
+            return Ok(());
+        };
+        let age = SystemTime::now()
+            .duration_since(updated)
+            .unwrap_or_default();
+        if age > max {
+            self.config.shell().note(format!(
+                "the index of registry `{}` was last updated {} ago, \
+                 longer ago than the `registry.max-index-age` of {}",
+                self.source_id.display_registry_name(),
+                humantime::format_duration(Duration::from_secs(age.as_secs())),
+                max_age.val
+            ))?;
+            self.needs_update = true;
+        }
+        Ok(())
+    }
+
+    /// Prints which versions were yanked or unyanked by the last update, if
+    /// any were.
+    fn report_yank_changes(&self) -> CargoResult<()> {
+        let changes = self.yank_changes()?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let mut msg = format!(
+            "since the last update of {}:",
+            self.source_id.display_index()
+        );
+        for change in changes {
+            let what = if change.yanked { "yanked" } else { "unyanked" };
+            msg.push_str(&format!(
+                "\n  `{} v{}` was {}",
+                change.name, change.version, what
+            ));
+        }
+        self.config.shell().note(msg)
+    }
+
     fn mark_updated(&self) {
         self.config.updated_sources().insert(self.source_id);
     }
@@ -181,6 +266,10 @@ impl<'cfg> RegistryData for RemoteRegistry<'cfg> {
         path: &Path,
         index_version: Option<&str>,
     ) -> Poll<CargoResult<LoadResponse>> {
+        if !self.index_age_checked {
+            self.index_age_checked = true;
+            self.check_index_age()?;
+        }
         if self.needs_update {
             return Poll::Pending;
         }
@@ -244,14 +333,19 @@ impl<'cfg> RegistryData for RemoteRegistry<'cfg> {
     fn config(&mut self) -> Poll<CargoResult<Option<RegistryConfig>>> {
         debug!("loading config");
         self.prepare()?;
-        self.config.assert_package_cache_locked(&self.index_path);
+        let path = self
+            .config
+            .assert_package_cache_locked(&self.index_path)
+            .join("config.json");
+        if let Some(cfg) = &self.registry_config {
+            return Poll::Ready(Ok(Some(cfg.clone())));
+        }
         match ready!(self.load(Path::new(""), Path::new("config.json"), None)?) {
             LoadResponse::Data { raw_data, .. } => {
                 trace!("config loaded");
-                let mut cfg: RegistryConfig = serde_json::from_slice(&raw_data)?;
-                if !self.config.cli_unstable().registry_auth {
-                    cfg.auth_required = false;
-                }
+                let mut cfg = RegistryConfig::from_slice(&raw_data, &path.display().to_string())?;
+                cfg.gate_auth_required(self.config);
+                self.registry_config = Some(cfg.clone());
                 Poll::Ready(Ok(Some(cfg)))
             }
             _ => Poll::Ready(Ok(None)),
@@ -291,9 +385,11 @@ impl<'cfg> RegistryData for RemoteRegistry<'cfg> {
         self.config.http()?;
 
         self.prepare()?;
+        let before = self.head().ok();
         self.head.set(None);
         *self.tree.borrow_mut() = None;
         self.current_sha.set(None);
+        self.registry_config = None;
         let path = self.config.assert_package_cache_locked(&self.index_path);
         if !self.quiet {
             self.config
@@ -318,12 +414,22 @@ impl<'cfg> RegistryData for RemoteRegistry<'cfg> {
         // index.
         paths::create(&path.join(LAST_UPDATED_FILE))?;
 
+        self.updated_from = before;
+        if self
+            .config
+            .get::<Option<bool>>("registry.report-yank-changes")?
+            == Some(true)
+        {
+            self.report_yank_changes()?;
+        }
+
         Ok(())
     }
 
     fn invalidate_cache(&mut self) {
         // To fully invalidate, undo `mark_updated`s work
         self.needs_update = true;
+        self.registry_config = None;
     }
 
     fn set_quiet(&mut self, quiet: bool) {
@@ -334,6 +440,96 @@ impl<'cfg> RegistryData for RemoteRegistry<'cfg> {
         self.is_updated()
     }
 
+    fn is_index_ready(&self) -> bool {
+        // Opening the repository directly instead of through `repo` avoids
+        // initializing one if it's missing.
+        let Ok(repo) = git2::Repository::open(self.index_path.as_path_unlocked()) else {
+            return false;
+        };
+        let has_config = || -> CargoResult<bool> {
+            let oid = self.index_git_ref.resolve(&repo)?;
+            let tree = repo.find_commit(oid)?.tree()?;
+            let has_config = tree.get_name("config.json").is_some();
+            Ok(has_config)
+        };
+        has_config().unwrap_or(false)
+    }
+
+    fn index_revision(&self) -> Option<InternedString> {
+        // While an update is pending, or may be asked for by the first load
+        // checking the age of the index, the commit summaries will be read
+        // from isn't known yet.
+        if self.needs_update || (!self.index_age_checked && !self.is_updated()) {
+            return None;
+        }
+        self.current_version()
+    }
+
+    fn version_introduced(&self, path: &Path, version: &Version) -> CargoResult<Option<String>> {
+        let repo = self.repo()?;
+        let mut walk = repo.revwalk()?;
+        walk.push(self.head()?)?;
+        walk.simplify_first_parent()?;
+        // Going back in time, the version was added by the oldest commit
+        // of the first run of commits that have it.
+        let mut introduced = None;
+        let mut last: Option<(Option<git2::Oid>, bool)> = None;
+        for oid in walk {
+            let oid = oid?;
+            let blob = repo
+                .find_commit(oid)?
+                .tree()?
+                .get_path(path)
+                .ok()
+                .map(|e| e.id());
+            let has_version = match last {
+                Some((last_blob, has_version)) if last_blob == blob => has_version,
+                _ => match blob {
+                    Some(blob) => has_version(repo.find_blob(blob)?.content(), version),
+                    None => false,
+                },
+            };
+            last = Some((blob, has_version));
+            if has_version {
+                introduced = Some(oid);
+            } else if introduced.is_some() {
+                break;
+            }
+        }
+        Ok(introduced.map(|oid| oid.to_string()))
+    }
+
+    fn yank_changes(&self) -> CargoResult<Vec<YankChange>> {
+        let Some(from) = self.updated_from else {
+            return Ok(Vec::new());
+        };
+        let repo = self.repo()?;
+        let old = repo.find_commit(from)?.tree()?;
+        let new = repo.find_commit(self.head()?)?.tree()?;
+        let diff = repo.diff_tree_to_tree(Some(&old), Some(&new), None)?;
+        let mut changes = Vec::new();
+        for delta in diff.deltas() {
+            // Only files that are in both commits can have versions whose
+            // yanked state changed.
+            if delta.status() != git2::Delta::Modified {
+                continue;
+            }
+            let before = yanked_states(repo.find_blob(delta.old_file().id())?.content());
+            let after = yanked_states(repo.find_blob(delta.new_file().id())?.content());
+            for ((name, version), yanked) in after {
+                if before.get(&(name.clone(), version.clone())) == Some(&!yanked) {
+                    changes.push(YankChange {
+                        name,
+                        version,
+                        yanked,
+                    });
+                }
+            }
+        }
+        changes.sort();
+        Ok(changes)
+    }
+
     fn download(&mut self, pkg: PackageId, checksum: &str) -> CargoResult<MaybeLock> {
         let registry_config = loop {
             match self.config()? {
@@ -365,6 +561,37 @@ impl<'cfg> RegistryData for RemoteRegistry<'cfg> {
     }
 }
 
+/// Whether the index file `data` of a package has a line for `version`.
+fn has_version(data: &[u8], version: &Version) -> bool {
+    #[derive(serde::Deserialize)]
+    struct Entry<'a> {
+        #[serde(borrow)]
+        vers: std::borrow::Cow<'a, str>,
+    }
+    data.split(|&b| b == b'\n').any(|line| {
+        serde_json::from_slice::<Entry<'_>>(line)
+            .ok()
+            .and_then(|entry| index::normalize_version(&entry.vers).ok())
+            .map_or(false, |vers| vers == *version)
+    })
+}
+
+/// Whether each version in the index file `data` is yanked, keyed by the
+/// name and version of its entry.
+fn yanked_states(data: &[u8]) -> BTreeMap<(String, String), bool> {
+    #[derive(serde::Deserialize)]
+    struct Entry {
+        name: String,
+        vers: String,
+        #[serde(default)]
+        yanked: Option<bool>,
+    }
+    data.split(|&b| b == b'\n')
+        .filter_map(|line| serde_json::from_slice::<Entry>(line).ok())
+        .map(|entry| ((entry.name, entry.vers), entry.yanked.unwrap_or(false)))
+        .collect()
+}
+
 impl<'cfg> Drop for RemoteRegistry<'cfg> {
     fn drop(&mut self) {
         // Just be sure to drop this before our other fields
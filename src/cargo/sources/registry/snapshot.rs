@@ -0,0 +1,151 @@
+use crate::core::PackageId;
+use crate::sources::registry::download;
+use crate::sources::registry::{LoadResponse, MaybeLock, RegistryConfig, RegistryData};
+use crate::util::errors::CargoResult;
+use crate::util::{Config, Filesystem};
+use anyhow::Context as _;
+use cargo_util::paths;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::task::Poll;
+
+/// A remote registry whose index is read from a snapshot directory instead of
+/// being fetched, set with `registries.<name>.index-snapshot`.
+///
+/// The snapshot is a plain copy of the files of the index, including its
+/// `config.json`. It is never updated, and neither git nor the network are
+/// used to read it. `.crate` files are still downloaded from the `dl` URL of
+/// the snapshot's `config.json` and cached like for any remote registry, so
+/// pointing `dl` at a `file://` URL makes builds fully hermetic.
+pub struct SnapshotRegistry<'cfg> {
+    index_path: Filesystem,
+    /// Path to the cache of `.crate` files (`$CARGO_HOME/registry/cache/$REG-HASH`).
+    cache_path: Filesystem,
+    config: &'cfg Config,
+    updated: bool,
+}
+
+impl<'cfg> SnapshotRegistry<'cfg> {
+    pub fn new(snapshot: &Path, config: &'cfg Config, name: &str) -> SnapshotRegistry<'cfg> {
+        SnapshotRegistry {
+            index_path: Filesystem::new(snapshot.to_path_buf()),
+            cache_path: config.registry_cache_path().join(name),
+            config,
+            updated: false,
+        }
+    }
+
+    /// Reads the `config.json` of the snapshot.
+    fn registry_config(&self) -> CargoResult<RegistryConfig> {
+        let path = self.index_path.as_path_unlocked().join("config.json");
+        let raw_data = paths::read_bytes(&path)?;
+        let mut cfg: RegistryConfig = serde_json::from_slice(&raw_data)
+            .with_context(|| format!("failed to parse `{}`", path.display()))?;
+        cfg.gate_auth_required(self.config);
+        Ok(cfg)
+    }
+}
+
+impl<'cfg> RegistryData for SnapshotRegistry<'cfg> {
+    fn prepare(&self) -> CargoResult<()> {
+        Ok(())
+    }
+
+    fn index_path(&self) -> &Filesystem {
+        &self.index_path
+    }
+
+    fn assert_index_locked<'a>(&self, path: &'a Filesystem) -> &'a Path {
+        // The snapshot is never modified by Cargo, so there's nothing to
+        // synchronize.
+        path.as_path_unlocked()
+    }
+
+    fn load(
+        &mut self,
+        root: &Path,
+        path: &Path,
+        _index_version: Option<&str>,
+    ) -> Poll<CargoResult<LoadResponse>> {
+        if !self.updated {
+            return Poll::Pending;
+        }
+        let raw_data = match paths::read_bytes(&root.join(path)) {
+            Err(e)
+                if e.downcast_ref::<io::Error>()
+                    .map_or(false, |ioe| ioe.kind() == io::ErrorKind::NotFound) =>
+            {
+                return Poll::Ready(Ok(LoadResponse::NotFound));
+            }
+            r => r,
+        }?;
+        // Without an index version nothing is written to the `.cache`
+        // directory, keeping the snapshot untouched.
+        Poll::Ready(Ok(LoadResponse::Data {
+            raw_data,
+            index_version: None,
+        }))
+    }
+
+    fn config(&mut self) -> Poll<CargoResult<Option<RegistryConfig>>> {
+        Poll::Ready(self.registry_config().map(Some))
+    }
+
+    fn block_until_ready(&mut self) -> CargoResult<()> {
+        if self.updated {
+            return Ok(());
+        }
+        // There is nothing to update, just check that the snapshot is there.
+        let index_path = self.index_path.as_path_unlocked();
+        if !index_path.is_dir() {
+            anyhow::bail!(
+                "index snapshot path is not a directory: {}",
+                index_path.display()
+            );
+        }
+        self.updated = true;
+        Ok(())
+    }
+
+    fn invalidate_cache(&mut self) {
+        // A snapshot never changes, so there is nothing to refresh.
+    }
+
+    fn set_quiet(&mut self, _quiet: bool) {}
+
+    fn is_updated(&self) -> bool {
+        self.updated
+    }
+
+    fn is_index_ready(&self) -> bool {
+        self.index_path
+            .as_path_unlocked()
+            .join("config.json")
+            .is_file()
+    }
+
+    fn download(&mut self, pkg: PackageId, checksum: &str) -> CargoResult<MaybeLock> {
+        let registry_config = self.registry_config()?;
+        download::download(
+            &self.cache_path,
+            &self.config,
+            pkg,
+            checksum,
+            registry_config,
+        )
+    }
+
+    fn finish_download(
+        &mut self,
+        pkg: PackageId,
+        checksum: &str,
+        data: &[u8],
+    ) -> CargoResult<File> {
+        download::finish_download(&self.cache_path, &self.config, pkg, checksum, data)
+    }
+
+    fn is_crate_downloaded(&self, pkg: PackageId) -> bool {
+        download::is_crate_downloaded(&self.cache_path, &self.config, pkg)
+    }
+}
@@ -105,6 +105,11 @@ impl<'cfg> Source for ReplacedSource<'cfg> {
         Ok(pkg.map_source(self.replace_with, self.to_replace))
     }
 
+    fn download_failed(&mut self, id: PackageId, err: &anyhow::Error) -> CargoResult<bool> {
+        let id = id.with_source_id(self.replace_with);
+        self.inner.download_failed(id, err)
+    }
+
     fn fingerprint(&self, id: &Package) -> CargoResult<String> {
         self.inner.fingerprint(id)
     }
@@ -2,12 +2,12 @@
 
 use crate::util::{config, config::ConfigKey, CanonicalUrl, CargoResult, Config, IntoUrl};
 use anyhow::{bail, format_err, Context as _};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
 use cargo_util::ProcessError;
 use core::fmt;
 use pasetors::keys::{AsymmetricPublicKey, AsymmetricSecretKey};
 use pasetors::paserk::FormatAsPaserk;
-use serde::Deserialize;
-use std::collections::HashMap;
 use std::error::Error;
 use std::io::{Read, Write};
 use std::ops::Deref;
@@ -118,25 +118,103 @@ impl<T> fmt::Debug for Secret<T> {
     }
 }
 
+/// The `[registry]` table and the `[registries.<name>]` tables, as far as
+/// authentication is concerned.
+struct RegistryConfig {
+    token: Option<String>,
+    credential_process: Option<config::PathAndArgs>,
+    secret_key: Option<String>,
+    secret_key_subject: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    session_login: Option<bool>,
+    session_process: Option<config::PathAndArgs>,
+}
+
+impl RegistryConfig {
+    /// Reads the authentication keys of the table at `key`.
+    ///
+    /// The keys are looked up one by one instead of deserializing the whole
+    /// table, whose other keys are read elsewhere and would be reported as
+    /// unused here.
+    fn get(config: &Config, key: &str) -> CargoResult<RegistryConfig> {
+        let field = |name: &str| format!("{key}.{name}");
+        Ok(RegistryConfig {
+            token: config.get(&field("token"))?,
+            credential_process: config.get(&field("credential-process"))?,
+            secret_key: config.get(&field("secret-key"))?,
+            secret_key_subject: config.get(&field("secret-key-subject"))?,
+            username: config.get(&field("username"))?,
+            password: config.get(&field("password"))?,
+            session_login: config.get(&field("session-login"))?,
+            session_process: config.get(&field("session-process"))?,
+        })
+    }
+}
+
+/// Finds the name of the alternative registry of a `SourceId`, by looking up
+/// its index URL in the configuration.
+pub fn registry_name(config: &Config, sid: &SourceId) -> CargoResult<Option<String>> {
+    // Find the SourceId's name by its index URL. If environment variables
+    // are available they will be preferred over configuration values.
+    //
+    // The fundamental problem is that we only know the index url of the registry
+    // for certain. For example, an unnamed registry source can come from the `--index`
+    // command line argument, or from a Cargo.lock file. For this reason, we always
+    // attempt to discover the name by looking it up by the index URL.
+    //
+    // This also allows the authorization token for a registry to be set
+    // without knowing the registry name by using the _INDEX and _TOKEN
+    // environment variables.
+    //
+    // Discover names from environment variables.
+    let index = sid.canonical_url();
+    let mut names: Vec<_> = config
+        .env()
+        .filter_map(|(k, v)| {
+            Some((
+                k.strip_prefix("CARGO_REGISTRIES_")?
+                    .strip_suffix("_INDEX")?,
+                v,
+            ))
+        })
+        .filter_map(|(k, v)| Some((k, CanonicalUrl::new(&v.into_url().ok()?).ok()?)))
+        .filter(|(_, v)| v == index)
+        .map(|(k, _)| k.to_lowercase())
+        .collect();
+
+    // Discover names from the configuration only if none were found in the environment.
+    if names.len() == 0 {
+        if let Some(registries) = config.values()?.get("registries") {
+            let (registries, _) = registries.table("registries")?;
+            for (name, value) in registries {
+                let key = format!("registries.{name}");
+                if let Some(v) = value.table(&key)?.0.get("index") {
+                    let (v, _) = v.string(&format!("{key}.index"))?;
+                    if index == &CanonicalUrl::new(&v.into_url()?)? {
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+    }
+    names.sort();
+    Ok(match names.len() {
+        0 => None,
+        1 => Some(std::mem::take(&mut names[0])),
+        _ => anyhow::bail!(
+            "multiple registries are configured with the same index url '{}': {}",
+            &sid.as_url(),
+            names.join(", ")
+        ),
+    })
+}
+
 /// Get the credential configuration for a `SourceId`.
 pub fn registry_credential_config(
     config: &Config,
     sid: &SourceId,
 ) -> CargoResult<RegistryCredentialConfig> {
-    #[derive(Deserialize)]
-    #[serde(rename_all = "kebab-case")]
-    struct RegistryConfig {
-        index: Option<String>,
-        token: Option<String>,
-        credential_process: Option<config::PathAndArgs>,
-        secret_key: Option<String>,
-        secret_key_subject: Option<String>,
-        #[serde(rename = "default")]
-        _default: Option<String>,
-        #[serde(rename = "protocol")]
-        _protocol: Option<String>,
-    }
-
     log::trace!("loading credential config for {}", sid);
     config.load_credentials()?;
     if !sid.is_remote_registry() {
@@ -156,7 +234,7 @@ pub fn registry_credential_config(
             secret_key,
             secret_key_subject,
             ..
-        } = config.get::<RegistryConfig>("registry")?;
+        } = RegistryConfig::get(config, "registry")?;
         return registry_credential_config_inner(
             true,
             None,
@@ -168,56 +246,7 @@ pub fn registry_credential_config(
         );
     }
 
-    // Find the SourceId's name by its index URL. If environment variables
-    // are available they will be preferred over configuration values.
-    //
-    // The fundamental problem is that we only know the index url of the registry
-    // for certain. For example, an unnamed registry source can come from the `--index`
-    // command line argument, or from a Cargo.lock file. For this reason, we always
-    // attempt to discover the name by looking it up by the index URL.
-    //
-    // This also allows the authorization token for a registry to be set
-    // without knowing the registry name by using the _INDEX and _TOKEN
-    // environment variables.
-    let name = {
-        // Discover names from environment variables.
-        let index = sid.canonical_url();
-        let mut names: Vec<_> = config
-            .env()
-            .filter_map(|(k, v)| {
-                Some((
-                    k.strip_prefix("CARGO_REGISTRIES_")?
-                        .strip_suffix("_INDEX")?,
-                    v,
-                ))
-            })
-            .filter_map(|(k, v)| Some((k, CanonicalUrl::new(&v.into_url().ok()?).ok()?)))
-            .filter(|(_, v)| v == index)
-            .map(|(k, _)| k.to_lowercase())
-            .collect();
-
-        // Discover names from the configuration only if none were found in the environment.
-        if names.len() == 0 {
-            names = config
-                .get::<HashMap<String, RegistryConfig>>("registries")?
-                .iter()
-                .filter_map(|(k, v)| Some((k, v.index.as_deref()?)))
-                .filter_map(|(k, v)| Some((k, CanonicalUrl::new(&v.into_url().ok()?).ok()?)))
-                .filter(|(_, v)| v == index)
-                .map(|(k, _)| k.to_string())
-                .collect();
-        }
-        names.sort();
-        match names.len() {
-            0 => None,
-            1 => Some(std::mem::take(&mut names[0])),
-            _ => anyhow::bail!(
-                "multiple registries are configured with the same index url '{}': {}",
-                &sid.as_url(),
-                names.join(", ")
-            ),
-        }
-    };
+    let name = registry_name(config, sid)?;
 
     // It's possible to have a registry configured in a Cargo config file,
     // then override it with configuration from environment variables.
@@ -240,7 +269,7 @@ pub fn registry_credential_config(
             secret_key_subject,
             credential_process,
             ..
-        } = config.get::<RegistryConfig>(&format!("registries.{name}"))?;
+        } = RegistryConfig::get(config, &format!("registries.{name}"))?;
         (token, credential_process, secret_key, secret_key_subject)
     } else {
         log::debug!("no registry name found for {sid}");
@@ -415,6 +444,279 @@ pub fn cache_token(config: &Config, sid: &SourceId, token: Secret<&str>) {
     );
 }
 
+/// Returns the value of an `Authorization` header for HTTP basic auth, if
+/// `username` and `password` are configured for the registry of `sid`.
+///
+/// This is used for downloading crates from simple registries that protect
+/// their download endpoint with basic auth rather than a token.
+pub fn basic_auth(config: &Config, sid: &SourceId) -> CargoResult<Option<Secret<String>>> {
+    let Some((username, password)) = basic_auth_credentials(config, sid)? else {
+        return Ok(None);
+    };
+    let credentials = STANDARD.encode(format!("{username}:{}", password.expose()));
+    Ok(Some(Secret::from(format!("Basic {credentials}"))))
+}
+
+/// Returns the `username` and `password` configured for HTTP basic auth with
+/// the registry of `sid`, if any.
+///
+/// Besides downloads, these are used to fetch the git index of the registry.
+pub fn basic_auth_credentials(
+    config: &Config,
+    sid: &SourceId,
+) -> CargoResult<Option<(String, Secret<String>)>> {
+    if sid.is_crates_io() {
+        return Ok(None);
+    }
+    config.load_credentials()?;
+    // Few registries use basic auth, so don't even look for the name of this
+    // one, which may be ambiguous, unless some registry is configured for it.
+    if !registries_key_set(config, &["username", "password"])? {
+        return Ok(None);
+    }
+    let Some(name) = registry_name(config, sid)? else {
+        return Ok(None);
+    };
+    let RegistryConfig {
+        username, password, ..
+    } = RegistryConfig::get(config, &format!("registries.{name}"))?;
+    match (username, password) {
+        (Some(username), Some(password)) => Ok(Some((username, Secret::from(password)))),
+        (None, None) => Ok(None),
+        _ => bail!(
+            "both `username` and `password` must be set to use HTTP basic auth \
+             for registry `{name}`"
+        ),
+    }
+}
+
+/// Whether any of `keys` is set in the `[registries.<name>]` table of any
+/// registry.
+///
+/// Looking up the name of a registry fails if several registries share its
+/// index, so this is checked first for keys few registries set.
+pub(crate) fn registries_key_set(config: &Config, keys: &[&str]) -> CargoResult<bool> {
+    let env_suffixes: Vec<_> = keys
+        .iter()
+        .map(|key| format!("_{}", key.to_uppercase().replace('-', "_")))
+        .collect();
+    let in_env = config.env().any(|(k, _)| {
+        k.strip_prefix("CARGO_REGISTRIES_")
+            .map_or(false, |k| env_suffixes.iter().any(|s| k.ends_with(s)))
+    });
+    if in_env {
+        return Ok(true);
+    }
+    let Some(registries) = config.values()?.get("registries") else {
+        return Ok(false);
+    };
+    let (registries, _) = registries.table("registries")?;
+    for (name, value) in registries {
+        let (table, _) = value.table(&format!("registries.{name}"))?;
+        if keys.iter().any(|key| table.contains_key(*key)) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// A way to log in to a registry that authenticates downloads with a
+/// short-lived session cookie rather than a token.
+pub trait SessionLogin {
+    /// Logs in to the registry of `sid`, whose API is at `api`, and returns
+    /// the cookie to send with downloads, like `session=0123`.
+    fn login(
+        &self,
+        config: &Config,
+        sid: &SourceId,
+        api: Option<&str>,
+    ) -> CargoResult<Secret<String>>;
+}
+
+/// Logs in with `registries.<name>.session-login`, by sending the HTTP basic
+/// auth credentials of the registry to `{api}/login` and keeping the cookies
+/// it sets.
+struct HttpSessionLogin;
+
+impl SessionLogin for HttpSessionLogin {
+    fn login(
+        &self,
+        config: &Config,
+        sid: &SourceId,
+        api: Option<&str>,
+    ) -> CargoResult<Secret<String>> {
+        let name = sid.display_registry_name();
+        let Some(api) = api else {
+            bail!(
+                "registry `{name}` has no `api` in its `config.json`, \
+                 which is needed to log in to a session"
+            );
+        };
+        let Some(authorization) = basic_auth(config, sid)? else {
+            bail!(
+                "`registries.{name}.username` and `registries.{name}.password` \
+                 must be set to log in to a session"
+            );
+        };
+        let url = format!("{}/login", api.trim_end_matches('/'));
+        let mut handle = crate::ops::http_handle(config)?;
+        handle.post(true)?;
+        handle.url(&url)?;
+        handle.post_fields_copy(&[])?;
+        let mut headers = curl::easy::List::new();
+        headers.append(&format!("Authorization: {}", authorization.expose()))?;
+        handle.http_headers(headers)?;
+        let mut body = Vec::new();
+        let mut response_headers = Vec::new();
+        {
+            let mut transfer = handle.transfer();
+            transfer.write_function(|buf| {
+                body.extend_from_slice(buf);
+                Ok(buf.len())
+            })?;
+            transfer.header_function(|header| {
+                if let Ok(header) = std::str::from_utf8(header) {
+                    response_headers.push(header.trim().to_string());
+                }
+                true
+            })?;
+            transfer
+                .perform()
+                .with_context(|| format!("failed to log in to registry `{name}`"))?;
+        }
+        let code = handle.response_code()?;
+        if !(200..300).contains(&code) {
+            let e = crate::util::errors::HttpNotSuccessful::new_from_handle(
+                &mut handle,
+                &url,
+                body,
+                response_headers,
+            );
+            return Err(
+                anyhow::Error::from(e).context(format!("failed to log in to registry `{name}`"))
+            );
+        }
+        let cookies = response_headers
+            .iter()
+            .filter_map(|header| header.split_once(':'))
+            .filter(|(key, _)| key.trim().eq_ignore_ascii_case("set-cookie"))
+            .map(|(_, value)| value.split(';').next().unwrap().trim())
+            .filter(|cookie| !cookie.is_empty())
+            .collect::<Vec<_>>();
+        if cookies.is_empty() {
+            bail!("logging in to registry `{name}` at `{url}` set no session cookie");
+        }
+        Ok(Secret::from(cookies.join("; ")))
+    }
+}
+
+/// Logs in with `registries.<name>.session-process`, a program that prints
+/// the session cookie.
+///
+/// The program gets the same environment as a credential process, plus
+/// `CARGO_REGISTRY_API_URL` if the registry has an API.
+struct ProcessSessionLogin {
+    process: config::PathAndArgs,
+}
+
+impl SessionLogin for ProcessSessionLogin {
+    fn login(
+        &self,
+        config: &Config,
+        sid: &SourceId,
+        api: Option<&str>,
+    ) -> CargoResult<Secret<String>> {
+        let name = sid.display_registry_name();
+        let mut process =
+            cargo_util::ProcessBuilder::new(self.process.path.resolve_program(config));
+        process
+            .args(&self.process.args)
+            .env(crate::CARGO_ENV, config.cargo_exe()?)
+            .env("CARGO_REGISTRY_INDEX_URL", sid.url().as_str());
+        if let Some(key) = sid.alt_registry_key() {
+            process.env("CARGO_REGISTRY_NAME_OPT", key);
+        }
+        if let Some(api) = api {
+            process.env("CARGO_REGISTRY_API_URL", api);
+        }
+        let output = process
+            .exec_with_output()
+            .with_context(|| format!("failed to log in to registry `{name}`"))?;
+        let cookie = String::from_utf8(output.stdout)
+            .with_context(|| format!("session cookie of registry `{name}` is not UTF-8"))?;
+        let cookie = cookie.trim();
+        if cookie.is_empty() {
+            bail!(
+                "`{}` printed no session cookie for registry `{name}`",
+                self.process.path.raw_value()
+            );
+        }
+        Ok(Secret::from(cookie.to_string()))
+    }
+}
+
+/// The way to log in to a session for the registry of `sid`, if it uses
+/// sessions.
+fn session_login(config: &Config, sid: &SourceId) -> CargoResult<Option<Box<dyn SessionLogin>>> {
+    if sid.is_crates_io() || !registries_key_set(config, &["session-login", "session-process"])? {
+        return Ok(None);
+    }
+    let Some(name) = registry_name(config, sid)? else {
+        return Ok(None);
+    };
+    let RegistryConfig {
+        session_login,
+        session_process,
+        ..
+    } = RegistryConfig::get(config, &format!("registries.{name}"))?;
+    match (session_login.unwrap_or(false), session_process) {
+        (true, Some(_)) => bail!(
+            "only one of `registries.{name}.session-login` and \
+             `registries.{name}.session-process` may be set"
+        ),
+        (true, None) => Ok(Some(Box::new(HttpSessionLogin))),
+        (false, Some(process)) => Ok(Some(Box::new(ProcessSessionLogin { process }))),
+        (false, None) => Ok(None),
+    }
+}
+
+/// Returns the session cookie to send with downloads from the registry of
+/// `sid`, whose API is at `api`, logging in first if there's no session yet.
+///
+/// Sessions are only kept in memory, for as long as Cargo runs. Returns
+/// `None` if the registry doesn't use sessions.
+pub fn session_cookie(
+    config: &Config,
+    sid: &SourceId,
+    api: Option<&str>,
+) -> CargoResult<Option<Secret<String>>> {
+    let url = sid.canonical_url();
+    if let Some(cookie) = config.registry_sessions().get(url) {
+        return Ok(Some(cookie.clone()));
+    }
+    let Some(login) = session_login(config, sid)? else {
+        return Ok(None);
+    };
+    config.load_credentials()?;
+    let cookie = login.login(config, sid, api)?;
+    config
+        .registry_sessions()
+        .insert(url.clone(), cookie.clone());
+    Ok(Some(cookie))
+}
+
+/// Forgets the session of the registry of `sid` that `cookie` belongs to,
+/// such as after it expired, so that the next download logs in again.
+///
+/// A session that replaced it in the meantime is kept.
+pub fn clear_session_cookie(config: &Config, sid: &SourceId, cookie: &str) {
+    let mut sessions = config.registry_sessions();
+    let url = sid.canonical_url();
+    if sessions.get(url).map(|c| c.as_deref().expose()) == Some(cookie) {
+        sessions.remove(url);
+    }
+}
+
 /// Returns the token to use for the given registry.
 /// If a `login_url` is provided and a token is not available, the
 /// login_url will be included in the returned error.
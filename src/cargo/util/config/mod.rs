@@ -50,7 +50,7 @@
 //! desired type.
 
 use std::borrow::Cow;
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::{HashMap, HashSet};
 use std::env;
@@ -72,6 +72,7 @@ use crate::core::{features, CliUnstable, Shell, SourceId, Workspace, WorkspaceRo
 use crate::ops::{self, RegistryCredentialConfig};
 use crate::util::auth::Secret;
 use crate::util::errors::CargoResult;
+use crate::util::network::retry::RetryBackoff;
 use crate::util::CanonicalUrl;
 use crate::util::{internal, toml as cargo_toml};
 use crate::util::{try_canonicalize, validate_package_name};
@@ -209,9 +210,15 @@ pub struct Config {
     /// Cache of credentials from configuration or credential providers.
     /// Maps from url to credential value.
     credential_cache: LazyCell<RefCell<HashMap<CanonicalUrl, CredentialCacheValue>>>,
+    /// Session cookies of registries that authenticate downloads with a
+    /// session, keyed by their url. These are never written to disk.
+    registry_sessions: LazyCell<RefCell<HashMap<CanonicalUrl, Secret<String>>>>,
     /// Lock, if held, of the global package cache along with the number of
     /// acquisitions so far.
     package_cache_lock: RefCell<Option<(Option<FileLock>, usize)>>,
+    /// Number of network retries taken so far, counted against
+    /// `registry.retry-budget`.
+    network_retries: Cell<u64>,
     /// Cached configuration parsed by Cargo
     http_config: LazyCell<CargoHttpConfig>,
     future_incompat_config: LazyCell<CargoFutureIncompatConfig>,
@@ -300,7 +307,9 @@ impl Config {
             env,
             updated_sources: LazyCell::new(),
             credential_cache: LazyCell::new(),
+            registry_sessions: LazyCell::new(),
             package_cache_lock: RefCell::new(None),
+            network_retries: Cell::new(0),
             http_config: LazyCell::new(),
             future_incompat_config: LazyCell::new(),
             net_config: LazyCell::new(),
@@ -489,6 +498,13 @@ impl Config {
             .borrow_mut()
     }
 
+    /// Session cookies of registries that were logged in to.
+    pub fn registry_sessions(&self) -> RefMut<'_, HashMap<CanonicalUrl, Secret<String>>> {
+        self.registry_sessions
+            .borrow_with(|| RefCell::new(HashMap::new()))
+            .borrow_mut()
+    }
+
     /// Gets all config values from disk.
     ///
     /// This will lazy-load the values as necessary. Callers are responsible
@@ -1731,6 +1747,12 @@ impl Config {
             .try_borrow_with(|| self.get::<CargoNetConfig>("net"))
     }
 
+    /// Number of network retries taken so far in this session, shared by
+    /// all [`Retry`](crate::util::network::retry::Retry) instances.
+    pub fn network_retries(&self) -> &Cell<u64> {
+        &self.network_retries
+    }
+
     pub fn build_config(&self) -> CargoResult<&CargoBuildConfig> {
         self.build_config
             .try_borrow_with(|| self.get::<CargoBuildConfig>("build"))
@@ -2375,6 +2397,8 @@ pub struct CargoHttpConfig {
     pub low_speed_limit: Option<u32>,
     pub timeout: Option<u64>,
     pub cainfo: Option<ConfigRelativePath>,
+    pub ssl_cert: Option<ConfigRelativePath>,
+    pub ssl_key: Option<ConfigRelativePath>,
     pub check_revoke: Option<bool>,
     pub user_agent: Option<String>,
     pub debug: Option<bool>,
@@ -2438,6 +2462,8 @@ pub struct SslVersionConfigRange {
 #[serde(rename_all = "kebab-case")]
 pub struct CargoNetConfig {
     pub retry: Option<u32>,
+    pub retry_backoff: Option<RetryBackoff>,
+    pub dns_retry_delay: Option<String>,
     pub offline: Option<bool>,
     pub git_fetch_with_cli: Option<bool>,
     pub ssh: Option<CargoSshConfig>,
@@ -100,6 +100,52 @@ impl fmt::Display for HttpNotSuccessful {
 
 impl std::error::Error for HttpNotSuccessful {}
 
+/// Error from [`Source::finish_download`] for downloaded data that the source
+/// rejected, but wants downloaded again, possibly from another URL the next
+/// [`Source::download`] returns.
+///
+/// [`Source::finish_download`]: crate::core::Source::finish_download
+/// [`Source::download`]: crate::core::Source::download
+#[derive(Debug)]
+pub struct DownloadRetry {
+    /// Why the download is retried, shown as a warning.
+    pub reason: String,
+}
+
+impl fmt::Display for DownloadRetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for DownloadRetry {}
+
+/// A successful download whose body is an HTML page, such as the error or
+/// captcha page of a CDN or proxy, instead of a `.crate` file.
+#[derive(Debug)]
+pub struct NotATarball {
+    pub url: String,
+    /// The `Content-Type` the server sent, if any.
+    pub content_type: Option<String>,
+    /// Whether the download is retried, as set with
+    /// `registry.non-tarball-downloads`.
+    pub spurious: bool,
+}
+
+impl fmt::Display for NotATarball {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "expected a `.crate` file from `{}`, but the server returned an HTML page \
+             (Content-Type: {}), likely an error page",
+            self.url,
+            self.content_type.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+impl std::error::Error for NotATarball {}
+
 // =============================================================================
 // Verbose error
 
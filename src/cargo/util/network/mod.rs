@@ -2,6 +2,8 @@
 
 use std::task::Poll;
 
+use crate::util::Config;
+
 pub mod retry;
 pub mod sleep;
 
@@ -19,6 +21,31 @@ impl<T> PollExt<T> for Poll<T> {
     }
 }
 
+/// Adds to a TLS error from curl whether the client or the server certificate
+/// is at fault, so that a problem with the client certificate set with
+/// `http.ssl-cert` isn't mistaken for one with the server.
+pub fn with_tls_context(err: curl::Error, config: &Config) -> anyhow::Error {
+    let client_cert = config
+        .http_config()
+        .map_or(false, |http| http.ssl_cert.is_some());
+    let msg = if err.is_ssl_certproblem() {
+        "the client certificate or its key could not be used, \
+         check `http.ssl-cert`, `http.ssl-key`, and the key's password"
+    } else if err.is_peer_failed_verification()
+        || err.is_ssl_cacert()
+        || err.is_ssl_cacert_badfile()
+        || err.is_ssl_issuer_error()
+    {
+        "the certificate of the server could not be verified"
+    } else if err.is_ssl_connect_error() && client_cert {
+        "the TLS handshake failed, the server may have rejected the client \
+         certificate set with `http.ssl-cert`"
+    } else {
+        return err.into();
+    };
+    anyhow::Error::from(err).context(msg)
+}
+
 // When dynamically linked against libcurl, we want to ignore some failures
 // when using old versions that don't support certain features.
 #[macro_export]
@@ -1,9 +1,10 @@
 //! Utilities for retrying a network operation.
 
-use crate::util::errors::HttpNotSuccessful;
+use crate::util::errors::{HttpNotSuccessful, NotATarball};
 use crate::{CargoResult, Config};
-use anyhow::Error;
+use anyhow::{Context as _, Error};
 use rand::Rng;
+use serde::Deserialize;
 use std::cmp::min;
 use std::time::Duration;
 
@@ -11,6 +12,20 @@ pub struct Retry<'a> {
     config: &'a Config,
     retries: u64,
     max_retries: u64,
+    backoff: RetryBackoff,
+    /// The delay before retrying a DNS resolution failure, from
+    /// `net.dns-retry-delay`.
+    dns_delay_ms: u64,
+}
+
+/// How the delay between retries grows, set with `net.retry-backoff`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryBackoff {
+    /// By 3 seconds per retry.
+    Linear,
+    /// Doubling with each retry.
+    Exponential,
 }
 
 pub enum RetryResult<T> {
@@ -25,6 +40,9 @@ const MAX_RETRY_SLEEP_MS: u64 = 10 * 1000;
 ///
 /// The actual amount of time will be a random value above this.
 const INITIAL_RETRY_SLEEP_BASE_MS: u64 = 500;
+/// The default delay before retrying a failure to resolve a host name
+/// (milliseconds).
+const DEFAULT_DNS_RETRY_SLEEP_MS: u64 = 1000;
 /// The maximum amount of additional time the initial retry will take (milliseconds).
 ///
 /// The initial delay will be [`INITIAL_RETRY_SLEEP_BASE_MS`] plus a random range
@@ -33,36 +51,93 @@ const INITIAL_RETRY_JITTER_MS: u64 = 1000;
 
 impl<'a> Retry<'a> {
     pub fn new(config: &'a Config) -> CargoResult<Retry<'a>> {
+        let net_config = config.net_config()?;
+        let dns_delay_ms = match &net_config.dns_retry_delay {
+            Some(delay) => humantime::parse_duration(delay)
+                .with_context(|| {
+                    format!("failed to parse `net.dns-retry-delay` value `{}`", delay)
+                })?
+                .as_millis() as u64,
+            None => DEFAULT_DNS_RETRY_SLEEP_MS,
+        };
         Ok(Retry {
             config,
             retries: 0,
-            max_retries: config.net_config()?.retry.unwrap_or(3) as u64,
+            max_retries: net_config.retry.unwrap_or(3) as u64,
+            backoff: net_config.retry_backoff.unwrap_or(RetryBackoff::Linear),
+            dns_delay_ms,
         })
     }
 
+    /// The number of attempts made so far, which is one more than the number
+    /// of retries.
+    pub fn attempts(&self) -> u64 {
+        self.retries + 1
+    }
+
     /// Returns `Ok(None)` for operations that should be re-tried.
     pub fn r#try<T>(&mut self, f: impl FnOnce() -> CargoResult<T>) -> RetryResult<T> {
         match f() {
-            Err(ref e) if maybe_spurious(e) && self.retries < self.max_retries => {
+            Err(e) if maybe_spurious(&e) && self.retries < self.max_retries => {
+                let budget_left = match self.budget_left() {
+                    Ok(budget_left) => budget_left,
+                    Err(e) => return RetryResult::Err(e),
+                };
+                if budget_left == 0 {
+                    let msg = "not retrying spurious network error, \
+                               the `registry.retry-budget` for this session is used up";
+                    if let Err(e) = self.config.shell().warn(msg) {
+                        return RetryResult::Err(e);
+                    }
+                    return RetryResult::Err(with_dns_context(e));
+                }
                 let err_msg = e
                     .downcast_ref::<HttpNotSuccessful>()
                     .map(|http_err| http_err.display_short())
                     .unwrap_or_else(|| e.root_cause().to_string());
                 let msg = format!(
                     "spurious network error ({} tries remaining): {err_msg}",
-                    self.max_retries - self.retries,
+                    min(self.max_retries - self.retries, budget_left),
                 );
                 if let Err(e) = self.config.shell().warn(msg) {
                     return RetryResult::Err(e);
                 }
                 self.retries += 1;
-                RetryResult::Retry(self.next_sleep_ms())
+                let taken = self.config.network_retries();
+                taken.set(taken.get() + 1);
+                // Name resolution often works again within a second or two,
+                // such as right after a container started, so there is no
+                // need to back off as from a struggling server.
+                if is_dns_error(&e) {
+                    RetryResult::Retry(self.dns_sleep_ms())
+                } else {
+                    RetryResult::Retry(self.next_sleep_ms())
+                }
             }
-            Err(e) => RetryResult::Err(e),
+            Err(e) => RetryResult::Err(with_dns_context(e)),
             Ok(r) => RetryResult::Success(r),
         }
     }
 
+    /// Returns how many more retries the session-wide `registry.retry-budget`
+    /// allows, which caps the per-operation `net.retry`.
+    fn budget_left(&self) -> CargoResult<u64> {
+        let budget = self.config.get::<Option<u64>>("registry.retry-budget")?;
+        Ok(match budget {
+            Some(budget) => budget.saturating_sub(self.config.network_retries().get()),
+            None => u64::MAX,
+        })
+    }
+
+    /// Gets the sleep duration before retrying a DNS resolution failure in
+    /// milliseconds.
+    fn dns_sleep_ms(&self) -> u64 {
+        if let Ok(sleep) = self.config.get_env("__CARGO_TEST_FIXED_RETRY_SLEEP_MS") {
+            return sleep.parse().expect("a u64");
+        }
+        self.dns_delay_ms
+    }
+
     /// Gets the next sleep duration in milliseconds.
     fn next_sleep_ms(&self) -> u64 {
         if let Ok(sleep) = self.config.get_env("__CARGO_TEST_FIXED_RETRY_SLEEP_MS") {
@@ -71,16 +146,43 @@ impl<'a> Retry<'a> {
 
         if self.retries == 1 {
             let mut rng = rand::thread_rng();
-            INITIAL_RETRY_SLEEP_BASE_MS + rng.gen_range(0..INITIAL_RETRY_JITTER_MS)
-        } else {
-            min(
+            return INITIAL_RETRY_SLEEP_BASE_MS + rng.gen_range(0..INITIAL_RETRY_JITTER_MS);
+        }
+        match self.backoff {
+            RetryBackoff::Linear => min(
                 ((self.retries - 1) * 3) * 1000 + INITIAL_RETRY_SLEEP_BASE_MS,
                 MAX_RETRY_SLEEP_MS,
-            )
+            ),
+            RetryBackoff::Exponential => min(
+                INITIAL_RETRY_SLEEP_BASE_MS.saturating_mul(1 << self.retries.min(32)),
+                MAX_RETRY_SLEEP_MS,
+            ),
         }
     }
 }
 
+/// Whether `err` is from failing to resolve a host name.
+fn is_dns_error(err: &Error) -> bool {
+    if let Some(curl_err) = err.downcast_ref::<curl::Error>() {
+        return curl_err.is_couldnt_resolve_host() || curl_err.is_couldnt_resolve_proxy();
+    }
+    if let Some(git_err) = err.downcast_ref::<git2::Error>() {
+        return git_err.class() == git2::ErrorClass::Net
+            && git_err.message().starts_with("failed to resolve address");
+    }
+    false
+}
+
+/// Says that DNS resolution failed on top of `err`, if that's what it is,
+/// as the underlying error doesn't always make that clear.
+fn with_dns_context(err: Error) -> Error {
+    if is_dns_error(&err) {
+        err.context("DNS resolution for the host failed")
+    } else {
+        err
+    }
+}
+
 fn maybe_spurious(err: &Error) -> bool {
     if let Some(git_err) = err.downcast_ref::<git2::Error>() {
         match git_err.class() {
@@ -111,6 +213,9 @@ fn maybe_spurious(err: &Error) -> bool {
             return true;
         }
     }
+    if let Some(not_a_tarball) = err.downcast_ref::<NotATarball>() {
+        return not_a_tarball.spurious;
+    }
 
     use gix::protocol::transport::IsSpuriousError;
 
@@ -249,6 +354,105 @@ fn default_retry_schedule() {
     }
 }
 
+#[test]
+fn exponential_retry_schedule() {
+    use crate::core::Shell;
+
+    let spurious = || -> CargoResult<()> {
+        Err(anyhow::Error::from(HttpNotSuccessful {
+            code: 500,
+            url: "Uri".to_string(),
+            ip: None,
+            body: Vec::new(),
+            headers: Vec::new(),
+        }))
+    };
+    let mut config = Config::default().unwrap();
+    config.set_env(
+        [
+            ("CARGO_NET_RETRY".to_string(), "5".to_string()),
+            (
+                "CARGO_NET_RETRY_BACKOFF".to_string(),
+                "exponential".to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    *config.shell() = Shell::from_write(Box::new(Vec::new()));
+    let mut retry = Retry::new(&config).unwrap();
+    assert!(matches!(retry.r#try(|| spurious()), RetryResult::Retry(_)));
+    let mut sleeps = Vec::new();
+    for _ in 0..4 {
+        match retry.r#try(|| spurious()) {
+            RetryResult::Retry(sleep) => sleeps.push(sleep),
+            _ => panic!("unexpected non-retry"),
+        }
+    }
+    assert_eq!(sleeps, [2000, 4000, 8000, MAX_RETRY_SLEEP_MS]);
+    assert_eq!(retry.attempts(), 6);
+}
+
+#[test]
+fn retry_budget_is_shared() {
+    use crate::core::Shell;
+
+    let spurious = || -> CargoResult<()> {
+        Err(anyhow::Error::from(HttpNotSuccessful {
+            code: 500,
+            url: "Uri".to_string(),
+            ip: None,
+            body: Vec::new(),
+            headers: Vec::new(),
+        }))
+    };
+    let mut config = Config::default().unwrap();
+    config.set_env(
+        [("CARGO_REGISTRY_RETRY_BUDGET".to_string(), "2".to_string())]
+            .into_iter()
+            .collect(),
+    );
+    *config.shell() = Shell::from_write(Box::new(Vec::new()));
+    let mut first = Retry::new(&config).unwrap();
+    assert!(matches!(first.r#try(|| spurious()), RetryResult::Retry(_)));
+    let mut second = Retry::new(&config).unwrap();
+    assert!(matches!(second.r#try(|| spurious()), RetryResult::Retry(_)));
+    // Both retries of the budget are used up, even though neither operation
+    // reached its own `net.retry` limit.
+    assert!(matches!(second.r#try(|| spurious()), RetryResult::Err(_)));
+    assert!(matches!(first.r#try(|| spurious()), RetryResult::Err(_)));
+}
+
+#[test]
+fn dns_errors_retry_quickly() {
+    use crate::core::Shell;
+
+    let dns_error = || -> CargoResult<()> {
+        Err(curl::Error::new(curl_sys::CURLE_COULDNT_RESOLVE_HOST).into())
+    };
+    let mut config = Config::default().unwrap();
+    config.set_env(
+        [("CARGO_NET_DNS_RETRY_DELAY".to_string(), "200ms".to_string())]
+            .into_iter()
+            .collect(),
+    );
+    *config.shell() = Shell::from_write(Box::new(Vec::new()));
+    let mut retry = Retry::new(&config).unwrap();
+    for _ in 0..3 {
+        match retry.r#try(|| dns_error()) {
+            RetryResult::Retry(sleep) => assert_eq!(sleep, 200),
+            _ => panic!("unexpected non-retry"),
+        }
+    }
+    match retry.r#try(|| dns_error()) {
+        RetryResult::Err(e) => {
+            assert_eq!(e.to_string(), "DNS resolution for the host failed");
+            assert!(e.downcast_ref::<curl::Error>().is_some());
+        }
+        _ => panic!("unexpected retry"),
+    }
+}
+
 #[test]
 fn curle_http2_stream_is_spurious() {
     let code = curl_sys::CURLE_HTTP2_STREAM;
@@ -182,6 +182,51 @@ fn depend_on_alt_registry_depends_on_crates_io() {
         .run();
 }
 
+#[cargo_test]
+fn depend_on_crates_io_depends_on_alt_registry() {
+    registry::alt_init();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Same name as the package of the other registry, so resolving it
+    // against the wrong one would be noticed.
+    Package::new("baz", "0.0.1").publish();
+    Package::new("baz", "0.0.1").alternative(true).publish();
+    Package::new("bar", "0.0.1")
+        .registry_dep("baz", "0.0.1")
+        .publish();
+
+    // The alternative registry is only known by the URL in the index, not by
+    // its name in the configuration.
+    p.cargo("check")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `dummy-registry` index
+[UPDATING] `[ROOT]/alternative-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] baz v0.0.1 (registry `[ROOT]/alternative-registry`)
+[CHECKING] baz v0.0.1 (registry `[ROOT]/alternative-registry`)
+[CHECKING] bar v0.0.1
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] dev [unoptimized + debuginfo] target(s) in [..]s
+",
+        )
+        .run();
+}
+
 #[cargo_test]
 fn registry_and_path_dep_works() {
     registry::alt_init();
@@ -1506,3 +1551,356 @@ fn publish_with_transitive_dep() {
         .build();
     p2.cargo("publish").run();
 }
+
+#[cargo_test]
+fn index_snapshot() {
+    registry::alt_init();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+
+    // Turn the index into a plain directory, and make sure the git index
+    // isn't around anymore.
+    let snapshot = paths::root().join("snapshot");
+    fs::rename(paths::root().join("alternative-registry"), &snapshot).unwrap();
+    fs::remove_dir_all(snapshot.join(".git")).unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies.bar]
+                version = "0.0.1"
+                registry = "alternative"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            ".cargo/config.toml",
+            r#"
+                [registries.alternative]
+                index-snapshot = "../snapshot"
+            "#,
+        )
+        .build();
+
+    p.cargo("check")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `alternative`)
+[CHECKING] bar v0.0.1 (registry `alternative`)
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] dev [unoptimized + debuginfo] target(s) in [..]s
+",
+        )
+        .run();
+    // Nothing is written into the snapshot.
+    assert!(!snapshot.join(".cache").exists());
+}
+
+#[cargo_test]
+fn index_snapshot_flat_layout() {
+    registry::alt_init();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+    Package::new("quux", "0.0.1").alternative(true).publish();
+
+    // Moves the files of the packages from their shard directories into the
+    // root of the index.
+    let snapshot = paths::root().join("snapshot");
+    fs::rename(paths::root().join("alternative-registry"), &snapshot).unwrap();
+    fs::remove_dir_all(snapshot.join(".git")).unwrap();
+    fs::rename(snapshot.join("3/b/bar"), snapshot.join("bar")).unwrap();
+    fs::rename(snapshot.join("qu/ux/quux"), snapshot.join("quux")).unwrap();
+    fs::remove_dir_all(snapshot.join("3")).unwrap();
+    fs::remove_dir_all(snapshot.join("qu")).unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = { version = "0.0.1", registry = "alternative" }
+                quux = { version = "0.0.1", registry = "alternative" }
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            ".cargo/config.toml",
+            r#"
+                [registries.alternative]
+                index-snapshot = "../snapshot"
+            "#,
+        )
+        .build();
+
+    // The default layout looks for them in the shard directories.
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] no matching package named `[..]` found")
+        .run();
+
+    p.cargo("check")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_INDEX_LAYOUT", "flat")
+        .with_stderr_unordered(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `alternative`)
+[DOWNLOADED] quux v0.0.1 (registry `alternative`)
+[CHECKING] bar v0.0.1 (registry `alternative`)
+[CHECKING] quux v0.0.1 (registry `alternative`)
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] dev [unoptimized + debuginfo] target(s) in [..]s
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn index_branch() {
+    registry::alt_init();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+
+    // Move the commit publishing `bar` to the `stable` branch, so that the
+    // default branch doesn't have it.
+    let repo = git2::Repository::open(paths::root().join("alternative-registry")).unwrap();
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    repo.branch("stable", &head, false).unwrap();
+    let parent = head.parent(0).unwrap();
+    repo.reset(parent.as_object(), git2::ResetType::Hard, None)
+        .unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies.bar]
+                version = "0.0.1"
+                registry = "alternative"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] no matching package named `bar` found")
+        .run();
+
+    // The existing index checkout switches over to the branch.
+    p.cargo("check")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_INDEX_BRANCH", "stable")
+        .with_stderr(
+            "\
+[UPDATING] `alternative` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `alternative`)
+[CHECKING] bar v0.0.1 (registry `alternative`)
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] dev [unoptimized + debuginfo] target(s) in [..]s
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn registry_without_default_features() {
+    registry::alt_init();
+    Package::new("baz", "0.0.1")
+        .alternative(true)
+        .feature("default", &["heavy"])
+        .feature("heavy", &[])
+        .file(
+            "src/lib.rs",
+            r#"#[cfg(feature = "heavy")] compile_error!("heavy is enabled");"#,
+        )
+        .publish();
+    Package::new("bar", "0.0.1")
+        .alternative(true)
+        .registry_dep("baz", "0.0.1")
+        .publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies.bar]
+                version = "0.0.1"
+                registry = "alternative"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains("[..]heavy is enabled[..]")
+        .run();
+
+    p.cargo("check")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_DEFAULT_FEATURES", "false")
+        .with_stderr(
+            "\
+[WARNING] default features of packages from registry `alternative` are never enabled by their dependents in the index, as `registries.alternative.default-features` is false
+[CHECKING] baz v0.0.1 (registry `alternative`)
+[CHECKING] bar v0.0.1 (registry `alternative`)
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] dev [unoptimized + debuginfo] target(s) in [..]s
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn allow_missing_checksum() {
+    let _registry = RegistryBuilder::new().alternative().http_index().build();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+
+    // Drop the checksum from the index entry of `bar`.
+    let index_file = paths::root().join("alternative-registry/3/b/bar");
+    let mut entry: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&index_file).unwrap()).unwrap();
+    entry.as_object_mut().unwrap().remove("cksum");
+    fs::write(&index_file, entry.to_string()).unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies.bar]
+                version = "0.0.1"
+                registry = "alternative"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] no matching package named `bar` found")
+        .run();
+
+    p.cargo("check")
+        .env(
+            "CARGO_REGISTRIES_ALTERNATIVE_ALLOW_MISSING_CHECKSUM",
+            "true",
+        )
+        .with_stderr_contains(
+            "[WARNING] `bar v0.0.1 (registry `alternative`)` is used without verifying it, \
+             the index of registry `alternative` lists no checksum for it",
+        )
+        .with_stderr_contains("[DOWNLOADED] bar v0.0.1 (registry `alternative`)")
+        .run();
+    assert!(!p.read_lockfile().contains("checksum"));
+}
+
+#[cargo_test]
+fn registry_keys_not_reported_unused() {
+    registry::alt_init();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies.bar]
+                version = "0.0.1"
+                registry = "alternative"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            ".cargo/config.toml",
+            r#"
+                [registries.alternative]
+                index-layout = "sharded"
+
+                [registry]
+                shallow = false
+                http-fallback = false
+                hardlinks = "reject"
+                unexpected-top-level = "reject"
+                report-yank-changes = false
+                prefer-download-mirrors = false
+                verify-cached-downloads = true
+            "#,
+        )
+        .build();
+
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `alternative` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `alternative`)
+",
+        )
+        .run();
+
+    p.cargo("login --registry alternative TOKEN")
+        .with_stderr_does_not_contain("[..]unused config key[..]")
+        .run();
+}
+
+#[cargo_test]
+fn registries_sharing_an_index() {
+    let registry = registry::alt_init();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies.bar]
+                version = "0.0.1"
+                registry = "alternative"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Nothing needs to know which of the names is meant.
+    p.cargo("fetch")
+        .env(
+            "CARGO_REGISTRIES_ALTERNATIVE_INDEX",
+            registry.index_url().as_str(),
+        )
+        .env(
+            "CARGO_REGISTRIES_ALTERNATIVE2_INDEX",
+            registry.index_url().as_str(),
+        )
+        .with_stderr(
+            "\
+[UPDATING] `alternative` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `alternative`)
+",
+        )
+        .run();
+}
@@ -1052,6 +1052,44 @@ also links to native library `a`
         .run();
 }
 
+#[cargo_test]
+fn links_duplicates_from_index() {
+    // The `links` of the index is checked while resolving, before anything
+    // is downloaded.
+    Package::new("bar", "0.1.0")
+        .links("a")
+        .file("build.rs", "fn main() {}")
+        .file("src/lib.rs", "")
+        .publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+            [package]
+            name = "foo"
+            version = "0.1.0"
+            links = "a"
+
+            [dependencies]
+            bar = "0.1"
+            "#,
+        )
+        .file("build.rs", "fn main() {}")
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("build")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] failed to select a version for `bar`.")
+        .with_stderr_contains(
+            "[..]package `bar` links to the native library `a`, but it conflicts with a \
+             previous package which links to `a` as well:",
+        )
+        .with_stderr_does_not_contain("[DOWNLOADED] [..]")
+        .run();
+}
+
 #[cargo_test]
 fn links_duplicates_deep_dependency() {
     // this tests that the links_duplicates are caught at resolver time
@@ -505,3 +505,50 @@ fn no_deadlock_with_git_dependencies() {
         execs().run_output(&result);
     }
 }
+
+#[cargo_test]
+fn waits_for_package_cache_lock() {
+    use cargo::core::Shell;
+    use cargo::util::Config;
+    use cargo_test_support::paths;
+    use std::io::{BufRead, BufReader, Read};
+
+    pkg("bar", "0.0.1");
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.0"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // Holds the lock like another Cargo downloading into the same cache.
+    let config = Config::new(
+        Shell::from_write(Box::new(Vec::new())),
+        paths::root(),
+        cargo_home(),
+    );
+    let lock = config.acquire_package_cache_lock().unwrap();
+    let mut fetch = p.cargo("fetch").build_command();
+    fetch.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut fetch = fetch.spawn().unwrap();
+    let mut stderr = BufReader::new(fetch.stderr.take().unwrap());
+    let mut line = String::new();
+    while !line.contains("Blocking waiting for file lock on package cache") {
+        line.clear();
+        assert_ne!(stderr.read_line(&mut line).unwrap(), 0);
+    }
+    drop(lock);
+
+    let mut rest = String::new();
+    stderr.read_to_string(&mut rest).unwrap();
+    assert!(fetch.wait().unwrap().success(), "{}", rest);
+    assert!(rest.contains("Downloaded bar v0.0.1"), "{}", rest);
+}
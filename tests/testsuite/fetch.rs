@@ -133,3 +133,64 @@ fn fetch_warning() {
         .with_stderr("[WARNING] unused manifest key: package.misspelled")
         .run();
 }
+
+#[cargo_test]
+fn fetch_dep_kinds() {
+    Package::new("normal", "1.0.0").publish();
+    Package::new("build", "1.0.0").publish();
+    Package::new("dev", "1.0.0").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                normal = "1.0"
+
+                [build-dependencies]
+                build = "1.0"
+
+                [dev-dependencies]
+                dev = "1.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("fetch --dep-kind normal")
+        .with_status(101)
+        .with_stderr(
+            "\
+[ERROR] the `--dep-kind` flag is unstable, [..]
+See [..]
+See https://github.com/rust-lang/cargo/issues/5133 [..]
+",
+        )
+        .run();
+
+    p.cargo("fetch -Zunstable-options --dep-kind normal --dep-kind build")
+        .masquerade_as_nightly_cargo(&["unstable-options"])
+        .with_stderr_unordered(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] normal v1.0.0 (registry `dummy-registry`)
+[DOWNLOADED] build v1.0.0 (registry `dummy-registry`)
+",
+        )
+        .run();
+
+    p.cargo("fetch -Zunstable-options --dep-kind dev")
+        .masquerade_as_nightly_cargo(&["unstable-options"])
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] dev v1.0.0 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
@@ -338,7 +338,7 @@ Caused by:
 
 Caused by:
   failed to clone into: [..]
-
+{dns}
 Caused by:
   network failure seems to have happened
   if a proxy or similar is necessary `net.git-fetch-with-cli` may help here
@@ -347,6 +347,11 @@ Caused by:
 Caused by:
   {trailer}
 ",
+            dns = if cargo_uses_gitoxide() {
+                ""
+            } else {
+                "\nCaused by:\n  DNS resolution for the host failed\n"
+            },
             trailer = if cargo_uses_gitoxide() {
                 "An IO error occurred when talking to the server\n\nCaused by:\n  ssh: Could not resolve hostname needs-proxy.invalid[..]"
             } else {
@@ -435,3 +440,77 @@ Caused by:
 
     t.join().ok().unwrap();
 }
+
+// Tests that the HTTP basic auth credentials of a registry are offered for
+// its git index.
+#[cargo_test]
+fn registry_index_http_auth_offered() {
+    if cargo_uses_gitoxide() {
+        return;
+    }
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let t = thread::spawn(move || {
+        let mut conn = BufReader::new(server.accept().unwrap().0);
+        let mut authorizations = Vec::new();
+        for _ in 0..2 {
+            let headers: Vec<_> = (&mut conn)
+                .lines()
+                .map(|s| s.unwrap())
+                .take_while(|s| s.len() > 2)
+                .collect();
+            authorizations.push(
+                headers
+                    .iter()
+                    .find_map(|s| s.trim().strip_prefix("Authorization: ").map(str::to_string)),
+            );
+            conn.get_mut()
+                .write_all(
+                    b"HTTP/1.1 401 Unauthorized\r\n\
+                  WWW-Authenticate: Basic realm=\"wheee\"\r\n\
+                  Content-Length: 0\r\n\
+                  \r\n",
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            authorizations,
+            [None, Some("Basic dXNlcjpzZWNyZXQ=".to_string())]
+        );
+    });
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = { version = "0.1", registry = "private" }
+            "#,
+        )
+        .file("src/main.rs", "")
+        .file(
+            ".cargo/config",
+            &format!(
+                r#"
+                    [net]
+                    retry = 0
+
+                    [registries.private]
+                    index = "http://{addr}/index"
+                    username = "user"
+                    password = "secret"
+                "#
+            ),
+        )
+        .build();
+
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains("[..]failed to authenticate when downloading repository")
+        .run();
+    t.join().ok().unwrap();
+}
@@ -508,6 +508,142 @@ fn gitoxide_clones_registry_with_shallow_protocol_and_follow_up_fetch_maintains_
     Ok(())
 }
 
+#[cargo_test]
+fn registry_shallow_config_clones_and_updates_index_shallow() -> anyhow::Result<()> {
+    Package::new("bar", "1.0.0").publish();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.1.0"
+
+                [dependencies]
+                bar = "1.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+    p.cargo("fetch").env("CARGO_REGISTRY_SHALLOW", "true").run();
+
+    let repo = gix::open_opts(
+        find_remote_index(RepoMode::Shallow),
+        gix::open::Options::isolated(),
+    )?;
+    assert_eq!(
+        repo.rev_parse_single("origin/HEAD")?
+            .ancestors()
+            .all()?
+            .count(),
+        1,
+        "no -Zgitoxide is needed for a shallow clone of the index"
+    );
+    assert!(repo.is_shallow());
+
+    Package::new("bar", "1.1.0").publish();
+    Package::new("bar", "1.2.0").publish();
+    p.cargo("update")
+        .env("CARGO_REGISTRY_SHALLOW", "true")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[UPDATING] bar v1.0.0 -> v1.2.0
+",
+        )
+        .run();
+
+    assert_eq!(
+        repo.rev_parse_single("origin/HEAD")?
+            .ancestors()
+            .all()?
+            .count(),
+        1,
+        "updates only fetch the tip of the index"
+    );
+    assert!(repo.is_shallow());
+
+    p.cargo("check")
+        .env("CARGO_REGISTRY_SHALLOW", "true")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v1.2.0 (registry `dummy-registry`)
+[CHECKING] bar v1.2.0
+[CHECKING] foo v0.1.0 ([CWD])
+[FINISHED] [..]
+",
+        )
+        .run();
+
+    Ok(())
+}
+
+#[cargo_test(requires_git)]
+fn registry_shallow_config_with_git_fetch_with_cli() -> anyhow::Result<()> {
+    Package::new("bar", "1.0.0").publish();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.1.0"
+
+                [dependencies]
+                bar = "1.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_SHALLOW", "true")
+        .env("CARGO_NET_GIT_FETCH_WITH_CLI", "true")
+        .run();
+
+    let repo = gix::open_opts(
+        find_remote_index(RepoMode::Shallow),
+        gix::open::Options::isolated(),
+    )?;
+    assert_eq!(
+        repo.rev_parse_single("origin/HEAD")?
+            .ancestors()
+            .all()?
+            .count(),
+        1,
+        "`git fetch` is told to fetch with a depth of 1"
+    );
+    assert!(repo.is_shallow());
+
+    Package::new("bar", "1.1.0").publish();
+    p.cargo("update")
+        .env("CARGO_REGISTRY_SHALLOW", "true")
+        .env("CARGO_NET_GIT_FETCH_WITH_CLI", "true")
+        .run();
+
+    assert_eq!(
+        repo.rev_parse_single("origin/HEAD")?
+            .ancestors()
+            .all()?
+            .count(),
+        1,
+        "updates only fetch the tip of the index"
+    );
+    assert!(repo.is_shallow());
+    p.cargo("tree --depth 1")
+        .env("CARGO_REGISTRY_SHALLOW", "true")
+        .env("CARGO_NET_GIT_FETCH_WITH_CLI", "true")
+        .with_stdout(
+            "\
+foo v0.1.0 ([CWD])
+└── bar v1.1.0
+",
+        )
+        .run();
+
+    Ok(())
+}
+
 /// If there is shallow *and* non-shallow clones, non-shallow will naturally be returned due to sort order.
 #[cargo_test]
 fn gitoxide_clones_registry_without_shallow_protocol_and_follow_up_fetch_uses_shallowness(
@@ -526,3 +526,68 @@ fn crates_io_registry_url_is_optional() {
     p.cargo("build").with_stderr("[FINISHED] [..]").run();
     p.cargo("test").run();
 }
+
+#[cargo_test]
+fn summaries_cached_per_name() {
+    use super::config::ConfigBuilder;
+    use cargo::core::SourceId;
+    use cargo::sources::RegistrySource;
+    use std::collections::HashSet;
+
+    Package::new("bar", "0.0.1").local(true).publish();
+    Package::new("bar", "0.0.2").local(true).publish();
+
+    let config = ConfigBuilder::new().build();
+    let _lock = config.acquire_package_cache_lock().unwrap();
+    let path = registry_path();
+    let sid = SourceId::for_local_registry(&path).unwrap();
+    let mut source = RegistrySource::local(sid, &path, &HashSet::new(), &config);
+    assert_eq!(source.query_req("bar", "*").unwrap().len(), 2);
+
+    // Queries of the same name are answered from memory, without reading
+    // the index file again.
+    fs::remove_file(path.join("index/3/b/bar")).unwrap();
+    assert_eq!(source.query_req("bar", "=0.0.1").unwrap().len(), 1);
+    assert_eq!(source.query_req("bar", "*").unwrap().len(), 2);
+
+    // The cache lives as long as the source.
+    let mut source = RegistrySource::local(sid, &path, &HashSet::new(), &config);
+    assert!(source.query_req("bar", "*").unwrap().is_empty());
+}
+
+#[cargo_test]
+fn crate_file_checksum_verified() {
+    setup();
+    Package::new("bar", "0.0.1")
+        .local(true)
+        .file("src/lib.rs", "pub fn bar() {}")
+        .publish();
+    let crate_file = registry_path().join("bar-0.0.1.crate");
+    let mut data = fs::read(&crate_file).unwrap();
+    data.push(0);
+    fs::write(&crate_file, data).unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains("[..]failed to verify the checksum of `bar v0.0.1 (registry [..])`")
+        .run();
+    assert!(!paths::home()
+        .join(".cargo/registry/src")
+        .read_dir()
+        .map_or(false, |mut d| d.next().is_some()));
+}
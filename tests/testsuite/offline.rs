@@ -66,7 +66,7 @@ fn offline_missing_optional() {
 [ERROR] failed to download `opt_dep v1.0.0`
 
 Caused by:
-  attempting to make an HTTP request, but --offline was specified
+  `opt_dep v1.0.0` is not in the download cache and cannot be downloaded because --offline was specified
 ",
         )
         .with_status(101)
@@ -326,10 +326,10 @@ fn compile_offline_while_transitive_dep_not_cached() {
         .with_status(101)
         .with_stderr(
             "\
-[ERROR] failed to download `bar v0.1.0`
+[ERROR] failed to download `baz v1.0.0`
 
 Caused by:
-  attempting to make an HTTP request, but --offline was specified
+  `baz v1.0.0` is not in the download cache and cannot be downloaded because --offline was specified
 ",
         )
         .run();
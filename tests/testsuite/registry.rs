@@ -1,24 +1,42 @@
 //! Tests for normal registry dependencies.
 
 use cargo::core::SourceId;
+use cargo::sources::RegistrySource;
+use cargo::util::config::PackageCacheLock;
+use cargo::util::Config;
 use cargo_test_support::cargo_process;
 use cargo_test_support::paths::{self, CargoPathExt};
 use cargo_test_support::registry::{
     self, registry_path, Dependency, Package, RegistryBuilder, Response, TestRegistry,
 };
-use cargo_test_support::{basic_manifest, project};
+use cargo_test_support::{basic_manifest, project, Project};
 use cargo_test_support::{git, install::cargo_home, t};
 use cargo_util::paths::remove_dir_all;
+use std::collections::HashSet;
 use std::fmt::Write;
 use std::fs::{self, File};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
+use url::Url;
 
 fn setup_http() -> TestRegistry {
     RegistryBuilder::new().http_index().build()
 }
 
+/// Opens `registry` as a [`RegistrySource`], for tests of its API.
+///
+/// The returned lock has to be kept around for as long as the source is used.
+fn remote_source<'cfg>(
+    config: &'cfg Config,
+    registry: &TestRegistry,
+) -> (PackageCacheLock<'cfg>, SourceId, RegistrySource<'cfg>) {
+    let lock = config.acquire_package_cache_lock().unwrap();
+    let sid = SourceId::for_registry(registry.index_url()).unwrap();
+    let source = RegistrySource::remote(sid, &HashSet::new(), config).unwrap();
+    (lock, sid, source)
+}
+
 #[cargo_test]
 fn test_server_stops() {
     let server = setup_http();
@@ -373,6 +391,183 @@ Caused by:
 ",
         )
         .run();
+
+    // Nothing is left in the cache that could pass for a download.
+    let cached = glob::glob(
+        paths::home()
+            .join(".cargo/registry/cache/*/bad-cksum-*")
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap()
+    .count();
+    assert_eq!(cached, 0);
+}
+
+/// A sparse registry with `bar` that isn't set up to replace crates.io, with
+/// `registry.http-fallback` enabled.
+fn http_fallback_registry() -> TestRegistry {
+    let registry = RegistryBuilder::new()
+        .http_index()
+        .no_configure_registry()
+        .build();
+    // Configured before publishing, which would otherwise set the registry
+    // up a second time, replacing crates.io.
+    fs::write(
+        paths::home().join(".cargo/config"),
+        "[registry]\nhttp-fallback = true\n[net]\nretry = 0\n",
+    )
+    .unwrap();
+    Package::new("bar", "0.0.1").publish();
+    registry
+}
+
+fn http_fallback_project() -> Project {
+    project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build()
+}
+
+#[cargo_test]
+fn http_fallback_when_git_is_blocked() {
+    let server = http_fallback_registry();
+    // Nothing listens on this port, so connections to it are refused like
+    // by a firewall.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let git_index = Url::parse(&format!("http://127.0.0.1:{port}/index")).unwrap();
+
+    let p = http_fallback_project();
+    p.cargo("fetch")
+        .replace_crates_io(&git_index)
+        .env(
+            "__CARGO_TEST_CRATES_IO_SPARSE_URL_DO_NOT_USE_THIS",
+            server.index_url().as_str(),
+        )
+        .with_stderr_contains(
+            "[WARNING] failed to fetch the git index of registry `crates-io`, \
+             using its sparse index `sparse+http://127.0.0.1:[..]/index/` instead",
+        )
+        .with_stderr_contains("[DOWNLOADED] bar v0.0.1")
+        .run();
+    assert!(p.read_lockfile().contains("name = \"bar\""));
+}
+
+#[cargo_test]
+fn http_fallback_not_used_for_other_errors() {
+    let server = http_fallback_registry();
+    // The server is reachable, it just doesn't have the index.
+    let sparse = server.index_url();
+    let git_index = Url::parse(&format!(
+        "http://{}:{}/no-such-index",
+        sparse.host_str().unwrap(),
+        sparse.port().unwrap()
+    ))
+    .unwrap();
+
+    let p = http_fallback_project();
+    p.cargo("fetch")
+        .replace_crates_io(&git_index)
+        .env(
+            "__CARGO_TEST_CRATES_IO_SPARSE_URL_DO_NOT_USE_THIS",
+            server.index_url().as_str(),
+        )
+        .with_status(101)
+        .with_stderr_contains("[..]failed to fetch `http://127.0.0.1:[..]/no-such-index`")
+        .with_stderr_does_not_contain("[..]sparse index[..]")
+        .run();
+}
+
+#[cargo_test]
+fn report_yank_changes() {
+    registry::init();
+    Package::new("bar", "0.0.1").publish();
+    Package::new("bar", "0.0.2").yanked(true).publish();
+    Package::new("baz", "0.1.0").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                baz = "0.1.0"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo("generate-lockfile").run();
+
+    // Yank 0.0.1 and unyank 0.0.2. The lines published last take precedence.
+    Package::new("bar", "0.0.1").yanked(true).publish();
+    Package::new("bar", "0.0.2").publish();
+    Package::new("baz", "0.1.1").publish();
+
+    p.cargo("update")
+        .env("CARGO_REGISTRY_REPORT_YANK_CHANGES", "true")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[NOTE] since the last update of `dummy-registry` index:
+  `bar v0.0.1` was yanked
+  `bar v0.0.2` was unyanked
+[UPDATING] baz v0.1.0 -> v0.1.1
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn all_failed_downloads_reported() {
+    registry::init();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    for name in ["bar", "baz"] {
+        let pkg = Package::new(name, "0.0.1");
+        pkg.publish();
+        t!(File::create(&pkg.archive_dst()));
+    }
+
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "  failed to verify the checksum of `bar v0.0.1 (registry `dummy-registry`)`",
+        )
+        .with_stderr_contains(
+            "  failed to verify the checksum of `baz v0.0.1 (registry `dummy-registry`)`",
+        )
+        .run();
 }
 
 #[cargo_test]
@@ -871,6 +1066,66 @@ fn yanks_in_lockfiles_are_ok_with_new_dep() {
     p.cargo("check").with_stdout("").run();
 }
 
+#[cargo_test]
+fn yanked_lines_mixed_into_index_file() {
+    let _server = setup_http();
+    Package::new("bar", "0.0.1").publish();
+    Package::new("bar", "0.0.2").publish();
+    Package::new("bar", "0.0.3").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "*"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo("generate-lockfile").run();
+    assert!(p.read_lockfile().contains("0.0.3"));
+
+    // Older index lines have no `yanked` field at all, which means the
+    // version isn't yanked.
+    let index_file = registry_path().join("3/b/bar");
+    let lines: Vec<String> = fs::read_to_string(&index_file)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let mut entry: serde_json::Value = serde_json::from_str(line).unwrap();
+            let yanked = entry["vers"] == "0.0.3";
+            let fields = entry.as_object_mut().unwrap();
+            if yanked {
+                fields.insert("yanked".to_string(), true.into());
+            } else {
+                fields.remove("yanked");
+            }
+            entry.to_string()
+        })
+        .collect();
+    fs::write(&index_file, lines.join("\n")).unwrap();
+
+    // The lock file pins the yanked version, so it's still used.
+    p.cargo("check")
+        .with_stderr_contains("[DOWNLOADED] bar v0.0.3 (registry `dummy-registry`)")
+        .run();
+
+    // But a fresh resolve doesn't pick it.
+    p.cargo("update")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNGRADING] bar v0.0.3 -> v0.0.2
+",
+        )
+        .run();
+}
+
 #[cargo_test]
 fn update_with_lockfile_if_packages_missing_http() {
     let _server = setup_http();
@@ -1068,6 +1323,53 @@ fn dev_dependency_not_used() {
         .run();
 }
 
+#[cargo_test]
+fn dependency_kind_missing_from_index() {
+    let _server = setup_http();
+    Package::new("baz", "0.0.1").publish();
+    Package::new("devonly", "0.0.1").publish();
+    Package::new("bar", "0.0.1")
+        .dep("baz", "*")
+        .dev_dep("devonly", "*")
+        .publish();
+
+    // Older index lines have no `kind` for their dependencies, which means
+    // a normal dependency.
+    let index_file = registry_path().join("3/b/bar");
+    let mut entry: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&index_file).unwrap()).unwrap();
+    for dep in entry["deps"].as_array_mut().unwrap() {
+        if dep["kind"] == "normal" {
+            dep.as_object_mut().unwrap().remove("kind");
+        }
+    }
+    fs::write(&index_file, entry.to_string()).unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "*"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo("tree")
+        .with_stdout(
+            "\
+foo v0.0.1 ([CWD])
+└── bar v0.0.1
+    └── baz v0.0.1
+",
+        )
+        .run();
+}
+
 #[cargo_test]
 fn bad_license_file_http() {
     let registry = setup_http();
@@ -2237,6 +2539,7 @@ Caused by:
 
 Caused by:
   [..] contains a file at \"foo-0.1.0/src/lib.rs\" which isn't under \"foo-0.2.0\"
+  set `registry.unexpected-top-level` to `locate` to unpack packages from the directory that contains their `Cargo.toml`
 ",
         )
         .run();
@@ -2530,7 +2833,7 @@ fn package_lock_inside_package_is_overwritten() {
         .file("src/main.rs", "fn main() {}")
         .build();
 
-    Package::new("bar", "0.0.1")
+    let cksum = Package::new("bar", "0.0.1")
         .file("src/lib.rs", "")
         .file(".cargo-ok", "")
         .publish();
@@ -2542,11 +2845,16 @@ fn package_lock_inside_package_is_overwritten() {
     let ok = cargo_home()
         .join("registry")
         .join("src")
-        .join(format!("-{}", hash))
+        .join(format!("registry-{}", hash))
         .join("bar-0.0.1")
         .join(".cargo-ok");
 
-    assert_eq!(ok.metadata().unwrap().len(), 2);
+    assert_eq!(
+        fs::read_to_string(ok).unwrap(),
+        format!(
+            r#"{{"v":1,"package":"bar v0.0.1 (registry `dummy-registry`)","cksum":"{cksum}"}}"#
+        )
+    );
 }
 
 #[cargo_test]
@@ -2580,13 +2888,15 @@ fn package_lock_as_a_symlink_inside_package_is_overwritten() {
     let pkg_root = cargo_home()
         .join("registry")
         .join("src")
-        .join(format!("-{}", hash))
+        .join(format!("registry-{}", hash))
         .join("bar-0.0.1");
     let ok = pkg_root.join(".cargo-ok");
     let librs = pkg_root.join("src/lib.rs");
 
     // Is correctly overwritten and doesn't affect the file linked to
-    assert_eq!(ok.metadata().unwrap().len(), 2);
+    assert!(fs::read_to_string(ok)
+        .unwrap()
+        .starts_with(r#"{"v":1,"package":"bar v0.0.1 (registry `dummy-registry`)""#));
     assert_eq!(fs::read_to_string(librs).unwrap(), "pub fn f() {}");
 }
 
@@ -2888,6 +3198,81 @@ internal server error
 ").run();
 }
 
+#[cargo_test]
+fn dl_through_http_proxy() {
+    // Downloads honor `http.proxy`, and go straight to the server without it.
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    let _server = RegistryBuilder::new().http_api().build();
+    Package::new("bar", "1.0.0").publish();
+
+    let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let requested = std::thread::spawn(move || {
+        let (socket, _) = proxy.accept().unwrap();
+        let mut buf = BufReader::new(socket);
+        let mut line = String::new();
+        buf.read_line(&mut line).unwrap();
+        let mut header = String::new();
+        while buf.read_line(&mut header).unwrap() > 2 {
+            header.clear();
+        }
+        buf.get_mut()
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        line
+    });
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.1.0"
+
+                [dependencies]
+                bar = "1.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_HTTP_PROXY", format!("http://{proxy_addr}"))
+        .env("CARGO_NET_RETRY", "0")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `http://127.0.0.1:[..]/dl/bar/1.0.0/download`
+
+Caused by:
+  failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` (127.0.0.1), got 502
+  body:
+",
+        )
+        .run();
+
+    // The proxy is sent the absolute URL of the download.
+    let line = requested.join().unwrap();
+    assert!(
+        line.starts_with("GET http://127.0.0.1:") && line.contains("/dl/bar/1.0.0/download"),
+        "unexpected proxy request: {line}"
+    );
+
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v1.0.0 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
 /// Creates a random prefix to randomly spread out the package names
 /// to somewhat evenly distribute the different failures at different
 /// points.
@@ -3135,7 +3520,9 @@ fn corrupted_ok_overwritten() {
     fs::write(&ok, "").unwrap();
     assert_eq!(fs::read_to_string(&ok).unwrap(), "");
     p.cargo("fetch").with_stderr("").run();
-    assert_eq!(fs::read_to_string(&ok).unwrap(), "ok");
+    assert!(fs::read_to_string(&ok)
+        .unwrap()
+        .starts_with(r#"{"v":1,"package":"bar v1.0.0 (registry `dummy-registry`)""#));
 }
 
 #[cargo_test]
@@ -3391,7 +3778,7 @@ warning: spurious network error (1 tries remaining): \
     failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` (127.0.0.1), got 503
 body:
 Please slow down
-error: failed to download from `http://127.0.0.1:[..]/dl/bar/1.0.0/download`
+error: failed to download from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` after 4 attempts
 
 Caused by:
   failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` (127.0.0.1), got 503
@@ -3403,3 +3790,3674 @@ Caused by:
   Please slow down
 ").run();
 }
+
+#[cargo_test]
+fn html_download_rejected() {
+    let pages = Arc::new(Mutex::new(0));
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/dl/bar/1.0.0/download", move |req, server| {
+            let mut pages = pages.lock().unwrap();
+            if *pages < 2 {
+                *pages += 1;
+                Response {
+                    code: 200,
+                    headers: vec!["Content-Type: text/html; charset=utf-8".to_string()],
+                    body: b"\n<!DOCTYPE html><html><body>Are you a robot?</body></html>".to_vec(),
+                }
+            } else {
+                server.dl(req)
+            }
+        })
+        .build();
+    Package::new("bar", "1.0.0").publish();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.1.0"
+
+                [dependencies]
+                bar = "1.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `http://127.0.0.1:[..]/dl/bar/1.0.0/download`
+
+Caused by:
+  expected a `.crate` file from `http://127.0.0.1:[..]/dl/bar/1.0.0/download`, \
+but the server returned an HTML page (Content-Type: text/html; charset=utf-8), likely an error page
+",
+        )
+        .run();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_NON_TARBALL_DOWNLOADS", "retry")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[WARNING] spurious network error (3 tries remaining): expected a `.crate` file from \
+`http://127.0.0.1:[..]/dl/bar/1.0.0/download`, but the server returned an HTML page \
+(Content-Type: text/html; charset=utf-8), likely an error page
+[DOWNLOADED] bar v1.0.0 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn deprecated_crate_warns_http() {
+    let _server = setup_http();
+    deprecated_crate_warns();
+}
+
+#[cargo_test]
+fn deprecated_crate_warns_git() {
+    deprecated_crate_warns();
+}
+
+fn deprecated_crate_warns() {
+    Package::new("bar", "0.0.1")
+        .deprecated(Some("unmaintained"), Some("baz"))
+        .publish();
+    Package::new("qux", "0.0.1")
+        .deprecated(None, None)
+        .publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                qux = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("check")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `dummy-registry` index
+[WARNING] package `bar` is deprecated in registry `dummy-registry`: unmaintained
+consider using `baz` instead
+[WARNING] package `qux` is deprecated in registry `dummy-registry`
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] qux v0.0.1 (registry `dummy-registry`)
+[CHECKING] bar v0.0.1
+[CHECKING] qux v0.0.1
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] dev [unoptimized + debuginfo] target(s) in [..]s
+",
+        )
+        .run();
+
+    // Selecting the crate warns, even once it doesn't have to be downloaded.
+    p.cargo("check")
+        .with_stderr_contains(
+            "[WARNING] package `bar` is deprecated in registry `dummy-registry`: unmaintained",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn duplicate_checksums_across_versions() {
+    let _registry = RegistryBuilder::new().build();
+    let cksum = Package::new("bar", "0.1.0").publish();
+    let dup_cksum = Package::new("bar", "0.1.1").publish();
+
+    // Point 0.1.1 at the checksum of 0.1.0, as if it had been published
+    // with the wrong tarball.
+    let bar_path = Path::new("3/b/bar");
+    let bar_reg_path = registry_path().join(&bar_path);
+    let index = fs::read_to_string(&bar_reg_path).unwrap();
+    fs::write(&bar_reg_path, index.replace(&dup_cksum, &cksum)).unwrap();
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    let mut git_index = repo.index().unwrap();
+    git_index.add_path(&bar_path).unwrap();
+    git_index.write().unwrap();
+    git::commit(&repo);
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("generate-lockfile")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[WARNING] package `bar` has versions with identical checksums in registry `dummy-registry`: 0.1.0, 0.1.1
+this usually means a version was published pointing at the wrong tarball
+",
+        )
+        .run();
+
+    p.cargo("generate-lockfile")
+        .env("CARGO_REGISTRY_DUPLICATE_CHECKSUMS", "allow")
+        .with_stderr("[UPDATING] `dummy-registry` index")
+        .run();
+
+    p.cargo("generate-lockfile")
+        .env("CARGO_REGISTRY_DUPLICATE_CHECKSUMS", "deny")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[ERROR] failed to get `bar` as a dependency of package `foo v0.0.1 ([CWD])`
+
+Caused by:
+  failed to query replaced source registry `crates-io`
+
+Caused by:
+  package `bar` has versions with identical checksums in registry `dummy-registry`: 0.1.0, 0.1.1
+  this usually means a version was published pointing at the wrong tarball
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn ipfs_dl_requires_gateway() {
+    let _registry = RegistryBuilder::new().build();
+    Package::new("bar", "0.0.1").publish();
+
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    fs::write(
+        registry_path().join("config.json"),
+        r#"{"dl":"ipfs://{cid}"}"#,
+    )
+    .unwrap();
+    git::add(&repo);
+    git::commit(&repo);
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[ERROR] failed to download `bar v0.0.1`
+
+Caused by:
+  unable to get packages from source
+
+Caused by:
+  failed to download replaced source registry `crates-io`
+
+Caused by:
+  cannot download `bar v0.0.1` from `ipfs://bafkrei[..]`
+  set `registry.ipfs-gateway` to an HTTP gateway to fetch `ipfs://` URLs
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn ipfs_dl_through_gateway() {
+    let archive = paths::root().join("dl/bar/0.0.1/download");
+    let registry = RegistryBuilder::new()
+        .http_index()
+        .not_found_handler(move |req, server| {
+            // The content identifiers of sha256 checksums all start the same.
+            if req.url.path().starts_with("/gateway/ipfs/bafkrei") {
+                Response {
+                    code: 200,
+                    headers: vec![],
+                    body: fs::read(&archive).unwrap(),
+                }
+            } else {
+                server.not_found(req)
+            }
+        })
+        .build();
+    Package::new("bar", "0.0.1").publish();
+
+    registry.edit_config(|config| config["dl"] = "ipfs://{cid}".into());
+    let index_url = registry.index_url().as_str();
+    let gateway = Url::parse(index_url.strip_prefix("sparse+").unwrap())
+        .unwrap()
+        .join("/gateway")
+        .unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_IPFS_GATEWAY", gateway.as_str())
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn missing_locked_version() {
+    let _registry = RegistryBuilder::new().build();
+    Package::new("bar", "0.1.0").publish();
+    let bar_path = Path::new("3/b/bar");
+    let bar_reg_path = registry_path().join(&bar_path);
+    let old_index = fs::read_to_string(&bar_reg_path).unwrap();
+    Package::new("bar", "0.1.1").publish();
+    Package::new("baz", "0.1.0").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.1"
+                baz = "0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo("generate-lockfile").run();
+
+    // Remove 0.1.1 from the index, while Cargo.lock still pins it.
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    let mut index = repo.index().unwrap();
+    fs::write(&bar_reg_path, &old_index).unwrap();
+    index.add_path(&bar_path).unwrap();
+    index.write().unwrap();
+    git::commit(&repo);
+    Package::new("baz", "0.1.1").publish();
+
+    p.cargo("update -p baz")
+        .env("CARGO_REGISTRY_MISSING_LOCKED_VERSIONS", "warn")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[WARNING] package `bar v0.1.1` is locked in Cargo.lock, but registry `dummy-registry` no longer has that version
+[ERROR] failed to select a version for the requirement `bar = \"^0.1\"` (locked to 0.1.1)
+candidate versions found which didn't match: 0.1.0
+location searched: `dummy-registry` index (which is replacing registry `crates-io`)
+required by package `foo v0.0.1 ([CWD])`
+perhaps a crate was updated and forgotten to be re-vendored?
+",
+        )
+        .run();
+
+    p.cargo("update -p baz")
+        .env("CARGO_REGISTRY_MISSING_LOCKED_VERSIONS", "deny")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[ERROR] failed to get `bar` as a dependency of package `foo v0.0.1 ([CWD])`
+
+Caused by:
+  failed to query replaced source registry `crates-io`
+
+Caused by:
+  package `bar v0.1.1` is locked in Cargo.lock, but registry `dummy-registry` no longer has that version
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn provenance_log() {
+    let registry = RegistryBuilder::new().build();
+    let cksum = Package::new("bar", "0.0.1").publish();
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    let head = repo.head().unwrap().target().unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            ".cargo/config.toml",
+            r#"
+                [registry]
+                provenance-log = "provenance.jsonl"
+            "#,
+        )
+        .build();
+
+    p.cargo("fetch").run();
+    let log = p.read_file("provenance.jsonl");
+    let records = log.lines().collect::<Vec<_>>();
+    assert_eq!(records.len(), 1);
+    let record: serde_json::Value = serde_json::from_str(records[0]).unwrap();
+    assert_eq!(
+        record,
+        serde_json::json!({
+            "name": "bar",
+            "version": "0.0.1",
+            "source": registry.index_url().as_str(),
+            "url": format!(
+                "{}/bar/0.0.1/download",
+                Url::from_file_path(paths::root().join("dl")).unwrap()
+            ),
+            "checksum": cksum,
+            "index-revision": head.to_string(),
+        })
+    );
+
+    // Crates that are already downloaded aren't fetched, so nothing is logged.
+    p.cargo("fetch").run();
+    assert_eq!(p.read_file("provenance.jsonl"), log);
+}
+
+#[cargo_test]
+fn hostless_registry_keeps_existing_directories() {
+    // Registries without a host used to be stored under their hash alone.
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+    let p = symlink_project();
+    p.cargo("fetch").run();
+
+    let id = SourceId::for_registry(registry.index_url()).unwrap();
+    let hash = cargo::util::hex::short_hash(&id);
+    for dir in ["index", "cache", "src"] {
+        let dir = cargo_home().join("registry").join(dir);
+        fs::rename(
+            dir.join(format!("registry-{}", hash)),
+            dir.join(format!("-{}", hash)),
+        )
+        .unwrap();
+    }
+
+    p.cargo("fetch").with_stderr("").run();
+    assert!(cargo_home()
+        .join("registry/src")
+        .join(format!("-{}", hash))
+        .join("bar-0.0.1")
+        .exists());
+    assert!(!unpacked_bar(&registry).exists());
+}
+
+fn symlink_project() -> Project {
+    project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build()
+}
+
+fn unpacked_bar(registry: &TestRegistry) -> PathBuf {
+    let id = SourceId::for_registry(registry.index_url()).unwrap();
+    let hash = cargo::util::hex::short_hash(&id);
+    cargo_home()
+        .join("registry/src")
+        .join(format!("registry-{}", hash))
+        .join("bar-0.0.1")
+}
+
+#[cargo_test]
+fn corrupt_cached_download_fetched_again() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .publish();
+    let p = symlink_project();
+    p.cargo("fetch").run();
+
+    let unpacked = unpacked_bar(&registry);
+    let cached = cargo_home()
+        .join("registry/cache")
+        .join(unpacked.parent().unwrap().file_name().unwrap())
+        .join("bar-0.0.1.crate");
+    // Cut short, as if written by an interrupted process.
+    let data = fs::read(&cached).unwrap();
+    fs::write(&cached, &data[..data.len() / 2]).unwrap();
+    unpacked.rm_rf();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_VERIFY_CACHED_DOWNLOADS", "false")
+        .with_status(101)
+        .with_stderr_contains(
+            "[..]failed to unpack package `bar v0.0.1 (registry `dummy-registry`)`",
+        )
+        .run();
+
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[WARNING] the cached `.crate` file of `bar v0.0.1 (registry `dummy-registry`)` \
+doesn't match the checksum in the index, downloading it again
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+    assert_eq!(fs::read(&cached).unwrap(), data);
+    assert_eq!(
+        fs::read_to_string(unpacked.join("src/lib.rs")).unwrap(),
+        "pub fn f() {}"
+    );
+}
+
+#[cargo_test]
+fn symlink_in_package_rejected_by_default() {
+    registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .symlink("src/link.rs", "lib.rs")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[ERROR] failed to download replaced source registry `crates-io`
+
+Caused by:
+  failed to unpack package `bar v0.0.1 (registry `dummy-registry`)`
+
+Caused by:
+  invalid tarball downloaded, contains a symlink at \"bar-0.0.1/src/link.rs\"
+  set `registry.symlinks` to `copy` or `preserve` to allow symlinks in packages
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn symlink_in_package_copied() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .file("src/sub/mod.rs", "pub fn g() {}")
+        .symlink("src/link.rs", "lib.rs")
+        .symlink("src/linkdir", "sub")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .env("CARGO_REGISTRY_SYMLINKS", "copy")
+        .run();
+
+    let root = unpacked_bar(&registry);
+    let link = root.join("src/link.rs");
+    assert!(!link.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_to_string(link).unwrap(), "pub fn f() {}");
+    let linkdir = root.join("src/linkdir");
+    assert!(!linkdir.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(
+        fs::read_to_string(linkdir.join("mod.rs")).unwrap(),
+        "pub fn g() {}"
+    );
+}
+
+#[cargo_test]
+fn symlink_in_package_preserved() {
+    if !cargo_test_support::symlink_supported() {
+        return;
+    }
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .symlink("src/link.rs", "lib.rs")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .env("CARGO_REGISTRY_SYMLINKS", "preserve")
+        .run();
+
+    let link = unpacked_bar(&registry).join("src/link.rs");
+    assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+    assert_eq!(fs::read_to_string(link).unwrap(), "pub fn f() {}");
+}
+
+#[cargo_test]
+fn symlink_escaping_package_always_rejected() {
+    registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .symlink("src/link.rs", "../../bar-0.0.2/src/lib.rs")
+        .publish();
+
+    let p = symlink_project();
+    for policy in ["reject", "copy", "preserve"] {
+        p.cargo("fetch")
+            .env("CARGO_REGISTRY_SYMLINKS", policy)
+            .with_status(101)
+            .with_stderr_contains(
+                "  invalid tarball downloaded, contains a symlink at \
+                 \"bar-0.0.1/src/link.rs\" which points outside of \"bar-0.0.1\"",
+            )
+            .run();
+    }
+}
+
+#[cargo_test]
+fn hardlink_in_package_rejected_by_default() {
+    registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .hardlink("src/link.rs", "bar-0.0.1/src/lib.rs")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "  invalid tarball downloaded, contains a hardlink at \"bar-0.0.1/src/link.rs\"
+  set `registry.hardlinks` to `copy` to allow hardlinks in packages",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn hardlink_in_package_copied() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .hardlink("src/link.rs", "bar-0.0.1/src/lib.rs")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .env("CARGO_REGISTRY_HARDLINKS", "copy")
+        .run();
+
+    let root = unpacked_bar(&registry);
+    assert_eq!(
+        fs::read_to_string(root.join("src/link.rs")).unwrap(),
+        "pub fn f() {}"
+    );
+    // Changing the copy leaves the original alone.
+    fs::write(root.join("src/link.rs"), "").unwrap();
+    assert_eq!(
+        fs::read_to_string(root.join("src/lib.rs")).unwrap(),
+        "pub fn f() {}"
+    );
+}
+
+#[cargo_test]
+fn hardlink_escaping_package_always_rejected() {
+    registry::init();
+    let p = symlink_project();
+    for (version, target) in [("0.0.1", "bar-0.0.2/src/lib.rs"), ("0.0.2", "../evil.rs")] {
+        Package::new("bar", version)
+            .file("src/lib.rs", "pub fn f() {}")
+            .hardlink("src/link.rs", target)
+            .publish();
+        p.change_file(
+            "Cargo.toml",
+            &format!(
+                r#"
+                    [package]
+                    name = "foo"
+                    version = "0.1.0"
+
+                    [dependencies]
+                    bar = "={version}"
+                "#
+            ),
+        );
+        for policy in ["reject", "copy"] {
+            p.cargo("fetch")
+                .env("CARGO_REGISTRY_HARDLINKS", policy)
+                .with_status(101)
+                .with_stderr_contains(&format!(
+                    "  invalid tarball downloaded, contains a hardlink at \
+                     \"bar-{version}/src/link.rs\" which points outside of \"bar-{version}\"",
+                ))
+                .run();
+        }
+    }
+}
+
+#[cargo_test]
+fn cleanup_stale_leftovers() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+    let p = symlink_project();
+    p.cargo("fetch").run();
+
+    let src = unpacked_bar(&registry).parent().unwrap().to_path_buf();
+    let cache = cargo_home()
+        .join("registry/cache")
+        .join(src.file_name().unwrap());
+    let tmp = src.join(".tmp-baz-0.0.1");
+    fs::create_dir_all(tmp.join("baz-0.0.1/src")).unwrap();
+    let old_part = cache.join("baz-0.0.1.crate.part");
+    fs::write(&old_part, "").unwrap();
+    let two_hours_ago = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60),
+    );
+    filetime::set_file_mtime(&old_part, two_hours_ago).unwrap();
+    let new_part = cache.join("qux-0.0.1.crate.part");
+    fs::write(&new_part, "").unwrap();
+
+    // Nothing is removed unless asked to.
+    p.cargo("fetch").run();
+    assert!(tmp.exists());
+    assert!(old_part.exists());
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_CLEANUP_STALE", "1h")
+        .run();
+    assert!(!tmp.exists());
+    assert!(!old_part.exists());
+    assert!(new_part.exists());
+    assert!(unpacked_bar(&registry).join("Cargo.toml").exists());
+    assert!(cache.join("bar-0.0.1.crate").exists());
+}
+
+#[cargo_test]
+fn path_traversal_in_package_rejected() {
+    registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "")
+        .file_with_raw_path(b"../../evil.rs", "")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "  invalid tarball downloaded, contains a file at \
+             \"bar-0.0.1/../../evil.rs\" which escapes the package directory",
+        )
+        .run();
+    assert!(!cargo_home().join("registry/src/evil.rs").exists());
+}
+
+#[cargo_test]
+fn failed_unpack_leaves_nothing_behind() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .file_with_raw_path(b"../evil.rs", "")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains("  invalid tarball downloaded, [..]")
+        .run();
+    // `src/lib.rs` came before the bad entry, but isn't left behind.
+    let root = unpacked_bar(&registry);
+    assert!(!root.exists());
+    let leftovers: Vec<_> = fs::read_dir(root.parent().unwrap())
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+}
+
+#[cargo_test]
+fn unexpected_top_level_rejected_by_default() {
+    registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "")
+        .package_dir("bar")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "  invalid tarball downloaded, contains a file at \"bar/Cargo.toml\" \
+             which isn't under \"bar-0.0.1\"",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn unexpected_top_level_located() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn f() {}")
+        .extra_file("notes/todo.txt", "")
+        .package_dir("bar")
+        .publish();
+
+    let p = symlink_project();
+    p.change_file("src/main.rs", "fn main() { bar::f(); }");
+    p.cargo("check")
+        .env("CARGO_REGISTRY_UNEXPECTED_TOP_LEVEL", "locate")
+        .with_stderr_contains(
+            "[WARNING] package `bar v0.0.1 (registry `dummy-registry`)` is in a \
+             directory named \"bar\" instead of \"bar-0.0.1\" in its `.crate` file",
+        )
+        .with_stderr_contains(
+            "[WARNING] package `bar v0.0.1 (registry `dummy-registry`)` has files \
+             outside of its directory in its `.crate` file, which were skipped: \
+             [\"notes\"]",
+        )
+        .run();
+    let root = unpacked_bar(&registry);
+    assert!(root.join("Cargo.toml").is_file());
+    assert!(!root.join("notes").exists());
+    assert!(!root.parent().unwrap().join("notes").exists());
+}
+
+#[cargo_test]
+fn non_utf8_path_rejected_by_default() {
+    registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "")
+        .file_with_raw_path(b"caf\xe9.txt", "")
+        .publish();
+
+    symlink_project()
+        .cargo("fetch")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[ERROR] failed to download replaced source registry `crates-io`
+
+Caused by:
+  failed to unpack package `bar v0.0.1 (registry `dummy-registry`)`
+
+Caused by:
+  invalid tarball downloaded, contains a path that is not valid UTF-8: \"bar-0.0.1/caf\u{fffd}.txt\"
+  set `registry.non-utf8-paths` to `sanitize` to unpack such paths under a sanitized name
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn non_utf8_path_sanitized() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "")
+        .file_with_raw_path(b"caf\xe9.txt", "coffee")
+        .publish();
+
+    let p = symlink_project();
+    let expected = "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[WARNING] package `bar v0.0.1 (registry `dummy-registry`)` contains paths that are \
+not valid UTF-8, unpacked as:
+  bar-0.0.1/caf%E9.txt
+";
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_NON_UTF8_PATHS", "sanitize")
+        .with_stderr(&format!("[UPDATING] `dummy-registry` index\n{expected}"))
+        .run();
+    let root = unpacked_bar(&registry);
+    assert_eq!(
+        fs::read_to_string(root.join("caf%E9.txt")).unwrap(),
+        "coffee"
+    );
+
+    // Unpacking again gives the same name.
+    root.rm_rf();
+    cargo_home().join("registry/cache").rm_rf();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_NON_UTF8_PATHS", "sanitize")
+        .with_stderr(expected)
+        .run();
+    assert_eq!(
+        fs::read_to_string(root.join("caf%E9.txt")).unwrap(),
+        "coffee"
+    );
+}
+
+#[cargo_test]
+fn dl_retry_budget() {
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/dl/bar/1.0.0/download", |req, server| {
+            server.internal_server_error(req)
+        })
+        .build();
+    Package::new("bar", "1.0.0").publish();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.1.0"
+
+                [dependencies]
+                bar = "1.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_RETRY_BUDGET", "1")
+        .with_status(101)
+        .with_stderr("\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+warning: spurious network error (1 tries remaining): \
+    failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` (127.0.0.1), got 500
+body:
+internal server error
+warning: not retrying spurious network error, the `registry.retry-budget` for this session is used up
+[ERROR] failed to download from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` after 2 attempts
+
+Caused by:
+  failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/1.0.0/download` (127.0.0.1), got 500
+  body:
+  internal server error
+").run();
+}
+
+#[cargo_test]
+fn validate_locked_index() {
+    let _registry = RegistryBuilder::new().build();
+    Package::new("bar", "0.1.0").publish();
+    Package::new("baz", "0.1.0").publish();
+    Package::new("qux", "0.1.0").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.1"
+                baz = "0.1"
+                qux = "0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+    p.cargo("generate-lockfile").run();
+
+    // Break entries of two crates in the lock file.
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    let mut index = repo.index().unwrap();
+    for name in ["bar", "baz"] {
+        let path = format!("3/b/{name}");
+        let reg_path = registry_path().join(&path);
+        let mut contents = fs::read_to_string(&reg_path).unwrap();
+        contents.push_str(&format!("{{\"name\":\"{name}\",\"vers\":\"0.1.1\"}}\n"));
+        fs::write(&reg_path, contents).unwrap();
+        index.add_path(Path::new(&path)).unwrap();
+    }
+    index.write().unwrap();
+    git::commit(&repo);
+    Package::new("qux", "0.1.1").publish();
+
+    // Unparsable entries are skipped by default.
+    p.cargo("update -p qux")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[UPDATING] qux v0.1.0 -> v0.1.1
+",
+        )
+        .run();
+
+    p.cargo("update -p qux")
+        .env("CARGO_REGISTRY_VALIDATE_LOCKED_INDEX", "true")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[ERROR] failed to get `bar` as a dependency of package `foo v0.0.1 ([CWD])`
+
+Caused by:
+  failed to query replaced source registry `crates-io`
+
+Caused by:
+  the index of registry `dummy-registry` has entries that fail to parse for packages in Cargo.lock:
+    `bar` line 2: missing field `deps` at line 1 column 29
+    `baz` line 2: missing field `deps` at line 1 column 29
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn prime_from_lock() {
+    use super::config::ConfigBuilder;
+    use cargo::core::PackageId;
+
+    let registry = registry::init();
+    let cksum = Package::new("bar", "0.1.0").publish();
+    Package::new("bar", "0.1.1").publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    let bar = PackageId::new("bar", "0.1.0", sid).unwrap();
+    source.prime_from_lock(&[bar]).unwrap();
+
+    // Both the in-memory and on-disk caches are filled, so the index isn't
+    // needed anymore to get the checksum.
+    let index_root = config.registry_index_path().into_path_unlocked();
+    let index = fs::read_dir(&index_root).unwrap().next().unwrap().unwrap();
+    assert!(index.path().join(".cache/3/b/bar").is_file());
+    fs::rename(index.path().join(".git"), index_root.join("moved")).unwrap();
+    let mut summaries = Vec::new();
+    let dep = cargo::core::Dependency::parse("bar", Some("=0.1.0"), sid).unwrap();
+    let _ =
+        cargo::core::Source::query(&mut source, &dep, cargo::core::QueryKind::Exact, &mut |s| {
+            summaries.push(s)
+        });
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].checksum(), Some(&*cksum));
+}
+
+#[cargo_test]
+fn dump_hashes() {
+    use super::config::ConfigBuilder;
+    use cargo::core::PackageId;
+
+    let registry = registry::init();
+    let bar = Package::new("bar", "0.1.0").publish();
+    let baz = Package::new("baz", "0.2.0").publish();
+    Package::new("qux", "0.3.0").publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    assert_eq!(source.dump_hashes(), Vec::new());
+
+    let pkgs = [
+        PackageId::new("baz", "0.2.0", sid).unwrap(),
+        PackageId::new("bar", "0.1.0", sid).unwrap(),
+    ];
+    source.prime_from_lock(&pkgs).unwrap();
+    assert_eq!(
+        source.dump_hashes(),
+        vec![
+            ("bar".to_string(), "0.1.0".to_string(), bar),
+            ("baz".to_string(), "0.2.0".to_string(), baz),
+        ]
+    );
+}
+
+#[cargo_test]
+fn index_entries_parsed_lazily() {
+    use super::config::ConfigBuilder;
+    use cargo::core::PackageId;
+
+    let registry = registry::init();
+    let cksum = Package::new("bar", "0.1.0").publish();
+    Package::new("bar", "0.1.1").publish();
+    Package::new("bar", "0.2.0").publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    let bar = PackageId::new("bar", "0.1.0", sid).unwrap();
+    source.prime_from_lock(&[bar]).unwrap();
+
+    // Reading the index file only parses the entry of the locked version.
+    assert_eq!(
+        source.dump_hashes(),
+        vec![("bar".to_string(), "0.1.0".to_string(), cksum)]
+    );
+}
+
+fn check_is_index_ready(registry: TestRegistry) {
+    use super::config::ConfigBuilder;
+    use cargo::core::Source;
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    assert!(!source.is_index_ready());
+    // Asking doesn't set anything up on disk.
+    assert!(!source.is_index_ready());
+
+    while source.config().is_pending() {
+        source.block_until_ready().unwrap();
+    }
+    assert!(source.is_index_ready());
+    let source = RegistrySource::remote(sid, &HashSet::new(), &config).unwrap();
+    assert!(source.is_index_ready());
+}
+
+#[cargo_test]
+fn is_index_ready_git() {
+    check_is_index_ready(registry::init());
+}
+
+#[cargo_test]
+fn is_index_ready_http() {
+    check_is_index_ready(setup_http());
+}
+
+#[cargo_test]
+fn bytes_transferred() {
+    use super::config::ConfigBuilder;
+    use cargo::core::{Dependency, PackageId, QueryKind, Source};
+
+    let registry = setup_http();
+    let bar = Package::new("bar", "0.1.0");
+    bar.publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    assert_eq!(source.bytes_transferred(), 0);
+
+    let dep = Dependency::parse("bar", Some("=0.1.0"), sid).unwrap();
+    while source
+        .query(&dep, QueryKind::Exact, &mut |_| {})
+        .is_pending()
+    {
+        source.block_until_ready().unwrap();
+    }
+    let index_bytes = source.bytes_transferred();
+    assert!(index_bytes > 0);
+
+    let pkg = PackageId::new("bar", "0.1.0", sid).unwrap();
+    source.download(pkg).unwrap();
+    let data = fs::read(bar.archive_dst()).unwrap();
+    let len = data.len() as u64;
+    source.finish_download(pkg, data).unwrap();
+    assert_eq!(source.bytes_transferred(), index_bytes + len);
+
+    source.reset_bytes_transferred();
+    assert_eq!(source.bytes_transferred(), 0);
+}
+
+#[cargo_test]
+fn parallel_gunzip() {
+    let registry = registry::init();
+    let contents = "// filler\n".repeat(10_000);
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", &contents)
+        .publish();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_PARALLEL_GUNZIP", "true")
+        .env("CARGO_REGISTRY_UNPACK_BUFFER_SIZE", "1024")
+        .run();
+    let bar = unpacked_bar(&registry);
+    assert_eq!(
+        fs::read_to_string(bar.join("src/lib.rs")).unwrap(),
+        contents
+    );
+    assert!(fs::read_to_string(bar.join(".cargo-ok"))
+        .unwrap()
+        .starts_with(r#"{"v":1,"package":"bar v0.0.1 (registry `dummy-registry`)""#));
+}
+
+#[cargo_test]
+fn api_crate_info() {
+    use super::config::ConfigBuilder;
+    use cargo_test_support::registry::Response;
+
+    let registry = RegistryBuilder::new()
+        .http_api()
+        .http_index()
+        .add_responder("/api/v1/crates/bar", |_, _| Response {
+            code: 200,
+            headers: vec![],
+            body: br#"{
+                "crate": {
+                    "name": "bar",
+                    "description": "a bar",
+                    "homepage": null,
+                    "documentation": null,
+                    "repository": "https://example.com/bar",
+                    "keywords": ["drinks"],
+                    "max_version": "0.1.0"
+                },
+                "versions": []
+            }"#
+            .to_vec(),
+        })
+        .build();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+
+    let info = source.api_crate_info("bar").unwrap();
+    assert_eq!(info.name, "bar");
+    assert_eq!(info.description.as_deref(), Some("a bar"));
+    assert_eq!(info.homepage, None);
+    assert_eq!(info.repository.as_deref(), Some("https://example.com/bar"));
+    assert_eq!(info.keywords, ["drinks"]);
+    assert!(info.categories.is_empty());
+    assert_eq!(info.max_version, "0.1.0");
+
+    let err = source.api_crate_info("baz").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "failed to fetch the metadata of `baz` from registry `{}`",
+            registry.index_url()
+        )
+    );
+}
+
+#[cargo_test]
+fn mirror_pins() {
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    bar.publish();
+    Package::new("baz", "0.0.1").publish();
+
+    // Only the mirror serves a working `bar`.
+    let mirror = paths::root().join("mirror");
+    let mirrored = mirror.join("bar/0.0.1/download");
+    fs::create_dir_all(mirrored.parent().unwrap()).unwrap();
+    fs::copy(bar.archive_dst(), &mirrored).unwrap();
+    fs::write(bar.archive_dst(), "broken").unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file(
+            ".cargo/config.toml",
+            &format!(
+                r#"
+                    [registry.mirror-pins]
+                    bar = "file:///nowhere"
+                    "bar:0.0.1" = "{}"
+                "#,
+                Url::from_file_path(&mirror).unwrap()
+            ),
+        )
+        .build();
+
+    p.cargo("fetch")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] baz v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn misfiled_index_entry() {
+    let _registry = RegistryBuilder::new().build();
+    Package::new("bar", "0.1.0").publish();
+    Package::new("bar", "0.1.1").publish();
+
+    // Rename 0.1.1 as if a `baz` entry had ended up in the file of `bar`.
+    let bar_path = Path::new("3/b/bar");
+    let bar_reg_path = registry_path().join(&bar_path);
+    let index = fs::read_to_string(&bar_reg_path).unwrap();
+    let (first, second) = index.split_once('\n').unwrap();
+    let second = second.replace(r#""name":"bar""#, r#""name":"baz""#);
+    fs::write(&bar_reg_path, format!("{first}\n{second}")).unwrap();
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    let mut git_index = repo.index().unwrap();
+    git_index.add_path(&bar_path).unwrap();
+    git_index.write().unwrap();
+    git::commit(&repo);
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("generate-lockfile")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[WARNING] skipping `baz v0.1.1` in the index file of `bar` in registry `dummy-registry`
+the package name doesn't match the file it is listed in, which means the index is corrupt
+",
+        )
+        .run();
+    assert!(p.read_lockfile().contains("0.1.0"));
+}
+
+#[cargo_test]
+fn cksum_algo_sha512() {
+    use sha2::{Digest, Sha512};
+
+    let _server = setup_http();
+    let bar = Package::new("bar", "0.0.1");
+    let cksum = bar.publish();
+    let sha512 = hex::encode(Sha512::digest(fs::read(bar.archive_dst()).unwrap()));
+    let index_path = registry_path().join("3/b/bar");
+    let index = fs::read_to_string(&index_path).unwrap();
+    let cksum = format!(r#""cksum":"{cksum}""#);
+    let set_cksum = |value: &str, algo: &str| {
+        let with_algo = format!(r#""cksum":"{value}","cksum_algo":"{algo}""#);
+        fs::write(&index_path, index.replace(&cksum, &with_algo)).unwrap();
+    };
+    let p = symlink_project();
+
+    set_cksum(&sha512, "blake3");
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "[..]the index lists a checksum of `bar v0.0.1 (registry `dummy-registry`)` \
+             with the unsupported algorithm `blake3`, so it can't be verified",
+        )
+        .run();
+
+    set_cksum(&sha512, "sha512");
+    remove_dir_all(cargo_home().join("registry/index")).unwrap();
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+    // Only sha256 checksums are recorded in the lock file.
+    assert!(!p.read_lockfile().contains("checksum"));
+}
+
+#[cargo_test]
+fn dl_size_mismatch_aborts() {
+    let _server = setup_http();
+    let bar = Package::new("bar", "0.0.1");
+    let cksum = bar.publish();
+    let size = fs::metadata(bar.archive_dst()).unwrap().len();
+    let index_path = registry_path().join("3/b/bar");
+    let index = fs::read_to_string(&index_path).unwrap();
+    let set_size = |size: u64| {
+        let cksum = format!(r#""cksum":"{cksum}""#);
+        let with_size = format!(r#"{cksum},"size":{size}"#);
+        fs::write(&index_path, index.replace(&cksum, &with_size)).unwrap();
+    };
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    set_size(size + 1);
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr(&format!(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `[..]/dl/bar/0.0.1/download`
+
+Caused by:
+  the server sends {size} bytes for `bar v0.0.1`, but the index lists {} bytes
+",
+            size + 1
+        ))
+        .run();
+
+    set_size(size);
+    // Don't pick up the previous size from the index cache.
+    remove_dir_all(cargo_home().join("registry/index")).unwrap();
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn dl_size_not_checked_for_error_pages() {
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/dl/bar/0.0.1/download", |_, _| Response {
+            code: 404,
+            headers: vec![],
+            body: b"this error page is larger than the package".to_vec(),
+        })
+        .build();
+    let cksum = Package::new("bar", "0.0.1").publish();
+    let index_path = registry_path().join("3/b/bar");
+    let index = fs::read_to_string(&index_path).unwrap();
+    let cksum = format!(r#""cksum":"{cksum}""#);
+    fs::write(
+        &index_path,
+        index.replace(&cksum, &format!(r#"{cksum},"size":1"#)),
+    )
+    .unwrap();
+
+    let p = symlink_project();
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `http://127.0.0.1:[..]/dl/bar/0.0.1/download`
+
+Caused by:
+  `bar v0.0.1` was not found on the download server of its registry
+
+Caused by:
+  failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/0.0.1/download` (127.0.0.1), got 404
+  body:
+  this error page is larger than the package
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn dl_size_mismatch_without_content_length() {
+    use std::io::{BufRead, BufReader, Write as _};
+    use std::net::TcpListener;
+
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    let cksum = bar.publish();
+    let crate_data = fs::read(bar.archive_dst()).unwrap();
+    let size = crate_data.len();
+    let half = size / 2;
+    let index_path = registry_path().join("3/b/bar");
+    let index = fs::read_to_string(&index_path).unwrap();
+    let cksum = format!(r#""cksum":"{cksum}""#);
+    let with_size = format!(r#"{cksum},"size":{size}"#);
+    fs::write(&index_path, index.replace(&cksum, &with_size)).unwrap();
+    let repo = git2::Repository::open(registry_path()).unwrap();
+    git::add(&repo);
+    git::commit(&repo);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let mut conn = BufReader::new(listener.accept().unwrap().0);
+        for line in (&mut conn).lines() {
+            if line.unwrap().is_empty() {
+                break;
+            }
+        }
+        // Without a `Content-Length` the body just ends when the
+        // connection is closed, so it looks complete.
+        let conn = conn.get_mut();
+        conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").unwrap();
+        conn.write_all(&crate_data[..half]).unwrap();
+    });
+
+    let p = symlink_project();
+    p.change_file(
+        ".cargo/config.toml",
+        &format!(
+            r#"
+                [registry.mirror-pins]
+                bar = "http://{addr}"
+            "#
+        ),
+    );
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(&format!(
+            "  received {half} bytes for `bar v0.0.1 (registry `dummy-registry`)`, \
+             but the index lists {size} bytes"
+        ))
+        .run();
+    server.join().unwrap();
+}
+
+#[cargo_test]
+fn invalid_config_json() {
+    let _server = setup_http();
+    Package::new("bar", "0.0.1").publish();
+    let config_path = registry_path().join("config.json");
+    let p = symlink_project();
+
+    fs::write(&config_path, r#"{"api": "http://example.com"}"#).unwrap();
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "\
+[..]failed to parse the registry configuration at `http://127.0.0.1:[..]/index/config.json`, \
+the registry index may be stale or corrupt
+
+Caused by:
+  the `dl` key is missing, it is needed to know where to download crates from",
+        )
+        .run();
+
+    fs::write(&config_path, "").unwrap();
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "\
+[..]failed to parse the registry configuration at `http://127.0.0.1:[..]/index/config.json`, \
+the registry index may be stale or corrupt
+
+Caused by:
+  EOF while parsing a value at line 1 column 0",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn http_user_agent() {
+    let agents = Arc::new(Mutex::new(Vec::new()));
+    let (index_agents, dl_agents) = (agents.clone(), agents.clone());
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/index/3/b/bar", move |req, server| {
+            index_agents.lock().unwrap().push(req.user_agent.clone());
+            server.index(req)
+        })
+        .add_responder("/dl/bar/0.0.1/download", move |req, server| {
+            dl_agents.lock().unwrap().push(req.user_agent.clone());
+            server.dl(req)
+        })
+        .build();
+    Package::new("bar", "0.0.1").publish();
+    let p = symlink_project();
+
+    p.cargo("fetch").run();
+    let sent = std::mem::take(&mut *agents.lock().unwrap());
+    assert_eq!(sent.len(), 2);
+    for agent in sent {
+        assert!(agent.unwrap().starts_with("cargo 1."));
+    }
+
+    clear_registry_cache();
+    remove_dir_all(cargo_home().join("registry/index")).unwrap();
+    p.cargo("fetch")
+        .env("CARGO_HTTP_USER_AGENT", "acme-ci/1.0")
+        .run();
+    let sent = std::mem::take(&mut *agents.lock().unwrap());
+    assert_eq!(sent, vec![Some("acme-ci/1.0".to_string()); 2]);
+}
+
+#[cargo_test]
+fn download_not_found() {
+    let _server = setup_http();
+    Package::new("bar", "0.0.1").publish();
+    fs::remove_file(paths::root().join("dl/bar/0.0.1/download")).unwrap();
+
+    let p = symlink_project();
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `http://127.0.0.1:[..]/dl/bar/0.0.1/download`
+
+Caused by:
+  `bar v0.0.1` was not found on the download server of its registry
+
+Caused by:
+  failed to get successful HTTP response from `http://127.0.0.1:[..]/dl/bar/0.0.1/download` (127.0.0.1), got 404
+  body:
+  not found
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn max_concurrent_downloads() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // Unlike the server of the registry, this one answers every download on
+    // a thread of its own, so that downloads at the same time would overlap.
+    let dl = TcpListener::bind("127.0.0.1:0").unwrap();
+    let dl_addr = dl.local_addr().unwrap();
+    let active = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let root = paths::root();
+    let dl_server = {
+        let (active, peak) = (active.clone(), peak.clone());
+        std::thread::spawn(move || {
+            let handlers = dl
+                .incoming()
+                .take(3)
+                .map(|socket| {
+                    let (active, peak) = (active.clone(), peak.clone());
+                    let root = root.clone();
+                    let socket = socket.unwrap();
+                    std::thread::spawn(move || {
+                        peak.fetch_max(active.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                        let mut buf = BufReader::new(socket);
+                        let mut line = String::new();
+                        buf.read_line(&mut line).unwrap();
+                        let path = line.split_whitespace().nth(1).unwrap().to_string();
+                        while buf.read_line(&mut line).unwrap() > 2 {
+                            line.clear();
+                        }
+                        std::thread::sleep(Duration::from_millis(100));
+                        let body = fs::read(root.join(&path[1..])).unwrap();
+                        // The next download may only start once this one is
+                        // answered.
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        let head = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            body.len()
+                        );
+                        let socket = buf.get_mut();
+                        socket.write_all(head.as_bytes()).unwrap();
+                        socket.write_all(&body).unwrap();
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handler in handlers {
+                handler.join().unwrap();
+            }
+        })
+    };
+
+    let server = setup_http();
+    server.edit_config(|config| {
+        config["dl"] = format!("http://{dl_addr}/dl").into();
+        config["max-concurrent-downloads"] = 1.into();
+    });
+
+    Package::new("bar", "0.0.1").publish();
+    Package::new("baz", "0.0.1").publish();
+    Package::new("qux", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.0.1"
+                qux = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        // Otherwise curl waits to reuse the first connection for the other
+        // downloads, instead of opening one for each of them right away.
+        .env("CARGO_HTTP_MULTIPLEXING", "false")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] baz v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] qux v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+    dl_server.join().unwrap();
+    assert_eq!(peak.load(Ordering::SeqCst), 1);
+}
+
+#[cargo_test]
+fn max_concurrent_downloads_with_failed_download() {
+    let server = setup_http();
+    server.edit_config(|config| config["max-concurrent-downloads"] = 1.into());
+
+    Package::new("bar", "0.0.1").publish();
+    Package::new("baz", "0.0.1").publish();
+    Package::new("qux", "0.0.1").publish();
+    fs::remove_file(paths::root().join("dl/bar/0.0.1/download")).unwrap();
+    fs::remove_file(paths::root().join("dl/baz/0.0.1/download")).unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.0.1"
+                qux = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // The failed downloads free their slot for the ones after them.
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains("[DOWNLOADED] qux v0.0.1 (registry `dummy-registry`)")
+        .with_stderr_contains("  `bar v0.0.1` was not found on the download server of its registry")
+        .with_stderr_contains("  `baz v0.0.1` was not found on the download server of its registry")
+        .run();
+}
+
+fn redirecting_registry() -> TestRegistry {
+    RegistryBuilder::new()
+        .http_index()
+        .add_responder("/dl/bar/0.0.1/download", |req, server| {
+            if req.url.query() == Some("redirected") {
+                return server.dl(req);
+            }
+            // The server listens on 127.0.0.1, redirect to another name of it.
+            let port = req.url.port().unwrap();
+            Response {
+                code: 302,
+                headers: vec![format!(
+                    "Location: http://localhost:{port}/dl/bar/0.0.1/download?redirected"
+                )],
+                body: Vec::new(),
+            }
+        })
+        .build()
+}
+
+#[cargo_test]
+fn redirect_to_allowed_host() {
+    let _server = redirecting_registry();
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_ALLOWED_REDIRECT_HOSTS", "localhost")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn download_endpoint_after_redirect() {
+    use super::config::ConfigBuilder;
+    use cargo::core::Workspace;
+    use cargo::ops;
+
+    let server = redirecting_registry();
+    Package::new("bar", "0.0.1").publish();
+    let p = cache_store_project();
+    // The cargo home of `ConfigBuilder` is the home directory itself.
+    fs::copy(
+        paths::home().join(".cargo/config"),
+        paths::home().join("config"),
+    )
+    .unwrap();
+
+    let config = ConfigBuilder::new().cwd("foo").build();
+    let ws = Workspace::new(&p.root().join("Cargo.toml"), &config).unwrap();
+    let (mut pkg_set, resolve) = ops::resolve_ws(&ws).unwrap();
+    let bar = resolve.iter().find(|id| id.name() == "bar").unwrap();
+    pkg_set.record_download_endpoints();
+    pkg_set.get_one(bar).unwrap();
+    let port = server.index_url().port().unwrap();
+    assert_eq!(
+        pkg_set.download_endpoint(bar).unwrap(),
+        format!("http://localhost:{port}/dl/bar/0.0.1/download?redirected")
+    );
+}
+
+#[cargo_test]
+fn redirect_to_disallowed_host() {
+    let _server = redirecting_registry();
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env(
+            "CARGO_REGISTRY_ALLOWED_REDIRECT_HOSTS",
+            "mirror.example.com",
+        )
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `http://127.0.0.1:[..]/dl/bar/0.0.1/download`
+
+Caused by:
+  refusing to follow the redirect of `http://127.0.0.1:[..]/dl/bar/0.0.1/download` \
+for `bar v0.0.1` to `http://localhost:[..]/dl/bar/0.0.1/download?redirected`
+  downloads may only be redirected to the host of the download URL or to the hosts \
+in `registry.allowed-redirect-hosts`
+",
+        )
+        .run();
+
+    // Without the setting, any redirect is followed.
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn relative_dl_url() {
+    let server = setup_http();
+    server.edit_config(|config| config["dl"] = "/dl".into());
+
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn relative_config_urls() {
+    use super::config::ConfigBuilder;
+    use cargo::core::Source;
+    use std::task::Poll;
+
+    let registry = setup_http();
+    registry.edit_config(|config| {
+        config["dl"] = "/dl/{crate}/{version}".into();
+        config["api"] = "/api".into();
+    });
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+    let cfg = loop {
+        match source.config() {
+            Poll::Ready(cfg) => break cfg.unwrap().unwrap(),
+            Poll::Pending => source.block_until_ready().unwrap(),
+        }
+    };
+    // The markers of `dl` are only replaced for a download, so resolving it
+    // here would escape them.
+    assert_eq!(cfg.dl, "/dl/{crate}/{version}");
+    let index_url = registry.index_url().as_str();
+    let base = Url::parse(index_url.strip_prefix("sparse+").unwrap_or(index_url)).unwrap();
+    assert_eq!(
+        cfg.api.as_deref(),
+        Some(base.join("/api").unwrap().as_str())
+    );
+}
+
+#[cargo_test]
+fn max_download_size() {
+    let _server = setup_http();
+    Package::new("bar", "0.0.1").publish();
+    let p = symlink_project();
+
+    p.cargo("fetch")
+        .env("CARGO_HTTP_MAX_DOWNLOAD_SIZE", "10B")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[ERROR] failed to download from `[..]/dl/bar/0.0.1/download`
+
+Caused by:
+  `bar v0.0.1` is larger than the 10 B of `http.max-download-size`
+",
+        )
+        .run();
+
+    p.cargo("fetch")
+        .env("CARGO_HTTP_MAX_DOWNLOAD_SIZE", "1MB")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn download_size_warning() {
+    let _server = setup_http();
+    let mut sizes = Vec::new();
+    for (name, path) in [("bar", "3/b/bar"), ("baz", "3/b/baz")] {
+        let pkg = Package::new(name, "0.0.1");
+        let cksum = pkg.publish();
+        let size = fs::metadata(pkg.archive_dst()).unwrap().len();
+        let index_path = registry_path().join(path);
+        let index = fs::read_to_string(&index_path).unwrap();
+        let cksum = format!(r#""cksum":"{cksum}""#);
+        let with_size = format!(r#"{cksum},"size":{size}"#);
+        fs::write(&index_path, index.replace(&cksum, &with_size)).unwrap();
+        sizes.push(size);
+    }
+    Package::new("qux", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.0.1"
+                qux = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    let total = sizes[0] + sizes[1];
+    p.cargo("fetch")
+        .env(
+            "CARGO_REGISTRY_DOWNLOAD_SIZE_WARNING",
+            format!("{}B", total),
+        )
+        .with_stderr_unordered(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] baz v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] qux v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+
+    remove_dir_all(cargo_home().join("registry/cache")).unwrap();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_DOWNLOAD_SIZE_WARNING", "100B")
+        .with_stderr_unordered(
+            "\
+[DOWNLOADING] crates ...
+[WARNING] downloading [..] of packages, more than the 100 B of `registry.download-size-warning`
+the largest downloads are:
+  bar v0.0.1 ([..] B)
+  baz v0.0.1 ([..] B)
+1 more package(s) of unknown size aren't counted
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] baz v0.0.1 (registry `dummy-registry`)
+[DOWNLOADED] qux v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn unpack_collision() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch").run();
+
+    // Pretend another package with the same name and version was unpacked
+    // in its place.
+    let ok = unpacked_bar(&registry).join(".cargo-ok");
+    fs::write(&ok, r#"{"package":"bar v0.0.1 (other)","cksum":"1234"}"#).unwrap();
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr(
+            "\
+[ERROR] failed to download `bar v0.0.1`
+
+Caused by:
+  unable to get packages from source
+
+Caused by:
+  failed to download replaced source registry `crates-io`
+
+Caused by:
+  failed to unpack package `bar v0.0.1 (registry `dummy-registry`)`
+
+Caused by:
+  `[..]bar-0.0.1` already contains `bar v0.0.1 (other)` unpacked from a `.crate` \
+file with checksum `1234`, but `bar v0.0.1 (registry `dummy-registry`)` has checksum `[..]`
+  refusing to overwrite it; if the package changed in the registry, remove the \
+directory to unpack it again
+",
+        )
+        .run();
+
+    // Packages unpacked by older versions of Cargo are trusted.
+    fs::write(&ok, "ok").unwrap();
+    p.cargo("check")
+        .with_stderr_contains("[FINISHED] [..]")
+        .run();
+}
+
+#[cargo_test]
+fn verify_unpacked_manifest() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("check")
+        .env("CARGO_REGISTRY_VERIFY_UNPACKED_MANIFEST", "true")
+        .run();
+    let ok = fs::read_to_string(unpacked_bar(&registry).join(".cargo-ok")).unwrap();
+    assert!(ok.contains(r#""manifest_cksum":"#), "{ok}");
+
+    // Reused as is.
+    p.cargo("check")
+        .env("CARGO_REGISTRY_VERIFY_UNPACKED_MANIFEST", "true")
+        .with_stderr("[FINISHED] [..]")
+        .run();
+
+    let manifest = unpacked_bar(&registry).join("Cargo.toml");
+    let mut contents = fs::read_to_string(&manifest).unwrap();
+    contents.push_str("\n[features]\nevil = []\n");
+    fs::write(&manifest, contents).unwrap();
+    p.cargo("check")
+        .env("CARGO_REGISTRY_VERIFY_UNPACKED_MANIFEST", "true")
+        .with_status(101)
+        .with_stderr(
+            "\
+[ERROR] failed to download `bar v0.0.1`
+
+Caused by:
+  unable to get packages from source
+
+Caused by:
+  failed to download replaced source registry `crates-io`
+
+Caused by:
+  failed to unpack package `bar v0.0.1 (registry `dummy-registry`)`
+
+Caused by:
+  the `Cargo.toml` of `bar v0.0.1 (registry `dummy-registry`)` in `[..]bar-0.0.1` has \
+checksum `[..]`, but it had checksum `[..]` when it was unpacked
+  the file was modified after unpacking; remove the directory to unpack the package again
+",
+        )
+        .run();
+
+    // Not checked unless enabled.
+    p.cargo("check").with_stderr("[FINISHED] [..]").run();
+}
+
+fn cache_store_project() -> Project {
+    project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build()
+}
+
+/// Forgets everything the previous runs of Cargo downloaded, like a fresh
+/// CI runner.
+fn clear_registry_cache() {
+    paths::home().join(".cargo/registry/cache").rm_rf();
+    paths::home().join(".cargo/registry/src").rm_rf();
+}
+
+#[cargo_test]
+fn cache_store_path() {
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    let cksum = bar.publish();
+    let store = paths::root().join("store");
+    let entry = store.join(format!("bar-0.0.1-{cksum}.crate"));
+
+    let p = cache_store_project();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_CACHE_STORE_PATH", &store)
+        .with_stderr_contains("[DOWNLOADED] bar v0.0.1 [..]")
+        .run();
+    assert_eq!(
+        fs::read(&entry).unwrap(),
+        fs::read(bar.archive_dst()).unwrap()
+    );
+
+    // The registry doesn't have it anymore, but the store does.
+    fs::remove_file(bar.archive_dst()).unwrap();
+    clear_registry_cache();
+    p.cargo("check")
+        .env("CARGO_REGISTRY_CACHE_STORE_PATH", &store)
+        .with_stderr(
+            "\
+[CHECKING] bar v0.0.1
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] [..]
+",
+        )
+        .run();
+
+    // A corrupt entry isn't used.
+    fs::write(&entry, "not a crate").unwrap();
+    clear_registry_cache();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_CACHE_STORE_PATH", &store)
+        .with_status(101)
+        .with_stderr_contains(
+            "[WARNING] the cache store has a corrupt copy of `bar v0.0.1 [..]`, \
+             downloading it instead",
+        )
+        .with_stderr_contains("[ERROR] failed to download from `[..]/bar/0.0.1/download`")
+        .run();
+}
+
+#[cargo_test]
+fn cache_store_compression_level() {
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    let cksum = bar.publish();
+    let store = paths::root().join("store");
+
+    let p = cache_store_project();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_CACHE_STORE_PATH", &store)
+        .env("CARGO_REGISTRY_CACHE_STORE_COMPRESSION_LEVEL", "9")
+        .run();
+    assert!(!store.join(format!("bar-0.0.1-{cksum}.crate")).exists());
+    let entry = store.join(format!("bar-0.0.1-{cksum}.crate.recompressed"));
+    assert_ne!(
+        fs::read(&entry).unwrap()[1..],
+        fs::read(bar.archive_dst()).unwrap()[..]
+    );
+
+    // The original is restored from the recompressed entry, so it still
+    // matches the checksum in the index.
+    fs::remove_file(bar.archive_dst()).unwrap();
+    clear_registry_cache();
+    p.cargo("check")
+        .env("CARGO_REGISTRY_CACHE_STORE_PATH", &store)
+        .with_stderr(
+            "\
+[CHECKING] bar v0.0.1
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] [..]
+",
+        )
+        .run();
+
+    clear_registry_cache();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_CACHE_STORE_PATH", &store)
+        .env("CARGO_REGISTRY_CACHE_STORE_COMPRESSION_LEVEL", "10")
+        .with_status(101)
+        .with_stderr_contains(
+            "[..]`registry.cache-store.compression-level` must be between 0 and 9, got 10",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn cache_store_command() {
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    let cksum = bar.publish();
+
+    // Keeps the entries in the directory it is passed, and logs what it does.
+    let store = project()
+        .at("store")
+        .file("Cargo.toml", &basic_manifest("store", "1.0.0"))
+        .file(
+            "src/main.rs",
+            r#"
+                use std::fs;
+                use std::io::Write;
+                use std::path::Path;
+
+                fn main() {
+                    let args: Vec<String> = std::env::args().skip(1).collect();
+                    let dir = Path::new(&args[0]);
+                    let entry = dir.join(format!("{}-{}-{}", args[2], args[3], args[4]));
+                    let mut log = fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(dir.join("log"))
+                        .unwrap();
+                    writeln!(log, "{} {} {}", args[1], args[2], args[3]).unwrap();
+                    match args[1].as_str() {
+                        "get" => {
+                            if entry.exists() {
+                                fs::copy(&entry, &args[5]).unwrap();
+                            }
+                        }
+                        "put" => {
+                            fs::copy(&args[5], &entry).unwrap();
+                        }
+                        "exists" => println!("{}", entry.exists()),
+                        _ => std::process::exit(1),
+                    }
+                }
+            "#,
+        )
+        .build();
+    store.cargo("build").run();
+    let dir = paths::root().join("store-data");
+    fs::create_dir(&dir).unwrap();
+
+    let p = cache_store_project();
+    p.change_file(
+        ".cargo/config",
+        &format!(
+            r#"
+                [registry.cache-store]
+                command = ["{}", "{}"]
+            "#,
+            store
+                .bin("store")
+                .display()
+                .to_string()
+                .replace('\\', "\\\\"),
+            dir.display().to_string().replace('\\', "\\\\"),
+        ),
+    );
+    p.cargo("fetch").run();
+    assert!(dir.join(format!("bar-0.0.1-{cksum}")).is_file());
+
+    fs::remove_file(bar.archive_dst()).unwrap();
+    clear_registry_cache();
+    p.cargo("check").run();
+    assert_eq!(
+        fs::read_to_string(dir.join("log")).unwrap(),
+        "get bar 0.0.1\nexists bar 0.0.1\nput bar 0.0.1\nget bar 0.0.1\n"
+    );
+}
+
+#[cargo_test]
+fn checksum_mismatch_rotates_mirrors() {
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    bar.publish();
+    let good = fs::read(bar.archive_dst()).unwrap();
+    // The same size as the real thing, but different content.
+    let bad: Vec<u8> = good.iter().rev().copied().collect();
+    fs::write(bar.archive_dst(), &bad).unwrap();
+    let mirror = |name: &str, data: &[u8]| {
+        let dir = paths::root().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("bar-0.0.1.crate"), data).unwrap();
+        Url::from_file_path(&dir).unwrap().to_string() + "/{crate}-{version}.crate"
+    };
+    let bad_mirror = mirror("bad-mirror", &bad);
+    let good_mirror = mirror("good-mirror", &good);
+
+    let p = cache_store_project();
+    p.change_file(
+        ".cargo/config",
+        &format!(
+            r#"
+                [registry]
+                download-mirrors = ["{bad_mirror}", "{good_mirror}"]
+            "#
+        ),
+    );
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[WARNING] `bar v0.0.1 (registry `dummy-registry`)` downloaded from `[..]/dl/bar/0.0.1/download` \
+doesn't match the checksum in the index, downloading it again
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[WARNING] `bar v0.0.1 (registry `dummy-registry`)` downloaded from `[..]/bad-mirror/bar-0.0.1.crate` \
+doesn't match the checksum in the index, downloading it again
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+
+    // Only fails once every mirror served the wrong content.
+    fs::write(paths::root().join("good-mirror/bar-0.0.1.crate"), &bad).unwrap();
+    clear_registry_cache();
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "\
+Caused by:
+  failed to verify the checksum of `bar v0.0.1 (registry `dummy-registry`)`, the downloads \
+from these URLs don't match the checksum in the index:
+    [..]/dl/bar/0.0.1/download
+    [..]/bad-mirror/bar-0.0.1.crate
+    [..]/good-mirror/bar-0.0.1.crate
+",
+        )
+        .run();
+
+    // Without mirrors, the registry is asked again.
+    p.change_file(".cargo/config", "");
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_CHECKSUM_MISMATCH_RETRIES", "2")
+        .with_status(101)
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[WARNING] `bar v0.0.1 [..]` downloaded from `[..]/download` doesn't match [..]
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[WARNING] `bar v0.0.1 [..]` downloaded from `[..]/download` doesn't match [..]
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[ERROR] failed to download replaced source registry `crates-io`
+
+Caused by:
+  failed to verify the checksum of `bar v0.0.1 (registry `dummy-registry`)`, the downloads \
+from these URLs don't match the checksum in the index:
+    [..]/dl/bar/0.0.1/download
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn interrupted_download_resumed() {
+    use std::io::{BufRead, BufReader, Write as _};
+    use std::net::TcpListener;
+
+    let registry = registry::init();
+    let mut bar = Package::new("bar", "0.0.1");
+    bar.file("src/lib.rs", "pub fn f() {}");
+    bar.publish();
+    let crate_data = fs::read(bar.archive_dst()).unwrap();
+    let half = crate_data.len() / 2;
+
+    let p = symlink_project();
+    for honor_range in [false, true] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let crate_data = crate_data.clone();
+        let server = std::thread::spawn(move || {
+            let mut ranges = Vec::new();
+            for attempt in 0..2 {
+                let mut conn = BufReader::new(listener.accept().unwrap().0);
+                let headers: Vec<_> = (&mut conn)
+                    .lines()
+                    .map(|line| line.unwrap())
+                    .take_while(|line| !line.is_empty())
+                    .collect();
+                ranges.push(
+                    headers
+                        .iter()
+                        .find_map(|h| h.strip_prefix("Range: ").map(str::to_string)),
+                );
+                let conn = conn.get_mut();
+                let len = crate_data.len();
+                if attempt == 0 {
+                    // The connection drops halfway through.
+                    write!(conn, "HTTP/1.1 200 OK\r\nContent-Length: {len}\r\n\r\n").unwrap();
+                    conn.write_all(&crate_data[..half]).unwrap();
+                } else if honor_range {
+                    write!(
+                        conn,
+                        "HTTP/1.1 206 Partial Content\r\n\
+                         Content-Length: {}\r\n\
+                         Content-Range: bytes {half}-{}/{len}\r\n\r\n",
+                        len - half,
+                        len - 1
+                    )
+                    .unwrap();
+                    conn.write_all(&crate_data[half..]).unwrap();
+                } else {
+                    // Sends everything again, as if there was no `Range`.
+                    write!(conn, "HTTP/1.1 200 OK\r\nContent-Length: {len}\r\n\r\n").unwrap();
+                    conn.write_all(&crate_data).unwrap();
+                }
+            }
+            ranges
+        });
+
+        p.change_file(
+            ".cargo/config.toml",
+            &format!(
+                r#"
+                    [registry.mirror-pins]
+                    bar = "http://{addr}"
+                "#
+            ),
+        );
+        clear_registry_cache();
+        p.cargo("fetch")
+            .env("__CARGO_TEST_FIXED_RETRY_SLEEP_MS", "1")
+            .with_stderr_contains("[WARNING] spurious network error (3 tries remaining): [..]")
+            .with_stderr_contains("[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)")
+            .run();
+        assert_eq!(
+            server.join().unwrap(),
+            [None, Some(format!("bytes={half}-"))]
+        );
+        assert_eq!(
+            fs::read_to_string(unpacked_bar(&registry).join("src/lib.rs")).unwrap(),
+            "pub fn f() {}"
+        );
+    }
+}
+
+#[cargo_test]
+fn prefer_download_mirrors_falls_through() {
+    registry::init();
+    let bar = Package::new("bar", "0.0.1");
+    bar.publish();
+    let good_dir = paths::root().join("good-mirror");
+    fs::create_dir_all(&good_dir).unwrap();
+    fs::copy(bar.archive_dst(), good_dir.join("bar-0.0.1.crate")).unwrap();
+    let good_mirror =
+        Url::from_file_path(&good_dir).unwrap().to_string() + "/{crate}-{version}.crate";
+    // Doesn't have any files.
+    let missing_mirror = Url::from_file_path(paths::root().join("missing-mirror"))
+        .unwrap()
+        .to_string()
+        + "/{crate}-{version}.crate";
+
+    let p = cache_store_project();
+    p.change_file(
+        ".cargo/config",
+        &format!(
+            r#"
+                [net]
+                retry = 0
+
+                [registry]
+                download-mirrors = ["{missing_mirror}", "{good_mirror}"]
+                prefer-download-mirrors = true
+            "#
+        ),
+    );
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[WARNING] failed to download `bar v0.0.1 (registry `dummy-registry`)` from \
+`[..]/missing-mirror/bar-0.0.1.crate`, trying the next download URL
+the error was: [..]
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+
+    // The registry itself comes last.
+    fs::remove_file(good_dir.join("bar-0.0.1.crate")).unwrap();
+    clear_registry_cache();
+    p.cargo("fetch")
+        .with_stderr_contains(
+            "[WARNING] failed to download [..]/good-mirror/bar-0.0.1.crate`, [..]",
+        )
+        .with_stderr_contains("[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)")
+        .run();
+}
+
+#[cargo_test]
+fn unpack_marker_versions() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+    let p = cache_store_project();
+    p.cargo("fetch").run();
+    let dir = unpacked_bar(&registry);
+    let ok = dir.join(".cargo-ok");
+    assert!(fs::read_to_string(&ok).unwrap().starts_with(r#"{"v":1,"#));
+    let was_reused = || {
+        let reused = dir.join("stray").exists();
+        fs::write(dir.join("stray"), "").unwrap();
+        reused
+    };
+    assert!(!was_reused());
+
+    // A newer version of Cargo may have unpacked it differently.
+    fs::write(&ok, r#"{"v":2,"package":{"name":"bar"}}"#).unwrap();
+    p.cargo("fetch").run();
+    assert!(!was_reused());
+    assert!(fs::read_to_string(&ok).unwrap().starts_with(r#"{"v":1,"#));
+
+    // Older versions of Cargo didn't record anything.
+    fs::write(&ok, "ok").unwrap();
+    p.cargo("fetch").run();
+    assert!(was_reused());
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_LEGACY_UNPACK_MARKERS", "strict")
+        .run();
+    assert!(!was_reused());
+    assert!(fs::read_to_string(&ok).unwrap().starts_with(r#"{"v":1,"#));
+}
+
+#[cargo_test]
+fn normalized_index_versions() {
+    let _server = setup_http();
+    Package::new("bar", "1.0.0").publish();
+    let index_path = registry_path().join("3/b/bar");
+    let index = fs::read_to_string(&index_path).unwrap();
+    fs::write(
+        &index_path,
+        index.replace(r#""vers":"1.0.0""#, r#""vers":"1.0""#),
+    )
+    .unwrap();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "=1.0.0"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v1.0.0 (registry `dummy-registry`)
+",
+        )
+        .run();
+    assert!(p.read_lockfile().contains("version = \"1.0.0\""));
+    let cache = cargo_home().join("registry/cache");
+    let cache = fs::read_dir(&cache)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    assert!(cache.join("bar-1.0.0.crate").is_file());
+
+    p.cargo("generate-lockfile")
+        .env("CARGO_REGISTRY_NORMALIZE_VERSIONS", "false")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] no matching package named `bar` found")
+        .run();
+}
+
+#[cargo_test]
+fn max_index_age() {
+    registry::init();
+    Package::new("bar", "0.0.1").publish();
+    let p = cache_store_project();
+    p.cargo("generate-lockfile").run();
+
+    // With a lock file, a recent enough index isn't updated.
+    Package::new("bar", "0.0.2").publish();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_MAX_INDEX_AGE", "1h")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+
+    let index = fs::read_dir(cargo_home().join("registry/index"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let two_hours_ago = filetime::FileTime::from_system_time(
+        std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 60 * 60),
+    );
+    filetime::set_file_mtime(index.join(".last-updated"), two_hours_ago).unwrap();
+    p.cargo("fetch").with_stderr("").run();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_MAX_INDEX_AGE", "1h")
+        .with_stderr(
+            "\
+[NOTE] the index of registry `dummy-registry` was last updated 2h [..]ago, \
+longer ago than the `registry.max-index-age` of 1h
+[UPDATING] `dummy-registry` index
+",
+        )
+        .run();
+    // The update reset the age.
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_MAX_INDEX_AGE", "1h")
+        .with_stderr("")
+        .run();
+}
+
+#[cargo_test]
+fn sorted_download_order() {
+    let _server = setup_http();
+    for name in ["zed", "alpha", "mid"] {
+        Package::new(name, "0.1.0").publish();
+    }
+    Package::new("alpha", "0.2.0").publish();
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                zed = "0.1.0"
+                mid = "0.1.0"
+                alpha = "0.2.0"
+                alpha01 = { package = "alpha", version = "0.1.0" }
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_DOWNLOAD_ORDER", "sorted")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] alpha v0.1.0 (registry `dummy-registry`)
+[DOWNLOADED] alpha v0.2.0 (registry `dummy-registry`)
+[DOWNLOADED] mid v0.1.0 (registry `dummy-registry`)
+[DOWNLOADED] zed v0.1.0 (registry `dummy-registry`)
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn session_login() {
+    // Every login hands out a new session, and the first one is treated as
+    // if it expired right away.
+    let logins = Arc::new(Mutex::new(0));
+    let logins2 = logins.clone();
+    let mut builder = RegistryBuilder::new()
+        .alternative()
+        .http_index()
+        .http_api()
+        .add_responder("/login", move |req, _| {
+            let mut logins = logins2.lock().unwrap();
+            *logins += 1;
+            assert_eq!(req.method, "post");
+            assert_eq!(req.authorization.as_deref(), Some("Basic dXNlcjpwYXNz"));
+            Response {
+                code: 200,
+                headers: vec![format!("Set-Cookie: session={}; Path=/; HttpOnly", logins)],
+                body: vec![],
+            }
+        });
+    for name in ["bar", "baz"] {
+        builder =
+            builder.add_responder(format!("/dl/{name}/0.0.1/download"), move |req, server| {
+                if req.cookie.as_deref() == Some("session=2") {
+                    server.dl(req)
+                } else {
+                    server.unauthorized(req)
+                }
+            });
+    }
+    let _registry = builder.build();
+    Package::new("bar", "0.0.1").alternative(true).publish();
+    Package::new("baz", "0.0.1").alternative(true).publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = { version = "0.0.1", registry = "alternative" }
+                baz = { version = "0.0.1", registry = "alternative" }
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_SESSION_LOGIN", "true")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_USERNAME", "user")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_PASSWORD", "pass")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `alternative` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `alternative`)
+[DOWNLOADED] baz v0.0.1 (registry `alternative`)
+",
+        )
+        .run();
+    // Both downloads were rejected, but only one logged in again.
+    assert_eq!(*logins.lock().unwrap(), 2);
+
+    // A session that keeps being rejected is an error.
+    clear_registry_cache();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_SESSION_LOGIN", "true")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_USERNAME", "user")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_PASSWORD", "pass")
+        .with_status(101)
+        .with_stderr_contains("  the server rejected the session even after logging in again")
+        .run();
+}
+
+#[cargo_test]
+fn verify_all_repairs_and_reports() {
+    use cargo::core::{PackageId, Shell};
+    use cargo::sources::VerifyAction;
+
+    let registry = registry::init();
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "mod extra;")
+        .file("src/extra.rs", "")
+        .publish();
+    Package::new("baz", "0.0.1").publish();
+
+    let p = cache_store_project();
+    p.cargo("fetch").run();
+    // Files deleted from an unpacked package aren't noticed otherwise.
+    fs::remove_file(unpacked_bar(&registry).join("src/extra.rs")).unwrap();
+
+    let config = Config::new(
+        Shell::from_write(Box::new(Vec::new())),
+        paths::root(),
+        cargo_home(),
+    );
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    let ids = ["bar", "baz", "missing"].map(|name| PackageId::new(name, "0.0.1", sid).unwrap());
+    let report = source.verify_all(&ids).unwrap();
+
+    assert!(!report.is_ok());
+    assert_eq!(
+        report.verified,
+        vec![
+            (
+                ids[0],
+                vec![VerifyAction::Repaired(vec!["src/extra.rs".to_string()])]
+            ),
+            (
+                ids[1],
+                vec![VerifyAction::Downloaded, VerifyAction::Unpacked]
+            ),
+        ]
+    );
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, ids[2]);
+    assert!(unpacked_bar(&registry).join("src/extra.rs").is_file());
+
+    // Everything is in place now.
+    let report = source.verify_all(&ids[..2]).unwrap();
+    assert!(report.is_ok());
+    assert!(report
+        .verified
+        .iter()
+        .all(|(_, actions)| actions.is_empty()));
+}
+
+#[cargo_test]
+fn version_introduced() {
+    use cargo::core::Shell;
+
+    let registry = registry::init();
+    let head = || {
+        git2::Repository::open(registry_path())
+            .unwrap()
+            .head()
+            .unwrap()
+            .target()
+            .unwrap()
+            .to_string()
+    };
+    Package::new("bar", "0.0.1").publish();
+    let first = head();
+    Package::new("baz", "0.0.1").publish();
+    Package::new("bar", "0.0.2").publish();
+    let second = head();
+
+    let p = cache_store_project();
+    p.cargo("generate-lockfile").run();
+
+    let config = Config::new(
+        Shell::from_write(Box::new(Vec::new())),
+        paths::root(),
+        cargo_home(),
+    );
+    let (_lock, _, source) = remote_source(&config, &registry);
+    let introduced = |vers: &str| {
+        source
+            .version_introduced("bar", &vers.parse().unwrap())
+            .unwrap()
+    };
+    assert_eq!(introduced("0.0.1"), Some(first));
+    assert_eq!(introduced("0.0.2"), Some(second));
+    assert_eq!(introduced("0.0.3"), None);
+}
+
+#[cargo_test]
+fn cache_layout_version() {
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+    let p = cache_store_project();
+    p.cargo("fetch").run();
+
+    let sid = SourceId::for_registry(registry.index_url()).unwrap();
+    let cache = cargo_home()
+        .join("registry/cache")
+        .join(format!("registry-{}", cargo::util::hex::short_hash(&sid)));
+    let layout = cache.join("cache-layout-version");
+    assert_eq!(fs::read_to_string(&layout).unwrap(), "1\n");
+
+    fs::write(&layout, "2\n").unwrap();
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains(
+            "  the download cache at `[..]` was written by a newer version of Cargo, with layout version 2, \
+but this version of Cargo only supports layout version 1
+  use a newer version of Cargo, or remove the directory to download the packages again",
+        )
+        .run();
+
+    // Caches from before the file was added have the current layout.
+    fs::remove_file(&layout).unwrap();
+    p.cargo("fetch").run();
+}
+
+/// Writes the `index.sha256` manifest an index mirror serves for the files
+/// of the test registry.
+fn write_mirror_manifest() {
+    fn walk(root: &Path, dir: &Path, manifest: &mut String) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let rel = path.strip_prefix(root).unwrap();
+            if rel.to_str().unwrap().starts_with('.') || rel == Path::new("index.sha256") {
+                continue;
+            }
+            if path.is_dir() {
+                walk(root, &path, manifest);
+            } else {
+                let cksum = registry::cksum(&fs::read(&path).unwrap());
+                let rel = rel.to_str().unwrap().replace('\\', "/");
+                writeln!(manifest, "{cksum}  {rel}").unwrap();
+            }
+        }
+    }
+    let mut manifest = String::new();
+    walk(&registry_path(), &registry_path(), &mut manifest);
+    fs::write(registry_path().join("index.sha256"), manifest).unwrap();
+}
+
+#[cargo_test]
+fn index_mirror() {
+    let config_fetches = Arc::new(Mutex::new(0));
+    let config_fetches2 = config_fetches.clone();
+    let server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/index/config.json", move |req, server| {
+            *config_fetches2.lock().unwrap() += 1;
+            server.index(req)
+        })
+        .build();
+    let mirror = server.index_url().as_str().replace("sparse+", "");
+    Package::new("bar", "0.0.1").publish();
+    write_mirror_manifest();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRIES_DUMMY_REGISTRY_INDEX_MIRROR", &mirror)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+",
+        )
+        .run();
+    let local = glob::glob(
+        paths::home()
+            .join(".cargo/registry/index/*-mirror")
+            .to_str()
+            .unwrap(),
+    )
+    .unwrap()
+    .next()
+    .unwrap()
+    .unwrap();
+    assert!(local.join("3/b/bar").is_file());
+
+    // Only the shards that changed are fetched again, and removed ones are
+    // removed locally as well.
+    Package::new("bar", "0.0.2").publish();
+    fs::create_dir_all(local.join("3/q")).unwrap();
+    fs::write(local.join("3/q/qux"), "").unwrap();
+    let manifest = fs::read_to_string(local.join("index.sha256")).unwrap();
+    fs::write(
+        local.join("index.sha256"),
+        format!("{manifest}{}  3/q/qux\n", registry::cksum(b"")),
+    )
+    .unwrap();
+    write_mirror_manifest();
+    p.cargo("update")
+        .env("CARGO_REGISTRIES_DUMMY_REGISTRY_INDEX_MIRROR", &mirror)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[UPDATING] bar v0.0.1 -> v0.0.2
+",
+        )
+        .run();
+    assert_eq!(*config_fetches.lock().unwrap(), 1);
+    assert!(!local.join("3/q/qux").exists());
+}
+
+#[cargo_test]
+fn release_build_skips_dev_dependency_downloads() {
+    let _server = setup_http();
+    Package::new("bar", "0.0.1").publish();
+    Package::new("baz", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+
+                [dev-dependencies]
+                baz = "0.0.1"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("build --release")
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+[COMPILING] bar v0.0.1
+[COMPILING] foo v0.0.1 ([CWD])
+[FINISHED] release [optimized] target(s) in [..]s
+",
+        )
+        .run();
+
+    // The dev-dependency is only downloaded once something needs it.
+    p.cargo("test --release --no-run")
+        .with_stderr_contains("[DOWNLOADED] baz v0.0.1 (registry `dummy-registry`)")
+        .run();
+}
+
+#[cargo_test]
+fn record_and_replay_session() {
+    let cassette = paths::root().join("cassette");
+    let server = RegistryBuilder::new()
+        .http_index()
+        .record(&cassette)
+        .build();
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    let expected = "\
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `dummy-registry`)
+";
+    p.cargo("fetch").with_stderr(expected).run();
+    server.join();
+    assert!(cassette.join("interactions.json").is_file());
+
+    // Start over with an empty registry, on a server that only knows what was
+    // recorded.
+    paths::root().join("registry").rm_rf();
+    paths::root().join("dl").rm_rf();
+    paths::home().join(".cargo").rm_rf();
+    p.root().join("Cargo.lock").rm_rf();
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .replay(&cassette)
+        .build();
+
+    p.cargo("fetch").with_stderr(expected).run();
+
+    // Anything that wasn't recorded is not found.
+    p.change_file(
+        "Cargo.toml",
+        r#"
+            [package]
+            name = "foo"
+            version = "0.0.1"
+
+            [dependencies]
+            baz = "0.0.1"
+        "#,
+    );
+    p.cargo("fetch")
+        .with_status(101)
+        .with_stderr_contains("[ERROR] no matching package named `baz` found")
+        .run();
+}
+
+#[cargo_test]
+fn dl_host_mismatch() {
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/index/config.json", |req, _server| {
+            // The server listens on 127.0.0.1, download from another name of it.
+            let port = req.url.port().unwrap();
+            Response {
+                code: 200,
+                headers: vec![],
+                body: format!(r#"{{"dl":"http://localhost:{port}/dl"}}"#).into_bytes(),
+            }
+        })
+        .build();
+    Package::new("bar", "0.0.1").publish();
+    Package::new("baz", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_DL_HOST_MISMATCH", "deny")
+        .with_status(101)
+        .with_stderr(
+            "\
+[UPDATING] `dummy-registry` index
+[ERROR] failed to download `baz v0.0.1`
+
+Caused by:
+  unable to get packages from source
+
+Caused by:
+  failed to download replaced source registry `crates-io`
+
+Caused by:
+  registry `dummy-registry` downloads crates from `localhost`, \
+which is not the host of its index
+  Add the host to `registry.known-dl-hosts` if this is expected.
+",
+        )
+        .run();
+
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_DL_HOST_MISMATCH", "deny")
+        .env("CARGO_REGISTRY_KNOWN_DL_HOSTS", "localhost")
+        .with_stderr(
+            "\
+[DOWNLOADING] crates ...
+[DOWNLOADED] [..]
+[DOWNLOADED] [..]
+",
+        )
+        .run();
+
+    // Each host is only reported once.
+    paths::home().join(".cargo/registry/cache").rm_rf();
+    p.cargo("fetch")
+        .env("CARGO_REGISTRY_DL_HOST_MISMATCH", "warn")
+        .with_stderr(
+            "\
+[WARNING] registry `dummy-registry` downloads crates from `localhost`, \
+which is not the host of its index
+Add the host to `registry.known-dl-hosts` if this is expected.
+[DOWNLOADING] crates ...
+[DOWNLOADED] [..]
+[DOWNLOADED] [..]
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn unpack_matching() {
+    use super::config::ConfigBuilder;
+    use cargo::core::{PackageId, Source};
+
+    let registry = setup_http();
+    let mut bar = Package::new("bar", "0.0.1");
+    bar.file("build.rs", "fn main() {}")
+        .file("src/lib.rs", "")
+        .file("proto/a.proto", "")
+        .file("proto/b.txt", "")
+        .publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    let pkg = PackageId::new("bar", "0.0.1", sid).unwrap();
+    let tarball = File::open(bar.archive_dst()).unwrap();
+    let patterns = ["build.rs", "**/*.proto"].map(|p| glob::Pattern::new(p).unwrap());
+
+    let dir = source.unpack_matching(pkg, &tarball, &patterns).unwrap();
+    assert!(dir.join("build.rs").is_file());
+    assert!(dir.join("proto/a.proto").is_file());
+    assert!(!dir.join("proto/b.txt").exists());
+    assert!(!dir.join("src/lib.rs").exists());
+    assert!(!dir.join(".cargo-ok").exists());
+    assert!(dir.join(".cargo-partial").is_file());
+
+    // Unpacking the whole package completes it.
+    source.download(pkg).unwrap();
+    source
+        .finish_download(pkg, fs::read(bar.archive_dst()).unwrap())
+        .unwrap();
+    assert!(dir.join("src/lib.rs").is_file());
+    assert!(dir.join("proto/b.txt").is_file());
+    assert!(dir.join(".cargo-ok").is_file());
+    assert!(!dir.join(".cargo-partial").exists());
+}
+
+#[cargo_test]
+fn query_iter_parses_lazily() {
+    use super::config::ConfigBuilder;
+    use cargo::core::{Dependency, QueryKind, Source};
+    use cargo::util::network::PollExt;
+
+    let registry = setup_http();
+    for patch in 0..10 {
+        Package::new("bar", &format!("0.1.{patch}")).publish();
+    }
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    let dep = Dependency::parse("bar", Some("0.1"), sid).unwrap();
+
+    while source.query_iter(&dep).is_pending() {
+        source.block_until_ready().unwrap();
+    }
+    let first = source
+        .query_iter(&dep)
+        .expect("index is loaded")
+        .unwrap()
+        .next()
+        .unwrap();
+    assert_eq!(first.name().as_str(), "bar");
+    // Only the version that was asked for has been parsed.
+    assert_eq!(source.dump_hashes().len(), 1);
+
+    let all = source
+        .query_vec(&dep, QueryKind::Exact)
+        .expect("index is loaded")
+        .unwrap();
+    assert_eq!(all.len(), 10);
+    assert_eq!(source.dump_hashes().len(), 10);
+}
+
+#[cargo_test]
+fn query_req() {
+    use cargo::core::Shell;
+
+    let registry = registry::init();
+    for vers in ["1.0.0", "1.2.0", "1.4.0", "2.0.0"] {
+        Package::new("bar", vers).publish();
+    }
+
+    let config = Config::new(
+        Shell::from_write(Box::new(Vec::new())),
+        paths::root(),
+        cargo_home(),
+    );
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+
+    let mut versions: Vec<_> = source
+        .query_req("bar", ">=1.2, <2")
+        .unwrap()
+        .iter()
+        .map(|s| s.version().to_string())
+        .collect();
+    versions.sort();
+    assert_eq!(versions, ["1.2.0", "1.4.0"]);
+    assert!(source.query_req("baz", "*").unwrap().is_empty());
+
+    let err = source.query_req("bar", "one point two").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "failed to parse the version requirement `one point two` for dependency `bar`"
+    );
+}
+
+#[cargo_test]
+fn index_line() {
+    use cargo::core::{PackageId, Shell};
+
+    let registry = registry::init();
+    Package::new("bar", "1.0.0").publish();
+    Package::new("bar", "1.1.0").yanked(true).publish();
+
+    let config = Config::new(
+        Shell::from_write(Box::new(Vec::new())),
+        paths::root(),
+        cargo_home(),
+    );
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+
+    let bar = source.query_req("bar", "=1.0.0").unwrap()[0].package_id();
+    // Lines aren't kept by default.
+    assert_eq!(source.index_line(bar).unwrap(), None);
+
+    source.keep_index_lines(true);
+    let index = fs::read_to_string(registry_path().join("3/b/bar")).unwrap();
+    let lines: Vec<_> = index.lines().collect();
+    assert_eq!(source.index_line(bar).unwrap().as_deref(), Some(lines[0]));
+    let yanked = PackageId::new("bar", "1.1.0", sid).unwrap();
+    assert_eq!(
+        source.index_line(yanked).unwrap().as_deref(),
+        Some(lines[1])
+    );
+    let missing = PackageId::new("bar", "2.0.0", sid).unwrap();
+    assert_eq!(source.index_line(missing).unwrap(), None);
+}
+
+#[cargo_test]
+fn index_read_cache_shared_between_sources() {
+    use super::config::ConfigBuilder;
+    use cargo::core::Source;
+    use cargo::sources::IndexReadCache;
+    use std::rc::Rc;
+
+    let registry = registry::init();
+    Package::new("bar", "1.0.0").publish();
+
+    let cache = Rc::new(IndexReadCache::new());
+    let config = ConfigBuilder::new().build();
+    let (lock, sid, mut source) = remote_source(&config, &registry);
+    let versions = |source: &mut RegistrySource<'_>| {
+        let mut versions: Vec<_> = source
+            .query_req("bar", "*")
+            .unwrap()
+            .iter()
+            .map(|s| s.version().to_string())
+            .collect();
+        versions.sort();
+        versions
+    };
+    source.set_index_read_cache(Rc::clone(&cache));
+    assert_eq!(versions(&mut source), ["1.0.0"]);
+
+    // Another source reuses what the first one read, so it doesn't even
+    // recreate the on-disk cache of the index file.
+    let index_root = config.registry_index_path().into_path_unlocked();
+    let index = fs::read_dir(&index_root).unwrap().next().unwrap().unwrap();
+    let cache_file = index.path().join(".cache/3/b/bar");
+    assert!(cache_file.is_file());
+    fs::remove_file(&cache_file).unwrap();
+    let mut source = RegistrySource::remote(sid, &HashSet::new(), &config).unwrap();
+    source.set_index_read_cache(Rc::clone(&cache));
+    assert_eq!(versions(&mut source), ["1.0.0"]);
+    assert!(!cache_file.exists());
+    drop(lock);
+
+    // Once the index moves on, what was read before isn't used anymore.
+    Package::new("bar", "1.1.0").publish();
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+    source.set_index_read_cache(Rc::clone(&cache));
+    source.invalidate_cache();
+    assert_eq!(versions(&mut source), ["1.0.0", "1.1.0"]);
+}
+
+#[cargo_test]
+fn crate_exists() {
+    use super::config::ConfigBuilder;
+
+    let registry = registry::init();
+    Package::new("foo-bar", "0.0.1").publish();
+    Package::new("baz", "0.0.1").yanked(true).publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+    assert!(source.crate_exists("foo-bar").unwrap());
+    assert!(source.crate_exists("foo_bar").unwrap());
+    assert!(source.crate_exists("Foo-Bar").unwrap());
+    // Yanked versions still count.
+    assert!(source.crate_exists("baz").unwrap());
+    assert!(!source.crate_exists("foo").unwrap());
+    assert!(!source.crate_exists("foo-baz").unwrap());
+}
+
+#[cargo_test]
+fn index_io_stats() {
+    use super::config::ConfigBuilder;
+
+    let registry = registry::init();
+    Package::new("bar", "1.0.0").publish();
+    Package::new("bar", "1.1.0").publish();
+    Package::new("baz", "1.0.0").publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+    assert_eq!(source.io_stats(), Default::default());
+
+    source.query_req("bar", "=1.0.0").unwrap();
+    let stats = source.io_stats();
+    assert_eq!(stats.files_read, 1);
+    assert_eq!(stats.cache_misses, 1);
+    assert!(stats.bytes_read > 0);
+    assert!(stats.lines_parsed >= 1);
+
+    // Versions already in memory aren't read again.
+    source.query_req("bar", "*").unwrap();
+    let again = source.io_stats();
+    assert_eq!(again.files_read, 1);
+    assert_eq!(again.bytes_read, stats.bytes_read);
+    assert!(again.cache_hits > stats.cache_hits);
+    assert_eq!(again.lines_parsed, 2);
+
+    source.query_req("baz", "*").unwrap();
+    let stats = source.io_stats();
+    assert_eq!(stats.files_read, 2);
+    assert_eq!(stats.cache_misses, 2);
+    assert_eq!(stats.lines_parsed, 3);
+}
+
+#[cargo_test]
+fn query_parses_only_matching_versions() {
+    use super::config::ConfigBuilder;
+
+    let registry = registry::init();
+    for patch in 0..50 {
+        Package::new("bar", &format!("1.0.{patch}"))
+            .dep("baz", "1.0")
+            .feature("std", &[])
+            .publish();
+    }
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+
+    let summaries = source.query_req("bar", "=1.0.7").unwrap();
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].version().to_string(), "1.0.7");
+    assert_eq!(summaries[0].dependencies().len(), 1);
+    assert_eq!(source.io_stats().lines_parsed, 1);
+
+    let summaries = source.query_req("bar", ">=1.0.40").unwrap();
+    assert_eq!(summaries.len(), 10);
+    assert_eq!(source.io_stats().lines_parsed, 11);
+}
+
+#[cargo_test]
+fn download_without_query() {
+    use super::config::ConfigBuilder;
+    use cargo::core::source::MaybePackage;
+    use cargo::core::{PackageId, Source};
+
+    let registry = registry::init();
+    let bar = Package::new("bar", "1.0.0");
+    bar.publish();
+    let data = fs::read(bar.archive_dst()).unwrap();
+
+    let sid = SourceId::for_registry(registry.index_url()).unwrap();
+    let pkg = PackageId::new("bar", "1.0.0", sid).unwrap();
+    // The second time around, the checksum comes from the on-disk cache of
+    // the index that the first one wrote.
+    for _ in 0..2 {
+        let config = ConfigBuilder::new().build();
+        let (_lock, _, mut source) = remote_source(&config, &registry);
+        match source.download(pkg).unwrap() {
+            MaybePackage::Download { url, .. } => {
+                assert!(url.ends_with("/bar/1.0.0/download"), "{url}")
+            }
+            MaybePackage::Ready(_) => panic!("`bar` is not downloaded yet"),
+        }
+        // The checksum is known without ever querying `bar`, so a download
+        // that doesn't match is rejected.
+        let err = source
+            .finish_download(pkg, b"not bar".to_vec())
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("failed to verify the checksum of `bar v1.0.0"),
+            "{err:#}"
+        );
+        assert_eq!(source.io_stats().files_read, 1);
+    }
+
+    // And one that matches is accepted.
+    let config = ConfigBuilder::new().build();
+    let (_lock, _, mut source) = remote_source(&config, &registry);
+    source.download(pkg).unwrap();
+    let bar = source.finish_download(pkg, data).unwrap();
+    assert_eq!(bar.package_id(), pkg);
+}
+
+#[cargo_test]
+fn query_unshardable_name() {
+    use super::config::ConfigBuilder;
+    use cargo::core::{Dependency, PackageId, QueryKind, Source};
+    use cargo::util::network::PollExt;
+    use std::task::Poll;
+
+    let registry = registry::init();
+    Package::new("bar", "0.0.1").publish();
+
+    let config = ConfigBuilder::new().build();
+    let (_lock, sid, mut source) = remote_source(&config, &registry);
+    for (name, expected) in [
+        ("", "an empty name"),
+        ("éa", "the name `éa`"),
+        ("aébc", "the name `aébc`"),
+    ] {
+        // `Dependency` insists on a name, but nothing stops a `PackageId`,
+        // for example from a lock file, from having an empty one.
+        let pkg = PackageId::new(name, "0.0.1", sid).unwrap();
+        let err = loop {
+            match source.is_yanked(pkg) {
+                Poll::Ready(res) => break res.unwrap_err(),
+                Poll::Pending => source.block_until_ready().unwrap(),
+            }
+        };
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "cannot look up a package with {expected} in registry `{}`, \
+                 as it has no path in the index",
+                sid.display_registry_name()
+            )
+        );
+    }
+
+    // Names with multi-byte characters that shard cleanly still work.
+    let dep = Dependency::parse("ébar", None, sid).unwrap();
+    while source.query_vec(&dep, QueryKind::Exact).is_pending() {
+        source.block_until_ready().unwrap();
+    }
+    let found = source
+        .query_vec(&dep, QueryKind::Exact)
+        .expect("index is loaded")
+        .unwrap();
+    assert!(found.is_empty());
+}
+
+#[cargo_test]
+fn client_certificate_problem() {
+    // Accepts connections without ever answering, as the client gives up
+    // on its certificate before the handshake gets anywhere.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let tls_port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let _connections: Vec<_> = listener.incoming().collect();
+    });
+    let _server = RegistryBuilder::new()
+        .http_index()
+        .add_responder("/index/config.json", move |_req, _server| Response {
+            code: 200,
+            headers: vec![],
+            body: format!(r#"{{"dl":"https://127.0.0.1:{tls_port}/dl"}}"#).into_bytes(),
+        })
+        .build();
+    Package::new("bar", "0.0.1").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file("client.pem", "not a certificate")
+        .build();
+
+    p.cargo("fetch")
+        .env("CARGO_HTTP_SSL_CERT", "client.pem")
+        .env("CARGO_HTTP_SSL_PASSWORD", "sekrit-password")
+        .env("CARGO_HTTP_DEBUG", "true")
+        .env("CARGO_LOG", "network=debug")
+        .with_status(101)
+        .with_stderr_contains(
+            "\
+Caused by:
+  the client certificate or its key could not be used, \
+check `http.ssl-cert`, `http.ssl-key`, and the key's password
+
+Caused by:
+  [58] [..]",
+        )
+        .with_stderr_does_not_contain("[..]sekrit-password[..]")
+        .run();
+
+    p.cargo("fetch")
+        .env("CARGO_HTTP_SSL_KEY", "client.pem")
+        .with_status(101)
+        .with_stderr_contains("[..]`http.ssl-key` is set, but not `http.ssl-cert`")
+        .run();
+}
@@ -588,3 +588,79 @@ note: Waiting [..]
     assert_eq!(authorizations.len(), 7);
     assert!(!log.contains("a-unique_token"));
 }
+
+fn basic_auth_registry() -> cargo_test_support::registry::TestRegistry {
+    RegistryBuilder::new()
+        .alternative()
+        .http_index()
+        .add_responder("/dl/bar/0.0.1/download", |req, server| {
+            // "user:pass" in base64
+            if req.authorization.as_deref() == Some("Basic dXNlcjpwYXNz") {
+                server.dl(req)
+            } else {
+                server.unauthorized(req)
+            }
+        })
+        .build()
+}
+
+#[cargo_test]
+fn basic_auth() {
+    let _registry = basic_auth_registry();
+
+    let p = make_project();
+    p.cargo("build")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_USERNAME", "user")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_PASSWORD", "pass")
+        .with_stderr(SUCCESS_OUTPUT)
+        .run();
+}
+
+#[cargo_test]
+fn basic_auth_wrong_credentials() {
+    let _registry = basic_auth_registry();
+
+    let p = make_project();
+    p.cargo("build")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_USERNAME", "user")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_PASSWORD", "wrong")
+        .with_status(101)
+        .with_stderr(
+            r#"[UPDATING] `alternative` index
+[DOWNLOADING] crates ...
+error: failed to download from `[..]/dl/bar/0.0.1/download`
+
+Caused by:
+  the server requires authentication, the HTTP basic auth credentials for this registry may be missing or wrong
+  set `registries.<name>.username` and `registries.<name>.password` to authenticate
+
+Caused by:
+  failed to get successful HTTP response from `[..]` (127.0.0.1), got 401
+  body:
+  Unauthorized message from server.
+"#,
+        )
+        .run();
+}
+
+#[cargo_test]
+fn basic_auth_missing_password() {
+    let _registry = basic_auth_registry();
+
+    let p = make_project();
+    p.cargo("build")
+        .env("CARGO_REGISTRIES_ALTERNATIVE_USERNAME", "user")
+        .with_status(101)
+        .with_stderr(
+            r#"[UPDATING] `alternative` index
+[ERROR] failed to download `bar v0.0.1 (registry `alternative`)`
+
+Caused by:
+  unable to get packages from source
+
+Caused by:
+  both `username` and `password` must be set to use HTTP basic auth for registry `alternative`
+"#,
+        )
+        .run();
+}
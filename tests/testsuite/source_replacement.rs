@@ -2,7 +2,7 @@
 
 use std::fs;
 
-use cargo_test_support::registry::{Package, RegistryBuilder, TestRegistry};
+use cargo_test_support::registry::{self, Package, RegistryBuilder, TestRegistry};
 use cargo_test_support::{cargo_process, paths, project, t};
 
 fn setup_replacement(config: &str) -> TestRegistry {
@@ -248,3 +248,164 @@ fn undefined_default() {
         )
         .run();
 }
+
+/// Sets up crates.io to be replaced with the "alternative" registry, falling
+/// back to crates.io itself with the given `fallback-policy`.
+fn setup_fallbacks(policy: &str) {
+    let alt = registry::alt_init();
+    let root = paths::root();
+    t!(fs::create_dir(&root.join(".cargo")));
+    t!(fs::write(
+        root.join(".cargo/config"),
+        format!(
+            r#"
+                [source.crates-io]
+                replace-with = 'internal'
+
+                [source.internal]
+                registry = '{}'
+                fallbacks = ['dummy-registry']
+                fallback-policy = '{}'
+            "#,
+            alt.index_url(),
+            policy
+        )
+    ));
+}
+
+#[cargo_test]
+fn fallbacks_first() {
+    setup_fallbacks("first");
+    Package::new("bar", "0.0.1")
+        .file("src/lib.rs", "pub fn public() {}")
+        .publish();
+    Package::new("bar", "0.0.1")
+        .alternative(true)
+        .file("src/lib.rs", "pub fn internal() {}")
+        .publish();
+    Package::new("baz", "0.1.0")
+        .file("src/lib.rs", "pub fn public() {}")
+        .publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+                baz = "0.1.0"
+            "#,
+        )
+        .file(
+            "src/lib.rs",
+            "pub fn f() { bar::internal(); baz::public(); }",
+        )
+        .build();
+
+    p.cargo("check")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `internal` index
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.1 (registry `internal`)
+[DOWNLOADED] baz v0.1.0 (registry `dummy-registry`)
+[CHECKING] bar v0.0.1
+[CHECKING] baz v0.1.0
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] [..]
+",
+        )
+        .run();
+
+    // Both are locked as coming from crates.io, the replaced source.
+    let lock = p.read_lockfile();
+    assert_eq!(
+        lock.matches("registry+https://github.com/rust-lang/crates.io-index")
+            .count(),
+        2
+    );
+}
+
+#[cargo_test]
+fn fallbacks_merge() {
+    setup_fallbacks("merge");
+    Package::new("bar", "0.0.1").alternative(true).publish();
+    Package::new("bar", "0.0.2").publish();
+
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("check")
+        .with_stderr_unordered(
+            "\
+[UPDATING] `internal` index
+[UPDATING] `dummy-registry` index
+[DOWNLOADING] crates ...
+[DOWNLOADED] bar v0.0.2 (registry `dummy-registry`)
+[CHECKING] bar v0.0.2
+[CHECKING] foo v0.0.1 ([CWD])
+[FINISHED] [..]
+",
+        )
+        .run();
+}
+
+#[cargo_test]
+fn fallbacks_unknown_source() {
+    let _alt = registry::alt_init();
+    let root = paths::root();
+    t!(fs::create_dir(&root.join(".cargo")));
+    t!(fs::write(
+        root.join(".cargo/config"),
+        r#"
+            [source.crates-io]
+            replace-with = 'internal'
+
+            [source.internal]
+            directory = 'vendor'
+            fallbacks = ['missing']
+        "#
+    ));
+    let p = project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "0.0.1"
+
+                [dependencies]
+                bar = "0.0.1"
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .build();
+
+    p.cargo("check")
+        .with_status(101)
+        .with_stderr_contains(
+            "\
+Caused by:
+  could not find a configured source with the name `missing` listed in \
+`source.internal.fallbacks` (configuration in `[..]config`)
+",
+        )
+        .run();
+}
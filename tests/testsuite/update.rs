@@ -571,7 +571,7 @@ fn update_precise_first_run() {
       "license": null,
       "license_file": null,
       "links": null,
-      "manifest_path": "[..]/home/.cargo/registry/src/-[..]/serde-0.2.0/Cargo.toml",
+      "manifest_path": "[..]/home/.cargo/registry/src/registry-[..]/serde-0.2.0/Cargo.toml",
       "metadata": null,
       "publish": null,
       "name": "serde",
@@ -591,7 +591,7 @@ fn update_precise_first_run() {
             "lib"
           ],
           "name": "serde",
-          "src_path": "[..]/home/.cargo/registry/src/-[..]/serde-0.2.0/src/lib.rs",
+          "src_path": "[..]/home/.cargo/registry/src/registry-[..]/serde-0.2.0/src/lib.rs",
           "test": true
         }
       ],